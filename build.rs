@@ -6,7 +6,11 @@ fn main() {
     // prost_build::Config::new()
     //     .out_dir("src/generated")
     //     .compile_protos(
-    //         &["schema/osmformat.proto", "schema/fileformat.proto"],
+    //         &[
+    //             "schema/osmformat.proto",
+    //             "schema/fileformat.proto",
+    //             "schema/vector_tile.proto",
+    //         ],
     //         &["schema"],
     //     )
     //     .expect("failed to compile protobuf");
@@ -8,5 +8,7 @@ export function pvt(): Promise<void>
 export class Planet {
   constructor(tiles: Array<string>)
   tile(z: number, x: number, y: number): Promise<Uint8Array>
+  /** The same tile as `tile`, encoded as a standard Mapbox Vector Tile. */
+  tileMvt(z: number, x: number, y: number): Promise<Uint8Array>
   abort(z: number, x: number, y: number): void
 }
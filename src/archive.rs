@@ -0,0 +1,360 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use memmap2::Mmap;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::manifest::Manifest;
+use crate::util::{finish, timer};
+
+// A .pvt archive packs everything needed to serve a planet into a single file.
+//
+// The file is a plain, uncompressed ustar archive. This lets flatdata open the
+// Osm archive in place with its TarArchiveResourceStorage, and lets anyone unpack
+// a planet directory again with `tar -xf`. Tar puts every entry's data on a 512
+// byte boundary, so the Mutant and flatdata vectors can be memory mapped without
+// copying them out of the archive.
+//
+// The first entry, pvt.index, is our header and offset table, so that we do not
+// have to walk the tar headers to find the entries. All integers are little endian:
+//
+//   magic        8 bytes  "PVTARCH\0"
+//   version      u32
+//   entry count  u32
+//   entries      ENTRY_SIZE bytes each
+//     name       NAME_SIZE bytes, zero padded
+//     offset     u64, from the start of the archive
+//     len        u64
+
+pub const MAGIC: &[u8; 8] = b"PVTARCH\0";
+pub const VERSION: u32 = 1;
+pub const INDEX_NAME: &str = "pvt.index";
+
+const HEADER_SIZE: usize = 16;
+const NAME_SIZE: usize = 48;
+const ENTRY_SIZE: usize = NAME_SIZE + 16;
+const BLOCK_SIZE: u64 = 512;
+
+// The flatdata resources of the Osm archive. Each has a corresponding .schema resource.
+const FLATDATA_RESOURCES: [&str; 11] = [
+    "Osm.archive",
+    "header",
+    "nodes",
+    "hilbert_node_pairs",
+    "ways",
+    "relations",
+    "members",
+    "tags",
+    "tags_index",
+    "nodes_index",
+    "stringtable",
+];
+
+// Optional flatdata resources, which are only archived when the planet has them.
+const OPTIONAL_FLATDATA_RESOURCES: [&str; 3] =
+    ["node_metadata", "way_metadata", "relation_metadata"];
+
+// The Hilbert tree, its rendered content, and the build outputs of the planet.
+const PLANET_RESOURCES: [&str; 11] = [
+    "hilbert_way_pairs",
+    "hilbert_relation_pairs",
+    "hilbert_tiles",
+    "hilbert_leaves",
+    "hilbert_leaves_external_ways",
+    "hilbert_leaves_external_relations",
+    "n",
+    "w",
+    "r",
+    "rules.yaml",
+    "manifest.yaml",
+];
+
+type Err = Box<dyn std::error::Error>;
+
+/// The names of all of the files in a planet directory that go into a .pvt archive.
+pub fn resource_names() -> Vec<String> {
+    let mut names = Vec::with_capacity(FLATDATA_RESOURCES.len() * 2 + PLANET_RESOURCES.len());
+    for name in FLATDATA_RESOURCES {
+        names.push(name.to_string());
+        names.push(format!("{}.schema", name));
+    }
+    for name in PLANET_RESOURCES {
+        names.push(name.to_string());
+    }
+    names
+}
+
+/// Packs the planet directory of the manifest into the single .pvt file at `data.archive`.
+pub fn create(manifest: &Manifest, overwrite: bool) -> Result<(), Err> {
+    let planet = &manifest.data.planet;
+    let archive_path = &manifest.data.archive;
+
+    if archive_path.exists() && !overwrite {
+        let msg = format!(
+            "Archive already exists at: {}. If you want to overwrite it, add the argument --overwrite.",
+            archive_path.display()
+        );
+        return Err(Box::new(Error::new(ErrorKind::AlreadyExists, msg)));
+    }
+
+    let t = timer(&format!("Archiving planet to {}", archive_path.display()));
+
+    let mut files = Vec::new();
+    for name in resource_names() {
+        let path = planet.join(&name);
+        let len = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                let msg = format!(
+                    "Unable to archive {}. Has the planet been built and rendered? Err: {}",
+                    path.display(),
+                    e
+                );
+                return Err(Box::new(Error::new(e.kind(), msg)));
+            }
+        };
+        files.push((name, len));
+    }
+    for name in OPTIONAL_FLATDATA_RESOURCES {
+        for name in [name.to_string(), format!("{}.schema", name)] {
+            if let Ok(metadata) = fs::metadata(planet.join(&name)) {
+                files.push((name, metadata.len()));
+            }
+        }
+    }
+
+    if let Some(dir) = archive_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // The index is the first entry, and every tar entry is a 512 byte header
+    // followed by its data padded to 512 bytes, so we know every offset up front.
+    let index_len = (HEADER_SIZE + ENTRY_SIZE * files.len()) as u64;
+    let mut index = Vec::with_capacity(index_len as usize);
+    index.write_all(MAGIC)?;
+    index.write_u32::<LittleEndian>(VERSION)?;
+    index.write_u32::<LittleEndian>(files.len() as u32)?;
+
+    let mut offset = BLOCK_SIZE + align(index_len) + BLOCK_SIZE;
+    for (name, len) in &files {
+        index.write_all(&entry_name(name)?)?;
+        index.write_u64::<LittleEndian>(offset)?;
+        index.write_u64::<LittleEndian>(*len)?;
+        offset += align(*len) + BLOCK_SIZE;
+    }
+
+    // Write to a temporary file first, so that a failed archive never clobbers a good one.
+    let tmp_path = archive_path.with_extension("pvt.tmp");
+    let file = File::create(&tmp_path)?;
+    let mut writer = tar::Builder::new(BufWriter::with_capacity(1024 * 1024 * 32, file));
+
+    writer.append(&tar_header(INDEX_NAME, index_len)?, index.as_slice())?;
+
+    for (name, len) in &files {
+        let file = File::open(planet.join(name))?;
+        writer.append(&tar_header(name, *len)?, file)?;
+        println!("  {} {} bytes", name, len);
+    }
+
+    let mut writer = writer.into_inner()?;
+    writer.flush()?;
+    drop(writer);
+
+    verify(&tmp_path, &files)?;
+    fs::rename(&tmp_path, archive_path)?;
+
+    finish(t);
+    Ok(())
+}
+
+/// A read-only, memory mapped .pvt archive.
+#[derive(Debug)]
+pub struct Archive {
+    pub path: PathBuf,
+    mmap: Mmap,
+    entries: BTreeMap<String, Range<usize>>,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        // The index is the data of the first tar entry, right after its header block.
+        let base = BLOCK_SIZE as usize;
+        if mmap.len() < base + HEADER_SIZE || &mmap[base..base + 8] != MAGIC {
+            let msg = format!("{} is not a .pvt archive.", path.display());
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+
+        let version = LittleEndian::read_u32(&mmap[base + 8..base + 12]);
+        if version != VERSION {
+            let msg = format!(
+                "Unsupported .pvt archive version {}. Expected version {}. Rebuild it with pvt archive.",
+                version, VERSION
+            );
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+
+        let count = LittleEndian::read_u32(&mmap[base + 12..base + 16]) as usize;
+        if mmap.len() < base + HEADER_SIZE + ENTRY_SIZE * count {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The .pvt archive offset table is truncated.",
+            ));
+        }
+
+        let mut entries = BTreeMap::new();
+        for i in 0..count {
+            let start = base + HEADER_SIZE + ENTRY_SIZE * i;
+            let entry = &mmap[start..start + ENTRY_SIZE];
+            let name_bytes = &entry[..NAME_SIZE];
+            let name_len = name_bytes.iter().position(|b| *b == 0).unwrap_or(NAME_SIZE);
+            let name = match std::str::from_utf8(&name_bytes[..name_len]) {
+                Ok(name) => name.to_string(),
+                Err(_) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Invalid entry name in .pvt archive.",
+                    ))
+                }
+            };
+            let offset = LittleEndian::read_u64(&entry[NAME_SIZE..NAME_SIZE + 8]) as usize;
+            let len = LittleEndian::read_u64(&entry[NAME_SIZE + 8..]) as usize;
+            if offset + len > mmap.len() {
+                let msg = format!("Entry {} is out of bounds of the .pvt archive.", name);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            entries.insert(name, offset..offset + len);
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            mmap,
+            entries,
+        })
+    }
+
+    /// The byte range of the named entry in the archive file.
+    pub fn range(&self, name: &str) -> Option<Range<usize>> {
+        self.entries.get(name).cloned()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries.get(name).map(|r| &self.mmap[r.clone()])
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    /// The manifest the planet was built with.
+    /// The data paths point at the archive rather than a planet directory.
+    pub fn manifest(&self) -> Result<Manifest, Err> {
+        let bytes = match self.get("manifest.yaml") {
+            Some(bytes) => bytes,
+            None => {
+                let msg = format!("No manifest.yaml in {}", self.path.display());
+                return Err(Box::new(Error::new(ErrorKind::NotFound, msg)));
+            }
+        };
+        let mut manifest: Manifest = serde_yaml::from_slice(bytes)?;
+        manifest.data.planet = self.path.clone();
+        manifest.data.archive = self.path.clone();
+        Ok(manifest)
+    }
+}
+
+// Checks the offset table of a freshly written archive against the tar headers
+// before it replaces the old one.
+fn verify(path: &Path, files: &[(String, u64)]) -> Result<(), Err> {
+    let archive = Archive::open(path)?;
+    if archive.names().count() != files.len() {
+        return Err(Box::new(Error::other("Archive entry count mismatch.")));
+    }
+
+    let mut tar = tar::Archive::new(File::open(path)?);
+    for entry in tar.entries_with_seek()? {
+        let entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if name == INDEX_NAME {
+            continue;
+        }
+        let start = entry.raw_file_position() as usize;
+        let len = entry.size() as usize;
+        if archive.range(&name) != Some(start..start + len) {
+            let msg = format!("Archive entry {} was not written correctly.", name);
+            return Err(Box::new(Error::other(msg)));
+        }
+    }
+
+    for (name, len) in files {
+        match archive.get(name) {
+            Some(data) if data.len() as u64 == *len => (),
+            _ => {
+                let msg = format!("Archive entry {} was not written correctly.", name);
+                return Err(Box::new(Error::other(msg)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn tar_header(name: &str, len: u64) -> io::Result<tar::Header> {
+    let mut header = tar::Header::new_ustar();
+    header.set_path(name)?;
+    header.set_size(len);
+    header.set_mode(0o644);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    Ok(header)
+}
+
+fn entry_name(name: &str) -> io::Result<[u8; NAME_SIZE]> {
+    let bytes = name.as_bytes();
+    if bytes.len() >= NAME_SIZE {
+        let msg = format!("Archive entry name is too long: {}", name);
+        return Err(Error::new(ErrorKind::InvalidInput, msg));
+    }
+    let mut buf = [0_u8; NAME_SIZE];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    Ok(buf)
+}
+
+fn align(offset: u64) -> u64 {
+    offset.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest;
+
+    #[test]
+    fn test_nodes4_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        manifest.data.archive = dir.path().join("nodes4.pvt");
+        create(&manifest, true).unwrap();
+
+        let archive = Archive::open(&manifest.data.archive).unwrap();
+        assert_eq!(archive.names().count(), resource_names().len());
+
+        for name in resource_names() {
+            let range = archive.range(&name).unwrap();
+            assert!((range.start as u64).is_multiple_of(BLOCK_SIZE));
+            let bytes = fs::read(manifest.data.planet.join(&name)).unwrap();
+            assert_eq!(archive.get(&name).unwrap(), bytes.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_align() {
+        assert_eq!(align(0), 0);
+        assert_eq!(align(1), 512);
+        assert_eq!(align(512), 512);
+        assert_eq!(align(513), 1024);
+    }
+}
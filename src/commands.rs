@@ -13,7 +13,7 @@ pub fn cli() -> Command {
     let overwrite_arg = arg!(-o --overwrite "Overwrite existing planet").default_value("false");
 
     let convert = Command::new("convert")
-        .about("Converts an OSM PBF to planet data")
+        .about("Converts an OSM PBF or OSM XML to planet data")
         .args([manifest_path.clone(), overwrite_arg.clone()]);
 
     let render = Command::new("render")
@@ -26,11 +26,47 @@ pub fn cli() -> Command {
 
     let build = Command::new("build")
         .about("Converts, renders, and archives a planet")
-        .args([manifest_path.clone(), overwrite_arg.clone()]);
+        .args([
+            manifest_path.clone(),
+            overwrite_arg.clone(),
+            arg!(-r --resume "Skip the stages that are already done for the same inputs")
+                .conflicts_with("overwrite"),
+        ]);
+
+    let update = Command::new("update")
+        .about("Applies an OsmChange (.osc) diff to a planet, then renders and archives it")
+        .args([
+            manifest_path.clone(),
+            arg!(<OSC_PATH> "Path to the OsmChange file"),
+            arg!(-s --"sequence-number" <SEQUENCE_NUMBER> "Replication sequence number of the diff. Defaults to the next one")
+                .value_parser(clap::value_parser!(i64)),
+        ]);
+
+    let export = Command::new("export")
+        .about("Exports every tile of a rendered planet into a tile archive, or the planet into an osm.pbf")
+        .args([
+            manifest_path.clone(),
+            arg!(<OUTPUT_PATH> "Path to the exported file"),
+            arg!(-f --format <FORMAT> "Format of the exported archive: pmtiles, mbtiles or pbf")
+                .default_value("pmtiles"),
+            arg!(-t --"tile-format" <TILE_FORMAT> "Encoding of the tiles: mvt or pvt")
+                .default_value("mvt"),
+            arg!(--bbox <BBOX> "Only export the part of the planet in west,south,east,north to pbf")
+                .required(false),
+            arg!(--"hilbert-range" <RANGE> "Only export the part of the planet in the Hilbert range start,end to pbf")
+                .required(false),
+            overwrite_arg.clone(),
+        ]);
 
     let report = Command::new("report")
         .about("Reports statistics about the planet and matching rules.")
         .args([manifest_path.clone()]);
 
-    pvt.subcommands([convert, render, archive, build, report])
+    let validate = Command::new("validate")
+        .about("Checks that a rendered planet is consistent, and fails if it is not")
+        .args([manifest_path.clone()]);
+
+    pvt.subcommands([
+        convert, render, archive, build, update, export, report, validate,
+    ])
 }
@@ -0,0 +1,183 @@
+use flate2::{write::GzEncoder, Compression};
+use rusqlite::{params, Connection};
+use std::io::Write;
+use std::path::Path;
+
+use super::{bounds, encoded_tiles, vector_layers, TileFormat};
+use crate::hilbert::tree::HilbertTree;
+
+// MBTiles 1.3
+// https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md
+
+type Err = Box<dyn std::error::Error>;
+
+pub fn export(tree: &HilbertTree, output: &Path, tile_format: TileFormat) -> Result<(), Err> {
+    let mut conn = Connection::open(output)?;
+    conn.execute_batch(
+        "CREATE TABLE metadata (name text, value text);
+         CREATE TABLE tiles (zoom_level integer, tile_column integer, tile_row integer, tile_data blob);
+         CREATE UNIQUE INDEX tile_index on tiles (zoom_level, tile_column, tile_row);",
+    )?;
+
+    let tx = conn.transaction()?;
+    let mut count = 0;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (tile, data) in encoded_tiles(tree, tile_format) {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            let gzipped = encoder.finish()?;
+            // MBTiles rows are in TMS, which counts y from the south.
+            let tms_y = (1_u32 << tile.z) - 1 - tile.y;
+            insert.execute(params![tile.z, tile.x, tms_y, gzipped])?;
+            count += 1;
+        }
+    }
+    tx.commit()?;
+    println!("  {} tiles", count);
+
+    let leaf_zoom = tree.manifest.render.leaf_zoom;
+    let (west, south, east, north) = bounds(tree);
+    let name = tree
+        .manifest
+        .data
+        .archive
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let format = match tile_format {
+        TileFormat::Mvt => "pbf",
+        TileFormat::Pvt => "pvt",
+    };
+    let json = serde_json::json!({ "vector_layers": vector_layers(tree) });
+
+    let metadata = [
+        ("name", name),
+        ("format", format.to_string()),
+        ("type", "baselayer".to_string()),
+        ("bounds", format!("{},{},{},{}", west, south, east, north)),
+        (
+            "center",
+            format!("{},{},0", (west + east) / 2_f64, (south + north) / 2_f64),
+        ),
+        ("minzoom", "0".to_string()),
+        ("maxzoom", leaf_zoom.to_string()),
+        ("generator", "planet-vector-tile".to_string()),
+        ("json", json.to_string()),
+    ];
+    for (name, value) in metadata {
+        conn.execute(
+            "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+            params![name, value],
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manifest, pvt_builder::PVTBuilder, source::Source, tile::Tile};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_nodes4_mbtiles() {
+        let manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        let tree = HilbertTree::open(&manifest).unwrap();
+        let path = manifest.data.planet.join("nodes4.mbtiles");
+        let _ = std::fs::remove_file(&path);
+        export(&tree, &path, TileFormat::Pvt).unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let maxzoom: String = conn
+            .query_row(
+                "SELECT value FROM metadata WHERE name = 'maxzoom'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(maxzoom, "12");
+
+        let json: String = conn
+            .query_row(
+                "SELECT value FROM metadata WHERE name = 'json'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            json["vector_layers"].as_array().unwrap().len(),
+            tree.rules.layers.len() + 1
+        );
+
+        // Every row is a valid TMS tile for its zoom.
+        let mut stmt = conn
+            .prepare("SELECT zoom_level, tile_row, tile_data FROM tiles")
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, u8>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })
+            .unwrap();
+        for row in rows {
+            let (z, tms_y, data) = row.unwrap();
+            assert!(tms_y < 1 << z);
+            // gzip magic
+            assert_eq!(&data[0..2], &[0x1f, 0x8b]);
+        }
+    }
+
+    #[test]
+    fn test_odd_zooms() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest::parse("tests/fixtures/relations_sort.yaml").unwrap();
+        let tree = HilbertTree::open(&manifest).unwrap();
+        let path = dir.path().join("relations.mbtiles");
+        export(&tree, &path, TileFormat::Pvt).unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles")
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, u8>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, u32>(2)?,
+                    row.get::<_, Vec<u8>>(3)?,
+                ))
+            })
+            .unwrap();
+        let mut zooms = Vec::new();
+        for row in rows {
+            let (z, x, tms_y, data) = row.unwrap();
+            zooms.push(z);
+            if z & 1 == 0 {
+                continue;
+            }
+            // An odd zoom tile is the tile that find composes for it.
+            let tile = Tile::from_zxy(z, x, (1 << z) - 1 - tms_y);
+            let mut pvt = Vec::new();
+            GzDecoder::new(data.as_slice())
+                .read_to_end(&mut pvt)
+                .unwrap();
+            let mut builder = PVTBuilder::new();
+            tree.compose_tile(&tile, &mut builder);
+            assert_eq!(pvt, builder.build());
+        }
+        // The boundary is in every zoom.
+        zooms.sort();
+        zooms.dedup();
+        assert_eq!(zooms, (0..=12).collect::<Vec<u8>>());
+    }
+}
@@ -0,0 +1,191 @@
+mod mbtiles;
+mod pbf;
+mod pmtiles;
+
+pub use pbf::Extent;
+
+use flatdata::FileResourceStorage;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::{
+    hilbert::{compose::LABEL_LAYER, tree::HilbertTree},
+    manifest::Manifest,
+    mvt,
+    osmflat::osmflat_generated::osm::Osm,
+    tile::{planet_vector_tile_generated::root_as_pvttile, Tile},
+    util::{finish, timer},
+};
+
+type Err = Box<dyn std::error::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    PMTiles,
+    MBTiles,
+    Pbf,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Self, Err> {
+        match s {
+            "pmtiles" => Ok(Format::PMTiles),
+            "mbtiles" => Ok(Format::MBTiles),
+            "pbf" => Ok(Format::Pbf),
+            _ => {
+                let msg = format!(
+                    "Unsupported export format: {}. Expected pmtiles, mbtiles or pbf.",
+                    s
+                );
+                Err(Box::new(Error::new(ErrorKind::InvalidInput, msg)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileFormat {
+    Mvt,
+    Pvt,
+}
+
+impl TileFormat {
+    pub fn parse(s: &str) -> Result<Self, Err> {
+        match s {
+            "mvt" => Ok(TileFormat::Mvt),
+            "pvt" => Ok(TileFormat::Pvt),
+            _ => {
+                let msg = format!("Unsupported tile format: {}. Expected mvt or pvt.", s);
+                Err(Box::new(Error::new(ErrorKind::InvalidInput, msg)))
+            }
+        }
+    }
+}
+
+/// Exports every tile of a rendered planet into a single tile archive at `output`,
+/// or the OSM entities of the planet within `extent` into an osm.pbf.
+pub fn export(
+    manifest: &Manifest,
+    output: &Path,
+    format: Format,
+    tile_format: TileFormat,
+    extent: Extent,
+    overwrite: bool,
+) -> Result<(), Err> {
+    if format != Format::Pbf && extent != Extent::All {
+        let msg = "A bbox or Hilbert range can only be exported to the pbf format.";
+        return Err(Box::new(Error::new(ErrorKind::InvalidInput, msg)));
+    }
+    if output.exists() {
+        if overwrite {
+            fs::remove_file(output)?;
+        } else {
+            let msg = format!(
+                "Export already exists at: {}. If you want to overwrite it, add the argument --overwrite.",
+                output.display()
+            );
+            return Err(Box::new(Error::new(ErrorKind::AlreadyExists, msg)));
+        }
+    }
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let t = timer(&format!("Exporting planet to {}", output.display()));
+    match format {
+        Format::PMTiles => pmtiles::export(&HilbertTree::open(manifest)?, output, tile_format)?,
+        Format::MBTiles => mbtiles::export(&HilbertTree::open(manifest)?, output, tile_format)?,
+        // An osm.pbf only needs the flatdata, so the planet does not have to be rendered.
+        Format::Pbf => {
+            let flatdata = Osm::open(FileResourceStorage::new(&manifest.data.planet))?;
+            pbf::export(&flatdata, output, extent)?
+        }
+    }
+    finish(t);
+    Ok(())
+}
+
+/// Every non-empty tile of the tree, encoded in the given tile format.
+/// The Hilbert tile levels and the odd zoom tiles within them come first, then the leaves.
+pub fn encoded_tiles(
+    tree: &HilbertTree,
+    tile_format: TileFormat,
+) -> impl Iterator<Item = (Tile, Vec<u8>)> + '_ {
+    tree.pvt_h_tile_iterator()
+        .chain(tree.pvt_leaf_iterator())
+        .filter_map(move |(tile, pvt)| {
+            let data = match tile_format {
+                TileFormat::Mvt => match mvt::pvt_to_mvt(&pvt) {
+                    Ok(mvt) => mvt,
+                    Err(e) => {
+                        eprintln!("Unable to encode MVT for {} Err: {:?}", tile, e);
+                        return None;
+                    }
+                },
+                TileFormat::Pvt => pvt,
+            };
+            if is_empty(&data, tile_format) {
+                None
+            } else {
+                Some((tile, data))
+            }
+        })
+}
+
+fn is_empty(data: &[u8], tile_format: TileFormat) -> bool {
+    match tile_format {
+        // The MVT encoder leaves out empty layers.
+        TileFormat::Mvt => data.is_empty(),
+        TileFormat::Pvt => match root_as_pvttile(data) {
+            Ok(pvt_tile) => match pvt_tile.layers() {
+                Some(layers) => layers
+                    .iter()
+                    .all(|l| l.features().is_none_or(|f| f.is_empty())),
+                None => true,
+            },
+            Err(_) => true,
+        },
+    }
+}
+
+/// The TileJSON vector_layers, one for each layer in the rules and the label layer.
+pub fn vector_layers(tree: &HilbertTree) -> Vec<serde_json::Value> {
+    let leaf_zoom = tree.manifest.render.leaf_zoom;
+    tree.rules
+        .layers
+        .iter()
+        .map(|name| name.as_str())
+        .chain([LABEL_LAYER])
+        .map(|name| {
+            serde_json::json!({
+                "id": name,
+                "fields": {},
+                "minzoom": 0,
+                "maxzoom": leaf_zoom,
+            })
+        })
+        .collect()
+}
+
+/// The bounds of the planet in degrees: (west, south, east, north).
+/// Falls back to the whole world when the source had no bounding box.
+pub fn bounds(tree: &HilbertTree) -> (f64, f64, f64, f64) {
+    let header = tree.flatdata.header();
+    let scale = header.coord_scale() as f64;
+    let (left, bottom, right, top) = (
+        header.bbox_left(),
+        header.bbox_bottom(),
+        header.bbox_right(),
+        header.bbox_top(),
+    );
+    if scale == 0_f64 || (left == 0 && bottom == 0 && right == 0 && top == 0) {
+        return (-180_f64, -85.0511287798066, 180_f64, 85.0511287798066);
+    }
+    (
+        left as f64 / scale,
+        bottom as f64 / scale,
+        right as f64 / scale,
+        top as f64 / scale,
+    )
+}
@@ -0,0 +1,740 @@
+use ahash::AHashMap;
+use byteorder::{NetworkEndian, WriteBytesExt};
+use flate2::{write::ZlibEncoder, Compression};
+use pbr::ProgressBar;
+use prost::Message;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+
+use crate::location;
+use crate::osmflat::osmflat_generated::osm::{EntityType, Metadata, Osm};
+use crate::osmflat::osmpbf;
+use crate::osmflat::tags::iter_tags;
+
+// Writes the flatdata of a planet back out as an osm.pbf, so that a planet, or a part of
+// it, can be handed to other OSM tools. The entities of a planet are in Hilbert order,
+// but an osm.pbf is sorted by type and then by id, so they are sorted by id first.
+//
+// An extract keeps the nodes in its extent, the ways with a node in it along with all of
+// their nodes, and the relations with a kept member, including relations of relations.
+// Refs and members that were dangling in the planet have lost their OSM id, so they are
+// left out.
+
+type Err = Box<dyn std::error::Error>;
+
+// The number of entities in a PrimitiveBlock, as written by osmosis and osmium.
+const BLOCK_SIZE: usize = 8000;
+
+/// The part of the planet that is exported to an osm.pbf.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Extent {
+    All,
+    /// West, south, east, north in degrees.
+    BBox([f64; 4]),
+    /// Half-open range of Hilbert locations of nodes, at full resolution.
+    Hilbert(u64, u64),
+}
+
+impl Extent {
+    pub fn parse(bbox: Option<&str>, hilbert_range: Option<&str>) -> Result<Self, Err> {
+        match (bbox, hilbert_range) {
+            (None, None) => Ok(Extent::All),
+            (Some(bbox), None) => {
+                let values: Vec<f64> = bbox
+                    .split(',')
+                    .map(|v| v.trim().parse::<f64>())
+                    .collect::<Result<_, _>>()?;
+                match values[..] {
+                    [west, south, east, north] if west <= east && south <= north => {
+                        Ok(Extent::BBox([west, south, east, north]))
+                    }
+                    _ => invalid(format!(
+                        "Invalid bbox: {}. Expected west,south,east,north.",
+                        bbox
+                    )),
+                }
+            }
+            (None, Some(range)) => {
+                let values: Vec<u64> = range
+                    .split(',')
+                    .map(|v| v.trim().parse::<u64>())
+                    .collect::<Result<_, _>>()?;
+                match values[..] {
+                    [start, end] if start < end => Ok(Extent::Hilbert(start, end)),
+                    _ => invalid(format!(
+                        "Invalid Hilbert range: {}. Expected start,end.",
+                        range
+                    )),
+                }
+            }
+            (Some(_), Some(_)) => {
+                invalid("Export either a bbox or a Hilbert range, not both.".to_string())
+            }
+        }
+    }
+
+    fn contains(&self, lonlat: (i32, i32)) -> bool {
+        match self {
+            Extent::All => true,
+            Extent::BBox([west, south, east, north]) => {
+                let (lon, lat) = location::lonlat_to_decimal_lonlat(lonlat);
+                lon >= *west && lon <= *east && lat >= *south && lat <= *north
+            }
+            Extent::Hilbert(start, end) => {
+                let h = location::lonlat_to_h(lonlat);
+                h >= *start && h < *end
+            }
+        }
+    }
+}
+
+fn invalid<T>(msg: String) -> Result<T, Err> {
+    Err(Box::new(Error::new(ErrorKind::InvalidInput, msg)))
+}
+
+// The granularity of the blocks in nanodegrees. A planet without a coordinate scale gets
+// the default of OSM PBF, 100 nanodegrees.
+fn granularity(coord_scale: i32) -> i32 {
+    match coord_scale {
+        0 => 100,
+        coord_scale => 1_000_000_000 / coord_scale,
+    }
+}
+
+pub fn export(flatdata: &Osm, output: &Path, extent: Extent) -> Result<(), Err> {
+    let selection = Selection::new(flatdata, extent);
+    let nodes = sorted_by_id(&selection.nodes, |i| flatdata.nodes()[i].osm_id());
+    let ways = sorted_by_id(&selection.ways, |i| flatdata.ways()[i].osm_id());
+    let relations = sorted_by_id(&selection.relations, |i| flatdata.relations()[i].osm_id());
+
+    let granularity = granularity(flatdata.header().coord_scale());
+
+    let mut out = BufWriter::new(File::create(output)?);
+    write_blob(
+        &mut out,
+        "OSMHeader",
+        &header_block(flatdata, extent, granularity),
+    )?;
+
+    let num_blocks = [&nodes, &ways, &relations]
+        .iter()
+        .map(|ids| ids.len().div_ceil(BLOCK_SIZE))
+        .sum::<usize>();
+    let mut pb = ProgressBar::new(num_blocks as u64);
+    pb.message("Writing blocks...");
+    for ids in nodes.chunks(BLOCK_SIZE) {
+        write_blob(
+            &mut out,
+            "OSMData",
+            &dense_nodes_block(flatdata, ids, granularity),
+        )?;
+        pb.inc();
+    }
+    for ids in ways.chunks(BLOCK_SIZE) {
+        write_blob(&mut out, "OSMData", &ways_block(flatdata, ids, granularity))?;
+        pb.inc();
+    }
+    for ids in relations.chunks(BLOCK_SIZE) {
+        write_blob(
+            &mut out,
+            "OSMData",
+            &relations_block(flatdata, ids, granularity),
+        )?;
+        pb.inc();
+    }
+    pb.finish();
+    out.flush()?;
+
+    println!(
+        "  {} nodes, {} ways, {} relations",
+        nodes.len(),
+        ways.len(),
+        relations.len()
+    );
+    Ok(())
+}
+
+/// The entities of the planet that are exported, by their index in the flatdata.
+struct Selection {
+    nodes: Vec<bool>,
+    ways: Vec<bool>,
+    relations: Vec<bool>,
+}
+
+impl Selection {
+    fn new(flatdata: &Osm, extent: Extent) -> Self {
+        let nodes = flatdata.nodes();
+        let ways = flatdata.ways();
+        let relations = flatdata.relations();
+        if extent == Extent::All {
+            return Self {
+                nodes: vec![true; nodes.len()],
+                ways: vec![true; ways.len()],
+                relations: vec![true; relations.len()],
+            };
+        }
+
+        let inside: Vec<bool> = nodes
+            .par_iter()
+            .map(|node| extent.contains((node.lon(), node.lat())))
+            .collect();
+
+        let nodes_index = flatdata.nodes_index();
+        let way_refs = |i: usize| {
+            let refs = ways[i].refs();
+            let end = range_end(i, ways.len(), refs.end, nodes_index.len());
+            nodes_index[refs.start as usize..end]
+                .iter()
+                .filter_map(|r| r.value())
+                .map(|idx| idx as usize)
+        };
+
+        let kept_ways: Vec<bool> = (0..ways.len())
+            .into_par_iter()
+            .map(|i| way_refs(i).any(|idx| inside[idx]))
+            .collect();
+
+        // Ways are kept whole, so their nodes outside of the extent are kept as well.
+        let mut kept_nodes = inside;
+        for i in (0..ways.len()).filter(|i| kept_ways[*i]) {
+            for idx in way_refs(i) {
+                kept_nodes[idx] = true;
+            }
+        }
+
+        // Relations are kept when they have a kept member. Keeping a relation can keep
+        // its parent relations in turn, so this repeats until nothing changes.
+        let members = flatdata.members();
+        let mut kept_relations = vec![false; relations.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, relation) in relations.iter().enumerate() {
+                if kept_relations[i] {
+                    continue;
+                }
+                let range = relation.members();
+                let end = range_end(i, relations.len(), range.end as u64, members.len());
+                let keep = members[range.start as usize..end].iter().any(|member| {
+                    let Some(idx) = member.idx() else {
+                        return false;
+                    };
+                    match member.entity_type() {
+                        EntityType::Node => kept_nodes[idx as usize],
+                        EntityType::Way => kept_ways[idx as usize],
+                        EntityType::Relation => kept_relations[idx as usize],
+                        _ => false,
+                    }
+                });
+                if keep {
+                    kept_relations[i] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        Self {
+            nodes: kept_nodes,
+            ways: kept_ways,
+            relations: kept_relations,
+        }
+    }
+}
+
+fn sorted_by_id(kept: &[bool], osm_id: impl Fn(usize) -> i64 + Sync) -> Vec<usize> {
+    let mut ids: Vec<usize> = (0..kept.len()).filter(|i| kept[*i]).collect();
+    ids.par_sort_unstable_by_key(|i| osm_id(*i));
+    ids
+}
+
+// The range of the last entity has no end, since sorting the planet does not carry over
+// the sentinel, so it ends at the given last end instead. An end of 0 is not enough to
+// tell, since an empty range at the start of its vector also ends at 0.
+fn range_end(i: usize, len: usize, end: u64, last_end: usize) -> usize {
+    if i + 1 < len {
+        end as usize
+    } else {
+        last_end
+    }
+}
+
+/// The strings of one PrimitiveBlock. Index 0 is the empty string, since a 0 in the
+/// keys_vals of dense nodes ends the tags of a node.
+struct BlockStrings<'a> {
+    index: AHashMap<&'a [u8], u32>,
+    table: Vec<Vec<u8>>,
+}
+
+impl<'a> BlockStrings<'a> {
+    fn new() -> Self {
+        Self {
+            index: AHashMap::from([(&b""[..], 0)]),
+            table: vec![Vec::new()],
+        }
+    }
+
+    fn sid(&mut self, s: &'a [u8]) -> u32 {
+        *self.index.entry(s).or_insert_with(|| {
+            self.table.push(s.to_vec());
+            (self.table.len() - 1) as u32
+        })
+    }
+
+    fn into_block(self, group: osmpbf::PrimitiveGroup, granularity: i32) -> osmpbf::PrimitiveBlock {
+        osmpbf::PrimitiveBlock {
+            stringtable: osmpbf::StringTable { s: self.table },
+            primitivegroup: vec![group],
+            granularity: Some(granularity),
+            lat_offset: None,
+            lon_offset: None,
+            // Timestamps are in seconds.
+            date_granularity: Some(1000),
+        }
+    }
+}
+
+fn header_block(flatdata: &Osm, extent: Extent, granularity: i32) -> osmpbf::HeaderBlock {
+    let header = flatdata.header();
+    let strings = flatdata.stringtable();
+    let string =
+        |idx: u64| String::from_utf8_lossy(strings.substring_raw(idx as usize)).to_string();
+
+    let bbox = match extent {
+        Extent::BBox([west, south, east, north]) => Some(osmpbf::HeaderBBox {
+            left: (west * 1e9) as i64,
+            right: (east * 1e9) as i64,
+            top: (north * 1e9) as i64,
+            bottom: (south * 1e9) as i64,
+        }),
+        _ if header.bbox_left() == 0
+            && header.bbox_right() == 0
+            && header.bbox_top() == 0
+            && header.bbox_bottom() == 0 =>
+        {
+            None
+        }
+        _ => Some(osmpbf::HeaderBBox {
+            left: header.bbox_left() as i64 * granularity as i64,
+            right: header.bbox_right() as i64 * granularity as i64,
+            top: header.bbox_top() as i64 * granularity as i64,
+            bottom: header.bbox_bottom() as i64 * granularity as i64,
+        }),
+    };
+
+    // The writing program is always the first string of the planet, so a string index
+    // of 0 means that the header did not have the field.
+    osmpbf::HeaderBlock {
+        bbox,
+        required_features: vec!["OsmSchema-V0.6".to_string(), "DenseNodes".to_string()],
+        optional_features: vec!["Sort.Type_then_ID".to_string()],
+        writingprogram: Some("planet-vector-tile".to_string()),
+        source: (header.source_idx() != 0).then(|| string(header.source_idx())),
+        osmosis_replication_timestamp: (header.replication_timestamp() != 0)
+            .then(|| header.replication_timestamp()),
+        osmosis_replication_sequence_number: (header.replication_sequence_number() != 0)
+            .then(|| header.replication_sequence_number()),
+        osmosis_replication_base_url: (header.replication_base_url_idx() != 0)
+            .then(|| string(header.replication_base_url_idx())),
+    }
+}
+
+fn dense_nodes_block(flatdata: &Osm, ids: &[usize], granularity: i32) -> osmpbf::PrimitiveBlock {
+    let nodes = flatdata.nodes();
+    let metadata = flatdata.node_metadata();
+    let tags_end = flatdata
+        .ways()
+        .first()
+        .map(|w| w.tag_first_idx())
+        .or_else(|| flatdata.relations().first().map(|r| r.tag_first_idx()))
+        .unwrap_or(flatdata.tags_index().len() as u64);
+
+    let mut strings = BlockStrings::new();
+    let mut dense = osmpbf::DenseNodes {
+        denseinfo: metadata.map(|_| osmpbf::DenseInfo::default()),
+        ..Default::default()
+    };
+    let (mut id, mut lat, mut lon) = (0, 0, 0);
+    let (mut timestamp, mut changeset, mut user_sid) = (0, 0, 0);
+    for &i in ids {
+        let node = &nodes[i];
+        dense.id.push(node.osm_id() - id);
+        dense.lat.push(node.lat() as i64 - lat);
+        dense.lon.push(node.lon() as i64 - lon);
+        id = node.osm_id();
+        lat = node.lat() as i64;
+        lon = node.lon() as i64;
+
+        let range = node.tags();
+        let range = range.start..range_end(i, nodes.len(), range.end, tags_end as usize) as u64;
+        for (key, val) in iter_tags(flatdata, range) {
+            dense.keys_vals.push(strings.sid(key) as i32);
+            dense.keys_vals.push(strings.sid(val) as i32);
+        }
+        dense.keys_vals.push(0);
+
+        if let (Some(info), Some(metadata)) = (dense.denseinfo.as_mut(), metadata) {
+            let m = &metadata[i];
+            let sid = strings.sid(flatdata.stringtable().substring_raw(m.user_idx() as usize));
+            info.version.push(m.version());
+            info.timestamp.push(m.timestamp() - timestamp);
+            info.changeset.push(m.changeset() - changeset);
+            info.uid.push(0);
+            info.user_sid.push(sid as i32 - user_sid);
+            timestamp = m.timestamp();
+            changeset = m.changeset();
+            user_sid = sid as i32;
+        }
+    }
+
+    let group = osmpbf::PrimitiveGroup {
+        dense: Some(dense),
+        ..Default::default()
+    };
+    strings.into_block(group, granularity)
+}
+
+fn ways_block(flatdata: &Osm, ids: &[usize], granularity: i32) -> osmpbf::PrimitiveBlock {
+    let ways = flatdata.ways();
+    let nodes = flatdata.nodes();
+    let nodes_index = flatdata.nodes_index();
+    let metadata = flatdata.way_metadata();
+    let tags_end = flatdata
+        .relations()
+        .first()
+        .map(|r| r.tag_first_idx())
+        .unwrap_or(flatdata.tags_index().len() as u64);
+
+    let mut strings = BlockStrings::new();
+    let mut group = osmpbf::PrimitiveGroup::default();
+    for &i in ids {
+        let way = &ways[i];
+        let range = way.tags();
+        let range = range.start..range_end(i, ways.len(), range.end, tags_end as usize) as u64;
+        let (keys, vals) = tags(flatdata, range, &mut strings);
+        let refs = way.refs();
+        let end = range_end(i, ways.len(), refs.end, nodes_index.len());
+        let mut prev = 0;
+        let refs = nodes_index[refs.start as usize..end]
+            .iter()
+            .filter_map(|r| r.value())
+            .map(|idx| {
+                let id = nodes[idx as usize].osm_id();
+                let delta = id - prev;
+                prev = id;
+                delta
+            })
+            .collect();
+        group.ways.push(osmpbf::Way {
+            id: way.osm_id(),
+            keys,
+            vals,
+            info: metadata.map(|m| info(flatdata, &m[i], &mut strings)),
+            refs,
+        });
+    }
+    strings.into_block(group, granularity)
+}
+
+fn relations_block(flatdata: &Osm, ids: &[usize], granularity: i32) -> osmpbf::PrimitiveBlock {
+    let relations = flatdata.relations();
+    let members = flatdata.members();
+    let metadata = flatdata.relation_metadata();
+    let tags_end = flatdata.tags_index().len();
+
+    let mut strings = BlockStrings::new();
+    let mut group = osmpbf::PrimitiveGroup::default();
+    for &i in ids {
+        let relation = &relations[i];
+        let range = relation.tags();
+        let range = range.start..range_end(i, relations.len(), range.end, tags_end) as u64;
+        let (keys, vals) = tags(flatdata, range, &mut strings);
+        let mut pbf_relation = osmpbf::Relation {
+            id: relation.osm_id(),
+            keys,
+            vals,
+            info: metadata.map(|m| info(flatdata, &m[i], &mut strings)),
+            ..Default::default()
+        };
+
+        let range = relation.members();
+        let end = range_end(i, relations.len(), range.end as u64, members.len());
+        let mut prev = 0;
+        for member in &members[range.start as usize..end] {
+            let Some(idx) = member.idx() else {
+                continue;
+            };
+            let (id, member_type) = match member.entity_type() {
+                EntityType::Node => (
+                    flatdata.nodes()[idx as usize].osm_id(),
+                    osmpbf::relation::MemberType::Node,
+                ),
+                EntityType::Way => (
+                    flatdata.ways()[idx as usize].osm_id(),
+                    osmpbf::relation::MemberType::Way,
+                ),
+                EntityType::Relation => (
+                    relations[idx as usize].osm_id(),
+                    osmpbf::relation::MemberType::Relation,
+                ),
+                _ => continue,
+            };
+            let role = flatdata
+                .stringtable()
+                .substring_raw(member.role_idx() as usize);
+            pbf_relation.roles_sid.push(strings.sid(role) as i32);
+            pbf_relation.memids.push(id - prev);
+            pbf_relation.types.push(member_type as i32);
+            prev = id;
+        }
+        group.relations.push(pbf_relation);
+    }
+    strings.into_block(group, granularity)
+}
+
+fn tags<'a>(
+    flatdata: &'a Osm,
+    range: std::ops::Range<u64>,
+    strings: &mut BlockStrings<'a>,
+) -> (Vec<u32>, Vec<u32>) {
+    iter_tags(flatdata, range)
+        .map(|(key, val)| (strings.sid(key), strings.sid(val)))
+        .unzip()
+}
+
+fn info<'a>(flatdata: &'a Osm, m: &Metadata, strings: &mut BlockStrings<'a>) -> osmpbf::Info {
+    let user = flatdata.stringtable().substring_raw(m.user_idx() as usize);
+    osmpbf::Info {
+        version: Some(m.version()),
+        timestamp: Some(m.timestamp()),
+        changeset: Some(m.changeset()),
+        uid: None,
+        user_sid: Some(strings.sid(user)),
+        visible: None,
+    }
+}
+
+/// Writes a message as a zlib compressed blob, preceded by its blob header.
+fn write_blob(out: &mut impl Write, blob_type: &str, message: &impl Message) -> Result<(), Err> {
+    let raw = message.encode_to_vec();
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&raw)?;
+    let blob = osmpbf::Blob {
+        raw_size: Some(raw.len() as i32),
+        zlib_data: Some(encoder.finish()?),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let blob_header = osmpbf::BlobHeader {
+        r#type: blob_type.to_string(),
+        indexdata: None,
+        datasize: blob.len() as i32,
+    }
+    .encode_to_vec();
+
+    out.write_i32::<NetworkEndian>(blob_header.len() as i32)?;
+    out.write_all(&blob_header)?;
+    out.write_all(&blob)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest;
+    use crate::osmflat;
+    use crate::osmflat::osmpbf::{build_block_index, read_block, BlockType};
+    use flatdata::FileResourceStorage;
+    use std::collections::BTreeMap;
+
+    type Tags = Vec<(Vec<u8>, Vec<u8>)>;
+    type Members = Vec<(char, i64, Vec<u8>)>;
+
+    /// The entities of a planet by their OSM id, with their refs and members as OSM ids.
+    #[derive(Debug, PartialEq)]
+    struct Entities {
+        nodes: BTreeMap<i64, (i32, i32, Tags)>,
+        ways: BTreeMap<i64, (Tags, Vec<i64>)>,
+        relations: BTreeMap<i64, (Tags, Members)>,
+    }
+
+    impl Entities {
+        // Only for freshly converted planets, which end every vector with a sentinel.
+        fn new(osm: &Osm) -> Self {
+            let tags = |range: std::ops::Range<u64>| -> Tags {
+                iter_tags(osm, range)
+                    .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                    .collect()
+            };
+            let nodes = osm.nodes();
+            let ways = osm.ways();
+            let relations = osm.relations();
+            let nodes_index = osm.nodes_index();
+            let strings = osm.stringtable();
+            Self {
+                nodes: nodes
+                    .iter()
+                    .map(|n| (n.osm_id(), (n.lat(), n.lon(), tags(n.tags()))))
+                    .collect(),
+                ways: ways
+                    .iter()
+                    .map(|w| {
+                        let refs = nodes_index[w.refs().start as usize..w.refs().end as usize]
+                            .iter()
+                            .filter_map(|r| r.value())
+                            .map(|idx| nodes[idx as usize].osm_id())
+                            .collect();
+                        (w.osm_id(), (tags(w.tags()), refs))
+                    })
+                    .collect(),
+                relations: relations
+                    .iter()
+                    .map(|r| {
+                        let members = r
+                            .members()
+                            .filter_map(|i| {
+                                let m = &osm.members()[i as usize];
+                                let idx = m.idx()? as usize;
+                                let (t, id) = match m.entity_type() {
+                                    EntityType::Node => ('n', nodes[idx].osm_id()),
+                                    EntityType::Way => ('w', ways[idx].osm_id()),
+                                    _ => ('r', relations[idx].osm_id()),
+                                };
+                                let role = strings.substring_raw(m.role_idx() as usize);
+                                Some((t, id, role.to_vec()))
+                            })
+                            .collect();
+                        (r.osm_id(), (tags(r.tags()), members))
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    fn convert(source: &Path, planet: &Path) -> Osm {
+        let mut manifest = manifest::parse("tests/fixtures/relations_convert.yaml").unwrap();
+        manifest.data.source = vec![source.to_path_buf()];
+        manifest.data.planet = planet.to_path_buf();
+        osmflat::convert(&manifest).unwrap()
+    }
+
+    fn export_relations(extent: Extent) -> Entities {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest::parse("tests/fixtures/relations_sort.yaml").unwrap();
+        let flatdata = Osm::open(FileResourceStorage::new(&manifest.data.planet)).unwrap();
+        let path = dir.path().join("relations.osm.pbf");
+        export(&flatdata, &path, extent).unwrap();
+        Entities::new(&convert(&path, &dir.path().join("export")))
+    }
+
+    fn node_ids(data: &[u8]) -> Vec<i64> {
+        let mut ids = Vec::new();
+        for idx in build_block_index(data) {
+            if idx.block_type != BlockType::DenseNodes {
+                continue;
+            }
+            let block: osmpbf::PrimitiveBlock = read_block(data, &idx).unwrap();
+            for group in block.primitivegroup {
+                let mut id = 0;
+                for delta in group.dense.unwrap().id {
+                    id += delta;
+                    ids.push(id);
+                }
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn test_nodes4_round_trip() {
+        let manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        let flatdata = Osm::open(FileResourceStorage::new(&manifest.data.planet)).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nodes4.osm.pbf");
+        export(&flatdata, &path, Extent::All).unwrap();
+
+        let source = std::fs::read("tests/fixtures/nodes4.osm.pbf").unwrap();
+        let exported = std::fs::read(&path).unwrap();
+        let ids = node_ids(&exported);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(ids, node_ids(&source));
+
+        let header: osmpbf::HeaderBlock =
+            read_block(&exported, &build_block_index(&exported)[0]).unwrap();
+        assert_eq!(
+            header.required_features,
+            vec!["OsmSchema-V0.6", "DenseNodes"]
+        );
+
+        let source = convert(
+            Path::new("tests/fixtures/nodes4.osm.pbf"),
+            &dir.path().join("source"),
+        );
+        let exported = convert(&path, &dir.path().join("export"));
+        assert_eq!(Entities::new(&exported), Entities::new(&source));
+    }
+
+    #[test]
+    fn test_relations_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = convert(
+            Path::new("tests/fixtures/relations.osm"),
+            &dir.path().join("source"),
+        );
+        let source = Entities::new(&source);
+        assert_eq!(source.ways.len(), 7);
+        assert_eq!(source.relations.len(), 4);
+        assert_eq!(export_relations(Extent::All), source);
+    }
+
+    #[test]
+    fn test_extent_keeps_ways_whole() {
+        // Only the west end of the motorway is in the bbox.
+        let exported = export_relations(Extent::BBox([-122.31, 36.97, -122.29, 36.98]));
+        assert_eq!(exported.nodes.keys().collect::<Vec<_>>(), vec![&40, &41]);
+        assert_eq!(exported.ways.keys().collect::<Vec<_>>(), vec![&104]);
+        assert_eq!(exported.ways[&104].1, vec![40, 41]);
+        // The bus route is kept for its way, but its stop outside of the bbox is not.
+        assert_eq!(exported.relations.keys().collect::<Vec<_>>(), vec![&201]);
+        assert_eq!(exported.relations[&201].1, vec![('w', 104, Vec::new())]);
+
+        // Only one corner of the building is in the Hilbert range.
+        let h = location::lonlat_to_h((-1_220_290_000, 369_610_000));
+        let exported = export_relations(Extent::Hilbert(h, h + 1));
+        assert_eq!(
+            exported.nodes.keys().collect::<Vec<_>>(),
+            vec![&10, &11, &12, &13]
+        );
+        assert_eq!(exported.ways[&100].1, vec![10, 11, 12, 13, 10]);
+        assert!(exported.relations.is_empty());
+    }
+
+    #[test]
+    fn test_granularity() {
+        assert_eq!(granularity(10_000_000), 100);
+        assert_eq!(granularity(0), 100);
+    }
+
+    #[test]
+    fn test_extent() {
+        assert_eq!(Extent::parse(None, None).unwrap(), Extent::All);
+        assert_eq!(
+            Extent::parse(Some("-122.1,36.9,-121.9,37.1"), None).unwrap(),
+            Extent::BBox([-122.1, 36.9, -121.9, 37.1])
+        );
+        assert_eq!(
+            Extent::parse(None, Some("10,20")).unwrap(),
+            Extent::Hilbert(10, 20)
+        );
+        assert!(Extent::parse(Some("1,2,3"), None).is_err());
+        assert!(Extent::parse(None, Some("20,10")).is_err());
+        assert!(Extent::parse(Some("0,0,1,1"), Some("10,20")).is_err());
+
+        let extent = Extent::BBox([-122.1, 36.9, -121.9, 37.1]);
+        assert!(extent.contains((-1_220_000_000, 370_000_000)));
+        assert!(!extent.contains((-1_210_000_000, 370_000_000)));
+        let h = location::lonlat_to_h((-1_220_000_000, 370_000_000));
+        assert!(Extent::Hilbert(h, h + 1).contains((-1_220_000_000, 370_000_000)));
+    }
+}
@@ -0,0 +1,375 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::{write::GzEncoder, Compression};
+use memmap2::Mmap;
+use serde_json::json;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::{bounds, encoded_tiles, vector_layers, TileFormat};
+use crate::{hilbert::tree::HilbertTree, tile::Tile};
+
+// PMTiles v3
+// https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md
+
+const HEADER_SIZE: usize = 127;
+// The header and root directory must fit in the first 16 KiB,
+// so that a client can fetch both with one request.
+const ROOT_SIZE: usize = 16384;
+const GZIP: u8 = 2;
+const TILE_TYPE_UNKNOWN: u8 = 0;
+const TILE_TYPE_MVT: u8 = 1;
+
+type Err = Box<dyn std::error::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+pub fn export(tree: &HilbertTree, output: &Path, tile_format: TileFormat) -> Result<(), Err> {
+    // The tiles come out of the tree in our own Hilbert order, which is not the PMTiles
+    // tile id order, so we spill the tile data and then copy it out in tile id order.
+    let tmp_path = output.with_extension("pmtiles.tmp");
+    let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+    let mut entries = Vec::new();
+    let mut offset = 0_u64;
+    let mut min_zoom = u8::MAX;
+    let mut max_zoom = 0_u8;
+
+    for (tile, data) in encoded_tiles(tree, tile_format) {
+        let gzipped = gzip(&data)?;
+        tmp.write_all(&gzipped)?;
+        entries.push(Entry {
+            tile_id: tile_id(&tile),
+            offset,
+            length: gzipped.len() as u32,
+            run_length: 1,
+        });
+        offset += gzipped.len() as u64;
+        min_zoom = min_zoom.min(tile.z);
+        max_zoom = max_zoom.max(tile.z);
+    }
+    tmp.flush()?;
+    drop(tmp);
+    println!("  {} tiles", entries.len());
+
+    if entries.is_empty() {
+        min_zoom = 0;
+    }
+
+    let tmp_file = File::open(&tmp_path)?;
+    let tmp_mmap = if offset > 0 {
+        Some(unsafe { Mmap::map(&tmp_file)? })
+    } else {
+        None
+    };
+
+    // Lay the tile data out in tile id order.
+    entries.sort_unstable_by_key(|e| e.tile_id);
+    let mut clustered = Vec::with_capacity(entries.len());
+    let mut tile_data_offset = 0_u64;
+    for e in &entries {
+        clustered.push(Entry {
+            offset: tile_data_offset,
+            ..*e
+        });
+        tile_data_offset += e.length as u64;
+    }
+
+    let (root, leaves) = build_directories(&clustered)?;
+
+    let metadata = json!({
+        "name": tree.manifest.data.archive.file_stem().map(|s| s.to_string_lossy()),
+        "format": match tile_format {
+            TileFormat::Mvt => "pbf",
+            TileFormat::Pvt => "pvt",
+        },
+        "generator": "planet-vector-tile",
+        "vector_layers": vector_layers(tree),
+    });
+    let metadata = gzip(metadata.to_string().as_bytes())?;
+
+    let (west, south, east, north) = bounds(tree);
+    let header = Header {
+        root_offset: HEADER_SIZE as u64,
+        root_length: root.len() as u64,
+        metadata_offset: (HEADER_SIZE + root.len()) as u64,
+        metadata_length: metadata.len() as u64,
+        leaves_offset: (HEADER_SIZE + root.len() + metadata.len()) as u64,
+        leaves_length: leaves.len() as u64,
+        tile_data_offset: (HEADER_SIZE + root.len() + metadata.len() + leaves.len()) as u64,
+        tile_data_length: tile_data_offset,
+        addressed_tiles: entries.len() as u64,
+        tile_entries: entries.len() as u64,
+        tile_contents: entries.len() as u64,
+        tile_type: match tile_format {
+            TileFormat::Mvt => TILE_TYPE_MVT,
+            TileFormat::Pvt => TILE_TYPE_UNKNOWN,
+        },
+        min_zoom,
+        max_zoom,
+        bounds: (e7(west), e7(south), e7(east), e7(north)),
+        center_zoom: min_zoom,
+        center: (e7((west + east) / 2_f64), e7((south + north) / 2_f64)),
+    };
+
+    let mut writer = BufWriter::with_capacity(1024 * 1024 * 32, File::create(output)?);
+    header.write(&mut writer)?;
+    writer.write_all(&root)?;
+    writer.write_all(&metadata)?;
+    writer.write_all(&leaves)?;
+    if let Some(mmap) = &tmp_mmap {
+        for e in &entries {
+            let start = e.offset as usize;
+            writer.write_all(&mmap[start..start + e.length as usize])?;
+        }
+    }
+    writer.flush()?;
+
+    drop(tmp_mmap);
+    fs::remove_file(&tmp_path)?;
+    Ok(())
+}
+
+/// The PMTiles tile id: the count of tiles in all lower zooms,
+/// plus the position of the tile along the Hilbert curve of its zoom.
+pub fn tile_id(tile: &Tile) -> u64 {
+    zxy_to_tile_id(tile.z, tile.x, tile.y)
+}
+
+pub fn zxy_to_tile_id(z: u8, x: u32, y: u32) -> u64 {
+    let mut acc = 0_u64;
+    for i in 0..z {
+        acc += 1_u64 << (2 * i);
+    }
+
+    let n = 1_u64 << z;
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut d = 0_u64;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = ((x & s) > 0) as u64;
+        let ry = ((y & s) > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        // Rotate
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    acc + d
+}
+
+// Splits the entries into leaf directories when they do not all fit in the root.
+fn build_directories(entries: &[Entry]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let root = gzip(&serialize_directory(entries))?;
+    if root.len() <= ROOT_SIZE - HEADER_SIZE {
+        return Ok((root, Vec::new()));
+    }
+
+    let mut leaf_size = 4096;
+    loop {
+        let mut root_entries = Vec::new();
+        let mut leaves = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = gzip(&serialize_directory(chunk))?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                // A run length of 0 points at a leaf directory.
+                run_length: 0,
+            });
+            leaves.extend(leaf);
+        }
+        let root = gzip(&serialize_directory(&root_entries))?;
+        if root.len() <= ROOT_SIZE - HEADER_SIZE {
+            return Ok((root, leaves));
+        }
+        leaf_size *= 2;
+    }
+}
+
+fn serialize_directory(entries: &[Entry]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(entries.len() * 8);
+    write_varint(&mut buf, entries.len() as u64);
+
+    let mut last_id = 0;
+    for e in entries {
+        write_varint(&mut buf, e.tile_id - last_id);
+        last_id = e.tile_id;
+    }
+    for e in entries {
+        write_varint(&mut buf, e.run_length as u64);
+    }
+    for e in entries {
+        write_varint(&mut buf, e.length as u64);
+    }
+    for (i, e) in entries.iter().enumerate() {
+        // 0 means the data directly follows the previous entry's data.
+        if i > 0 && e.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+            write_varint(&mut buf, 0);
+        } else {
+            write_varint(&mut buf, e.offset + 1);
+        }
+    }
+    buf
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8 & 0x7f) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn e7(degrees: f64) -> i32 {
+    (degrees * 10_000_000_f64) as i32
+}
+
+struct Header {
+    root_offset: u64,
+    root_length: u64,
+    metadata_offset: u64,
+    metadata_length: u64,
+    leaves_offset: u64,
+    leaves_length: u64,
+    tile_data_offset: u64,
+    tile_data_length: u64,
+    addressed_tiles: u64,
+    tile_entries: u64,
+    tile_contents: u64,
+    tile_type: u8,
+    min_zoom: u8,
+    max_zoom: u8,
+    bounds: (i32, i32, i32, i32),
+    center_zoom: u8,
+    center: (i32, i32),
+}
+
+impl Header {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"PMTiles")?;
+        w.write_u8(3)?;
+        for v in [
+            self.root_offset,
+            self.root_length,
+            self.metadata_offset,
+            self.metadata_length,
+            self.leaves_offset,
+            self.leaves_length,
+            self.tile_data_offset,
+            self.tile_data_length,
+            self.addressed_tiles,
+            self.tile_entries,
+            self.tile_contents,
+        ] {
+            w.write_u64::<LittleEndian>(v)?;
+        }
+        // Clustered, internal compression, tile compression.
+        w.write_u8(1)?;
+        w.write_u8(GZIP)?;
+        w.write_u8(GZIP)?;
+        w.write_u8(self.tile_type)?;
+        w.write_u8(self.min_zoom)?;
+        w.write_u8(self.max_zoom)?;
+        w.write_i32::<LittleEndian>(self.bounds.0)?;
+        w.write_i32::<LittleEndian>(self.bounds.1)?;
+        w.write_i32::<LittleEndian>(self.bounds.2)?;
+        w.write_i32::<LittleEndian>(self.bounds.3)?;
+        w.write_u8(self.center_zoom)?;
+        w.write_i32::<LittleEndian>(self.center.0)?;
+        w.write_i32::<LittleEndian>(self.center.1)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest;
+    use byteorder::{ByteOrder, LittleEndian};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_tile_id() {
+        assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+        assert_eq!(zxy_to_tile_id(1, 0, 0), 1);
+        assert_eq!(zxy_to_tile_id(1, 0, 1), 2);
+        assert_eq!(zxy_to_tile_id(1, 1, 1), 3);
+        assert_eq!(zxy_to_tile_id(1, 1, 0), 4);
+        assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+        assert_eq!(zxy_to_tile_id(3, 0, 0), 21);
+        assert_eq!(zxy_to_tile_id(3, 7, 0), 84);
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 1);
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, vec![1, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_directory() {
+        let entries = [
+            Entry {
+                tile_id: 5,
+                offset: 0,
+                length: 10,
+                run_length: 1,
+            },
+            Entry {
+                tile_id: 7,
+                offset: 10,
+                length: 4,
+                run_length: 1,
+            },
+        ];
+        let buf = serialize_directory(&entries);
+        assert_eq!(buf, vec![2, 5, 2, 1, 1, 10, 4, 1, 0]);
+    }
+
+    #[test]
+    fn test_nodes4_pmtiles() {
+        let manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        let tree = HilbertTree::open(&manifest).unwrap();
+        let path = manifest.data.planet.join("nodes4.pmtiles");
+        export(&tree, &path, TileFormat::Pvt).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(&bytes[0..7], b"PMTiles");
+        assert_eq!(bytes[7], 3);
+
+        let root_offset = LittleEndian::read_u64(&bytes[8..16]) as usize;
+        let root_length = LittleEndian::read_u64(&bytes[16..24]) as usize;
+        let tile_data_offset = LittleEndian::read_u64(&bytes[56..64]) as usize;
+        let tile_data_length = LittleEndian::read_u64(&bytes[64..72]) as usize;
+        let addressed_tiles = LittleEndian::read_u64(&bytes[72..80]);
+        assert_eq!(root_offset, HEADER_SIZE);
+        assert_eq!(tile_data_offset + tile_data_length, bytes.len());
+
+        let mut root = Vec::new();
+        GzDecoder::new(&bytes[root_offset..root_offset + root_length])
+            .read_to_end(&mut root)
+            .unwrap();
+        assert_eq!(root[0] as u64, addressed_tiles);
+    }
+}
@@ -0,0 +1,110 @@
+use std::cmp::Reverse;
+use std::collections::binary_heap::{BinaryHeap, PeekMut};
+use std::fs;
+use std::mem::size_of;
+use std::ops::Range;
+
+use rayon::prelude::*;
+
+use crate::mutant::Mutant;
+
+// Sorts a Mutant that can be larger than memory. Sorting a memory mapped file in place
+// touches every page of it at random, which thrashes once the file does not fit in RAM.
+// Instead, the file is copied in runs that fit in the memory budget into a runs file
+// next to it, and each run is sorted on its own. The sorted runs are then merged back
+// into the Mutant, which reads every run and writes the Mutant in order.
+
+type Err = Box<dyn std::error::Error>;
+
+/// Sorts the Mutant by key, using no more than about `memory` bytes at a time.
+/// A Mutant that fits in the budget is simply sorted in place.
+pub fn sort_by_key<T, K, F>(m: &Mutant<T>, memory: usize, key: F) -> Result<(), Err>
+where
+    T: Clone + Send,
+    K: Ord + Copy,
+    F: Fn(&T) -> K + Sync,
+{
+    let slice = m.mutable_slice();
+    let len = slice.len();
+    let run_len = (memory / size_of::<T>()).max(1);
+    if len <= run_len {
+        slice.par_sort_unstable_by_key(key);
+        return Ok(());
+    }
+
+    let Some(dir) = m.path.parent() else {
+        return Err(format!("{} is not in a directory", m.path.display()).into());
+    };
+    let Some(file_name) = m.path.file_name() else {
+        return Err(format!("{} is not a file", m.path.display()).into());
+    };
+    let runs_name = format!("{}_runs", file_name.to_string_lossy());
+    let mut m_runs = Mutant::<T>::with_capacity(dir, &runs_name, len)?;
+
+    let mut runs: Vec<Range<usize>> = Vec::with_capacity(len / run_len + 1);
+    for chunk in slice.chunks(run_len) {
+        let start = m_runs.len;
+        m_runs.append(chunk)?;
+        m_runs.mutable_slice()[start..].par_sort_unstable_by_key(&key);
+        runs.push(start..m_runs.len);
+    }
+    println!(
+        "Merging {} sorted runs of {} MB.",
+        runs.len(),
+        run_len * size_of::<T>() / 1_000_000
+    );
+
+    // The heap holds the next item of every run that is not merged yet.
+    let sorted_runs = m_runs.slice();
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for (r, run) in runs.iter().enumerate() {
+        heap.push(Reverse((key(&sorted_runs[run.start]), r)));
+    }
+    for item in slice.iter_mut() {
+        let Some(mut top) = heap.peek_mut() else {
+            break;
+        };
+        let Reverse((_, r)) = *top;
+        let run = &mut runs[r];
+        *item = sorted_runs[run.start].clone();
+        run.start += 1;
+        if run.start == run.end {
+            PeekMut::pop(top);
+        } else {
+            *top = Reverse((key(&sorted_runs[run.start]), r));
+        }
+    }
+
+    let path = m_runs.path.clone();
+    drop(m_runs);
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort_by_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let len = 10_000;
+        let m = Mutant::<u64>::new(dir.path(), "values", len).unwrap();
+        for (i, v) in m.mutable_slice().iter_mut().enumerate() {
+            *v = (i as u64 * 7919) % 1009;
+        }
+        let mut expected = m.slice().to_vec();
+        expected.sort();
+
+        // 10 runs, the last one shorter than the rest.
+        sort_by_key(&m, 1_001 * size_of::<u64>(), |v| *v).unwrap();
+        assert_eq!(m.slice(), &expected[..]);
+        assert!(!dir.path().join("values_runs").exists());
+
+        // Fits in memory.
+        let m2 = Mutant::<u64>::new(dir.path(), "values2", 3).unwrap();
+        m2.mutable_slice().copy_from_slice(&[3, 1, 2]);
+        sort_by_key(&m2, 1_000, |v| *v).unwrap();
+        assert_eq!(m2.slice(), &[1, 2, 3]);
+    }
+}
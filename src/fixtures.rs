@@ -1,5 +1,8 @@
+mod archive;
+mod external_sort;
 mod filter;
 mod hilbert;
+mod label;
 mod location;
 mod manifest;
 mod mutant;
@@ -13,7 +16,6 @@ mod tile;
 mod tile_attributes;
 mod util;
 
-use fs_extra::dir::{copy, CopyOptions};
 use hilbert::tree::HilbertTree;
 use humantime::format_duration;
 use std::{error::Error, fs, time::Instant};
@@ -22,12 +24,20 @@ fn main() {
     let time = Instant::now();
 
     let _ = fs::remove_dir_all("tests/fixtures/nodes4");
+    let _ = fs::remove_dir_all("tests/fixtures/relations");
     let _ = fs::remove_dir_all("tests/fixtures/santa_cruz");
+    let _ = fs::remove_file("tests/fixtures/nodes4.pvt");
+    let _ = fs::remove_file("tests/fixtures/relations.pvt");
+    let _ = fs::remove_file("tests/fixtures/santa_cruz.pvt");
 
     build(
         "./tests/fixtures/nodes4_convert.yaml",
         "tests/fixtures/nodes4_sort.yaml",
     );
+    build(
+        "./tests/fixtures/relations_convert.yaml",
+        "tests/fixtures/relations_sort.yaml",
+    );
     build(
         "./tests/fixtures/santa_cruz_convert.yaml",
         "tests/fixtures/santa_cruz_sort.yaml",
@@ -63,18 +73,12 @@ fn build(convert_manifest_path_str: &str, sort_manifest_path_str: &str) {
 
     fs::create_dir_all(&sort_manifest.data.planet).unwrap();
 
-    let mut opts = CopyOptions::default();
-    opts.content_only = true;
-    copy(
-        convert_manifest.data.planet,
-        &sort_manifest.data.planet,
-        &opts,
-    )
-    .unwrap();
+    util::copy_planet(&convert_manifest.data.planet, &sort_manifest.data.planet).unwrap();
 
-    sort::sort_flatdata(flatdata, &sort_manifest.data.planet).unwrap_or_else(quit);
+    sort::sort_flatdata(flatdata, &sort_manifest.data).unwrap_or_else(quit);
     let mut tree = HilbertTree::new(&sort_manifest).unwrap_or_else(quit);
     tree.render_tile_content().unwrap_or_else(quit);
+    archive::create(&sort_manifest, true).unwrap_or_else(quit);
 }
 
 fn quit<T>(e: Box<dyn Error>) -> T {
@@ -1587,6 +1587,176 @@ pub mod osm {
             self.set_h(other.h());
         }
     }
+    /// Metadata of a `Node`, `Way`, or `Relation`, as found in the OSM source.
+    ///
+    /// Only stored when the planet is converted with `include_metadata`.
+    #[repr(transparent)]
+    #[derive(Clone)]
+    pub struct Metadata {
+        data: [u8; 25],
+    }
+
+    impl Metadata {
+        /// Unsafe since the struct might not be self-contained
+        pub unsafe fn new_unchecked() -> Self {
+            Self { data: [0; 25] }
+        }
+    }
+
+    impl flatdata::Struct for Metadata {
+        unsafe fn create_unchecked() -> Self {
+            Self { data: [0; 25] }
+        }
+
+        const SIZE_IN_BYTES: usize = 25;
+        const IS_OVERLAPPING_WITH_NEXT: bool = false;
+    }
+
+    impl Metadata {
+        pub fn new() -> Self {
+            Self { data: [0; 25] }
+        }
+
+        /// Create reference from byte array of matching size
+        pub fn from_bytes(data: &[u8; 25]) -> &Self {
+            // Safety: This is safe since Metadata is repr(transparent)
+            unsafe { std::mem::transmute(data) }
+        }
+
+        /// Create reference from byte array of matching size
+        pub fn from_bytes_mut(data: &mut [u8; 25]) -> &mut Self {
+            // Safety: This is safe since Metadata is repr(transparent)
+            unsafe { std::mem::transmute(data) }
+        }
+
+        /// Create reference from byte array
+        pub fn from_bytes_slice(data: &[u8]) -> Result<&Self, flatdata::ResourceStorageError> {
+            // We cannot rely on TryFrom here, since it does not yet support > 33 bytes
+            if data.len() < 25 {
+                assert_eq!(data.len(), 25);
+                return Err(flatdata::ResourceStorageError::UnexpectedDataSize);
+            }
+            let ptr = data.as_ptr() as *const [u8; 25];
+            // Safety: We checked length before
+            Ok(Self::from_bytes(unsafe { &*ptr }))
+        }
+
+        /// Create reference from byte array
+        pub fn from_bytes_slice_mut(
+            data: &mut [u8],
+        ) -> Result<&mut Self, flatdata::ResourceStorageError> {
+            // We cannot rely on TryFrom here, since it does not yet support > 33 bytes
+            if data.len() < 25 {
+                assert_eq!(data.len(), 25);
+                return Err(flatdata::ResourceStorageError::UnexpectedDataSize);
+            }
+            let ptr = data.as_ptr() as *mut [u8; 25];
+            // Safety: We checked length before
+            Ok(Self::from_bytes_mut(unsafe { &mut *ptr }))
+        }
+
+        pub fn as_bytes(&self) -> &[u8; 25] {
+            &self.data
+        }
+    }
+
+    impl Default for Metadata {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    unsafe impl flatdata::NoOverlap for Metadata {}
+
+    impl Metadata {
+        /// Version of the entity.
+        #[inline]
+        pub fn version(&self) -> i32 {
+            let value = flatdata_read_bytes!(i32, self.data.as_ptr(), 0, 32);
+            unsafe { std::mem::transmute::<i32, i32>(value) }
+        }
+
+        /// Timestamp of the last edit, expressed in seconds since the epoch.
+        #[inline]
+        pub fn timestamp(&self) -> i64 {
+            let value = flatdata_read_bytes!(i64, self.data.as_ptr(), 32, 64);
+            unsafe { std::mem::transmute::<i64, i64>(value) }
+        }
+
+        /// Id of the changeset of the last edit.
+        #[inline]
+        pub fn changeset(&self) -> i64 {
+            let value = flatdata_read_bytes!(i64, self.data.as_ptr(), 96, 64);
+            unsafe { std::mem::transmute::<i64, i64>(value) }
+        }
+
+        /// User of the last edit (reference to `stringtable`).
+        #[inline]
+        pub fn user_idx(&self) -> u64 {
+            let value = flatdata_read_bytes!(u64, self.data.as_ptr(), 160, 40);
+            unsafe { std::mem::transmute::<u64, u64>(value) }
+        }
+    }
+
+    impl std::fmt::Debug for Metadata {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.debug_struct("Metadata")
+                .field("version", &self.version())
+                .field("timestamp", &self.timestamp())
+                .field("changeset", &self.changeset())
+                .field("user_idx", &self.user_idx())
+                .finish()
+        }
+    }
+
+    impl std::cmp::PartialEq for Metadata {
+        #[inline]
+        fn eq(&self, other: &Self) -> bool {
+            self.version() == other.version()
+                && self.timestamp() == other.timestamp()
+                && self.changeset() == other.changeset()
+                && self.user_idx() == other.user_idx()
+        }
+    }
+
+    impl Metadata {
+        /// Version of the entity.
+        #[inline]
+        #[allow(missing_docs)]
+        pub fn set_version(&mut self, value: i32) {
+            flatdata_write_bytes!(i32; value, self.data, 0, 32)
+        }
+
+        /// Timestamp of the last edit, expressed in seconds since the epoch.
+        #[inline]
+        #[allow(missing_docs)]
+        pub fn set_timestamp(&mut self, value: i64) {
+            flatdata_write_bytes!(i64; value, self.data, 32, 64)
+        }
+
+        /// Id of the changeset of the last edit.
+        #[inline]
+        #[allow(missing_docs)]
+        pub fn set_changeset(&mut self, value: i64) {
+            flatdata_write_bytes!(i64; value, self.data, 96, 64)
+        }
+
+        /// User of the last edit (reference to `stringtable`).
+        #[inline]
+        #[allow(missing_docs)]
+        pub fn set_user_idx(&mut self, value: u64) {
+            flatdata_write_bytes!(u64; value, self.data, 160, 40)
+        }
+
+        /// Copies the data from `other` into this struct.
+        #[inline]
+        pub fn fill_from(&mut self, other: &Metadata) {
+            self.set_version(other.version());
+            self.set_timestamp(other.timestamp());
+            self.set_changeset(other.changeset());
+            self.set_user_idx(other.user_idx());
+        }
+    }
     #[derive(Debug, PartialEq, Eq)]
     #[repr(u8)]
     pub enum EntityType {
@@ -1615,6 +1785,9 @@ pub mod osm {
         tags: &'static [super::osm::Tag],
         tags_index: &'static [super::osm::TagIndex],
         nodes_index: &'static [super::osm::NodeIndex],
+        node_metadata: Option<&'static [super::osm::Metadata]>,
+        way_metadata: Option<&'static [super::osm::Metadata]>,
+        relation_metadata: Option<&'static [super::osm::Metadata]>,
         stringtable: flatdata::RawData<'static>,
     }
 
@@ -1696,6 +1869,24 @@ pub mod osm {
             self.nodes_index
         }
 
+        /// Metadata of the nodes, parallel to `nodes`.
+        #[inline]
+        pub fn node_metadata(&self) -> Option<&[super::osm::Metadata]> {
+            self.node_metadata
+        }
+
+        /// Metadata of the ways, parallel to `ways`.
+        #[inline]
+        pub fn way_metadata(&self) -> Option<&[super::osm::Metadata]> {
+            self.way_metadata
+        }
+
+        /// Metadata of the relations, parallel to `relations`.
+        #[inline]
+        pub fn relation_metadata(&self) -> Option<&[super::osm::Metadata]> {
+            self.relation_metadata
+        }
+
         /// List of strings separated by `\0`.
         #[inline]
         pub fn stringtable(&self) -> flatdata::RawData {
@@ -1723,6 +1914,9 @@ pub mod osm {
                 .field("tags", &self.tags())
                 .field("tags_index", &self.tags_index())
                 .field("nodes_index", &self.nodes_index())
+                .field("node_metadata", &self.node_metadata())
+                .field("way_metadata", &self.way_metadata())
+                .field("relation_metadata", &self.relation_metadata())
                 .field("stringtable", &self.stringtable())
                 .finish()
         }
@@ -1876,6 +2070,42 @@ pub mod osm {
                     resource.and_then(|x| <&[super::osm::NodeIndex]>::from_bytes(x)),
                 )?
             };
+            let node_metadata = {
+                use flatdata::check_optional_resource as check;
+                let max_size = None;
+                let resource =
+                    extend(storage.read("node_metadata", schema::osm::resources::NODE_METADATA));
+                check(
+                    "node_metadata",
+                    |r| r.len(),
+                    max_size,
+                    resource.and_then(|x| <&[super::osm::Metadata]>::from_bytes(x)),
+                )?
+            };
+            let way_metadata = {
+                use flatdata::check_optional_resource as check;
+                let max_size = None;
+                let resource =
+                    extend(storage.read("way_metadata", schema::osm::resources::WAY_METADATA));
+                check(
+                    "way_metadata",
+                    |r| r.len(),
+                    max_size,
+                    resource.and_then(|x| <&[super::osm::Metadata]>::from_bytes(x)),
+                )?
+            };
+            let relation_metadata = {
+                use flatdata::check_optional_resource as check;
+                let max_size = None;
+                let resource =
+                    extend(storage.read("relation_metadata", schema::osm::resources::RELATION_METADATA));
+                check(
+                    "relation_metadata",
+                    |r| r.len(),
+                    max_size,
+                    resource.and_then(|x| <&[super::osm::Metadata]>::from_bytes(x)),
+                )?
+            };
             let stringtable = {
                 use flatdata::check_resource as check;
                 let max_size = Some(1099511627776);
@@ -1902,6 +2132,9 @@ pub mod osm {
                 tags,
                 tags_index,
                 nodes_index,
+                node_metadata,
+                way_metadata,
+                relation_metadata,
                 stringtable,
             })
         }
@@ -2229,6 +2462,102 @@ pub mod osm {
             )
         }
 
+        #[inline]
+        /// Stores [`node_metadata`] in the archive.
+        ///
+        /// [`node_metadata`]: struct.Osm.html#method.node_metadata
+        pub fn set_node_metadata(&self, vector: &[super::osm::Metadata]) -> ::std::io::Result<()> {
+            use flatdata::SliceExt;
+            self.storage.write(
+                "node_metadata",
+                schema::osm::resources::NODE_METADATA,
+                vector.as_bytes(),
+            )
+        }
+
+        /// Opens [`node_metadata`] in the archive for buffered writing.
+        ///
+        /// Elements can be added to the vector until the [`ExternalVector::close`] method
+        /// is called. To flush the data fully into the archive, this method must be called
+        /// in the end.
+        ///
+        /// [`node_metadata`]: struct.Osm.html#method.node_metadata
+        /// [`ExternalVector::close`]: flatdata/struct.ExternalVector.html#method.close
+        #[inline]
+        pub fn start_node_metadata(
+            &self,
+        ) -> ::std::io::Result<flatdata::ExternalVector<super::osm::Metadata>> {
+            flatdata::create_external_vector(
+                &*self.storage,
+                "node_metadata",
+                schema::osm::resources::NODE_METADATA,
+            )
+        }
+
+        #[inline]
+        /// Stores [`way_metadata`] in the archive.
+        ///
+        /// [`way_metadata`]: struct.Osm.html#method.way_metadata
+        pub fn set_way_metadata(&self, vector: &[super::osm::Metadata]) -> ::std::io::Result<()> {
+            use flatdata::SliceExt;
+            self.storage.write(
+                "way_metadata",
+                schema::osm::resources::WAY_METADATA,
+                vector.as_bytes(),
+            )
+        }
+
+        /// Opens [`way_metadata`] in the archive for buffered writing.
+        ///
+        /// Elements can be added to the vector until the [`ExternalVector::close`] method
+        /// is called. To flush the data fully into the archive, this method must be called
+        /// in the end.
+        ///
+        /// [`way_metadata`]: struct.Osm.html#method.way_metadata
+        /// [`ExternalVector::close`]: flatdata/struct.ExternalVector.html#method.close
+        #[inline]
+        pub fn start_way_metadata(
+            &self,
+        ) -> ::std::io::Result<flatdata::ExternalVector<super::osm::Metadata>> {
+            flatdata::create_external_vector(
+                &*self.storage,
+                "way_metadata",
+                schema::osm::resources::WAY_METADATA,
+            )
+        }
+
+        #[inline]
+        /// Stores [`relation_metadata`] in the archive.
+        ///
+        /// [`relation_metadata`]: struct.Osm.html#method.relation_metadata
+        pub fn set_relation_metadata(&self, vector: &[super::osm::Metadata]) -> ::std::io::Result<()> {
+            use flatdata::SliceExt;
+            self.storage.write(
+                "relation_metadata",
+                schema::osm::resources::RELATION_METADATA,
+                vector.as_bytes(),
+            )
+        }
+
+        /// Opens [`relation_metadata`] in the archive for buffered writing.
+        ///
+        /// Elements can be added to the vector until the [`ExternalVector::close`] method
+        /// is called. To flush the data fully into the archive, this method must be called
+        /// in the end.
+        ///
+        /// [`relation_metadata`]: struct.Osm.html#method.relation_metadata
+        /// [`ExternalVector::close`]: flatdata/struct.ExternalVector.html#method.close
+        #[inline]
+        pub fn start_relation_metadata(
+            &self,
+        ) -> ::std::io::Result<flatdata::ExternalVector<super::osm::Metadata>> {
+            flatdata::create_external_vector(
+                &*self.storage,
+                "relation_metadata",
+                schema::osm::resources::RELATION_METADATA,
+            )
+        }
+
         /// Stores [`stringtable`] in the archive.
         ///
         /// [`stringtable`]: struct.Osm.html#method.stringtable
@@ -2372,6 +2701,16 @@ struct NodeIndex
 }
 }
 
+namespace osm {
+struct Metadata
+{
+    version : i32 : 32;
+    timestamp : i64 : 64;
+    changeset : i64 : 64;
+    user_idx : u64 : 40;
+}
+}
+
 namespace osm {
 archive Osm
 {
@@ -2401,6 +2740,15 @@ archive Osm
     tags_index : vector< .osm.TagIndex >;
     @explicit_reference( .osm.NodeIndex.value, .osm.Osm.nodes )
     nodes_index : vector< .osm.NodeIndex >;
+    @optional
+    @explicit_reference( .osm.Metadata.user_idx, .osm.Osm.stringtable )
+    node_metadata : vector< .osm.Metadata >;
+    @optional
+    @explicit_reference( .osm.Metadata.user_idx, .osm.Osm.stringtable )
+    way_metadata : vector< .osm.Metadata >;
+    @optional
+    @explicit_reference( .osm.Metadata.user_idx, .osm.Osm.stringtable )
+    relation_metadata : vector< .osm.Metadata >;
     stringtable : raw_data;
 }
 }
@@ -2634,6 +2982,66 @@ archive Osm
 }
 }
 
+"#;
+                pub const NODE_METADATA: &str = r#"namespace osm {
+struct Metadata
+{
+    version : i32 : 32;
+    timestamp : i64 : 64;
+    changeset : i64 : 64;
+    user_idx : u64 : 40;
+}
+}
+
+namespace osm {
+archive Osm
+{
+    @optional
+    @explicit_reference( .osm.Metadata.user_idx, .osm.Osm.stringtable )
+    node_metadata : vector< .osm.Metadata >;
+}
+}
+
+"#;
+                pub const WAY_METADATA: &str = r#"namespace osm {
+struct Metadata
+{
+    version : i32 : 32;
+    timestamp : i64 : 64;
+    changeset : i64 : 64;
+    user_idx : u64 : 40;
+}
+}
+
+namespace osm {
+archive Osm
+{
+    @optional
+    @explicit_reference( .osm.Metadata.user_idx, .osm.Osm.stringtable )
+    way_metadata : vector< .osm.Metadata >;
+}
+}
+
+"#;
+                pub const RELATION_METADATA: &str = r#"namespace osm {
+struct Metadata
+{
+    version : i32 : 32;
+    timestamp : i64 : 64;
+    changeset : i64 : 64;
+    user_idx : u64 : 40;
+}
+}
+
+namespace osm {
+archive Osm
+{
+    @optional
+    @explicit_reference( .osm.Metadata.user_idx, .osm.Osm.stringtable )
+    relation_metadata : vector< .osm.Metadata >;
+}
+}
+
 "#;
                 pub const STRINGTABLE: &str = r#"namespace osm {
 archive Osm
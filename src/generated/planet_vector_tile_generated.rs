@@ -689,6 +689,7 @@ impl<'a> PVTFeature<'a> {
     pub const VT_KEYS: flatbuffers::VOffsetT = 6;
     pub const VT_VALUES: flatbuffers::VOffsetT = 8;
     pub const VT_GEOMETRIES: flatbuffers::VOffsetT = 10;
+    pub const VT_AREA: flatbuffers::VOffsetT = 14;
 
     #[inline]
     pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
@@ -710,6 +711,7 @@ impl<'a> PVTFeature<'a> {
         if let Some(x) = args.keys {
             builder.add_keys(x);
         }
+        builder.add_area(args.area);
         builder.finish()
     }
 
@@ -759,6 +761,17 @@ impl<'a> PVTFeature<'a> {
             >>(PVTFeature::VT_GEOMETRIES, None)
         }
     }
+    #[inline]
+    pub fn area(&self) -> bool {
+        // Safety:
+        // Created from valid Table for this object
+        // which contains a valid value in this slot
+        unsafe {
+            self._tab
+                .get::<bool>(PVTFeature::VT_AREA, Some(false))
+                .unwrap()
+        }
+    }
 }
 
 impl flatbuffers::Verifiable for PVTFeature<'_> {
@@ -783,6 +796,7 @@ impl flatbuffers::Verifiable for PVTFeature<'_> {
             .visit_field::<flatbuffers::ForwardsUOffset<
                 flatbuffers::Vector<'_, flatbuffers::ForwardsUOffset<PVTGeometry>>,
             >>("geometries", Self::VT_GEOMETRIES, false)?
+            .visit_field::<bool>("area", Self::VT_AREA, false)?
             .finish();
         Ok(())
     }
@@ -796,6 +810,7 @@ pub struct PVTFeatureArgs<'a> {
             flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<PVTGeometry<'a>>>,
         >,
     >,
+    pub area: bool,
 }
 impl<'a> Default for PVTFeatureArgs<'a> {
     #[inline]
@@ -805,6 +820,7 @@ impl<'a> Default for PVTFeatureArgs<'a> {
             keys: None,
             values: None,
             geometries: None,
+            area: false,
         }
     }
 }
@@ -839,6 +855,11 @@ impl<'a: 'b, 'b> PVTFeatureBuilder<'a, 'b> {
             .push_slot_always::<flatbuffers::WIPOffset<_>>(PVTFeature::VT_GEOMETRIES, geometries);
     }
     #[inline]
+    pub fn add_area(&mut self, area: bool) {
+        self.fbb_
+            .push_slot::<bool>(PVTFeature::VT_AREA, area, false);
+    }
+    #[inline]
     pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> PVTFeatureBuilder<'a, 'b> {
         let start = _fbb.start_table();
         PVTFeatureBuilder {
@@ -860,6 +881,7 @@ impl core::fmt::Debug for PVTFeature<'_> {
         ds.field("keys", &self.keys());
         ds.field("values", &self.values());
         ds.field("geometries", &self.geometries());
+        ds.field("area", &self.area());
         ds.finish()
     }
 }
@@ -6,6 +6,7 @@ use super::{
 };
 use crate::{
     location::h_to_xy,
+    osmflat::tags,
     rules::{IncludeTagIdxs, RuleEval},
     tile::planet_vector_tile_generated::*,
 };
@@ -16,7 +17,7 @@ use crate::{
     hilbert::hilbert_tile::HilbertTile,
     hilbert::tree::HilbertTree,
     location::lonlat_to_xy,
-    osmflat::osmflat_generated::osm::{Tag, TagIndex},
+    osmflat::osmflat_generated::osm::{Metadata, Tag, TagIndex},
     pvt_builder::PVTBuilder,
     source::Source,
     tile::{
@@ -27,6 +28,10 @@ use crate::{
     },
 };
 
+/// The layer of the label points of areas, after the layers of the rules. The features
+/// have the tags of their area and are located at its pole of inaccessibility.
+pub const LABEL_LAYER: &str = "labels";
+
 impl Source for HilbertTree {
     fn compose_tile(&self, tile: &Tile, builder: &mut PVTBuilder) {
         match self.find(tile) {
@@ -134,12 +139,33 @@ impl HilbertTree {
         let tags_index = self.flatdata.tags_index();
         let tags_index_len = tags_index.len();
         let strings = self.flatdata.stringtable();
-
-        let mut layers: Vec<Vec<WIPOffset<PVTFeature>>> = vec![vec![]; self.rules.layers.len()];
+        let node_metadata = self.flatdata.node_metadata();
+        let way_metadata = self.flatdata.way_metadata();
+        let relation_metadata = self.flatdata.relation_metadata();
+
+        let label_layer = self.rules.layers.len();
+        let mut layers: Vec<Vec<WIPOffset<PVTFeature>>> = vec![vec![]; label_layer + 1];
+
+        // An odd zoom tile is composed from the content of the even zoom tile that contains
+        // it, which is twice its size, and a tile past the leaf zoom from the content of the
+        // leaf that contains it. Only the features within its own bounds are kept, and their
+        // paths are clipped rather than clamped to the tile.
+        let bounds = if tile.z & 1 == 1 || tile.z > self.manifest.render.leaf_zoom {
+            Some(tile.bbox())
+        } else {
+            None
+        };
+        let in_bounds = |xy: (u32, u32)| bounds.as_ref().is_none_or(|b| b.contains(xy));
 
         for i in relations_it {
             let relation = &relations[i];
 
+            let h = relation_pairs[i].h();
+            let xy = h_to_xy(h); // h is already in Mercator.
+            if !in_bounds(xy) {
+                continue;
+            }
+
             let tags_index_start = relation.tag_first_idx() as usize;
             let tags_index_end = if i + 1 < relations_len {
                 relations[i + 1].tag_first_idx() as usize
@@ -156,6 +182,7 @@ impl HilbertTree {
             let (keys, vals) = build_tags(
                 tags_index_range,
                 relation.osm_id(),
+                relation_metadata.map(|m| &m[i]),
                 tags_index,
                 tags,
                 strings,
@@ -167,10 +194,8 @@ impl HilbertTree {
             let vals_vec = builder.fbb.create_vector(&vals);
 
             // Point geometries for the hilbert location of the relation
-            // This is useful to see that we have included a relation for debugging,
-            // and it also is used as a label point for multipolygons and boundaries.
-            let h = relation_pairs[i].h();
-            let xy = h_to_xy(h); // h is already in Mercator.
+            // This is useful to see that we have included a relation for debugging.
+            // Multipolygons are located at their label point, so they are labeled there.
             let tile_point = tile.project(xy);
             let points = builder.fbb.create_vector(&[tile_point]);
             let mut geom_builder = PVTGeometryBuilder::new(&mut builder.fbb);
@@ -184,12 +209,24 @@ impl HilbertTree {
                     keys: Some(keys_vec),
                     values: Some(vals_vec),
                     geometries: Some(geoms),
+                    area: false,
                 },
             );
 
             for layer_i in &rule_eval.layers {
                 layers[*layer_i].push(feature)
             }
+
+            if !rule_eval.layers.is_empty()
+                && tags::has_tag(
+                    &self.flatdata,
+                    tags_index_start as u64..tags_index_end as u64,
+                    b"type",
+                    b"multipolygon",
+                )
+            {
+                layers[label_layer].push(feature);
+            }
         }
 
         for i in nodes_it {
@@ -212,6 +249,11 @@ impl HilbertTree {
                 continue;
             }
 
+            let xy = lonlat_to_xy((node.lon(), node.lat()));
+            if !in_bounds(xy) {
+                continue;
+            }
+
             let rule_eval = self
                 .rules
                 .evaluate_tags(&self.flatdata, tags_index_range.clone());
@@ -220,6 +262,7 @@ impl HilbertTree {
             let (keys, vals) = build_tags(
                 tags_index_range,
                 node.osm_id(),
+                node_metadata.map(|m| &m[i]),
                 tags_index,
                 tags,
                 strings,
@@ -231,9 +274,6 @@ impl HilbertTree {
             let vals_vec = builder.fbb.create_vector(&vals);
 
             // Geometries
-            let lon = node.lon();
-            let lat = node.lat();
-            let xy = lonlat_to_xy((lon, lat));
             let tile_point = tile.project(xy);
             let points = builder.fbb.create_vector(&[tile_point]);
             let mut geom_builder = PVTGeometryBuilder::new(&mut builder.fbb);
@@ -248,6 +288,7 @@ impl HilbertTree {
                     keys: Some(keys_vec),
                     values: Some(vals_vec),
                     geometries: Some(geoms),
+                    area: false,
                 },
             );
 
@@ -259,6 +300,34 @@ impl HilbertTree {
         for i in ways_it {
             let way = &ways[i];
 
+            let range = way.refs();
+            let refs_index_start = range.start as usize;
+            let refs_index_end = if range.end != 0 {
+                range.end as usize
+            } else {
+                nodes_index_len
+            };
+            let mut xys = Vec::with_capacity(refs_index_end - refs_index_start);
+            for i in refs_index_start..refs_index_end {
+                if let Some(r) = nodes_index[i].value() {
+                    let n = &nodes[r as usize];
+                    xys.push(lonlat_to_xy((n.lon(), n.lat())));
+                }
+            }
+            if let Some(bounds) = &bounds {
+                if !bounds.overlaps(&xys) {
+                    continue;
+                }
+            }
+            let paths = if bounds.is_some() {
+                tile.project_clipped(&xys)
+            } else {
+                vec![xys.iter().map(|xy| tile.project(*xy)).collect()]
+            };
+            if paths.is_empty() {
+                continue;
+            }
+
             let range = way.tags();
             let tags_index_start = range.start as usize;
             let tags_index_end = if range.end != 0 {
@@ -276,9 +345,22 @@ impl HilbertTree {
                 .rules
                 .evaluate_tags(&self.flatdata, tags_index_range.clone());
 
+            // Closed ways are areas unless their tags make them lines, such as a closed
+            // highway. Areas also get a point feature in the label layer. It is at the
+            // Hilbert location of the way, its pole of inaccessibility.
+            let is_closed = refs_index_end - refs_index_start >= 4
+                && nodes_index[refs_index_start].value().is_some()
+                && nodes_index[refs_index_start].value() == nodes_index[refs_index_end - 1].value();
+            let is_area = is_closed
+                && tags::is_area(
+                    &self.flatdata,
+                    tags_index_range.start as u64..tags_index_range.end as u64,
+                );
+
             let (keys, vals) = build_tags(
                 tags_index_range,
                 way.osm_id(),
+                way_metadata.map(|m| &m[i]),
                 tags_index,
                 tags,
                 strings,
@@ -290,33 +372,17 @@ impl HilbertTree {
             let vals_vec = builder.fbb.create_vector(&vals);
 
             // Geometries
-            let range = way.refs();
-            let refs_index_start = range.start as usize;
-            let refs_index_end = if range.end != 0 {
-                range.end as usize
-            } else {
-                nodes_index_len
-            };
-
-            let mut path = Vec::with_capacity(refs_index_end - refs_index_start);
-            for i in refs_index_start..refs_index_end {
-                if let Some(r) = nodes_index[i].value() {
-                    let n = &nodes[r as usize];
-                    let lon = n.lon();
-                    let lat = n.lat();
-                    let xy = lonlat_to_xy((lon, lat));
-                    let tile_point = tile.project(xy);
-                    path.push(tile_point);
-                }
+            let mut geoms = Vec::with_capacity(paths.len());
+            for path in &paths {
+                let points = builder.fbb.create_vector(path);
+                geoms.push(PVTGeometry::create(
+                    &mut builder.fbb,
+                    &PVTGeometryArgs {
+                        points: Some(points),
+                    },
+                ));
             }
-            let points = builder.fbb.create_vector(&path);
-            let geom = PVTGeometry::create(
-                &mut builder.fbb,
-                &PVTGeometryArgs {
-                    points: Some(points),
-                },
-            );
-            let geoms = builder.fbb.create_vector(&[geom]);
+            let geoms = builder.fbb.create_vector(&geoms);
 
             let feature = PVTFeature::create(
                 &mut builder.fbb,
@@ -325,17 +391,43 @@ impl HilbertTree {
                     keys: Some(keys_vec),
                     values: Some(vals_vec),
                     geometries: Some(geoms),
+                    area: is_area,
                 },
             );
 
             for layer_i in &rule_eval.layers {
                 layers[*layer_i].push(feature)
             }
+
+            let h = way_pairs[i].h();
+            // Untagged areas, such as the rings of multipolygons, have nothing to label.
+            let is_tagged = tags_index_start != tags_index_end;
+            if is_area && is_tagged && !rule_eval.layers.is_empty() && in_bounds(h_to_xy(h)) {
+                // The label has the tags of the area, but it is a point.
+                let tile_point = tile.project(h_to_xy(h));
+                let points = builder.fbb.create_vector(&[tile_point]);
+                let mut geom_builder = PVTGeometryBuilder::new(&mut builder.fbb);
+                geom_builder.add_points(points);
+                let geom = geom_builder.finish();
+                let geoms = builder.fbb.create_vector(&[geom]);
+                let label = PVTFeature::create(
+                    &mut builder.fbb,
+                    &PVTFeatureArgs {
+                        id: h,
+                        keys: Some(keys_vec),
+                        values: Some(vals_vec),
+                        geometries: Some(geoms),
+                        area: false,
+                    },
+                );
+
+                layers[label_layer].push(label);
+            }
         }
 
         for (i, features) in layers.iter().enumerate() {
             let features_vec = builder.fbb.create_vector(features);
-            let name_str = self.rules.layers[i].as_str();
+            let name_str = self.rules.layers.get(i).map_or(LABEL_LAYER, |l| l.as_str());
             let name = builder.attributes.upsert_string(name_str);
             let layer = PVTLayer::create(
                 &mut builder.fbb,
@@ -349,9 +441,11 @@ impl HilbertTree {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn build_tags(
     tags_index_range: Range<usize>,
     osm_id: i64,
+    metadata: Option<&Metadata>,
     tags_index: &[TagIndex],
     tags: &[Tag],
     strings: RawData,
@@ -386,38 +480,55 @@ fn build_tags(
         (keys, vals)
     };
 
-    if all_tags {
-        return include_all_tags();
-    }
-
-    match &rule_eval.include {
-        IncludeTagIdxs::None => (Vec::from([rule_key]), Vec::from([rule_val])),
-        IncludeTagIdxs::All => include_all_tags(),
-        IncludeTagIdxs::Keys(key_str_idxs) => {
-            let mut keys: Vec<u32> = Vec::with_capacity(key_str_idxs.len());
-            let mut vals: Vec<u32> = Vec::with_capacity(key_str_idxs.len());
-            keys.push(rule_key);
-            vals.push(rule_val);
-
-            for tag_idx in &tags_index[tags_index_range] {
-                let tag_i = tag_idx.value() as usize;
-                let tag = &tags[tag_i];
-                let key_idx = tag.key_idx() as usize;
-                if key_str_idxs.contains(&key_idx) {
-                    let k = unsafe { strings.substring_unchecked(tag.key_idx() as usize) };
-                    let v = unsafe { strings.substring_unchecked(tag.value_idx() as usize) };
-                    keys.push(builder.attributes.upsert_string(k));
-                    vals.push(builder.attributes.upsert_string_value(v));
+    let (mut keys, mut vals) = if all_tags {
+        include_all_tags()
+    } else {
+        match &rule_eval.include {
+            IncludeTagIdxs::None => (Vec::from([rule_key]), Vec::from([rule_val])),
+            IncludeTagIdxs::All => include_all_tags(),
+            IncludeTagIdxs::Keys(key_str_idxs) => {
+                let mut keys: Vec<u32> = Vec::with_capacity(key_str_idxs.len());
+                let mut vals: Vec<u32> = Vec::with_capacity(key_str_idxs.len());
+                keys.push(rule_key);
+                vals.push(rule_val);
+
+                for tag_idx in &tags_index[tags_index_range] {
+                    let tag_i = tag_idx.value() as usize;
+                    let tag = &tags[tag_i];
+                    let key_idx = tag.key_idx() as usize;
+                    if key_str_idxs.contains(&key_idx) {
+                        let k = unsafe { strings.substring_unchecked(tag.key_idx() as usize) };
+                        let v = unsafe { strings.substring_unchecked(tag.value_idx() as usize) };
+                        keys.push(builder.attributes.upsert_string(k));
+                        vals.push(builder.attributes.upsert_string_value(v));
+                    }
                 }
+                (keys, vals)
             }
-            (keys, vals)
         }
+    };
+
+    // Entities without metadata in the source have a version of 0.
+    if let Some(metadata) = metadata.filter(|m| m.version() != 0) {
+        let user = unsafe { strings.substring_unchecked(metadata.user_idx() as usize) };
+        let attributes = &mut builder.attributes;
+        keys.push(attributes.upsert_string("@version"));
+        vals.push(attributes.upsert_number_value(metadata.version() as f64));
+        keys.push(attributes.upsert_string("@timestamp"));
+        vals.push(attributes.upsert_number_value(metadata.timestamp() as f64));
+        keys.push(attributes.upsert_string("@changeset"));
+        vals.push(attributes.upsert_number_value(metadata.changeset() as f64));
+        keys.push(attributes.upsert_string("@user"));
+        vals.push(attributes.upsert_string_value(user));
     }
+
+    (keys, vals)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::manifest;
+    use crate::rules::Rules;
 
     use super::*;
 
@@ -433,7 +544,7 @@ mod tests {
         let mut builder = PVTBuilder::new();
         tree.compose_tile(&t, &mut builder);
 
-        assert_eq!(builder.layers.len(), 8);
+        assert_eq!(builder.layers.len(), 9);
 
         let vec_u8 = builder.build();
 
@@ -444,11 +555,12 @@ mod tests {
         for (i, layer) in layers.iter().enumerate() {
             let name_i = layer.name();
             let name = strings.get(name_i as usize);
-            assert_eq!(tree.rules.layers[i], name);
+            let layer_name = tree.rules.layers.get(i).map_or(LABEL_LAYER, |l| l.as_str());
+            assert_eq!(layer_name, name);
             // println!("{}", name);
         }
 
-        assert_eq!(layers.len(), 8);
+        assert_eq!(layers.len(), 9);
 
         let layer_str_idx = layers.get(0).name();
         let strings = pvt.strings().unwrap();
@@ -492,6 +604,153 @@ mod tests {
         assert_eq!(y, 58);
     }
 
+    #[test]
+    fn test_build_tags_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest::parse("tests/fixtures/relations_convert.yaml").unwrap();
+        manifest.data.planet = dir.path().to_path_buf();
+        manifest.data.include_metadata = true;
+        let flatdata = crate::osmflat::convert(&manifest).unwrap();
+        let rule_eval = &Rules::default(&manifest).evals[0];
+
+        // The cafe.
+        let node = &flatdata.nodes()[0];
+        let range = node.tags();
+        let tags = |metadata: Option<&Metadata>| {
+            let mut builder = PVTBuilder::new();
+            let (keys, vals) = build_tags(
+                range.start as usize..range.end as usize,
+                node.osm_id(),
+                metadata,
+                flatdata.tags_index(),
+                flatdata.tags(),
+                flatdata.stringtable(),
+                &mut builder,
+                rule_eval,
+                false,
+            );
+            let attributes = &builder.attributes;
+            let keys: Vec<String> = keys
+                .iter()
+                .map(|k| attributes.strings.get_index(*k as usize).unwrap().0.clone())
+                .collect();
+            let vals: Vec<f64> = vals
+                .iter()
+                .map(|v| attributes.values.get_index(*v as usize).unwrap().0.v())
+                .collect();
+            (keys, vals)
+        };
+
+        let (keys, vals) = tags(Some(&flatdata.node_metadata().unwrap()[0]));
+        assert_eq!(
+            keys,
+            [
+                "rule",
+                "osm_id",
+                "amenity",
+                "name",
+                "@version",
+                "@timestamp",
+                "@changeset",
+                "@user"
+            ]
+        );
+        assert_eq!(vals[4..7], [2.0, 1667296800.0, 128000000.0]);
+
+        let no_metadata = ["rule", "osm_id", "amenity", "name"];
+        assert_eq!(tags(None).0, no_metadata);
+        // Entities without metadata in their source have a version of 0.
+        assert_eq!(tags(Some(&Metadata::new())).0, no_metadata);
+    }
+
+    // The layer, osm_id and geometry lengths of each feature, and if it is marked as an area.
+    fn features(tile: &Tile, tree: &HilbertTree) -> Vec<(String, i64, Vec<usize>, bool)> {
+        let mut builder = PVTBuilder::new();
+        tree.compose_tile(tile, &mut builder);
+        let vec_u8 = builder.build();
+        let pvt = root_as_pvttile(&vec_u8).unwrap();
+        let strings = pvt.strings().unwrap();
+        let values = pvt.values().unwrap();
+
+        let mut features = Vec::new();
+        for layer in pvt.layers().unwrap() {
+            let name = strings.get(layer.name() as usize);
+            for feature in layer.features().unwrap() {
+                let keys = feature.keys().unwrap();
+                let vals = feature.values().unwrap();
+                let value = |key: &str| {
+                    keys.iter()
+                        .position(|k| strings.get(k as usize) == key)
+                        .map(|i| values.get(vals.get(i) as usize).v())
+                };
+                let lens = feature
+                    .geometries()
+                    .unwrap()
+                    .iter()
+                    .map(|g| g.points().unwrap().len())
+                    .collect();
+                features.push((
+                    name.to_string(),
+                    value("osm_id").unwrap() as i64,
+                    lens,
+                    feature.area(),
+                ));
+            }
+        }
+        features
+    }
+
+    // The tree of the relations fixture, with all of the tags.
+    fn relations_tree() -> HilbertTree {
+        let mut manifest = manifest::parse("tests/fixtures/relations_sort.yaml").unwrap();
+        manifest.render.all_tags = true;
+        HilbertTree::open(&manifest).unwrap()
+    }
+
+    // The z12 tile at a location.
+    fn leaf_tile(lonlat: (i32, i32)) -> Tile {
+        let (x, y) = lonlat_to_xy(lonlat);
+        Tile::from_zxy(12, x >> 20, y >> 20)
+    }
+
+    #[test]
+    fn test_area_ways() {
+        let tree = relations_tree();
+        // The tile of the museum and the roundabout.
+        let features = features(&leaf_tile((-1220200000, 369700000)), &tree);
+        let of = |osm_id: i64| {
+            features
+                .iter()
+                .filter(|f| f.1 == osm_id)
+                .map(|f| (f.0.as_str(), f.2.clone(), f.3))
+                .collect::<Vec<_>>()
+        };
+        // The building is an area, with a closed ring, and its label point is a point
+        // in the label layer.
+        assert_eq!(
+            of(100),
+            [("Buildings", vec![5], true), (LABEL_LAYER, vec![1], false)]
+        );
+        // The closed highway is a line, without a label.
+        assert_eq!(of(101), [("Major Roads", vec![5], false)]);
+    }
+
+    #[test]
+    fn test_multipolygon_label() {
+        let tree = relations_tree();
+        // The tile of the forest.
+        let features = features(&leaf_tile((-1220550000, 369950000)), &tree);
+        let labels: Vec<i64> = features
+            .iter()
+            .filter(|f| f.0 == LABEL_LAYER && f.2 == [1])
+            .map(|f| f.1)
+            .collect();
+        // Its rings are untagged ways, which are not labeled.
+        assert!(labels.contains(&200));
+        assert!(!labels.contains(&102));
+        assert!(!labels.contains(&103));
+    }
+
     #[test]
     fn test_tags_index() {
         let manifest = manifest::parse("tests/fixtures/santa_cruz_sort.yaml").unwrap();
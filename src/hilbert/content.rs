@@ -4,7 +4,7 @@ use humantime::format_duration;
 use pbr::ProgressBar;
 use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
-use super::{hilbert_tile::HilbertTile, leaf::Leaf};
+use super::{hilbert_tile::HilbertTile, leaf::Leaf, update::Previous};
 use crate::{
     filter::Filter, manifest::Manifest, mutant::Mutant, osmflat::osmflat_generated::osm::Osm,
     rules::Rules, util,
@@ -14,6 +14,8 @@ type Err = Box<dyn std::error::Error>;
 
 static mut ONE: u32 = 0;
 
+/// Renders the content of every tile. After an update, the tiles that it does not touch
+/// keep the content they had before, and only the touched ones are rendered.
 pub fn render_tile_content(
     m_leaves: &Mutant<Leaf>,
     m_tiles: &Mutant<HilbertTile>,
@@ -21,6 +23,7 @@ pub fn render_tile_content(
     m_leaves_external_relations: &Mutant<u32>,
     flatdata: &Osm,
     manifest: &Manifest,
+    previous: Option<&Previous>,
 ) -> Result<(Mutant<u64>, Mutant<u32>, Mutant<u32>, Rules), Err> {
     let dir = &manifest.data.planet;
     let new_rules = Rules::build(&manifest, flatdata);
@@ -50,6 +53,7 @@ pub fn render_tile_content(
     let mut children = 0;
 
     let tiles_len = tiles.len();
+    let mut kept_tiles = 0;
 
     let t = util::timer("Rendering tile content...");
     let mut pb = ProgressBar::new(tiles_len as u64);
@@ -72,9 +76,19 @@ pub fn render_tile_content(
             None
         };
 
+        let previous_content = previous.and_then(|previous| {
+            let leaf = get_origin_leaf(i, z, leaf_zoom, tiles, leaves);
+            previous.content(z, leaf.h >> (2 * (leaf_zoom - z)))
+        });
+
         // Get a vec of indices to all of the entities in the tile.
         // We will then filter from this to render tile content.
-        let (nodes, ways, relations): (Vec<u64>, Vec<u32>, Vec<u32>) = if is_leaf_parent_zoom {
+        let (nodes, ways, relations): (Vec<u64>, Vec<u32>, Vec<u32>) = if let Some(content) =
+            previous_content
+        {
+            kept_tiles += 1;
+            content
+        } else if is_leaf_parent_zoom {
             let is_last_leaf_parent = children == total_children;
 
             let start_leaf = &leaves[tile.child as usize];
@@ -227,6 +241,13 @@ pub fn render_tile_content(
     m_w.trim();
     m_r.trim();
 
+    if previous.is_some() {
+        println!(
+            "Rendered {} tiles, kept the content of {}.",
+            tiles_len - kept_tiles,
+            kept_tiles
+        );
+    }
     println!(
         "Rendering tile content took {}",
         format_duration(t.elapsed())
@@ -291,6 +312,7 @@ mod tests {
             &m_leaves_external_relations,
             &flatdata,
             &manifest::parse("tests/fixtures/santa_cruz_sort.yaml").unwrap(),
+            None,
         )
         .unwrap();
     }
@@ -313,6 +335,7 @@ mod tests {
             &m_leaves_external_relations,
             &flatdata,
             &manifest::parse("tests/fixtures/santa_cruz_sort.yaml").unwrap(),
+            None,
         )
         .unwrap();
     }
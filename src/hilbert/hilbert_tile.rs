@@ -140,7 +140,10 @@ fn max_tiles_len(leaves: &[Leaf], leaf_zoom: u8) -> usize {
             count += potential_leaves;
             zoom -= 2;
         }
-        (count + 1) as usize
+        // No level has more tiles than there are leaves, which bounds sparse planets
+        // with a high leaf zoom far better.
+        let levels = (leaf_zoom / 2) as usize;
+        ((count + 1) as usize).min(len * levels + 1)
     }
 }
 
@@ -149,7 +152,7 @@ fn get_leaf_h(
     leaves: &[Leaf],
     leaf_parent_level_end: usize,
     child_i: usize,
-) -> u32 {
+) -> u64 {
     // When still working on parent level of the leaves, the end is set to 0.
     if leaf_parent_level_end == 0 {
         return leaves[child_i].h;
@@ -168,11 +171,11 @@ fn get_leaf_h(
     leaves[i].h
 }
 
-fn leaf_to_tile_h(h: u32, leaf_zoom: u8, zoom: u8) -> u32 {
+fn leaf_to_tile_h(h: u64, leaf_zoom: u8, zoom: u8) -> u64 {
     h >> (2 * (leaf_zoom - zoom))
 }
 
-fn child_h_range_end(h: u32) -> u32 {
+fn child_h_range_end(h: u64) -> u64 {
     let start = h << 4;
     start + 16
 }
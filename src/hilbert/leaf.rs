@@ -1,15 +1,20 @@
+use std::fs;
 use std::io::{Error, ErrorKind};
+use std::ops::Range;
 use std::path::Path;
 
+use crate::external_sort;
+use crate::manifest::Manifest;
 use crate::osmflat::osmflat_generated::osm::{EntityType, HilbertRelationPair, Osm};
-use crate::tile::tile_count_for_zoom;
+use crate::tile::{tile_count_for_zoom, Tile};
+use crate::util;
 use crate::util::{finish, timer};
-use crate::{location, util};
 use crate::{
-    location::h_to_zoom_h,
+    location::{h_to_xy, h_to_zoom_h},
     mutant::Mutant,
     osmflat::osmflat_generated::osm::{HilbertNodePair, HilbertWayPair},
 };
+use ahash::AHashSet;
 use dashmap::mapref::entry::Entry::{Occupied, Vacant};
 use dashmap::DashMap;
 use rayon::prelude::*;
@@ -30,7 +35,7 @@ pub struct Leaf {
     pub w: u32,
     pub r: u32,
     // Hilbert index for the leaf tile, at the leaf zoom
-    pub h: u32,
+    pub h: u64,
     // Indices to the first of ways in relations in w_ext and r_ext
     // denoting ways and relations that enter the given leaf tile
     // that exist outside of the leaf's n,w,r ranges.
@@ -49,7 +54,7 @@ pub fn build_leaves(
     let way_pairs = m_way_pairs.slice();
     let relation_pairs = m_relation_pairs.slice();
 
-    if node_pairs.len() == 0 && way_pairs.len() == 0 {
+    if node_pairs.is_empty() && way_pairs.is_empty() && relation_pairs.is_empty() {
         return Err(Box::new(Error::new(
             ErrorKind::Other,
             "No hilbert pairs found! Cannot build Hilbert Leaves.",
@@ -58,153 +63,69 @@ pub fn build_leaves(
 
     let time = util::timer("Building Hilbert Leaves...");
 
-    let mut n_i: usize = 0; // node hilbert pair index
-    let mut w_i: usize = 0; // way hilbert pair index
-    let mut r_i: usize = 0; // relation hilbert pair index
-
-    let mut lowest_h = 0;
-
-    // Find the lowest hilbert tile
-    if let Some(first_node_pair) = node_pairs.first() {
-        lowest_h = first_node_pair.h();
-        n_i = 1;
-        w_i = 0;
-        r_i = 0;
-    }
-    if let Some(first_way_pair) = way_pairs.first() {
-        let first_way_h = first_way_pair.h();
-        if first_way_h < lowest_h {
-            lowest_h = first_way_h;
-            n_i = 0;
-            w_i = 1;
-            r_i = 0;
-        }
-    }
-    if let Some(first_relation_pair) = relation_pairs.first() {
-        let first_relation_h = first_relation_pair.h();
-        if first_relation_h < lowest_h {
-            lowest_h = first_relation_h;
-            w_i = 0;
-            n_i = 0;
-            r_i = 1;
-        }
-    }
+    // The leaf tile of the pair at the given index, for each of the sorted pair vectors.
+    let node_tile_h = |i: usize| node_pairs.get(i).map(|p| h_to_zoom_h(p.h(), leaf_zoom));
+    let way_tile_h = |i: usize| way_pairs.get(i).map(|p| h_to_zoom_h(p.h(), leaf_zoom));
+    let relation_tile_h = |i: usize| relation_pairs.get(i).map(|p| h_to_zoom_h(p.h(), leaf_zoom));
 
     // First leaf Hilbert tile has the lowest hilbert location.
-    let mut tile_h = location::h_to_zoom_h(lowest_h, leaf_zoom) as u32;
+    let mut tile_h = [node_tile_h(0), way_tile_h(0), relation_tile_h(0)]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or(0);
     println!(
         "Lowest tile_h for leaves in hilbert tree: {}, leaf_zoom: {}",
-        lowest_h, leaf_zoom
+        tile_h, leaf_zoom
     );
 
     // NHTODO Implement the ability to grow the LeafTile mutant so that we don't have to allocate max size upfront?
-    let max_len = tile_count_for_zoom(leaf_zoom) as usize;
+    // Every leaf starts at a node, way or relation pair, so there are no more leaves than
+    // pairs, which is far fewer than the tiles of a high leaf zoom.
+    let pairs_len = node_pairs.len() + way_pairs.len() + relation_pairs.len();
+    let max_len = (tile_count_for_zoom(leaf_zoom) as usize).min(pairs_len);
     let mut m_leaves = Mutant::<Leaf>::new(dir, "hilbert_leaves", max_len)?;
     let leaves = m_leaves.mutable_slice();
 
-    let node_pairs = m_node_pairs.slice();
-    let node_pairs_len = node_pairs.len();
-    let way_pairs = m_way_pairs.slice();
-    let way_pairs_len = way_pairs.len();
-    let relation_pairs = m_relation_pairs.slice();
-    let relation_pairs_len = relation_pairs.len();
-
-    // First leaf tile
-    let first_leaf = Leaf {
-        n: 0,
-        w: 0,
-        r: 0,
-        h: tile_h,
-        w_ext: 0,
-        r_ext: 0,
-    };
-    // println!("0 {:?}", first_leaf);
-    leaves[0] = first_leaf;
-
-    let mut leaf_i = 1;
-
-    let mut next_node_tile_h = tile_h;
-    let mut next_way_tile_h = tile_h;
-    let mut next_relation_tile_h = tile_h;
+    let mut n_i: usize = 0; // node hilbert pair index
+    let mut w_i: usize = 0; // way hilbert pair index
+    let mut r_i: usize = 0; // relation hilbert pair index
+    let mut leaf_i = 0;
 
     loop {
-        let mut node_changed = false;
-        while n_i < node_pairs_len && next_node_tile_h <= tile_h {
-            let node_h = node_pairs[n_i].h();
-            let node_tile_h = location::h_to_zoom_h(node_h, leaf_zoom) as u32;
-            if node_tile_h > tile_h {
-                next_node_tile_h = node_tile_h;
-                node_changed = true;
-                break;
-            }
+        leaves[leaf_i] = Leaf {
+            n: n_i as u64,
+            w: w_i as u32,
+            r: r_i as u32,
+            h: tile_h,
+            w_ext: 0,
+            r_ext: 0,
+        };
+        leaf_i += 1;
+
+        // Move past the pairs in the leaf tile.
+        while node_tile_h(n_i).is_some_and(|h| h <= tile_h) {
             n_i += 1;
         }
-
-        let mut way_changed = false;
-        while w_i < way_pairs_len && next_way_tile_h <= tile_h {
-            let way_h = way_pairs[w_i].h();
-            let way_tile_h = location::h_to_zoom_h(way_h, leaf_zoom) as u32;
-            if way_tile_h > tile_h {
-                next_way_tile_h = way_tile_h;
-                way_changed = true;
-                break;
-            }
+        while way_tile_h(w_i).is_some_and(|h| h <= tile_h) {
             w_i += 1;
         }
+        while relation_tile_h(r_i).is_some_and(|h| h <= tile_h) {
+            r_i += 1;
+        }
 
-        // NHTODO This logic results in dropped features in certain tiles.
-        // let mut relation_changed = false;
-        // while r_i < relation_pairs_len && next_relation_tile_h <= tile_h {
-        //     let relation_h = relation_pairs[r_i].h();
-        //     let relation_tile_h = location::h_to_zoom_h(relation_h, leaf_zoom) as u32;
-        //     if relation_tile_h > tile_h {
-        //         next_relation_tile_h = relation_tile_h;
-        //         relation_changed = true;
-        //         break;
-        //     }
-        //     r_i += 1;
-        // }
-
-        // let mut next_tile_h = tile_h;
-
-        // if node_changed {
-        //     next_tile_h = next_node_tile_h;
-        // }
-
-        // if way_changed && next_way_tile_h < next_tile_h {
-        //     next_tile_h = next_way_tile_h;
-        // }
-
-        // if relation_changed && next_relation_tile_h < next_tile_h {
-        //     next_tile_h = next_relation_tile_h;
-        // }
-
-        // The old way before the relation work.
-        let next_tile_h = if !way_changed || (node_changed && next_node_tile_h < next_way_tile_h) {
-            next_node_tile_h
-        } else {
-            next_way_tile_h
-        };
-
-        if next_tile_h > tile_h {
-            let leaf = Leaf {
-                n: n_i as u64,
-                w: w_i as u32,
-                r: r_i as u32,
-                h: next_tile_h,
-                w_ext: 0,
-                r_ext: 0,
-            };
-            // println!("{} {:?}", leaf_i, leaf);
-            leaves[leaf_i] = leaf;
-            tile_h = next_tile_h;
-            leaf_i += 1;
-        } else {
-            break;
+        // The next leaf tile is the lowest tile of the pairs that are left, whichever
+        // kind of entity it has. A tile with only relations gets a leaf as well.
+        match [node_tile_h(n_i), way_tile_h(w_i), relation_tile_h(r_i)]
+            .into_iter()
+            .flatten()
+            .min()
+        {
+            Some(next_tile_h) => tile_h = next_tile_h,
+            None => break,
         }
     }
 
-    // The last increment of t_i falls through all whiles, so it is equal to the length.
     m_leaves.set_len(leaf_i);
     m_leaves.trim();
     println!("Finished in {} secs.", time.elapsed().as_secs());
@@ -220,40 +141,25 @@ pub fn populate_leaves_external_ways(
     leaf_zoom: u8,
 ) -> Result<Mutant<u32>, Box<dyn std::error::Error>> {
     // NHTODO Profile memory usage here.
-    let leaf_to_ways: DashMap<u32, BTreeSet<u32>> = DashMap::new();
+    let leaf_to_ways: DashMap<u64, BTreeSet<u32>> = DashMap::new();
 
-    let ways = flatdata.ways();
     let way_pairs = m_way_pairs.slice();
     let node_pairs = m_node_pairs.slice();
-    let nodes_index = flatdata.nodes_index();
-    let nodes_index_len = nodes_index.len();
 
     let t = timer("Populating external leaf ways...");
 
-    ways.par_iter().enumerate().for_each(|(i, way)| {
+    (0..way_pairs.len()).into_par_iter().for_each(|i| {
         let way_h = way_pairs[i].h();
-        let way_tile_h = h_to_zoom_h(way_h, leaf_zoom) as u32;
-
-        let refs = way.refs();
-        let start = refs.start as usize;
-        let end = if refs.end == 0 {
-            nodes_index_len
-        } else {
-            refs.end as usize
-        };
+        let way_tile_h = h_to_zoom_h(way_h, leaf_zoom);
 
-        for n in &nodes_index[start..end] {
-            if let Some(v) = n.value() {
-                let h = node_pairs[v as usize].h();
-                let tile_h = h_to_zoom_h(h, leaf_zoom) as u32;
-                if tile_h != way_tile_h {
-                    match leaf_to_ways.entry(tile_h) {
-                        Occupied(mut o) => {
-                            o.get_mut().insert(i as u32);
-                        }
-                        Vacant(v) => {
-                            v.insert(BTreeSet::from([i as u32]));
-                        }
+        for tile_h in way_tiles(flatdata, node_pairs, i, leaf_zoom) {
+            if tile_h != way_tile_h {
+                match leaf_to_ways.entry(tile_h) {
+                    Occupied(mut o) => {
+                        o.get_mut().insert(i as u32);
+                    }
+                    Vacant(v) => {
+                        v.insert(BTreeSet::from([i as u32]));
                     }
                 }
             }
@@ -289,103 +195,250 @@ pub fn populate_leaves_external_ways(
     Ok(leaves_ext_ways)
 }
 
+// A relation that enters a leaf tile other than its own.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct LeafRelation {
+    h: u64,
+    r: u32,
+}
+
+// How many relations to find the leaf tiles of at a time, before they are spilled.
+const RELATIONS_CHUNK: usize = 100_000;
+
+/// Lists every relation in the leaves of the tiles that it enters, other than its own.
+/// A relation enters the tiles of its members, of the paths of its member ways,
+/// and of the members of its member relations, however deeply they nest. The outlines
+/// of large boundaries enter a great many tiles, so rather than gathered in memory, the
+/// tiles are spilled to a file and sorted within the sort memory of the manifest.
 pub fn populate_leaves_external_relations(
-    dir: &Path,
+    manifest: &Manifest,
     flatdata: &Osm,
     m_node_pairs: &Mutant<HilbertNodePair>,
     m_way_pairs: &Mutant<HilbertWayPair>,
     m_relation_pairs: &Mutant<HilbertRelationPair>,
     m_leaves: &Mutant<Leaf>,
-    leaf_zoom: u8,
 ) -> Result<Mutant<u32>, Box<dyn std::error::Error>> {
     let t = timer("Populating external leaf relations...");
-    let leaf_to_relations: DashMap<u32, BTreeSet<u32>> = DashMap::new();
-    let relations = flatdata.relations();
-    let relations_len = relations.len();
-    let members = flatdata.members();
+    let dir = &manifest.data.planet;
+    let leaf_zoom = manifest.render.leaf_zoom;
+    let memory = manifest.data.sort_memory_mb * 1_000_000;
+    let relations_len = flatdata.relations().len();
 
     let node_pairs = m_node_pairs.slice();
     let way_pairs = m_way_pairs.slice();
     let relation_pairs = m_relation_pairs.slice();
 
-    relations
-        .par_iter()
-        .enumerate()
-        .for_each(|(relation_i, relation)| {
-            let relation_h = relation_pairs[relation_i].h();
-            let relation_tile_h = h_to_zoom_h(relation_h, leaf_zoom) as u32;
-
-            let start = relation.member_first_idx() as usize;
-            let end = if relation_i + 1 < relations_len {
-                relations[relation_i + 1].member_first_idx() as usize
-            } else {
-                members.len() as usize
-            };
-
-            // NHTODO Do we also want to track all of the tiles that member ways cover with their refs?
-
-            for m in &members[start..end] {
-                let Some(idx) = m.idx() else { continue; };
-                let i = idx as usize;
-
-                let h = match m.entity_type() {
-                    EntityType::Node => node_pairs[i].h(),
-                    EntityType::Way => way_pairs[i].h(),
-                    EntityType::Relation => relation_pairs[i].h(),
-                    _ => 0,
-                };
+    let relation_tiles = |relation_i: usize| {
+        relation_tiles(
+            flatdata,
+            node_pairs,
+            way_pairs,
+            relation_pairs,
+            relation_i,
+            leaf_zoom,
+        )
+    };
 
-                let tile_h = h_to_zoom_h(h, leaf_zoom) as u32;
-                if tile_h != relation_tile_h {
-                    match leaf_to_relations.entry(tile_h) {
-                        Occupied(mut o) => {
-                            o.get_mut().insert(relation_i as u32);
-                        }
-                        Vacant(v) => {
-                            v.insert(BTreeSet::from([relation_i as u32]));
-                        }
-                    }
-                }
-            }
-        });
+    let mut m_spill = Mutant::<LeafRelation>::with_capacity(dir, "hilbert_leaf_relations", 1024)?;
+    for chunk_start in (0..relations_len).step_by(RELATIONS_CHUNK) {
+        let chunk_end = (chunk_start + RELATIONS_CHUNK).min(relations_len);
+        let spilled: Vec<LeafRelation> = (chunk_start..chunk_end)
+            .into_par_iter()
+            .flat_map_iter(|relation_i| {
+                let r = relation_i as u32;
+                relation_tiles(relation_i)
+                    .into_iter()
+                    .map(move |h| LeafRelation { h, r })
+            })
+            .collect();
+        m_spill.append(&spilled)?;
+    }
+    external_sort::sort_by_key(&m_spill, memory, |lr| (lr.h, lr.r))?;
 
     let mut leaves_ext_relations =
         Mutant::<u32>::with_capacity(dir, "hilbert_leaves_external_relations", 1024)?;
     let leaves = m_leaves.mutable_slice();
-    let mut counter: u32 = 0;
+    let spilled = m_spill.slice();
+    let mut s = 0;
 
-    for i in 0..leaves.len() {
-        let leaf = &mut leaves[i];
+    for leaf in leaves.iter_mut() {
         let h = leaf.h;
-        if let Some(relations) = leaf_to_relations.get(&h) {
-            let mut it = relations.iter();
-            let Some(&first) = it.next() else { break; };
-            leaf.r_ext = counter;
-            counter += 1;
-            assert!(first < relations_len as u32);
-            leaves_ext_relations.push(first);
-            for &r_i in it {
-                assert!(r_i < relations_len as u32);
-                leaves_ext_relations.push(r_i);
-                counter += 1;
-            }
-        } else {
-            leaf.r_ext = counter;
+        leaf.r_ext = leaves_ext_relations.len as u32;
+        // Tiles without a leaf have no content for the relations to be a part of.
+        while s < spilled.len() && { spilled[s].h } < h {
+            s += 1;
+        }
+        while s < spilled.len() && { spilled[s].h } == h {
+            leaves_ext_relations.push(spilled[s].r);
+            s += 1;
         }
     }
 
+    let spill_path = m_spill.path.clone();
+    drop(m_spill);
+    fs::remove_file(spill_path)?;
+
     leaves_ext_relations.trim();
     finish(t);
     Ok(leaves_ext_relations)
 }
 
+/// The range of a way in the nodes index.
+pub fn way_refs(flatdata: &Osm, way_i: usize) -> Range<usize> {
+    let refs = flatdata.ways()[way_i].refs();
+    let end = if refs.end == 0 {
+        flatdata.nodes_index().len()
+    } else {
+        refs.end as usize
+    };
+    refs.start as usize..end
+}
+
+/// The range of a relation in the members.
+pub fn relation_members(flatdata: &Osm, relation_i: usize) -> Range<usize> {
+    let relations = flatdata.relations();
+    let start = relations[relation_i].member_first_idx() as usize;
+    let end = if relation_i + 1 < relations.len() {
+        relations[relation_i + 1].member_first_idx() as usize
+    } else {
+        flatdata.members().len()
+    };
+    start..end
+}
+
+/// The leaf tiles that the path of a way enters, sorted.
+pub fn way_tiles(
+    flatdata: &Osm,
+    node_pairs: &[HilbertNodePair],
+    way_i: usize,
+    leaf_zoom: u8,
+) -> Vec<u64> {
+    let xys = flatdata.nodes_index()[way_refs(flatdata, way_i)]
+        .iter()
+        .filter_map(|n| n.value())
+        .map(|v| h_to_xy(node_pairs[v as usize].h()));
+    let mut tiles = Vec::new();
+    push_path_tiles(xys, leaf_zoom, &mut tiles);
+    tiles.sort_unstable();
+    tiles.dedup();
+    tiles
+}
+
+/// The leaf tiles that a relation enters other than its own, sorted.
+pub fn relation_tiles(
+    flatdata: &Osm,
+    node_pairs: &[HilbertNodePair],
+    way_pairs: &[HilbertWayPair],
+    relation_pairs: &[HilbertRelationPair],
+    relation_i: usize,
+    leaf_zoom: u8,
+) -> Vec<u64> {
+    let members = flatdata.members();
+    let tile_h = |h: u64| h_to_zoom_h(h, leaf_zoom);
+
+    let mut tiles = Vec::new();
+    let mut visited = AHashSet::from([relation_i]);
+    let mut stack = vec![relation_i];
+    while let Some(r) = stack.pop() {
+        for m in &members[relation_members(flatdata, r)] {
+            let Some(idx) = m.idx() else {
+                continue;
+            };
+            let i = idx as usize;
+
+            match m.entity_type() {
+                EntityType::Node => tiles.push(tile_h(node_pairs[i].h())),
+                EntityType::Way => {
+                    tiles.push(tile_h(way_pairs[i].h()));
+                    tiles.extend(way_tiles(flatdata, node_pairs, i, leaf_zoom));
+                }
+                EntityType::Relation => {
+                    tiles.push(tile_h(relation_pairs[i].h()));
+                    if visited.insert(i) {
+                        stack.push(i);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let own_tile_h = tile_h(relation_pairs[relation_i].h());
+    tiles.sort_unstable();
+    tiles.dedup();
+    tiles.retain(|h| *h != own_tile_h);
+    tiles
+}
+
+// Pushes the leaf tiles that a path enters. Besides the tiles of its points, these are the
+// tiles that the segments between them cross, which a long segment can do without a point
+// in them. The segments are walked from tile to tile through the grid of the leaf zoom,
+// crossing into the next tile along whichever axis the segment reaches a tile edge first.
+fn push_path_tiles(xys: impl Iterator<Item = (u32, u32)>, leaf_zoom: u8, tiles: &mut Vec<u64>) {
+    let shift = 32 - leaf_zoom as u32;
+    let tile_size = (1_u64 << shift) as f64;
+    let mut previous: Option<(u32, u32)> = None;
+
+    for b in xys {
+        let (end_x, end_y) = (b.0 >> shift, b.1 >> shift);
+        let Some(a) = previous.replace(b) else {
+            tiles.push(Tile::from_zxy(leaf_zoom, end_x, end_y).h);
+            continue;
+        };
+        let (mut x, mut y) = (a.0 >> shift, a.1 >> shift);
+        if (x, y) == (end_x, end_y) {
+            continue;
+        }
+
+        // How far along the segment it crosses into the next column and row of tiles,
+        // and how far it goes across a whole tile.
+        let crossing = |from: u32, to: u32, tile: u32| {
+            let d = to as f64 - from as f64;
+            if d == 0.0 {
+                return (f64::INFINITY, f64::INFINITY);
+            }
+            let edge = if d > 0.0 { tile + 1 } else { tile } as f64 * tile_size;
+            ((edge - from as f64) / d, tile_size / d.abs())
+        };
+        let (mut next_x, step_x) = crossing(a.0, b.0, x);
+        let (mut next_y, step_y) = crossing(a.1, b.1, y);
+
+        // Every step is a tile closer to the end, so the walk ends there even when
+        // rounding has the segment reach an edge a little early or late.
+        while (x, y) != (end_x, end_y) {
+            if y == end_y || (x != end_x && next_x < next_y) {
+                x = if end_x > x { x + 1 } else { x - 1 };
+                next_x += step_x;
+            } else {
+                y = if end_y > y { y + 1 } else { y - 1 };
+                next_y += step_y;
+            }
+            tiles.push(Tile::from_zxy(leaf_zoom, x, y).h);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ahash::AHashSet;
+    use crate::location::{xy_to_h, zoom_h_to_h};
     use flatdata::FileResourceStorage;
     use std::path::PathBuf;
 
+    // A copy of the sorted relations planet in a tempdir, since the leaves are written to.
+    fn relations_planet(dir: &Path) -> Manifest {
+        let mut manifest = crate::manifest::parse("tests/fixtures/relations_sort.yaml").unwrap();
+        util::copy_planet(&manifest.data.planet, dir).unwrap();
+        manifest.data.planet = dir.to_path_buf();
+        manifest
+    }
+
+    // The index of the way or relation with an OSM id.
+    fn index_of(mut osm_ids: impl Iterator<Item = i64>, osm_id: i64) -> u32 {
+        osm_ids.position(|id| id == osm_id).unwrap() as u32
+    }
+
     #[test]
     fn test_populate_hilbert_leaves_external_ways() {
         let dir = PathBuf::from("tests/fixtures/santa_cruz/sort");
@@ -393,8 +446,6 @@ mod tests {
         let m_node_pairs =
             Mutant::<HilbertNodePair>::open(&dir, "hilbert_node_pairs", true).unwrap();
         let m_way_pairs = Mutant::<HilbertWayPair>::open(&dir, "hilbert_way_pairs", true).unwrap();
-        let m_relation_pairs =
-            Mutant::<HilbertRelationPair>::open(&dir, "hilbert_relation_pairs", true).unwrap();
         let m_leaves = Mutant::<Leaf>::open(&dir, "hilbert_leaves", false).unwrap();
 
         let m_ext = populate_leaves_external_ways(
@@ -407,10 +458,26 @@ mod tests {
         )
         .unwrap();
         let ext = m_ext.slice();
-        assert_eq!(ext.len(), 4633);
+
+        // Every leaf that the path of a way enters lists the way, unless it is the leaf
+        // of the way itself. Before the paths were followed, only the leaves with a node
+        // of the way listed it, which made 4633.
+        let leaves = m_leaves.slice();
+        let way_pairs = m_way_pairs.slice();
+        let is_leaf = |h: &u64| leaves.binary_search_by_key(h, |leaf| leaf.h).is_ok();
+        let count: usize = (0..way_pairs.len())
+            .map(|i| {
+                let own_tile_h = h_to_zoom_h(way_pairs[i].h(), 12);
+                way_tiles(&flatdata, m_node_pairs.slice(), i, 12)
+                    .iter()
+                    .filter(|&h| *h != own_tile_h && is_leaf(h))
+                    .count()
+            })
+            .sum();
+        assert_eq!(ext.len(), count);
 
         // Check that w_ext is ascending or equal for the leaves.
-        let mut leaves_it = m_leaves.slice().iter();
+        let mut leaves_it = leaves.iter();
         let mut leaf = leaves_it.next().unwrap();
         let mut next = leaves_it.next();
         while next.is_some() {
@@ -421,15 +488,173 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_populate_hilbert_leaves_external_ways_crossing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let manifest = relations_planet(tmp.path());
+        let dir = &manifest.data.planet;
+        let flatdata = Osm::open(FileResourceStorage::new(dir)).unwrap();
+        let m_node_pairs =
+            Mutant::<HilbertNodePair>::open(dir, "hilbert_node_pairs", true).unwrap();
+        let m_way_pairs = Mutant::<HilbertWayPair>::open(dir, "hilbert_way_pairs", true).unwrap();
+        let m_leaves = Mutant::<Leaf>::open(dir, "hilbert_leaves", false).unwrap();
+
+        let m_ext = populate_leaves_external_ways(
+            dir,
+            &flatdata,
+            &m_node_pairs,
+            &m_way_pairs,
+            &m_leaves,
+            12,
+        )
+        .unwrap();
+        let ext = m_ext.slice();
+        let leaves = m_leaves.slice();
+
+        // The external ways of each leaf, by its tile.
+        let leaf_ways = |x: u32, y: u32| -> Vec<u32> {
+            let h = Tile::from_zxy(12, x, y).h;
+            let i = leaves.binary_search_by_key(&h, |leaf| leaf.h).unwrap();
+            let end = leaves
+                .get(i + 1)
+                .map_or(ext.len(), |next| next.w_ext as usize);
+            ext[leaves[i].w_ext as usize..end].to_vec()
+        };
+        let motorway = index_of(flatdata.ways().iter().map(|w| w.osm_id()), 104);
+
+        // The motorway has its ends in the leaves to the west and east of Santa Cruz,
+        // and it crosses the leaf of Santa Cruz without a node in it.
+        let motorway_tile = h_to_zoom_h(m_way_pairs.slice()[motorway as usize].h(), 12);
+        assert_eq!(motorway_tile, Tile::from_zxy(12, 656, 1594).h);
+        assert!(leaf_ways(656, 1594).is_empty());
+        assert_eq!(leaf_ways(659, 1594), [motorway]);
+        assert_eq!(leaf_ways(662, 1594), [motorway]);
+        assert!(leaf_ways(665, 1591).is_empty());
+        assert_eq!(ext.len(), 2);
+    }
+
+    #[test]
+    fn test_populate_hilbert_leaves_external_relations() {
+        let tmp = tempfile::tempdir().unwrap();
+        let manifest = relations_planet(tmp.path());
+        let dir = &manifest.data.planet;
+        let flatdata = Osm::open(FileResourceStorage::new(dir)).unwrap();
+        let m_node_pairs =
+            Mutant::<HilbertNodePair>::open(dir, "hilbert_node_pairs", true).unwrap();
+        let m_way_pairs = Mutant::<HilbertWayPair>::open(dir, "hilbert_way_pairs", true).unwrap();
+        let m_relation_pairs =
+            Mutant::<HilbertRelationPair>::open(dir, "hilbert_relation_pairs", true).unwrap();
+        let m_leaves = Mutant::<Leaf>::open(dir, "hilbert_leaves", false).unwrap();
+
+        let m_ext = populate_leaves_external_relations(
+            &manifest,
+            &flatdata,
+            &m_node_pairs,
+            &m_way_pairs,
+            &m_relation_pairs,
+            &m_leaves,
+        )
+        .unwrap();
+        let ext = m_ext.slice();
+        assert!(!dir.join("hilbert_leaf_relations").exists());
+
+        // Every leaf that a ref of a member way is in lists the relation, unless it is
+        // the leaf of the relation itself.
+        let leaves = m_leaves.slice();
+        let relations = flatdata.relations();
+        let members = flatdata.members();
+        let ways = flatdata.ways();
+        let nodes_index = flatdata.nodes_index();
+        let node_pairs = m_node_pairs.slice();
+        let relation_pairs = m_relation_pairs.slice();
+        let leaf_relations = |h: u64| {
+            let i = leaves.binary_search_by_key(&h, |leaf| leaf.h).ok()?;
+            let end = leaves
+                .get(i + 1)
+                .map_or(ext.len(), |next| next.r_ext as usize);
+            Some(&ext[leaves[i].r_ext as usize..end])
+        };
+        let mut checked = 0;
+        for r in 0..relations.len() {
+            let own_tile_h = h_to_zoom_h(relation_pairs[r].h(), 12);
+            let start = relations[r].member_first_idx() as usize;
+            let end = relations
+                .get(r + 1)
+                .map_or(members.len(), |next| next.member_first_idx() as usize);
+            for m in &members[start..end] {
+                let (EntityType::Way, Some(idx)) = (m.entity_type(), m.idx()) else {
+                    continue;
+                };
+                let refs = ways[idx as usize].refs();
+                let refs_end = match refs.end {
+                    0 => nodes_index.len(),
+                    end => end as usize,
+                };
+                for n in &nodes_index[refs.start as usize..refs_end] {
+                    let Some(v) = n.value() else {
+                        continue;
+                    };
+                    let tile_h = h_to_zoom_h(node_pairs[v as usize].h(), 12);
+                    if tile_h == own_tile_h {
+                        continue;
+                    }
+                    if let Some(relations) = leaf_relations(tile_h) {
+                        assert!(relations.contains(&(r as u32)));
+                        checked += 1;
+                    }
+                }
+            }
+        }
+        assert!(checked > 0);
+
+        // The route is located between its ends, and it enters the leaves of its ends and
+        // the leaf of Santa Cruz, where its stop is. The city is located between its own
+        // way and that of its subarea, and it enters both of their leaves.
+        let route = index_of(relations.iter().map(|r| r.osm_id()), 201);
+        let city = index_of(relations.iter().map(|r| r.osm_id()), 202);
+        let by_tile = |x: u32, y: u32| leaf_relations(Tile::from_zxy(12, x, y).h).unwrap();
+        assert_eq!(by_tile(656, 1594), [route]);
+        assert_eq!(by_tile(659, 1594), [route, city]);
+        assert_eq!(by_tile(662, 1594), [route]);
+        assert_eq!(by_tile(665, 1591), [city]);
+        assert!(by_tile(658, 1595).is_empty());
+        assert!(by_tile(658, 1588).is_empty());
+        assert_eq!(ext.len(), 5);
+    }
+
+    #[test]
+    fn test_push_path_tiles() {
+        // Tiles are 2^30 at z2.
+        let tile = |x: f64, y: f64| ((x * (1 << 30) as f64) as u32, (y * (1 << 30) as f64) as u32);
+        let tiles_of = |path: &[(u32, u32)]| {
+            let mut tiles = Vec::new();
+            push_path_tiles(path.iter().copied(), 2, &mut tiles);
+            tiles
+        };
+        let h = |x: u32, y: u32| Tile::from_zxy(2, x, y).h;
+
+        // A straight segment crosses the tiles between its ends.
+        let path = [tile(0.5, 0.5), tile(3.5, 0.5)];
+        assert_eq!(tiles_of(&path), vec![h(0, 0), h(1, 0), h(2, 0), h(3, 0)]);
+        assert_eq!(h(3, 0), h_to_zoom_h(xy_to_h(path[1]), 2));
+
+        // A slanted one crosses into the next column before the next row, and back.
+        let path = [tile(0.5, 0.1), tile(1.5, 1.7), tile(0.2, 1.6)];
+        assert_eq!(tiles_of(&path), vec![h(0, 0), h(1, 0), h(1, 1), h(0, 1)]);
+
+        // A segment within a tile only has the tile of its first point.
+        assert_eq!(tiles_of(&[tile(0.1, 0.1), tile(0.9, 0.9)]), vec![h(0, 0)]);
+    }
+
     #[test]
     fn test_4nodes_leaf_tiles() {
         let dir = PathBuf::from("./tests/fixtures/nodes4/sort");
         let m_node_pairs =
             Mutant::<HilbertNodePair>::open(&dir, "hilbert_node_pairs", true).unwrap();
         let node_pairs = m_node_pairs.slice();
-        let mut leaf_tiles = AHashSet::<u32>::new();
+        let mut leaf_tiles = AHashSet::<u64>::new();
         for p in node_pairs {
-            let zoom_h = location::h_to_zoom_h(p.h(), 12) as u32;
+            let zoom_h = h_to_zoom_h(p.h(), 12);
             leaf_tiles.insert(zoom_h);
             // println!("{:?} zoom_h: {}", p, zoom_h);
         }
@@ -452,4 +677,80 @@ mod tests {
         // We know there are 3 unique leaf tiles for the 4 nodes.
         assert_eq!(m_leaves.len, 3);
     }
+
+    #[test]
+    fn test_build_leaves_relations() {
+        // Nodes in tiles 1 and 5, a way in tile 5, and relations in tiles 1, 3, 3 and 7.
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let h = |tile_h: u64| zoom_h_to_h(tile_h, 12);
+        let m_node_pairs = Mutant::<HilbertNodePair>::new(dir, "hilbert_node_pairs", 2).unwrap();
+        for (pair, tile_h) in m_node_pairs.mutable_slice().iter_mut().zip([1, 5]) {
+            pair.set_h(h(tile_h));
+        }
+        let m_way_pairs = Mutant::<HilbertWayPair>::new(dir, "hilbert_way_pairs", 1).unwrap();
+        m_way_pairs.mutable_slice()[0].set_h(h(5));
+        let m_relation_pairs =
+            Mutant::<HilbertRelationPair>::new(dir, "hilbert_relation_pairs", 4).unwrap();
+        let relation_pairs = m_relation_pairs.mutable_slice();
+        for (pair, tile_h) in relation_pairs.iter_mut().zip([1, 3, 3, 7]) {
+            pair.set_h(h(tile_h));
+        }
+
+        let m_leaves =
+            build_leaves(&m_node_pairs, &m_way_pairs, &m_relation_pairs, dir, 12).unwrap();
+        let leaves: Vec<(u64, u64, u32, u32)> = m_leaves
+            .slice()
+            .iter()
+            .map(|leaf| (leaf.h, leaf.n, leaf.w, leaf.r))
+            .collect();
+        assert_eq!(
+            leaves,
+            vec![(1, 0, 0, 0), (3, 1, 0, 1), (5, 1, 0, 3), (7, 2, 1, 3)]
+        );
+
+        // Relations make up leaves on their own.
+        let m_no_nodes = Mutant::<HilbertNodePair>::new(dir, "no_node_pairs", 0).unwrap();
+        let m_no_ways = Mutant::<HilbertWayPair>::new(dir, "no_way_pairs", 0).unwrap();
+        let m_leaves = build_leaves(&m_no_nodes, &m_no_ways, &m_relation_pairs, dir, 12).unwrap();
+        let hs: Vec<u64> = m_leaves.slice().iter().map(|leaf| leaf.h).collect();
+        assert_eq!(hs, vec![1, 3, 7]);
+
+        let m_no_relations =
+            Mutant::<HilbertRelationPair>::new(dir, "no_relation_pairs", 0).unwrap();
+        assert!(build_leaves(&m_no_nodes, &m_no_ways, &m_no_relations, dir, 12).is_err());
+    }
+
+    #[test]
+    fn test_build_leaves_keeps_relations() {
+        let dir = PathBuf::from("tests/fixtures/santa_cruz/sort");
+        let m_node_pairs =
+            Mutant::<HilbertNodePair>::open(&dir, "hilbert_node_pairs", true).unwrap();
+        let m_way_pairs = Mutant::<HilbertWayPair>::open(&dir, "hilbert_way_pairs", true).unwrap();
+        let m_relation_pairs =
+            Mutant::<HilbertRelationPair>::open(&dir, "hilbert_relation_pairs", true).unwrap();
+        let relation_pairs = m_relation_pairs.slice();
+
+        let out = tempfile::tempdir().unwrap();
+        let out = out.path();
+        let m_leaves =
+            build_leaves(&m_node_pairs, &m_way_pairs, &m_relation_pairs, out, 12).unwrap();
+        let leaves = m_leaves.slice();
+
+        // The leaf of each relation, by the range of relations that the leaf has.
+        let mut leaf_tiles = Vec::with_capacity(relation_pairs.len());
+        for (i, leaf) in leaves.iter().enumerate() {
+            let end = leaves
+                .get(i + 1)
+                .map_or(relation_pairs.len(), |next| next.r as usize);
+            leaf_tiles.extend((leaf.r as usize..end).map(|_| leaf.h));
+        }
+
+        // No relation is dropped, and each is in the leaf of its own tile.
+        let relation_tiles: Vec<u64> = relation_pairs
+            .iter()
+            .map(|pair| h_to_zoom_h(pair.h(), 12))
+            .collect();
+        assert_eq!(leaf_tiles, relation_tiles);
+    }
 }
@@ -6,3 +6,4 @@ mod hilbert_tile;
 mod leaf;
 mod pvt_iterator;
 pub mod tree;
+pub mod update;
@@ -1,6 +1,7 @@
 use crate::{pvt_builder::PVTBuilder, tile::Tile};
 
 use super::{
+    hilbert_tile::HilbertTile,
     leaf::Leaf,
     tree::{HilbertTree, ResultPair},
 };
@@ -53,7 +54,7 @@ impl<'a> Iterator for PVTLeafIterator<'a> {
             item: leaf,
             next: next_leaf,
         };
-        let h = leaf.h as u64;
+        let h = leaf.h;
         let tile = Tile::from_zh(self.leaf_zoom, h);
         let mut builder = PVTBuilder::new();
 
@@ -62,3 +63,96 @@ impl<'a> Iterator for PVTLeafIterator<'a> {
         Some((tile, vec_u8))
     }
 }
+
+impl HilbertTree {
+    pub fn pvt_h_tile_iterator(&self) -> PVTHilbertTileIterator<'_> {
+        PVTHilbertTileIterator::new(self)
+    }
+}
+
+/// Walks every HilbertTile level of the tree, depth first from z0. Each HilbertTile is
+/// followed by the four odd zoom tiles within it, which are composed from its content,
+/// the same as `find` does. The leaves are not included. Use PVTLeafIterator for those.
+pub struct PVTHilbertTileIterator<'a> {
+    tree: &'a HilbertTree,
+    h_tiles: &'a [HilbertTile],
+    leaf_zoom: u8,
+    // (z, h, index in h_tiles) of the tiles we have yet to visit.
+    stack: Vec<(u8, u64, usize)>,
+    // The odd zoom tiles of the last HilbertTile we visited, and its index in h_tiles.
+    odd_tiles: Vec<Tile>,
+    odd_i: usize,
+}
+
+impl<'a> PVTHilbertTileIterator<'a> {
+    pub fn new(tree: &'a HilbertTree) -> Self {
+        let h_tiles = tree.tiles.slice();
+        // The last tile is the root, z0.
+        let stack = if h_tiles.is_empty() {
+            Vec::new()
+        } else {
+            vec![(0, 0, h_tiles.len() - 1)]
+        };
+        PVTHilbertTileIterator {
+            tree,
+            h_tiles,
+            leaf_zoom: tree.manifest.render.leaf_zoom,
+            stack,
+            odd_tiles: Vec::new(),
+            odd_i: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for PVTHilbertTileIterator<'a> {
+    type Item = (Tile, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(tile) = self.odd_tiles.pop() {
+            return Some(self.compose(&tile, self.odd_i));
+        }
+
+        let (z, h, i) = self.stack.pop()?;
+        let h_tile = &self.h_tiles[i];
+
+        let tile = Tile::from_zh(z, h);
+        if z + 1 < self.leaf_zoom {
+            // Reversed so that we visit the odd zoom tiles in order.
+            self.odd_tiles = tile.children().into_iter().rev().collect();
+            self.odd_i = i;
+        }
+
+        // The children of the level above the leaves are leaves.
+        let child_z = z + 2;
+        if child_z < self.leaf_zoom {
+            let mask = h_tile.mask;
+            let mut child_i = h_tile.child as usize;
+            let mut children = Vec::with_capacity(16);
+            for pos in 0..16 {
+                if mask >> pos & 1 == 1 {
+                    children.push((child_z, h << 4 | pos as u64, child_i));
+                    child_i += 1;
+                }
+            }
+            // Reversed so that we visit the children in Hilbert order.
+            self.stack.extend(children.into_iter().rev());
+        }
+
+        Some(self.compose(&tile, i))
+    }
+}
+
+impl<'a> PVTHilbertTileIterator<'a> {
+    // Composes a tile from the content of the HilbertTile at index i.
+    fn compose(&self, tile: &Tile, i: usize) -> (Tile, Vec<u8>) {
+        let result_pair = ResultPair {
+            item: &self.h_tiles[i],
+            next: self.h_tiles.get(i + 1),
+        };
+        let mut builder = PVTBuilder::new();
+
+        self.tree.compose_h_tile(tile, result_pair, &mut builder);
+        let vec_u8 = builder.build();
+        (*tile, vec_u8)
+    }
+}
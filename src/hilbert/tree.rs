@@ -2,16 +2,21 @@ use super::{
     content::render_tile_content,
     hilbert_tile::{build_tiles, HilbertTile},
     leaf::{build_leaves, populate_leaves_external_relations, populate_leaves_external_ways, Leaf},
+    update::Previous,
 };
 use crate::{
+    archive::Archive,
     manifest::Manifest,
     mutant::Mutant,
     osmflat::osmflat_generated::osm::{HilbertNodePair, HilbertRelationPair, HilbertWayPair, Osm},
     rules::Rules,
     tile::Tile,
 };
-use flatdata::FileResourceStorage;
-use std::{fs, path::PathBuf};
+use flatdata::{FileResourceStorage, TarArchiveResourceStorage};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 type Err = Box<dyn std::error::Error>;
 
@@ -34,11 +39,7 @@ impl HilbertTree {
     pub fn new(manifest: &Manifest) -> Result<Self, Err> {
         let dir = &manifest.data.planet.clone();
 
-        // Copy the manifest to the build directory so we know exactly what it was at the time of build.
-        let mut planet_manifest = manifest.clone();
-        planet_manifest.data.planet = PathBuf::from("./");
-        let manifest_str = serde_yaml::to_string(&planet_manifest)?;
-        fs::write(dir.join("manifest.yaml"), manifest_str)?;
+        write_manifest(manifest)?;
 
         let leaf_zoom = manifest.render.leaf_zoom;
         let flatdata = Osm::open(FileResourceStorage::new(dir))?;
@@ -67,13 +68,12 @@ impl HilbertTree {
         )?;
 
         let m_leaves_external_relations = populate_leaves_external_relations(
-            dir,
+            manifest,
             &flatdata,
             &m_node_pairs,
             &m_way_pairs,
             &m_relation_pairs,
             &m_leaves,
-            leaf_zoom,
         )?;
 
         Ok(Self {
@@ -92,7 +92,56 @@ impl HilbertTree {
         })
     }
 
+    /// Builds the tree of an updated planet from the tree of the planet before the update.
+    /// Only the leaves that the update touches are built again, the rest are carried over.
+    pub fn update(manifest: &Manifest, previous: &Previous) -> Result<Self, Err> {
+        let dir = &manifest.data.planet.clone();
+
+        write_manifest(manifest)?;
+
+        let leaf_zoom = manifest.render.leaf_zoom;
+        let flatdata = Osm::open(FileResourceStorage::new(dir))?;
+
+        let m_node_pairs = Mutant::<HilbertNodePair>::open(dir, "hilbert_node_pairs", true)?;
+        let m_way_pairs = Mutant::<HilbertWayPair>::open(dir, "hilbert_way_pairs", true)?;
+        let m_relation_pairs =
+            Mutant::<HilbertRelationPair>::open(dir, "hilbert_relation_pairs", true)?;
+
+        let m_leaves =
+            previous.build_leaves(&m_node_pairs, &m_way_pairs, &m_relation_pairs, dir)?;
+        let m_tiles = build_tiles(&m_leaves, dir, leaf_zoom)?;
+        let m_leaves_external_ways = previous.populate_leaves_external_ways(dir, &m_leaves)?;
+        let m_leaves_external_relations =
+            previous.populate_leaves_external_relations(dir, &m_leaves)?;
+
+        Ok(Self {
+            manifest: manifest.clone(),
+            tiles: m_tiles,
+            leaves: m_leaves,
+            leaves_external_ways: m_leaves_external_ways,
+            leaves_external_relations: m_leaves_external_relations,
+            n: Mutant::<u64>::new(dir, "n", 0)?,
+            w: Mutant::<u32>::new(dir, "w", 0)?,
+            r: Mutant::<u32>::new(dir, "r", 0)?,
+            flatdata,
+            way_pairs: m_way_pairs,
+            relation_pairs: m_relation_pairs,
+            rules: Rules::default(manifest),
+        })
+    }
+
     pub fn render_tile_content(&mut self) -> Result<&Self, Err> {
+        self.render(None)
+    }
+
+    /// Renders the tile content of an updated planet, only where the update touches it.
+    pub fn render_updated_tile_content(&mut self, previous: &Previous) -> Result<&Self, Err> {
+        self.render(Some(previous))
+    }
+
+    fn render(&mut self, previous: Option<&Previous>) -> Result<&Self, Err> {
+        // The rules may have changed since the tree was built.
+        write_manifest(&self.manifest)?;
         let (n, w, r, new_rules) = render_tile_content(
             &self.leaves,
             &self.tiles,
@@ -100,6 +149,7 @@ impl HilbertTree {
             &self.leaves_external_relations,
             &self.flatdata,
             &self.manifest,
+            previous,
         )?;
         self.n = n;
         self.w = w;
@@ -143,19 +193,71 @@ impl HilbertTree {
         })
     }
 
+    /// Opens a planet from a single .pvt archive, mapping the flatdata and
+    /// Hilbert index files in place rather than from a planet directory.
+    pub fn open_archive(path: &Path) -> Result<Self, Err> {
+        let archive = Archive::open(path)?;
+        let manifest = archive.manifest()?;
+        // A .pvt archive is a tar, so flatdata can map the Osm archive right out of it.
+        let flatdata = Osm::open(TarArchiveResourceStorage::new(path)?)?;
+
+        let m_way_pairs =
+            Mutant::<HilbertWayPair>::open_archive(&archive, "hilbert_way_pairs", true)?;
+        let m_relation_pairs =
+            Mutant::<HilbertRelationPair>::open_archive(&archive, "hilbert_relation_pairs", true)?;
+        let m_leaves = Mutant::<Leaf>::open_archive(&archive, "hilbert_leaves", false)?;
+        let m_leaves_external_ways =
+            Mutant::<u32>::open_archive(&archive, "hilbert_leaves_external_ways", false)?;
+        let m_leaves_external_relations =
+            Mutant::<u32>::open_archive(&archive, "hilbert_leaves_external_relations", false)?;
+        let m_tiles = Mutant::<HilbertTile>::open_archive(&archive, "hilbert_tiles", false)?;
+        let m_n = Mutant::<u64>::open_archive(&archive, "n", false)?;
+        let m_w = Mutant::<u32>::open_archive(&archive, "w", false)?;
+        let m_r = Mutant::<u32>::open_archive(&archive, "r", false)?;
+
+        let rules = Rules::open_archive(&archive, &manifest);
+
+        Ok(Self {
+            manifest,
+            tiles: m_tiles,
+            leaves: m_leaves,
+            leaves_external_ways: m_leaves_external_ways,
+            leaves_external_relations: m_leaves_external_relations,
+            n: m_n,
+            w: m_w,
+            r: m_r,
+            flatdata,
+            way_pairs: m_way_pairs,
+            relation_pairs: m_relation_pairs,
+            rules,
+        })
+    }
+
+    /// Finds the Hilbert tile or leaf with the content of a tile. The tree only has even
+    /// zoom levels, so an odd zoom tile is found at the even zoom tile that contains it.
+    /// Tiles past the leaf zoom, up to the max overzoom, are found at their leaf.
     pub fn find(&self, tile: &Tile) -> FindResult {
         let leaf_zoom = self.manifest.render.leaf_zoom;
 
-        // Tiles do not exist beyond the leaf zoom, and we only use even zoom levels.
-        if tile.z & 1 == 1 || tile.z > leaf_zoom {
-            return FindResult::None;
+        if tile.z > leaf_zoom {
+            if tile.z > self.manifest.render.max_overzoom {
+                return FindResult::None;
+            }
+            return self.find(&tile.at_zoom(leaf_zoom));
+        }
+        if tile.z & 1 == 1 {
+            return match tile.parent() {
+                Some(parent) => self.find(&parent),
+                None => FindResult::None,
+            };
         }
 
         let h_tiles = self.tiles.slice();
         let leaves = self.leaves.slice();
-        let mut h_tile = h_tiles.last().unwrap();
+        // The last tile is the root, z0.
+        let mut i = h_tiles.len() - 1;
+        let mut h_tile = &h_tiles[i];
         let mut z = 2;
-        let mut i = 0;
         while z <= tile.z {
             let h = tile.h >> (2 * (tile.z - z));
             i = match child_index(h_tile, h) {
@@ -213,6 +315,15 @@ fn child_index(h_tile: &HilbertTile, child_h: u64) -> Option<usize> {
     Some(h_tile.child as usize + offset as usize)
 }
 
+// Copy the manifest to the build directory so we know exactly what it was at the time of build.
+fn write_manifest(manifest: &Manifest) -> Result<(), Err> {
+    let mut planet_manifest = manifest.clone();
+    planet_manifest.data.planet = PathBuf::from("./");
+    let manifest_str = serde_yaml::to_string(&planet_manifest)?;
+    fs::write(manifest.data.planet.join("manifest.yaml"), manifest_str)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::manifest;
@@ -246,10 +357,106 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_open_archive() {
+        let manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        let tree = HilbertTree::open(&manifest).unwrap();
+        let archive_tree = HilbertTree::open_archive(&manifest.data.archive).unwrap();
+
+        assert_eq!(tree.manifest.render, archive_tree.manifest.render);
+        assert_eq!(tree.tiles.len, archive_tree.tiles.len);
+        assert_eq!(tree.leaves.len, archive_tree.leaves.len);
+        assert_eq!(
+            tree.flatdata.nodes().len(),
+            archive_tree.flatdata.nodes().len()
+        );
+
+        for leaf in tree.leaves.slice() {
+            let t = Tile::from_zh(manifest.render.leaf_zoom, leaf.h);
+            match archive_tree.find(&t) {
+                FindResult::Leaf(pair) => {
+                    let (n, h) = (pair.item.n, pair.item.h);
+                    let (expected_n, expected_h) = (leaf.n, leaf.h);
+                    assert_eq!(n, expected_n);
+                    assert_eq!(h, expected_h);
+                }
+                _ => panic!("Should be a leaf."),
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_odd_zoom() {
+        let manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        let tree = HilbertTree::open(&manifest).unwrap();
+        let leaf_zoom = manifest.render.leaf_zoom;
+
+        for leaf in tree.leaves.slice() {
+            let t = Tile::from_zh(leaf_zoom, leaf.h);
+            // An odd zoom is found at the even zoom that contains it.
+            for z in [leaf_zoom - 3, leaf_zoom - 1] {
+                let odd = tree.find(&t.at_zoom(z));
+                let even = tree.find(&t.at_zoom(z - 1));
+                match (odd, even) {
+                    (FindResult::HilbertTile(odd), FindResult::HilbertTile(even)) => {
+                        assert!(std::ptr::eq(odd.item, even.item))
+                    }
+                    _ => panic!("Should be a HilbertTile."),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_overzoom() {
+        let mut manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        manifest.render.max_overzoom = 16;
+        let tree = HilbertTree::open(&manifest).unwrap();
+        let leaf_zoom = manifest.render.leaf_zoom;
+
+        for leaf in tree.leaves.slice() {
+            let t = Tile::from_zh(leaf_zoom, leaf.h);
+            // Past the leaf zoom, a tile is found at the leaf that contains it.
+            for z in leaf_zoom + 1..=16 {
+                match tree.find(&t.at_zoom(z)) {
+                    FindResult::Leaf(pair) => assert!(std::ptr::eq(pair.item, leaf)),
+                    _ => panic!("Should be a leaf."),
+                }
+            }
+            assert!(matches!(tree.find(&t.at_zoom(17)), FindResult::None));
+        }
+    }
+
+    #[test]
+    fn test_find_leaf_zoom_16() {
+        let dir = tempfile::tempdir().unwrap();
+        for entry in fs::read_dir("tests/fixtures/nodes4/sort").unwrap() {
+            let path = entry.unwrap().path();
+            fs::copy(&path, dir.path().join(path.file_name().unwrap())).unwrap();
+        }
+        let mut manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        manifest.data.planet = dir.path().to_path_buf();
+        manifest.render.leaf_zoom = 16;
+        let tree = HilbertTree::new(&manifest).unwrap();
+
+        let m_node_pairs =
+            Mutant::<HilbertNodePair>::open(dir.path(), "hilbert_node_pairs", true).unwrap();
+        for pair in m_node_pairs.slice() {
+            let t = Tile::from_zh(32, pair.h()).at_zoom(16);
+            match tree.find(&t) {
+                FindResult::Leaf(pair) => {
+                    let h = pair.item.h;
+                    assert_eq!(h, t.h);
+                }
+                _ => panic!("Should be a leaf."),
+            }
+        }
+    }
+
     #[test]
     fn test_struct_size() {
         assert_eq!(22, size_of::<HilbertTile>());
-        assert_eq!(28, size_of::<Leaf>());
+        assert_eq!(32, size_of::<Leaf>());
     }
 
     #[test]
@@ -0,0 +1,639 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use ahash::{AHashMap, AHashSet};
+use flatdata::FileResourceStorage;
+use rayon::prelude::*;
+
+use super::{
+    leaf::{relation_members, relation_tiles, way_refs, way_tiles, Leaf},
+    tree::{FindResult, HilbertTree},
+};
+use crate::{
+    location::h_to_zoom_h,
+    manifest::Manifest,
+    mutant::Mutant,
+    osmflat::{
+        osmflat_generated::osm::{
+            EntityType, HilbertNodePair, HilbertRelationPair, HilbertWayPair, Osm,
+        },
+        Diff,
+    },
+    tile::Tile,
+    util::{finish, timer},
+};
+
+type Err = Box<dyn std::error::Error>;
+
+/// The indexes of the nodes, ways and relations in a tile.
+pub type Content = (Vec<u64>, Vec<u32>, Vec<u32>);
+
+// An update only changes a planet where its diff touches it. An entity that the diff does
+// not change, nor any of its nodes or members, stays in the same leaf tiles once the
+// updated planet is sorted, only at another index. So the leaves that the update touches
+// are the leaf tiles of the changed entities, both before and after the update, and the
+// rest of the Hilbert tree is carried over with its indexes remapped.
+
+/// The Hilbert tree of a planet before an update, with the leaves the update touches.
+pub struct Previous {
+    pub tree: HilbertTree,
+    diff: Diff,
+    // The index of every entity of the unsorted update, once it is sorted.
+    node_idx: Mutant<u64>,
+    way_idx: Mutant<u32>,
+    relation_idx: Mutant<u32>,
+    // The old indexes of the ways and relations that changed,
+    // themselves or through their nodes and members.
+    ways: AHashSet<u32>,
+    relations: AHashSet<u32>,
+    // The leaf tiles the update touches, and the tiles above them by zoom.
+    pub leaves: BTreeSet<u64>,
+    tiles: AHashSet<(u8, u64)>,
+    // The leaf tiles that the changed ways and relations enter in the updated planet,
+    // other than their own.
+    external_ways: AHashMap<u64, Vec<u32>>,
+    external_relations: AHashMap<u64, Vec<u32>>,
+}
+
+// The leaf tiles of some of the entities of a planet.
+#[derive(Default)]
+struct Touched {
+    leaves: BTreeSet<u64>,
+    external_ways: AHashMap<u64, Vec<u32>>,
+    external_relations: AHashMap<u64, Vec<u32>>,
+}
+
+impl Previous {
+    /// Opens the tree of the planet of the manifest, and finds the leaves that the diff
+    /// touches, given the updated planet that is already sorted.
+    pub fn new(manifest: &Manifest, updated: &Manifest, diff: Diff) -> Result<Self, Err> {
+        let t = timer("Finding the leaves touched by the update...");
+        let tree = HilbertTree::open(manifest)?;
+        let leaf_zoom = manifest.render.leaf_zoom;
+        let dir = &updated.data.planet;
+
+        let flatdata = Osm::open(FileResourceStorage::new(dir))?;
+        let m_node_pairs = Mutant::<HilbertNodePair>::open(dir, "hilbert_node_pairs", true)?;
+        let m_way_pairs = Mutant::<HilbertWayPair>::open(dir, "hilbert_way_pairs", true)?;
+        let m_relation_pairs =
+            Mutant::<HilbertRelationPair>::open(dir, "hilbert_relation_pairs", true)?;
+        let m_old_node_pairs =
+            Mutant::<HilbertNodePair>::open(&manifest.data.planet, "hilbert_node_pairs", true)?;
+
+        // The sorted pairs still have the index of their entity in the unsorted update.
+        let node_pairs = m_node_pairs.slice();
+        let node_idx = Mutant::<u64>::new(dir, "update_node_idx", node_pairs.len())?;
+        let idx = node_idx.mutable_slice();
+        for (i, pair) in node_pairs.iter().enumerate() {
+            idx[pair.i() as usize] = i as u64;
+        }
+        let way_pairs = m_way_pairs.slice();
+        let way_idx = Mutant::<u32>::new(dir, "update_way_idx", way_pairs.len())?;
+        let idx = way_idx.mutable_slice();
+        for (i, pair) in way_pairs.iter().enumerate() {
+            idx[pair.i() as usize] = i as u32;
+        }
+        let relation_pairs = m_relation_pairs.slice();
+        let relation_idx = Mutant::<u32>::new(dir, "update_relation_idx", relation_pairs.len())?;
+        let idx = relation_idx.mutable_slice();
+        for (i, pair) in relation_pairs.iter().enumerate() {
+            idx[pair.i() as usize] = i as u32;
+        }
+
+        let (nodes, ways, relations) = changed_entities(&tree.flatdata, &diff);
+
+        let old = touched(
+            &tree.flatdata,
+            m_old_node_pairs.slice(),
+            tree.way_pairs.slice(),
+            tree.relation_pairs.slice(),
+            nodes.iter().copied(),
+            ways.iter().copied(),
+            relations.iter().copied(),
+            leaf_zoom,
+        );
+
+        // The kept ways and relations that changed through their nodes and members
+        // are in the updated planet as well, along with the created and modified ones.
+        let new_ways: AHashSet<u32> = diff
+            .ways
+            .changed()
+            .chain(ways.iter().filter_map(|&w| diff.ways.by_old_idx(w as u64)))
+            .map(|i| way_idx.slice()[i as usize])
+            .collect();
+        let new_relations: AHashSet<u32> = diff
+            .relations
+            .changed()
+            .chain(
+                relations
+                    .iter()
+                    .filter_map(|&r| diff.relations.by_old_idx(r as u64)),
+            )
+            .map(|i| relation_idx.slice()[i as usize])
+            .collect();
+        let new = touched(
+            &flatdata,
+            node_pairs,
+            way_pairs,
+            relation_pairs,
+            diff.nodes.changed().map(|i| node_idx.slice()[i as usize]),
+            new_ways.into_iter(),
+            new_relations.into_iter(),
+            leaf_zoom,
+        );
+
+        let mut leaves = old.leaves;
+        leaves.extend(new.leaves);
+        let mut tiles = AHashSet::new();
+        for h in &leaves {
+            let mut z = leaf_zoom;
+            while z >= 2 {
+                z -= 2;
+                tiles.insert((z, h >> (2 * (leaf_zoom - z))));
+            }
+        }
+
+        println!(
+            "The update touches {} of {} leaves.",
+            leaves.len(),
+            tree.leaves.len
+        );
+        finish(t);
+
+        Ok(Self {
+            tree,
+            diff,
+            node_idx,
+            way_idx,
+            relation_idx,
+            ways,
+            relations,
+            leaves,
+            tiles,
+            external_ways: new.external_ways,
+            external_relations: new.external_relations,
+        })
+    }
+
+    /// Builds the leaves of the updated planet. A leaf that the update does not touch holds
+    /// as many entities as it did before, so only the touched ones are counted again.
+    pub fn build_leaves(
+        &self,
+        m_node_pairs: &Mutant<HilbertNodePair>,
+        m_way_pairs: &Mutant<HilbertWayPair>,
+        m_relation_pairs: &Mutant<HilbertRelationPair>,
+        dir: &Path,
+    ) -> Result<Mutant<Leaf>, Err> {
+        let t = timer("Building the touched Hilbert Leaves...");
+        let leaf_zoom = self.tree.manifest.render.leaf_zoom;
+        let node_pairs = m_node_pairs.slice();
+        let way_pairs = m_way_pairs.slice();
+        let relation_pairs = m_relation_pairs.slice();
+        let old_leaves = self.tree.leaves.slice();
+
+        // The untouched leaves with their old index, and the touched leaf tiles, in order.
+        let mut tiles: Vec<(u64, Option<usize>)> = old_leaves
+            .iter()
+            .enumerate()
+            .filter(|(_, leaf)| !self.leaves.contains(&{ leaf.h }))
+            .map(|(i, leaf)| (leaf.h, Some(i)))
+            .chain(self.leaves.iter().map(|h| (*h, None)))
+            .collect();
+        tiles.sort_unstable_by_key(|(h, _)| *h);
+
+        let mut m_leaves = Mutant::<Leaf>::new(dir, "hilbert_leaves", tiles.len())?;
+        let leaves = m_leaves.mutable_slice();
+        let mut n_i: usize = 0;
+        let mut w_i: usize = 0;
+        let mut r_i: usize = 0;
+        let mut leaf_i = 0;
+
+        for (h, old_i) in tiles {
+            let start = (n_i, w_i, r_i);
+            match old_i {
+                Some(i) => {
+                    let (n, w, r) = self.old_leaf_len(i);
+                    n_i += n;
+                    w_i += w;
+                    r_i += r;
+                }
+                None => {
+                    let in_tile = |pair_h: u64| h_to_zoom_h(pair_h, leaf_zoom) == h;
+                    while node_pairs.get(n_i).is_some_and(|p| in_tile(p.h())) {
+                        n_i += 1;
+                    }
+                    while way_pairs.get(w_i).is_some_and(|p| in_tile(p.h())) {
+                        w_i += 1;
+                    }
+                    while relation_pairs.get(r_i).is_some_and(|p| in_tile(p.h())) {
+                        r_i += 1;
+                    }
+                }
+            }
+            // A touched tile can be left without any entities.
+            if start == (n_i, w_i, r_i) {
+                continue;
+            }
+            leaves[leaf_i] = Leaf {
+                n: start.0 as u64,
+                w: start.1 as u32,
+                r: start.2 as u32,
+                h,
+                w_ext: 0,
+                r_ext: 0,
+            };
+            leaf_i += 1;
+        }
+
+        if (n_i, w_i, r_i) != (node_pairs.len(), way_pairs.len(), relation_pairs.len()) {
+            return Err(
+                "The leaves of the update do not add up to the entities of the planet.".into(),
+            );
+        }
+
+        m_leaves.set_len(leaf_i);
+        m_leaves.trim();
+        finish(t);
+        Ok(m_leaves)
+    }
+
+    /// Lists the external ways of the updated leaves. The ways that did not change enter
+    /// the same leaves as before, and the changed ones the leaves they enter now.
+    pub fn populate_leaves_external_ways(
+        &self,
+        dir: &Path,
+        m_leaves: &Mutant<Leaf>,
+    ) -> Result<Mutant<u32>, Err> {
+        let t = timer("Populating external leaf ways of the update...");
+        let old_ext = self.tree.leaves_external_ways.slice();
+        let way_idx = self.way_idx.slice();
+        let mut leaves_ext_ways =
+            Mutant::<u32>::with_capacity(dir, "hilbert_leaves_external_ways", 1024)?;
+
+        for leaf in m_leaves.mutable_slice() {
+            leaf.w_ext = leaves_ext_ways.len as u32;
+            let old = match self.old_leaf(leaf.h) {
+                Some((old_leaf, next)) => {
+                    &old_ext
+                        [old_leaf.w_ext as usize..next.map_or(old_ext.len(), |l| l.w_ext as usize)]
+                }
+                None => &[],
+            };
+            let ways = external(
+                old,
+                &self.ways,
+                |w| {
+                    self.diff
+                        .ways
+                        .by_old_idx(w as u64)
+                        .map(|i| way_idx[i as usize])
+                },
+                self.external_ways.get(&{ leaf.h }),
+            );
+            leaves_ext_ways.append(&ways)?;
+        }
+
+        leaves_ext_ways.trim();
+        finish(t);
+        Ok(leaves_ext_ways)
+    }
+
+    /// Lists the external relations of the updated leaves, like the external ways.
+    pub fn populate_leaves_external_relations(
+        &self,
+        dir: &Path,
+        m_leaves: &Mutant<Leaf>,
+    ) -> Result<Mutant<u32>, Err> {
+        let t = timer("Populating external leaf relations of the update...");
+        let old_ext = self.tree.leaves_external_relations.slice();
+        let relation_idx = self.relation_idx.slice();
+        let mut leaves_ext_relations =
+            Mutant::<u32>::with_capacity(dir, "hilbert_leaves_external_relations", 1024)?;
+
+        for leaf in m_leaves.mutable_slice() {
+            leaf.r_ext = leaves_ext_relations.len as u32;
+            let old = match self.old_leaf(leaf.h) {
+                Some((old_leaf, next)) => {
+                    &old_ext
+                        [old_leaf.r_ext as usize..next.map_or(old_ext.len(), |l| l.r_ext as usize)]
+                }
+                None => &[],
+            };
+            let relations = external(
+                old,
+                &self.relations,
+                |r| {
+                    self.diff
+                        .relations
+                        .by_old_idx(r as u64)
+                        .map(|i| relation_idx[i as usize])
+                },
+                self.external_relations.get(&{ leaf.h }),
+            );
+            leaves_ext_relations.append(&relations)?;
+        }
+
+        leaves_ext_relations.trim();
+        finish(t);
+        Ok(leaves_ext_relations)
+    }
+
+    /// The content of a tile that the update does not touch, at its indexes in the
+    /// updated planet. None if the tile has to be rendered again.
+    pub fn content(&self, z: u8, h: u64) -> Option<Content> {
+        if self.tiles.contains(&(z, h)) {
+            return None;
+        }
+        let FindResult::HilbertTile(pair) = self.tree.find(&Tile::from_zh(z, h)) else {
+            return None;
+        };
+        let (tile, next) = (pair.item, pair.next);
+        let n = self.tree.n.slice();
+        let w = self.tree.w.slice();
+        let r = self.tree.r.slice();
+        let node_idx = self.node_idx.slice();
+        let way_idx = self.way_idx.slice();
+        let relation_idx = self.relation_idx.slice();
+
+        let nodes = n[tile.n as usize..next.map_or(n.len(), |t| t.n as usize)]
+            .iter()
+            .filter_map(|&i| self.diff.nodes.by_old_idx(i))
+            .map(|i| node_idx[i as usize])
+            .collect();
+        let ways = w[tile.w as usize..next.map_or(w.len(), |t| t.w as usize)]
+            .iter()
+            .filter_map(|&i| self.diff.ways.by_old_idx(i as u64))
+            .map(|i| way_idx[i as usize])
+            .collect();
+        let relations = r[tile.r as usize..next.map_or(r.len(), |t| t.r as usize)]
+            .iter()
+            .filter_map(|&i| self.diff.relations.by_old_idx(i as u64))
+            .map(|i| relation_idx[i as usize])
+            .collect();
+        Some((nodes, ways, relations))
+    }
+
+    // The old leaf of a leaf tile, with the leaf after it.
+    fn old_leaf(&self, h: u64) -> Option<(&Leaf, Option<&Leaf>)> {
+        let leaf_zoom = self.tree.manifest.render.leaf_zoom;
+        match self.tree.find(&Tile::from_zh(leaf_zoom, h)) {
+            FindResult::Leaf(pair) => Some((pair.item, pair.next)),
+            _ => None,
+        }
+    }
+
+    // How many nodes, ways and relations an old leaf has.
+    fn old_leaf_len(&self, i: usize) -> (usize, usize, usize) {
+        let leaves = self.tree.leaves.slice();
+        let flatdata = &self.tree.flatdata;
+        let leaf = &leaves[i];
+        let (n_end, w_end, r_end) = match leaves.get(i + 1) {
+            Some(next) => (next.n as usize, next.w as usize, next.r as usize),
+            None => (
+                flatdata.nodes().len(),
+                flatdata.ways().len(),
+                flatdata.relations().len(),
+            ),
+        };
+        (
+            n_end - leaf.n as usize,
+            w_end - leaf.w as usize,
+            r_end - leaf.r as usize,
+        )
+    }
+}
+
+impl Drop for Previous {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.node_idx.path);
+        let _ = fs::remove_file(&self.way_idx.path);
+        let _ = fs::remove_file(&self.relation_idx.path);
+    }
+}
+
+// The old indexes of the nodes, ways and relations that the diff changes. Besides the
+// modified and deleted ones, these are the ways of changed nodes, and the relations with
+// changed members, however deeply they nest.
+fn changed_entities(old: &Osm, diff: &Diff) -> (Vec<u64>, AHashSet<u32>, AHashSet<u32>) {
+    let nodes: AHashSet<u64> = diff.nodes.removed().collect();
+    let nodes_index = old.nodes_index();
+    let mut ways: AHashSet<u32> = diff.ways.removed().map(|i| i as u32).collect();
+    let node_ways: Vec<u32> = (0..old.ways().len())
+        .into_par_iter()
+        .filter(|&i| {
+            nodes_index[way_refs(old, i)]
+                .iter()
+                .any(|n| n.value().is_some_and(|v| nodes.contains(&v)))
+        })
+        .map(|i| i as u32)
+        .collect();
+    ways.extend(node_ways);
+
+    let members = old.members();
+    let mut relations: AHashSet<u32> = diff.relations.removed().map(|i| i as u32).collect();
+    loop {
+        let parents: Vec<u32> = (0..old.relations().len())
+            .into_par_iter()
+            .filter(|&r| !relations.contains(&(r as u32)))
+            .filter(|&r| {
+                members[relation_members(old, r)].iter().any(|m| {
+                    let Some(idx) = m.idx() else {
+                        return false;
+                    };
+                    match m.entity_type() {
+                        EntityType::Node => nodes.contains(&idx),
+                        EntityType::Way => ways.contains(&(idx as u32)),
+                        EntityType::Relation => relations.contains(&(idx as u32)),
+                        _ => false,
+                    }
+                })
+            })
+            .map(|r| r as u32)
+            .collect();
+        if parents.is_empty() {
+            break;
+        }
+        relations.extend(parents);
+    }
+
+    (nodes.into_iter().collect(), ways, relations)
+}
+
+// The leaf tiles of the given nodes, ways and relations, and the tiles their ways and
+// relations enter.
+#[allow(clippy::too_many_arguments)]
+fn touched(
+    flatdata: &Osm,
+    node_pairs: &[HilbertNodePair],
+    way_pairs: &[HilbertWayPair],
+    relation_pairs: &[HilbertRelationPair],
+    nodes: impl Iterator<Item = u64>,
+    ways: impl Iterator<Item = u32>,
+    relations: impl Iterator<Item = u32>,
+    leaf_zoom: u8,
+) -> Touched {
+    let tile_h = |h: u64| h_to_zoom_h(h, leaf_zoom);
+    let mut touched = Touched::default();
+
+    for n in nodes {
+        touched.leaves.insert(tile_h(node_pairs[n as usize].h()));
+    }
+    for w in ways {
+        let own_tile_h = tile_h(way_pairs[w as usize].h());
+        touched.leaves.insert(own_tile_h);
+        for h in way_tiles(flatdata, node_pairs, w as usize, leaf_zoom) {
+            touched.leaves.insert(h);
+            if h != own_tile_h {
+                touched.external_ways.entry(h).or_default().push(w);
+            }
+        }
+    }
+    for r in relations {
+        let i = r as usize;
+        touched.leaves.insert(tile_h(relation_pairs[i].h()));
+        for h in relation_tiles(
+            flatdata,
+            node_pairs,
+            way_pairs,
+            relation_pairs,
+            i,
+            leaf_zoom,
+        ) {
+            touched.leaves.insert(h);
+            touched.external_relations.entry(h).or_default().push(r);
+        }
+    }
+    touched
+}
+
+// The ways or relations that enter a leaf from outside of it. Those that did not change
+// are carried over from the old leaf, and the changed ones are added where they are now.
+fn external(
+    old: &[u32],
+    changed: &AHashSet<u32>,
+    remap: impl Fn(u32) -> Option<u32>,
+    new: Option<&Vec<u32>>,
+) -> Vec<u32> {
+    let mut external: Vec<u32> = old
+        .iter()
+        .filter(|i| !changed.contains(i))
+        .filter_map(|&i| remap(i))
+        .chain(new.into_iter().flatten().copied())
+        .collect();
+    external.sort_unstable();
+    external.dedup();
+    external
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manifest, osmflat, sort, util::copy_planet};
+
+    // Moves the end of way 106 and a corner of building 100, deletes the inner ring of
+    // the forest and creates a cafe.
+    const OSC: &str = r#"<?xml version='1.0' encoding='UTF-8'?>
+<osmChange version="0.6" generator="osmosis">
+  <modify>
+    <node id="53" version="2" timestamp="2022-12-01T10:00:00Z" lat="37.22" lon="-121.49"/>
+    <node id="10" version="2" timestamp="2022-12-01T10:00:00Z" lat="36.9595" lon="-122.0305"/>
+  </modify>
+  <delete>
+    <way id="103" version="2" timestamp="2022-12-01T10:00:00Z"/>
+  </delete>
+  <create>
+    <node id="2" version="1" timestamp="2022-12-01T10:00:00Z" lat="37.25" lon="-122.3">
+      <tag k="amenity" v="cafe"/>
+    </node>
+  </create>
+</osmChange>
+"#;
+
+    fn leaves(tree: &HilbertTree) -> Vec<(u64, u64, u32, u32, u32, u32)> {
+        let leaves = tree.leaves.slice();
+        leaves
+            .iter()
+            .map(|l| (l.h, l.n, l.w, l.r, l.w_ext, l.r_ext))
+            .collect()
+    }
+
+    // The content of every tile, in index order, since only the tiles that are rendered
+    // again are in the order of a full render.
+    fn contents(tree: &HilbertTree) -> Vec<(u32, u16, Content)> {
+        let tiles = tree.tiles.slice();
+        let (n, w, r) = (tree.n.slice(), tree.w.slice(), tree.r.slice());
+        (0..tiles.len())
+            .map(|i| {
+                let (tile, next) = (&tiles[i], tiles.get(i + 1));
+                let mut nodes = n[tile.n as usize..next.map_or(n.len(), |t| t.n as usize)].to_vec();
+                let mut ways = w[tile.w as usize..next.map_or(w.len(), |t| t.w as usize)].to_vec();
+                let mut relations =
+                    r[tile.r as usize..next.map_or(r.len(), |t| t.r as usize)].to_vec();
+                nodes.sort_unstable();
+                ways.sort_unstable();
+                relations.sort_unstable();
+                (tile.child, tile.mask, (nodes, ways, relations))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_update_tree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut manifest = manifest::parse("tests/fixtures/relations_sort.yaml").unwrap();
+        let planet = tmp.path().join("planet");
+        fs::create_dir_all(&planet).unwrap();
+        copy_planet(&manifest.data.planet, &planet).unwrap();
+        manifest.data.planet = planet;
+
+        let osc = tmp.path().join("test.osc");
+        fs::write(&osc, OSC).unwrap();
+        let mut updated = manifest.clone();
+        updated.data.planet = tmp.path().join("update");
+        fs::create_dir_all(&updated.data.planet).unwrap();
+        let (flatdata, diff) =
+            osmflat::update(&manifest, &osc, &updated.data.planet, None).unwrap();
+        sort::sort_flatdata(flatdata, &updated.data).unwrap();
+
+        // The same update, built and rendered in full.
+        let mut full = updated.clone();
+        full.data.planet = tmp.path().join("full");
+        fs::create_dir_all(&full.data.planet).unwrap();
+        copy_planet(&updated.data.planet, &full.data.planet).unwrap();
+        let mut full_tree = HilbertTree::new(&full).unwrap();
+        full_tree.render_tile_content().unwrap();
+
+        let previous = Previous::new(&manifest, &updated, diff).unwrap();
+        let leaf = |x, y| Tile::from_zxy(12, x, y).h;
+        // Way 106 and the city boundaries it is a part of.
+        assert!(previous.leaves.contains(&leaf(665, 1591)));
+        assert!(previous.leaves.contains(&leaf(658, 1588)));
+        // Building 100.
+        assert!(previous.leaves.contains(&leaf(659, 1594)));
+        // The motorway and the route of the bus stay as they are.
+        assert!(!previous.leaves.contains(&leaf(656, 1594)));
+        assert!(!previous.leaves.contains(&leaf(662, 1594)));
+        assert!(!previous.leaves.contains(&leaf(658, 1595)));
+        assert!(previous
+            .content(10, Tile::from_zxy(10, 165, 398).h)
+            .is_some());
+        assert!(previous
+            .content(10, Tile::from_zxy(10, 164, 398).h)
+            .is_none());
+
+        let mut tree = HilbertTree::update(&updated, &previous).unwrap();
+        tree.render_updated_tile_content(&previous).unwrap();
+
+        assert_eq!(leaves(&tree), leaves(&full_tree));
+        assert_eq!(
+            tree.leaves_external_ways.slice(),
+            full_tree.leaves_external_ways.slice()
+        );
+        assert_eq!(
+            tree.leaves_external_relations.slice(),
+            full_tree.leaves_external_relations.slice()
+        );
+        assert_eq!(contents(&tree), contents(&full_tree));
+    }
+}
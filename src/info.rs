@@ -167,6 +167,7 @@ pub fn generate_features<'a>(
             keys: Some(keys),
             values: Some(vals),
             geometries: Some(geometries),
+            area: false,
         },
     );
 
@@ -189,6 +190,7 @@ pub fn generate_features<'a>(
             keys: Some(keys),
             values: Some(vals),
             geometries: Some(center_geoms),
+            area: false,
         },
     );
 
@@ -209,6 +211,7 @@ pub fn generate_features<'a>(
             keys: Some(keys),
             values: Some(vals),
             geometries: Some(bearing_geoms),
+            area: false,
         },
     );
 
@@ -297,6 +300,7 @@ pub fn basic(tile: Tile) -> Vec<u8> {
             keys: Some(keys),
             values: Some(values),
             geometries: Some(geometries),
+            area: false,
         },
     );
     let boundary_features = builder.create_vector(&[boundary_feature]);
@@ -324,6 +328,7 @@ pub fn basic(tile: Tile) -> Vec<u8> {
             keys: Some(keys),
             values: Some(values),
             geometries: Some(center_geoms),
+            area: false,
         },
     );
     let center_features = builder.create_vector(&[center_feature]);
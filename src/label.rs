@@ -0,0 +1,288 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::f64::consts::SQRT_2;
+
+// Label points for areas, at their pole of inaccessibility: the point inside of an area
+// that is the farthest away from its outline. Unlike an interior point, it stays clear
+// of the edges of long, skinny areas, such as lakes along a valley or parks along a river.
+//
+// This is the polylabel algorithm of Mapbox:
+// https://github.com/mapbox/polylabel
+// https://blog.mapbox.com/a-new-algorithm-for-finding-a-visual-center-of-a-polygon-7c77e6492fbc
+
+type Point = (f64, f64);
+
+/// The pole of inaccessibility of an area, to within `precision`. The rings of the area
+/// are combined with the even-odd rule, so that inner rings are holes, whichever polygon
+/// of a multipolygon they belong to. Returns None for an area without an inside.
+pub fn polylabel(rings: &[Vec<Point>], precision: f64) -> Option<Point> {
+    let mut points = rings.iter().flatten();
+    let first = points.next()?;
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (first.0, first.1, first.0, first.1);
+    for (x, y) in points {
+        min_x = min_x.min(*x);
+        min_y = min_y.min(*y);
+        max_x = max_x.max(*x);
+        max_y = max_y.max(*y);
+    }
+    let width = max_x - min_x;
+    let height = max_y - min_y;
+    let cell_size = width.min(height);
+    if cell_size <= 0.0 {
+        return None;
+    }
+    // Without a positive precision, the search would only end once the cells are
+    // too small to split.
+    let precision = if precision > 0.0 {
+        precision
+    } else {
+        cell_size / 1000.0
+    };
+
+    // Cover the area with square cells.
+    let mut queue = BinaryHeap::new();
+    let h = cell_size / 2.0;
+    let mut x = min_x;
+    while x < max_x {
+        let mut y = min_y;
+        while y < max_y {
+            queue.push(Cell::new((x + h, y + h), h, rings));
+            y += cell_size;
+        }
+        x += cell_size;
+    }
+
+    // The centroid is a good first guess for most shapes.
+    let mut best = Cell::new(centroid(rings), 0.0, rings);
+    let bbox_cell = Cell::new((min_x + width / 2.0, min_y + height / 2.0), 0.0, rings);
+    if bbox_cell.d > best.d {
+        best = bbox_cell;
+    }
+
+    // Split the most promising cells, until no cell can have a better point by more
+    // than the precision.
+    while let Some(cell) = queue.pop() {
+        if cell.d > best.d {
+            best = cell;
+        }
+        if cell.max - best.d <= precision {
+            continue;
+        }
+        let h = cell.h / 2.0;
+        for (dx, dy) in [(-h, -h), (h, -h), (-h, h), (h, h)] {
+            queue.push(Cell::new((cell.p.0 + dx, cell.p.1 + dy), h, rings));
+        }
+    }
+
+    if best.d > 0.0 {
+        Some(best.p)
+    } else {
+        None
+    }
+}
+
+/// Joins lines into closed rings, such as the outer and inner ways of a multipolygon,
+/// by the ids of the nodes at their ends. Lines that do not close a ring are left out.
+pub fn assemble_rings<T: Copy + PartialEq>(mut lines: Vec<Vec<T>>) -> Vec<Vec<T>> {
+    lines.retain(|line| line.len() >= 2);
+    let mut rings = Vec::new();
+    while let Some(mut ring) = lines.pop() {
+        while ring.first() != ring.last() {
+            let end = ring[ring.len() - 1];
+            let Some(i) = lines
+                .iter()
+                .position(|line| line[0] == end || line[line.len() - 1] == end)
+            else {
+                break;
+            };
+            let mut line = lines.swap_remove(i);
+            if line[0] != end {
+                line.reverse();
+            }
+            ring.extend_from_slice(&line[1..]);
+        }
+        if ring.len() >= 4 && ring.first() == ring.last() {
+            rings.push(ring);
+        }
+    }
+    rings
+}
+
+#[derive(Clone, Copy)]
+struct Cell {
+    // The center of the cell.
+    p: Point,
+    // Half of the size of the cell.
+    h: f64,
+    // The distance from the center to the outline, negative outside of the area.
+    d: f64,
+    // The greatest distance that any point in the cell can have.
+    max: f64,
+}
+
+impl Cell {
+    fn new(p: Point, h: f64, rings: &[Vec<Point>]) -> Self {
+        let d = signed_distance(p, rings);
+        Self {
+            p,
+            h,
+            d,
+            max: d + h * SQRT_2,
+        }
+    }
+}
+
+impl PartialEq for Cell {
+    fn eq(&self, other: &Self) -> bool {
+        self.max == other.max
+    }
+}
+
+impl Eq for Cell {}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.max.total_cmp(&other.max)
+    }
+}
+
+// The distance from a point to the closest edge of the rings, negative when the point
+// is outside.
+fn signed_distance(p: Point, rings: &[Vec<Point>]) -> f64 {
+    let mut inside = false;
+    let mut min_dist_sq = f64::INFINITY;
+    for ring in rings {
+        let mut j = ring.len() - 1;
+        for i in 0..ring.len() {
+            let (a, b) = (ring[i], ring[j]);
+            if (a.1 > p.1) != (b.1 > p.1) && p.0 < (b.0 - a.0) * (p.1 - a.1) / (b.1 - a.1) + a.0 {
+                inside = !inside;
+            }
+            min_dist_sq = min_dist_sq.min(segment_distance_sq(p, a, b));
+            j = i;
+        }
+    }
+    let d = min_dist_sq.sqrt();
+    if inside {
+        d
+    } else {
+        -d
+    }
+}
+
+fn segment_distance_sq(p: Point, a: Point, b: Point) -> f64 {
+    let (mut x, mut y) = a;
+    let (dx, dy) = (b.0 - x, b.1 - y);
+    if dx != 0.0 || dy != 0.0 {
+        let t = ((p.0 - x) * dx + (p.1 - y) * dy) / (dx * dx + dy * dy);
+        if t > 1.0 {
+            (x, y) = b;
+        } else if t > 0.0 {
+            x += dx * t;
+            y += dy * t;
+        }
+    }
+    (p.0 - x).powi(2) + (p.1 - y).powi(2)
+}
+
+// The centroid of the largest ring.
+fn centroid(rings: &[Vec<Point>]) -> Point {
+    let mut best = (rings[0][0], 0.0);
+    for ring in rings {
+        let (mut area, mut x, mut y) = (0.0, 0.0, 0.0);
+        let mut j = ring.len() - 1;
+        for i in 0..ring.len() {
+            let (a, b) = (ring[i], ring[j]);
+            let f = a.0 * b.1 - b.0 * a.1;
+            x += (a.0 + b.0) * f;
+            y += (a.1 + b.1) * f;
+            area += f * 3.0;
+            j = i;
+        }
+        if area.abs() > best.1 {
+            best = ((x / area, y / area), area.abs());
+        }
+    }
+    best.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x: f64, y: f64, size: f64) -> Vec<Point> {
+        vec![
+            (x, y),
+            (x + size, y),
+            (x + size, y + size),
+            (x, y + size),
+            (x, y),
+        ]
+    }
+
+    #[test]
+    fn test_square() {
+        let (x, y) = polylabel(&[square(0.0, 0.0, 10.0)], 0.01).unwrap();
+        assert!((x - 5.0).abs() < 0.01);
+        assert!((y - 5.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_skinny_bend() {
+        // An L of two arms, 100 long and 2 wide. Its centroid is outside of it, and the
+        // label should be in the middle of an arm, 1 away from both sides.
+        let l = vec![
+            (0.0, 0.0),
+            (100.0, 0.0),
+            (100.0, 2.0),
+            (2.0, 2.0),
+            (2.0, 100.0),
+            (0.0, 100.0),
+            (0.0, 0.0),
+        ];
+        let rings = [l];
+        let (x, y) = polylabel(&rings, 0.01).unwrap();
+        assert!(signed_distance((x, y), &rings) > 0.98);
+    }
+
+    #[test]
+    fn test_hole() {
+        // A square with a hole in the middle leaves a label in the frame around it.
+        let rings = vec![square(0.0, 0.0, 30.0), square(5.0, 5.0, 20.0)];
+        let (x, y) = polylabel(&rings, 0.01).unwrap();
+        assert!(signed_distance((x, y), &rings) > 2.48);
+        assert!(!(5.0..25.0).contains(&x) || !(5.0..25.0).contains(&y));
+    }
+
+    #[test]
+    fn test_degenerate() {
+        assert_eq!(polylabel(&[], 1.0), None);
+        assert_eq!(
+            polylabel(&[vec![(0.0, 0.0), (5.0, 0.0), (0.0, 0.0)]], 1.0),
+            None
+        );
+    }
+
+    #[test]
+    fn test_assemble_rings() {
+        // Two ways that make up a ring, one of them reversed, a closed way,
+        // and a way that does not close.
+        let rings = assemble_rings(vec![
+            vec![1, 2, 3],
+            vec![1, 4, 3],
+            vec![5, 6, 7, 5],
+            vec![8, 9],
+        ]);
+        assert_eq!(rings.len(), 2);
+        assert!(rings.contains(&vec![5, 6, 7, 5]));
+        let ring = rings.iter().find(|r| r.len() == 5).unwrap();
+        assert_eq!(ring.first(), ring.last());
+        assert!(ring.contains(&2) && ring.contains(&4));
+    }
+}
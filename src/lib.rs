@@ -1,11 +1,15 @@
 #![allow(dead_code)]
 
+mod archive;
+mod external_sort;
 mod filter;
 mod hilbert;
+mod label;
 pub mod info;
 pub mod location;
 mod manifest;
 mod mutant;
+mod mvt;
 pub mod osmflat;
 mod parallel;
 mod pvt_builder;
@@ -27,6 +31,7 @@ use napi::tokio::{self};
 use pvt_builder::PVTBuilder;
 use source::Source;
 use std::error::Error;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
 use tile::Tile;
@@ -51,6 +56,17 @@ impl Planet {
             if tile == "info" {
                 let info = Box::new(Info::new()) as Box<dyn Source>;
                 sources.push(info);
+            } else if tile.ends_with(".pvt") {
+                match HilbertTree::open_archive(Path::new(tile)) {
+                    Ok(tree) => {
+                        let box_tree = Box::new(tree) as Box<dyn Source>;
+                        sources.push(box_tree);
+                    }
+                    Err(err) => {
+                        eprintln!("Unable to open archive {} Error: {:?}", tile, err);
+                        eprintln!("Skipping {}", tile);
+                    }
+                }
             } else {
                 match manifest::parse(tile) {
                     Ok(manifest) => match HilbertTree::open(&manifest) {
@@ -114,6 +130,47 @@ impl Planet {
         }
     }
 
+    /// The same tile as `tile`, encoded as a standard Mapbox Vector Tile.
+    #[napi]
+    pub async fn tile_mvt(&self, z: u8, x: u32, y: u32) -> Result<Uint8Array> {
+        let time = Instant::now();
+        let sources_rw = self.sources.clone();
+        let tile = Tile::from_zxy(z, x, y);
+        let task_handle = tokio::task::spawn(async move {
+            let mut builder = PVTBuilder::new();
+            let sources = sources_rw.read().await;
+            for i in 0..sources.len() {
+                let source = sources.get(i).unwrap();
+                source.compose_tile(&tile, &mut builder);
+            }
+            let vec_u8 = builder.build();
+            match mvt::pvt_to_mvt(&vec_u8) {
+                Ok(mvt) => Ok(mvt.into()),
+                Err(err) => Err(napi::Error::new(
+                    napi::Status::GenericFailure,
+                    format!("Unable to encode MVT: {:?}", err),
+                )),
+            }
+        });
+        match task_handle.await {
+            Ok(result) => {
+                println!(
+                    "{:8} {}/{}/{} mvt {} ms",
+                    tile.h,
+                    z,
+                    x,
+                    y,
+                    time.elapsed().as_millis()
+                );
+                result
+            }
+            Err(err) => Err(napi::Error::new(
+                napi::Status::GenericFailure,
+                format!("{:8} {}/{}/{} Error: {:?}", tile.h, z, x, y, err),
+            )),
+        }
+    }
+
     #[napi]
     pub fn abort(&self, z: u8, x: u32, y: u32) {
         // NHTODO Provide ability to abort task
@@ -129,7 +186,7 @@ impl Planet {
 pub async fn pvt() -> Result<()> {
     let manifest = manifest::parse("manifests/basic.yaml").unwrap();
     let flatdata = osmflat::convert(&manifest).unwrap_or_else(quit);
-    sort::sort_flatdata(flatdata, &manifest.data.planet).unwrap_or_else(quit);
+    sort::sort_flatdata(flatdata, &manifest.data).unwrap_or_else(quit);
     hilbert::tree::HilbertTree::new(&manifest).unwrap_or_else(quit);
     Ok(())
 }
@@ -82,6 +82,10 @@ pub fn lonlat_to_h(lonlat: (i32, i32)) -> u64 {
     xy2h(x, y, 32)
 }
 
+pub fn xy_to_h(xy: (u32, u32)) -> u64 {
+    xy2h(xy.0, xy.1, 32)
+}
+
 pub fn decimal_lonlat_to_h(dec_lonlat: (f64, f64)) -> u64 {
     let lonlat = decimal_lonlat_to_lonlat(dec_lonlat);
     lonlat_to_h(lonlat)
@@ -110,6 +114,11 @@ pub fn zoom_h_to_h(h: u64, z: u8) -> u64 {
     h << (2 * (32 - z))
 }
 
+/// Meters at the equator in Web Mercator points. Towards the poles, a meter spans more points.
+pub fn meters_to_xy(meters: f64) -> f64 {
+    meters * U32_SIZE / 40_075_016.685_578_5
+}
+
 // Tile Extent
 
 pub fn extent_for_zoom(z: u8) -> u32 {
@@ -1,9 +1,14 @@
+mod archive;
 mod commands;
+mod export;
+mod external_sort;
 mod filter;
 mod hilbert;
+mod label;
 mod location;
 pub mod manifest;
 mod mutant;
+mod mvt;
 mod osmflat;
 mod parallel;
 pub mod pvt_builder;
@@ -12,16 +17,19 @@ mod report;
 mod rules;
 mod sort;
 mod source;
+mod stage;
 mod tile;
 mod tile_attributes;
 mod u40;
 mod util;
+mod validate;
 
 use clap::ArgMatches;
-use hilbert::tree::HilbertTree;
+use hilbert::{tree::HilbertTree, update::Previous};
 use humantime::format_duration;
 use manifest::Manifest;
-use std::{error::Error, fs};
+use stage::{Stage, Stages};
+use std::{error::Error, fs, path::Path};
 
 fn main() {
     let time = util::timer("pvt");
@@ -31,7 +39,7 @@ fn main() {
         Some(sub) => sub,
         None => {
             eprintln!(
-                "pvt requires one of the following subcommands: convert, render, archive, build"
+                "pvt requires one of the following subcommands: convert, render, archive, build, update, export, report, validate"
             );
             std::process::exit(1);
         }
@@ -47,80 +55,216 @@ fn main() {
                 }
             }
 
-            let flatdata = osmflat::convert(&manifest).unwrap_or_else(quit);
-            sort::sort_flatdata(flatdata, &manifest.data.planet).unwrap_or_else(quit);
-
-            match HilbertTree::new(&manifest) {
-                Ok(_) => (),
-                Err(e) => {
-                    eprintln!(
-                        "Unable to open planet dir: {} Error: {:?}",
-                        manifest.data.planet.display(),
-                        e
-                    );
-                    eprintln!("Are you pointing to the right source, planet, and archive in your manifest?");
-                    std::process::exit(1);
-                }
-            };
+            let stages = Stages::new(&manifest);
+            stages
+                .run(Stage::Convert, false, || convert(&manifest, false))
+                .unwrap_or_else(quit);
+            stages
+                .run(Stage::Tree, false, || build_tree(&manifest))
+                .unwrap_or_else(quit);
         }
         ("render", matches) => {
             let manifest = get_manifest(matches);
 
-            let mut tree = match HilbertTree::open(&manifest) {
-                Ok(tree) => tree,
-                Err(e) => {
-                    eprintln!(
-                        "Unable to open planet dir: {} Error: {:?}",
-                        manifest.data.planet.display(),
-                        e
-                    );
-                    eprintln!("Are you pointing to the right source, planet, and archive in your manifest?");
-                    std::process::exit(1);
-                }
-            };
-
-            tree.render_tile_content().unwrap_or_else(quit);
+            // The tree only has to be rebuilt when it is missing or out of date,
+            // such as when the leaf zoom changed, and not when only the rules changed.
+            let stages = Stages::new(&manifest);
+            stages
+                .run(Stage::Tree, true, || build_tree(&manifest))
+                .unwrap_or_else(quit);
+            stages
+                .run(Stage::Render, false, || render(&manifest))
+                .unwrap_or_else(quit);
         }
-        ("archive", _) => {
-            println!("TODO: Make a .pvt archive.")
+        ("archive", matches) => {
+            let manifest = get_manifest(matches);
+            let overwrite = matches.get_one::<bool>("overwrite").unwrap();
+            Stages::new(&manifest)
+                .run(Stage::Archive, false, || {
+                    archive::create(&manifest, *overwrite)
+                })
+                .unwrap_or_else(quit);
         }
         ("build", matches) => {
             let manifest = get_manifest(matches);
             let overwrite = matches.get_one::<bool>("overwrite").unwrap();
+            let resume = *matches.get_one::<bool>("resume").unwrap();
             if *overwrite {
                 if let Err(e) = fs::remove_dir_all(&manifest.data.planet) {
                     eprintln!("Unable to remove planet dir: {}", e);
                 }
             }
 
-            let flatdata = osmflat::convert(&manifest).unwrap_or_else(quit);
-            sort::sort_flatdata(flatdata, &manifest.data.planet).unwrap_or_else(quit);
-
-            let mut tree = match HilbertTree::new(&manifest) {
-                Ok(tree) => tree,
-                Err(e) => {
-                    eprintln!(
-                        "Unable to open planet dir: {} Error: {:?}",
-                        manifest.data.planet.display(),
-                        e
-                    );
-                    eprintln!("Are you pointing to the right source, planet, and archive in your manifest?");
-                    std::process::exit(1);
-                }
+            let stages = Stages::new(&manifest);
+            stages
+                .run(Stage::Convert, resume, || convert(&manifest, resume))
+                .unwrap_or_else(quit);
+            stages
+                .run(Stage::Tree, resume, || build_tree(&manifest))
+                .unwrap_or_else(quit);
+            stages
+                .run(Stage::Render, resume, || render(&manifest))
+                .unwrap_or_else(quit);
+            stages
+                .run(Stage::Archive, resume, || archive::create(&manifest, true))
+                .unwrap_or_else(quit);
+        }
+        ("update", matches) => {
+            let manifest = get_manifest(matches);
+            let osc = matches.get_one::<String>("OSC_PATH").unwrap();
+            let sequence_number = matches.get_one::<i64>("sequence-number").copied();
+
+            // The updated planet is built in a scratch dir next to the old one, so that it is
+            // on the same filesystem, and replaces the old one once rendered.
+            let scratch = scratch_dir(&manifest.data.planet).unwrap_or_else(quit);
+            let mut update_manifest = manifest.clone();
+            update_manifest.data.planet = scratch.path().join("planet");
+
+            let (flatdata, diff) = osmflat::update(
+                &manifest,
+                Path::new(osc),
+                &update_manifest.data.planet,
+                sequence_number,
+            )
+            .unwrap_or_else(quit);
+            sort::sort_flatdata(flatdata, &update_manifest.data).unwrap_or_else(quit);
+
+            // The tree of the old planet can only be carried over where the diff does not
+            // touch it if it was rendered with the leaf zoom and rules of the manifest.
+            let old_stages = Stages::new(&manifest);
+            let previous = if old_stages.is_done(Stage::Tree) && old_stages.is_done(Stage::Render) {
+                Some(Previous::new(&manifest, &update_manifest, diff).unwrap_or_else(quit))
+            } else {
+                println!("The planet is not rendered for this manifest, so all of it is rendered.");
+                None
             };
 
-            tree.render_tile_content().unwrap_or_else(quit);
+            // The updated planet no longer matches the sources, so it has no convert stage.
+            let stages = Stages::new(&update_manifest);
+            stages
+                .run(Stage::Tree, false, || match &previous {
+                    Some(previous) => HilbertTree::update(&update_manifest, previous).map(|_| ()),
+                    None => build_tree(&update_manifest),
+                })
+                .unwrap_or_else(quit);
+            stages
+                .run(Stage::Render, false, || match &previous {
+                    Some(previous) => {
+                        let mut tree = HilbertTree::open(&update_manifest)?;
+                        tree.render_updated_tile_content(previous)?;
+                        Ok(())
+                    }
+                    None => render(&update_manifest),
+                })
+                .unwrap_or_else(quit);
+            // The old planet is still open until here.
+            drop(previous);
+
+            replace_planet(&manifest.data.planet, &update_manifest.data.planet)
+                .unwrap_or_else(quit);
+            archive::create(&manifest, true).unwrap_or_else(quit);
+        }
+        ("export", matches) => {
+            let manifest = get_manifest(matches);
+            let overwrite = matches.get_one::<bool>("overwrite").unwrap();
+            let output = matches.get_one::<String>("OUTPUT_PATH").unwrap();
+            let format = matches.get_one::<String>("format").unwrap();
+            let tile_format = matches.get_one::<String>("tile-format").unwrap();
+            let format = export::Format::parse(format).unwrap_or_else(quit);
+            let tile_format = export::TileFormat::parse(tile_format).unwrap_or_else(quit);
+            let extent = export::Extent::parse(
+                matches.get_one::<String>("bbox").map(|s| s.as_str()),
+                matches.get_one::<String>("hilbert-range").map(|s| s.as_str()),
+            )
+            .unwrap_or_else(quit);
+            export::export(
+                &manifest,
+                Path::new(output),
+                format,
+                tile_format,
+                extent,
+                *overwrite,
+            )
+            .unwrap_or_else(quit);
         }
         ("report", matches) => {
             let manifest = get_manifest(matches);
             report::generate(&manifest).unwrap_or_else(quit);
         }
+        ("validate", matches) => {
+            let manifest = get_manifest(matches);
+            let validation = validate::validate(&manifest).unwrap_or_else(quit);
+            validation.print();
+            if !validation.is_valid() {
+                std::process::exit(1);
+            }
+        }
         _ => unreachable!(),
     }
 
     println!("Total Time: {}", format_duration(time.elapsed()));
 }
 
+// A dir that is removed when dropped, in the same dir as the planet, so that a planet can
+// be moved in and out of it with a rename.
+fn scratch_dir(planet: &Path) -> Result<tempfile::TempDir, Box<dyn Error>> {
+    let parent = match planet.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok(tempfile::Builder::new()
+        .prefix(".pvt-scratch")
+        .tempdir_in(parent)?)
+}
+
+fn replace_planet(planet: &Path, updated: &Path) -> Result<(), Box<dyn Error>> {
+    let old = scratch_dir(planet)?;
+    fs::rename(planet, old.path().join("planet"))?;
+    fs::rename(updated, planet)?;
+    Ok(())
+}
+
+fn convert(manifest: &Manifest, resume: bool) -> Result<(), Box<dyn Error>> {
+    // A conversion that did not finish can not be resumed, so it starts over.
+    if resume && manifest.data.planet.exists() {
+        fs::remove_dir_all(&manifest.data.planet)?;
+    }
+    let flatdata = osmflat::convert(manifest)?;
+    sort::sort_flatdata(flatdata, &manifest.data)
+}
+
+fn build_tree(manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+    match HilbertTree::new(manifest) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!(
+                "Unable to open planet dir: {} Error: {:?}",
+                manifest.data.planet.display(),
+                e
+            );
+            eprintln!("Are you pointing to the right source, planet, and archive in your manifest?");
+            Err(e)
+        }
+    }
+}
+
+fn render(manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+    let mut tree = match HilbertTree::open(manifest) {
+        Ok(tree) => tree,
+        Err(e) => {
+            eprintln!(
+                "Unable to open planet dir: {} Error: {:?}",
+                manifest.data.planet.display(),
+                e
+            );
+            eprintln!("Are you pointing to the right source, planet, and archive in your manifest?");
+            return Err(e);
+        }
+    };
+    tree.render_tile_content()?;
+    Ok(())
+}
+
 fn quit<T>(e: Box<dyn Error>) -> T {
     eprintln!("Error: {}", e);
     std::process::exit(1);
@@ -1,3 +1,4 @@
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::BTreeSet;
 use std::fs;
@@ -19,13 +20,62 @@ pub struct Manifest {
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Data {
-    pub source: PathBuf,
+    // One or more sources. Entities in more than one source are only converted once.
+    #[serde(deserialize_with = "one_or_many")]
+    pub source: Vec<PathBuf>,
     pub planet: PathBuf,
     pub archive: PathBuf,
     // NHTODO Right now this only applies for a report. Do we also want to use it for the build?
     // Should this be in the report section?
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub include_leaves: Vec<u64>,
+    // Stores the version, timestamp, changeset and user of every entity
+    // in the planet. Off by default, since it makes the planet larger.
+    #[serde(default = "bool::default")]
+    pub include_metadata: bool,
+    // Only converts the nodes within [west, south, east, north], the ways and relations
+    // that reference them, and the member nodes and ways of those relations. The changes
+    // that pvt update applies are clipped the same way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clip_bbox: Option<[f64; 4]>,
+    // Like clip_bbox, for a polygon in a GeoJSON or .poly file.
+    // With both, the sources are clipped to their intersection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clip_polygon: Option<PathBuf>,
+    // Tags with these keys are not converted. A trailing * matches every key with
+    // the prefix, such as tiger:*.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drop_tags: Vec<String>,
+    // When given, only tags with these keys are converted. Also accepts prefixes with *.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keep_tags: Vec<String>,
+    // What to do with ways that reference nodes missing from the source.
+    #[serde(default)]
+    pub dangling_ways: DanglingWays,
+    // Precision in meters of the label points of areas, which are also their Hilbert
+    // locations. Finer labels take longer to find.
+    #[serde(default = "default_label_precision")]
+    pub label_precision: f64,
+    // Memory in MB for sorting the Hilbert pairs. Larger pairs files are sorted in runs
+    // of this size, which are then merged, rather than all at once in their memory map.
+    #[serde(default = "default_sort_memory_mb")]
+    pub sort_memory_mb: usize,
+    // Memory in MB for the index that deduplicates strings while converting or updating.
+    // Rare strings fall out of a full index and are stored again when they come back.
+    #[serde(default = "default_string_index_mb")]
+    pub string_index_mb: usize,
+}
+
+fn default_label_precision() -> f64 {
+    10.0
+}
+
+fn default_sort_memory_mb() -> usize {
+    16_000
+}
+
+fn default_string_index_mb() -> usize {
+    8_000
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -36,6 +86,14 @@ pub struct Render {
     // Helpful for debugging and figuring out style rules.
     #[serde(default = "bool::default")]
     pub all_tags: bool,
+    // The highest zoom that tiles are served at. Tiles past the leaf zoom are composed
+    // from the leaf that contains them. Set to the leaf zoom to not overzoom.
+    #[serde(default = "default_max_overzoom")]
+    pub max_overzoom: u8,
+}
+
+fn default_max_overzoom() -> u8 {
+    22
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -53,6 +111,17 @@ pub struct Rule {
     pub include: Option<IncludeTags>,
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum DanglingWays {
+    // Keep the missing refs as invalid indexes.
+    #[default]
+    Keep,
+    // Remove the missing refs, and drop the way if less than two nodes are left.
+    Truncate,
+    // Drop every way with a missing ref.
+    Drop,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum IncludeTags {
     None,
@@ -60,6 +129,22 @@ pub enum IncludeTags {
     Keys(BTreeSet<String>),
 }
 
+// Accepts both a single source and a list of sources.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<PathBuf>, D::Error> {
+    #[derive(serde_derive::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => vec![path],
+        OneOrMany::Many(paths) => paths,
+    })
+}
+
 pub fn parse(path_str: &str) -> Result<Manifest> {
     let mut path = PathBuf::from(path_str);
 
@@ -95,25 +180,32 @@ pub fn parse(path_str: &str) -> Result<Manifest> {
         return Err(Error::new(ErrorKind::InvalidData, msg));
     }
 
-    // Maximum supported zoom is 14.
-    if leaf_zoom > 14 {
+    // Maximum supported zoom is 16.
+    if leaf_zoom > 16 {
         let msg = format!(
-            "The maximum supported leaf zoom is 14. leaf_zoom: {}",
+            "The maximum supported leaf zoom is 16. leaf_zoom: {}",
             leaf_zoom
         );
         return Err(Error::new(ErrorKind::InvalidData, msg));
     }
 
+    let max_overzoom = manifest.render.max_overzoom;
+    if max_overzoom < leaf_zoom || max_overzoom > 22 {
+        let msg = format!(
+            "The max overzoom must be between the leaf zoom and 22. leaf_zoom: {} max_overzoom: {}",
+            leaf_zoom, max_overzoom
+        );
+        return Err(Error::new(ErrorKind::InvalidData, msg));
+    }
+
     let mut dir = path.clone();
     dir.pop();
 
     dir = dir.canonicalize()?;
 
-    let mut source = dir.clone();
     let mut planet = dir.clone();
     let mut archive = dir.clone();
 
-    source.push(manifest.data.source);
     planet.push(manifest.data.planet);
     archive.push(manifest.data.archive);
 
@@ -133,7 +225,19 @@ pub fn parse(path_str: &str) -> Result<Manifest> {
 
     // Make the paths in the manifest be relative to the directory of the manifest file.
     // Canonicalize to absolute paths to reduce ambiguity.
-    manifest.data.source = source.canonicalize().unwrap_or(source);
+    manifest.data.source = manifest
+        .data
+        .source
+        .iter()
+        .map(|source| {
+            let source = dir.join(source);
+            source.canonicalize().unwrap_or(source)
+        })
+        .collect();
+    manifest.data.clip_polygon = manifest.data.clip_polygon.map(|polygon| {
+        let polygon = dir.join(polygon);
+        polygon.canonicalize().unwrap_or(polygon)
+    });
     manifest.data.planet = planet.canonicalize().unwrap_or(planet);
     manifest.data.archive = archive.canonicalize().unwrap_or(archive);
 
@@ -174,15 +278,25 @@ mod tests {
 
         let m = Manifest {
             data: Data {
-                source: PathBuf::from("source"),
+                source: vec![PathBuf::from("source0"), PathBuf::from("source1")],
                 planet: PathBuf::from("planet"),
                 archive: PathBuf::from("archive"),
                 include_leaves: vec![],
+                include_metadata: true,
+                clip_bbox: Some([-122.1, 36.9, -121.9, 37.1]),
+                clip_polygon: Some(PathBuf::from("santa_cruz.poly")),
+                drop_tags: vec!["created_by".to_string(), "tiger:*".to_string()],
+                keep_tags: vec![],
+                dangling_ways: DanglingWays::Truncate,
+                label_precision: 5.0,
+                sort_memory_mb: 64,
+                string_index_mb: 128,
             },
             render: Render {
                 leaf_zoom: 12,
                 layer_order: vec!["layer0".to_string()],
                 all_tags: true,
+                max_overzoom: 16,
             },
             layers,
             rules,
@@ -205,4 +319,19 @@ mod tests {
 
         assert!(s2.len() > 300);
     }
+
+    #[test]
+    fn test_one_or_many_sources() {
+        let data: Data =
+            serde_yaml::from_str("source: a.osm.pbf\nplanet: p\narchive: a.pvt").unwrap();
+        assert_eq!(data.source, vec![PathBuf::from("a.osm.pbf")]);
+
+        let data: Data =
+            serde_yaml::from_str("source: [a.osm.pbf, b.osm.pbf]\nplanet: p\narchive: a.pvt")
+                .unwrap();
+        assert_eq!(
+            data.source,
+            vec![PathBuf::from("a.osm.pbf"), PathBuf::from("b.osm.pbf")]
+        );
+    }
 }
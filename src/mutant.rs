@@ -1,8 +1,9 @@
 #![allow(dead_code)]
 
+use crate::archive::Archive;
 use core::mem::size_of;
 use core::slice::{from_raw_parts, from_raw_parts_mut};
-use memmap2::MmapMut;
+use memmap2::{MmapMut, MmapOptions};
 use std::fs::File;
 use std::io::{Error, ErrorKind, Result};
 use std::ptr::copy_nonoverlapping;
@@ -163,6 +164,52 @@ impl<T: Sized> Mutant<T> {
         })
     }
 
+    /// Maps a file packed inside of a .pvt archive. The mapping is copy-on-write,
+    /// so the archive itself is never modified. These Mutants are meant to be read,
+    /// and growing them is not supported.
+    pub fn open_archive(archive: &Archive, file_name: &str, is_flatdata: bool) -> Result<Self> {
+        let range = match archive.range(file_name) {
+            Some(range) => range,
+            None => {
+                let msg = format!(
+                    "{} is not in the archive {}",
+                    file_name,
+                    archive.path.display()
+                );
+                return Err(Error::new(ErrorKind::NotFound, msg));
+            }
+        };
+
+        let file = File::open(&archive.path)?;
+        let file_size = range.len();
+        let mmap = unsafe {
+            MmapOptions::new()
+                .offset(range.start as u64)
+                .len(file_size)
+                .map_copy(&file)?
+        };
+
+        let contents_size = file_size - 8;
+        let capacity = contents_size / size_of::<T>();
+
+        let len = if is_flatdata {
+            capacity
+        } else {
+            let header_ptr = mmap.as_ptr() as *const u64;
+            unsafe { *header_ptr as usize }
+        };
+
+        Ok(Mutant {
+            file,
+            path: archive.path.join(file_name),
+            mmap,
+            len,
+            capacity,
+            is_flatdata,
+            phantom: PhantomData,
+        })
+    }
+
     pub fn mv(&mut self, new_name: &str) -> Result<()> {
         let mut path = self.path.clone();
         path.pop();
@@ -0,0 +1,563 @@
+use ahash::AHashMap;
+use flatbuffers::InvalidFlatbuffer;
+use prost::Message;
+
+use crate::tile::{
+    planet_vector_tile_generated::{
+        root_as_pvttile, PVTFeature, PVTTile, PVTTilePoint, PVTValue, PVTValueType,
+    },
+    TILE_EXTENT,
+};
+
+#[allow(clippy::all)]
+#[path = "./generated/vector_tile.rs"]
+pub mod vector_tile;
+use vector_tile::tile::{Feature, GeomType, Layer, Value};
+
+// MVT 2.1
+// https://github.com/mapbox/vector-tile-spec/tree/master/2.1
+
+pub const VERSION: u32 = 2;
+
+const MOVE_TO: u32 = 1;
+const LINE_TO: u32 = 2;
+const CLOSE_PATH: u32 = 7;
+
+/// Transcodes a finished PVT flatbuffer into a Mapbox Vector Tile,
+/// so that stock MapLibre, QGIS, and other tools can read our planets.
+pub fn pvt_to_mvt(pvt: &[u8]) -> Result<Vec<u8>, InvalidFlatbuffer> {
+    let pvt_tile = root_as_pvttile(pvt)?;
+    Ok(encode(&pvt_tile).encode_to_vec())
+}
+
+pub fn encode(pvt_tile: &PVTTile) -> vector_tile::Tile {
+    let strings: Vec<&str> = match pvt_tile.strings() {
+        Some(strings) => strings.iter().collect(),
+        None => Vec::new(),
+    };
+    let values: Vec<&PVTValue> = match pvt_tile.values() {
+        Some(values) => values.iter().collect(),
+        None => Vec::new(),
+    };
+
+    let mut mvt_layers = Vec::new();
+
+    if let Some(layers) = pvt_tile.layers() {
+        for pvt_layer in layers.iter() {
+            let features = match pvt_layer.features() {
+                Some(features) if !features.is_empty() => features,
+                // A layer should contain at least one feature.
+                _ => continue,
+            };
+
+            let mut layer = Layer {
+                version: VERSION,
+                name: lookup(&strings, pvt_layer.name()).to_string(),
+                features: Vec::with_capacity(features.len()),
+                keys: Vec::new(),
+                values: Vec::new(),
+                // The PVT tile points are already projected to the MapLibre internal extent.
+                extent: Some(TILE_EXTENT as u32),
+            };
+
+            // MVT keys and values are per layer, PVT keys and values are per tile.
+            let mut layer_keys: AHashMap<u32, u32> = AHashMap::new();
+            let mut layer_values: AHashMap<u32, u32> = AHashMap::new();
+
+            for pvt_feature in features.iter() {
+                let geometries = encode_geometry(&pvt_feature);
+                if geometries.is_empty() {
+                    continue;
+                }
+                let tags = encode_tags(
+                    &pvt_feature,
+                    &strings,
+                    &values,
+                    &mut layer,
+                    &mut layer_keys,
+                    &mut layer_values,
+                );
+                // An MVT feature has a single geometry type, so a PVT feature with
+                // several becomes one MVT feature for each, sharing the id and tags.
+                for (geom_type, geometry) in geometries {
+                    layer.features.push(Feature {
+                        id: Some(pvt_feature.id()),
+                        tags: tags.clone(),
+                        r#type: Some(geom_type as i32),
+                        geometry,
+                    });
+                }
+            }
+
+            if !layer.features.is_empty() {
+                mvt_layers.push(layer);
+            }
+        }
+    }
+
+    vector_tile::Tile { layers: mvt_layers }
+}
+
+fn encode_tags(
+    pvt_feature: &PVTFeature,
+    strings: &[&str],
+    values: &[&PVTValue],
+    layer: &mut Layer,
+    layer_keys: &mut AHashMap<u32, u32>,
+    layer_values: &mut AHashMap<u32, u32>,
+) -> Vec<u32> {
+    let (keys, vals) = match (pvt_feature.keys(), pvt_feature.values()) {
+        (Some(keys), Some(vals)) => (keys, vals),
+        _ => return Vec::new(),
+    };
+
+    let mut tags = Vec::with_capacity(keys.len() * 2);
+    for (k, v) in keys.iter().zip(vals.iter()) {
+        let key_idx = *layer_keys.entry(k).or_insert_with(|| {
+            layer.keys.push(lookup(strings, k).to_string());
+            (layer.keys.len() - 1) as u32
+        });
+        let val_idx = *layer_values.entry(v).or_insert_with(|| {
+            let value = match values.get(v as usize) {
+                Some(value) => to_mvt_value(value, strings),
+                None => Value {
+                    string_value: Some(String::new()),
+                    ..Default::default()
+                },
+            };
+            layer.values.push(value);
+            (layer.values.len() - 1) as u32
+        });
+        tags.push(key_idx);
+        tags.push(val_idx);
+    }
+    tags
+}
+
+fn to_mvt_value(value: &PVTValue, strings: &[&str]) -> Value {
+    let v = value.v();
+    match value.t() {
+        PVTValueType::String => Value {
+            string_value: Some(lookup(strings, v as u32).to_string()),
+            ..Default::default()
+        },
+        PVTValueType::Boolean => Value {
+            bool_value: Some(v != 0_f64),
+            ..Default::default()
+        },
+        // Whole numbers such as osm_id are much nicer to consume as integers.
+        _ if v.fract() == 0_f64 && v.abs() < 9007199254740992_f64 => {
+            if v < 0_f64 {
+                Value {
+                    sint_value: Some(v as i64),
+                    ..Default::default()
+                }
+            } else {
+                Value {
+                    uint_value: Some(v as u64),
+                    ..Default::default()
+                }
+            }
+        }
+        _ => Value {
+            double_value: Some(v),
+            ..Default::default()
+        },
+    }
+}
+
+fn lookup<'a>(strings: &[&'a str], idx: u32) -> &'a str {
+    strings.get(idx as usize).copied().unwrap_or("")
+}
+
+/// Each PVT feature has one or more geometries, each a list of tile points.
+/// Single points become a (multi) point, the closed paths of areas become polygons,
+/// and everything else becomes a (multi) linestring. A feature with several of these
+/// gives each of them, in that order. Compose marks the features of areas, since a
+/// closed path may just as well be a line, such as a roundabout.
+fn encode_geometry(pvt_feature: &PVTFeature) -> Vec<(GeomType, Vec<u32>)> {
+    let is_area = pvt_feature.area();
+    let geometries: Vec<Vec<(i32, i32)>> = match pvt_feature.geometries() {
+        Some(geometries) => geometries
+            .iter()
+            .map(|g| match g.points() {
+                Some(points) => points.iter().map(to_xy).collect(),
+                None => Vec::new(),
+            })
+            .filter(|points: &Vec<(i32, i32)>| !points.is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let mut points = Vec::new();
+    let mut rings = Vec::new();
+    let mut lines = Vec::new();
+    for geometry in geometries {
+        if geometry.len() == 1 {
+            points.push(geometry[0]);
+        } else if is_area && is_closed(&geometry) {
+            rings.push(dedup(&geometry));
+        } else {
+            lines.push(dedup(&geometry));
+        }
+    }
+
+    let mut encoded = Vec::new();
+
+    if !points.is_empty() {
+        let mut encoder = GeometryEncoder::new();
+        encoder.move_to(&points);
+        encoded.push((GeomType::Point, encoder.geometry));
+    }
+
+    // The first ring is an exterior ring. The rings wound the same way are exterior rings
+    // as well, and the ones wound the other way are the interior rings of the exterior
+    // ring before them. Exterior rings must have a positive area in tile coordinates
+    // (clockwise, with y pointing down) and interior rings a negative area.
+    let mut encoder = GeometryEncoder::new();
+    let mut exterior_sign = 0;
+    for mut ring in rings {
+        // The last point is implied by ClosePath.
+        ring.pop();
+        let area = signed_area(&ring);
+        if ring.len() < 3 || area == 0 {
+            continue;
+        }
+        if exterior_sign == 0 {
+            exterior_sign = area.signum();
+        }
+        let is_exterior = area.signum() == exterior_sign;
+        if is_exterior != (area > 0) {
+            ring.reverse();
+        }
+        encoder.move_to(&ring[..1]);
+        encoder.line_to(&ring[1..]);
+        encoder.close_path();
+    }
+    if !encoder.geometry.is_empty() {
+        encoded.push((GeomType::Polygon, encoder.geometry));
+    }
+
+    let mut encoder = GeometryEncoder::new();
+    for line in lines.iter().filter(|line| line.len() >= 2) {
+        encoder.move_to(&line[..1]);
+        encoder.line_to(&line[1..]);
+    }
+    if !encoder.geometry.is_empty() {
+        encoded.push((GeomType::Linestring, encoder.geometry));
+    }
+
+    encoded
+}
+
+fn to_xy(point: &PVTTilePoint) -> (i32, i32) {
+    (point.x() as i32, point.y() as i32)
+}
+
+fn is_closed(points: &[(i32, i32)]) -> bool {
+    points.len() >= 4 && points.first() == points.last()
+}
+
+fn dedup(points: &[(i32, i32)]) -> Vec<(i32, i32)> {
+    let mut deduped = points.to_vec();
+    deduped.dedup();
+    deduped
+}
+
+// Twice the area of the ring, via the surveyor's formula.
+fn signed_area(ring: &[(i32, i32)]) -> i64 {
+    let mut area = 0_i64;
+    for i in 0..ring.len() {
+        let (x1, y1) = ring[i];
+        let (x2, y2) = ring[(i + 1) % ring.len()];
+        area += x1 as i64 * y2 as i64 - x2 as i64 * y1 as i64;
+    }
+    area
+}
+
+struct GeometryEncoder {
+    geometry: Vec<u32>,
+    cursor: (i32, i32),
+}
+
+impl GeometryEncoder {
+    fn new() -> Self {
+        Self {
+            geometry: Vec::new(),
+            cursor: (0, 0),
+        }
+    }
+
+    fn move_to(&mut self, points: &[(i32, i32)]) {
+        self.geometry.push(command(MOVE_TO, points.len() as u32));
+        self.params(points);
+    }
+
+    fn line_to(&mut self, points: &[(i32, i32)]) {
+        self.geometry.push(command(LINE_TO, points.len() as u32));
+        self.params(points);
+    }
+
+    fn close_path(&mut self) {
+        self.geometry.push(command(CLOSE_PATH, 1));
+    }
+
+    fn params(&mut self, points: &[(i32, i32)]) {
+        for &(x, y) in points {
+            self.geometry.push(zigzag(x - self.cursor.0));
+            self.geometry.push(zigzag(y - self.cursor.1));
+            self.cursor = (x, y);
+        }
+    }
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+fn zigzag(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pvt_builder::PVTBuilder;
+    use crate::tile::planet_vector_tile_generated::{
+        PVTFeatureArgs, PVTGeometry, PVTGeometryArgs, PVTLayer, PVTLayerArgs,
+    };
+
+    fn add_feature(
+        builder: &mut PVTBuilder,
+        id: u64,
+        tag: (&str, &str),
+        is_area: bool,
+        geometries: &[&[(i16, i16)]],
+    ) {
+        let key = builder.attributes.upsert_string(tag.0);
+        let val = builder.attributes.upsert_string_value(tag.1);
+        let keys = builder.fbb.create_vector(&[key]);
+        let vals = builder.fbb.create_vector(&[val]);
+        let mut geoms = Vec::new();
+        for points in geometries {
+            let path: Vec<PVTTilePoint> = points
+                .iter()
+                .map(|(x, y)| PVTTilePoint::new(*x, *y))
+                .collect();
+            let points = builder.fbb.create_vector(&path);
+            geoms.push(PVTGeometry::create(
+                &mut builder.fbb,
+                &PVTGeometryArgs {
+                    points: Some(points),
+                },
+            ));
+        }
+        let geoms = builder.fbb.create_vector(&geoms);
+        let feature = PVTFeature::create(
+            &mut builder.fbb,
+            &PVTFeatureArgs {
+                id,
+                keys: Some(keys),
+                values: Some(vals),
+                geometries: Some(geoms),
+                area: is_area,
+            },
+        );
+        let features = builder.fbb.create_vector(&[feature]);
+        let name = builder.attributes.upsert_string("roads");
+        let layer = PVTLayer::create(
+            &mut builder.fbb,
+            &PVTLayerArgs {
+                name,
+                features: Some(features),
+            },
+        );
+        builder.add_layer(layer);
+    }
+
+    fn to_mvt<'a>(builder: &'a mut PVTBuilder<'a>) -> vector_tile::Tile {
+        let pvt = builder.build();
+        vector_tile::Tile::decode(pvt_to_mvt(&pvt).unwrap().as_slice()).unwrap()
+    }
+
+    // Decodes the paths of a geometry, with the first point repeated at the end of rings.
+    fn decode_paths(geometry: &[u32]) -> Vec<Vec<(i32, i32)>> {
+        let unzigzag = |n: u32| ((n >> 1) as i32) ^ -((n & 1) as i32);
+        let mut paths: Vec<Vec<(i32, i32)>> = Vec::new();
+        let mut cursor = (0, 0);
+        let mut i = 0;
+        while i < geometry.len() {
+            let (id, count) = (geometry[i] & 0x7, geometry[i] >> 3);
+            i += 1;
+            if id == CLOSE_PATH {
+                let path = paths.last_mut().unwrap();
+                path.push(path[0]);
+                continue;
+            }
+            for _ in 0..count {
+                cursor.0 += unzigzag(geometry[i]);
+                cursor.1 += unzigzag(geometry[i + 1]);
+                i += 2;
+                if id == MOVE_TO {
+                    paths.push(Vec::new());
+                }
+                paths.last_mut().unwrap().push(cursor);
+            }
+        }
+        paths
+    }
+
+    #[test]
+    fn test_zigzag() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+        assert_eq!(command(MOVE_TO, 1), 9);
+        assert_eq!(command(CLOSE_PATH, 1), 15);
+    }
+
+    #[test]
+    fn test_linestring() {
+        let mut builder = PVTBuilder::new();
+        let line: &[(i16, i16)] = &[(2, 2), (2, 10), (2, 10), (10, 10)];
+        add_feature(&mut builder, 7, ("highway", "primary"), false, &[line]);
+
+        let mvt = to_mvt(&mut builder);
+        assert_eq!(mvt.layers.len(), 1);
+        let layer = &mvt.layers[0];
+        assert_eq!(layer.name, "roads");
+        assert_eq!(layer.version, 2);
+        assert_eq!(layer.extent, Some(8192));
+        assert_eq!(layer.keys, vec!["highway".to_string()]);
+        assert_eq!(layer.values[0].string_value.as_deref(), Some("primary"));
+
+        let feature = &layer.features[0];
+        assert_eq!(feature.id, Some(7));
+        assert_eq!(feature.tags, vec![0, 0]);
+        assert_eq!(feature.r#type, Some(GeomType::Linestring as i32));
+        // The duplicate point is dropped.
+        assert_eq!(feature.geometry, vec![9, 4, 4, 18, 0, 16, 16, 0]);
+    }
+
+    #[test]
+    fn test_polygon_winding() {
+        let mut builder = PVTBuilder::new();
+        // Counter-clockwise in tile coordinates, so it needs to be reversed.
+        let ring: &[(i16, i16)] = &[(3, 6), (12, 22), (5, 6), (3, 6)];
+        add_feature(&mut builder, 1, ("building", "yes"), true, &[ring]);
+
+        let mvt = to_mvt(&mut builder);
+        let layer = &mvt.layers[0];
+        assert_eq!(layer.keys, vec!["building".to_string()]);
+        let feature = &layer.features[0];
+        assert_eq!(feature.tags, vec![0, 0]);
+        assert_eq!(feature.r#type, Some(GeomType::Polygon as i32));
+        // MoveTo(5,6) LineTo(12,22) (3,6) ClosePath
+        assert_eq!(feature.geometry, vec![9, 10, 12, 18, 14, 32, 17, 31, 15]);
+    }
+
+    #[test]
+    fn test_polygon_with_hole() {
+        let mut builder = PVTBuilder::new();
+        // Both rings are wound the wrong way around.
+        let exterior: &[(i16, i16)] = &[(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)];
+        let interior: &[(i16, i16)] = &[(2, 2), (8, 2), (8, 8), (2, 8), (2, 2)];
+        add_feature(
+            &mut builder,
+            1,
+            ("landuse", "forest"),
+            true,
+            &[exterior, interior],
+        );
+
+        let mvt = to_mvt(&mut builder);
+        assert_eq!(mvt.layers[0].features.len(), 1);
+        let feature = &mvt.layers[0].features[0];
+        assert_eq!(feature.r#type, Some(GeomType::Polygon as i32));
+        let rings = decode_paths(&feature.geometry);
+        assert_eq!(
+            rings,
+            vec![
+                vec![(10, 0), (10, 10), (0, 10), (0, 0), (10, 0)],
+                vec![(2, 8), (8, 8), (8, 2), (2, 2), (2, 8)],
+            ]
+        );
+        assert!(signed_area(&rings[0]) > 0);
+        assert!(signed_area(&rings[1]) < 0);
+    }
+
+    #[test]
+    fn test_closed_highway() {
+        let mut builder = PVTBuilder::new();
+        let roundabout: &[(i16, i16)] = &[(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)];
+        add_feature(
+            &mut builder,
+            1,
+            ("highway", "primary"),
+            false,
+            &[roundabout],
+        );
+
+        let mvt = to_mvt(&mut builder);
+        let feature = &mvt.layers[0].features[0];
+        assert_eq!(feature.r#type, Some(GeomType::Linestring as i32));
+        // The way is kept as is, with its closing point.
+        assert_eq!(
+            decode_paths(&feature.geometry),
+            vec![vec![(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)]]
+        );
+    }
+
+    #[test]
+    fn test_mixed_feature() {
+        let mut builder = PVTBuilder::new();
+        let ring: &[(i16, i16)] = &[(0, 0), (10, 0), (10, 10), (0, 0)];
+        let line: &[(i16, i16)] = &[(20, 20), (30, 30)];
+        let point: &[(i16, i16)] = &[(5, 5)];
+        add_feature(
+            &mut builder,
+            9,
+            ("building", "yes"),
+            true,
+            &[ring, line, point],
+        );
+
+        let mvt = to_mvt(&mut builder);
+        let features = &mvt.layers[0].features;
+        let types: Vec<Option<i32>> = features.iter().map(|f| f.r#type).collect();
+        assert_eq!(
+            types,
+            vec![
+                Some(GeomType::Point as i32),
+                Some(GeomType::Polygon as i32),
+                Some(GeomType::Linestring as i32),
+            ]
+        );
+        for feature in features {
+            assert_eq!(feature.id, Some(9));
+            assert_eq!(feature.tags, vec![0, 0]);
+        }
+        assert_eq!(decode_paths(&features[0].geometry), vec![vec![(5, 5)]]);
+        assert_eq!(
+            decode_paths(&features[1].geometry),
+            vec![vec![(0, 0), (10, 0), (10, 10), (0, 0)]]
+        );
+        assert_eq!(
+            decode_paths(&features[2].geometry),
+            vec![vec![(20, 20), (30, 30)]]
+        );
+    }
+
+    #[test]
+    fn test_point() {
+        let mut builder = PVTBuilder::new();
+        let point: &[(i16, i16)] = &[(25, 17)];
+        add_feature(&mut builder, 1, ("highway", "stop"), false, &[point]);
+
+        let mvt = to_mvt(&mut builder);
+        let feature = &mvt.layers[0].features[0];
+        assert_eq!(feature.r#type, Some(GeomType::Point as i32));
+        assert_eq!(feature.geometry, vec![9, 50, 34]);
+    }
+}
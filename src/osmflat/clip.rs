@@ -0,0 +1,527 @@
+use ahash::AHashSet;
+use geo::{coord, BoundingRect, Contains, LineString, MultiPolygon, Point, Polygon, Rect};
+use pbr::ProgressBar;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::manifest::Manifest;
+use crate::osmflat::osmflat_generated::osm::EntityType;
+use crate::parallel;
+
+use super::merge::PbfSource;
+use super::osmpbf;
+use super::osmpbf::read_block;
+use super::osmpbf::relation::MemberType;
+
+// Clips the sources to a bounding box and/or polygon during conversion.
+//
+// Nodes outside of the area are dropped. Ways with at least one node inside are kept
+// complete, with all of their nodes. Relations are kept when they have a member that
+// is kept, or a member relation that is kept. Kept relations are complete as well, with
+// their member nodes and their member ways and the nodes of those, so that a multipolygon
+// or a route crossing the edge of the area is not cut short. Their member relations are
+// not added, and are left unresolved when they are outside of the area, just like in any
+// other extract.
+
+type Error = Box<dyn std::error::Error>;
+
+/// The area of the manifest's clip_bbox and clip_polygon. With both, it is their intersection.
+#[derive(Debug)]
+pub struct ClipArea {
+    bbox: Option<Rect>,
+    polygon: Option<MultiPolygon>,
+}
+
+impl ClipArea {
+    /// None when the manifest does not clip.
+    pub fn from_manifest(manifest: &Manifest) -> Result<Option<Self>, Error> {
+        let bbox = manifest.data.clip_bbox.map(|[west, south, east, north]| {
+            Rect::new(coord! { x: west, y: south }, coord! { x: east, y: north })
+        });
+        let polygon = match &manifest.data.clip_polygon {
+            Some(path) => Some(read_polygon(path)?),
+            None => None,
+        };
+        if bbox.is_none() && polygon.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self { bbox, polygon }))
+    }
+
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        let point = Point::new(lon, lat);
+        self.bbox.is_none_or(|bbox| bbox.contains(&point))
+            && self
+                .polygon
+                .as_ref()
+                .is_none_or(|polygon| polygon.contains(&point))
+    }
+
+    /// The bounds of the area: (west, south, east, north).
+    pub fn bounds(&self) -> (f64, f64, f64, f64) {
+        let rect = match (
+            self.bbox,
+            self.polygon.as_ref().and_then(|p| p.bounding_rect()),
+        ) {
+            (Some(a), Some(b)) => Rect::new(
+                coord! { x: a.min().x.max(b.min().x), y: a.min().y.max(b.min().y) },
+                coord! { x: a.max().x.min(b.max().x), y: a.max().y.min(b.max().y) },
+            ),
+            (Some(rect), None) | (None, Some(rect)) => rect,
+            (None, None) => Rect::new(coord! { x: -180., y: -90. }, coord! { x: 180., y: 90. }),
+        };
+        (rect.min().x, rect.min().y, rect.max().x, rect.max().y)
+    }
+
+    /// The bounds as an osm.pbf header bbox in nanodegrees.
+    pub fn header_bbox(&self) -> osmpbf::HeaderBBox {
+        let (west, south, east, north) = self.bounds();
+        let nano = |degrees: f64| (degrees * 1e9).round() as i64;
+        osmpbf::HeaderBBox {
+            left: nano(west),
+            right: nano(east),
+            top: nano(north),
+            bottom: nano(south),
+        }
+    }
+}
+
+/// Reads a clip polygon from a GeoJSON file, or an Osmosis .poly file.
+pub fn read_polygon(path: &Path) -> Result<MultiPolygon, Error> {
+    let s = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Unable to read clip polygon: {}", path.display());
+            return Err(Box::new(e));
+        }
+    };
+    if path.extension().is_some_and(|ext| ext == "poly") {
+        parse_poly(&s)
+    } else {
+        parse_geojson(&s)
+    }
+}
+
+/// Parses the Osmosis polygon filter file format. Sections starting with ! are holes
+/// in the preceding polygon.
+pub fn parse_poly(s: &str) -> Result<MultiPolygon, Error> {
+    let mut polygons: Vec<Polygon> = Vec::new();
+    // The first line is the name of the polygon.
+    let mut lines = s.lines().map(str::trim).filter(|l| !l.is_empty()).skip(1);
+    while let Some(section) = lines.next() {
+        if section == "END" {
+            break;
+        }
+        let mut ring = Vec::new();
+        for line in lines.by_ref() {
+            if line == "END" {
+                break;
+            }
+            let mut values = line.split_whitespace();
+            match (values.next(), values.next()) {
+                (Some(lon), Some(lat)) => ring.push(coord! { x: lon.parse()?, y: lat.parse()? }),
+                _ => return Err(format!("Invalid line in .poly file: {}", line).into()),
+            }
+        }
+        let ring = LineString::new(ring);
+        if section.starts_with('!') {
+            match polygons.last_mut() {
+                Some(polygon) => polygon.interiors_push(ring),
+                None => return Err("A .poly file can not start with a hole.".into()),
+            }
+        } else {
+            polygons.push(Polygon::new(ring, vec![]));
+        }
+    }
+    Ok(MultiPolygon::new(polygons))
+}
+
+/// Parses the Polygons and MultiPolygons of a GeoJSON geometry, feature or feature collection.
+pub fn parse_geojson(s: &str) -> Result<MultiPolygon, Error> {
+    let json: Value = serde_json::from_str(s)?;
+    let mut polygons = Vec::new();
+    collect_polygons(&json, &mut polygons)?;
+    if polygons.is_empty() {
+        return Err("The clip polygon GeoJSON has no Polygon or MultiPolygon.".into());
+    }
+    Ok(MultiPolygon::new(polygons))
+}
+
+fn collect_polygons(json: &Value, polygons: &mut Vec<Polygon>) -> Result<(), Error> {
+    match json["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in json["features"].as_array().into_iter().flatten() {
+                collect_polygons(feature, polygons)?;
+            }
+        }
+        Some("Feature") => collect_polygons(&json["geometry"], polygons)?,
+        Some("Polygon") => polygons.push(json_polygon(&json["coordinates"])?),
+        Some("MultiPolygon") => {
+            for polygon in json["coordinates"].as_array().into_iter().flatten() {
+                polygons.push(json_polygon(polygon)?);
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn json_polygon(coordinates: &Value) -> Result<Polygon, Error> {
+    let mut rings = Vec::new();
+    for ring in coordinates.as_array().into_iter().flatten() {
+        let mut coords = Vec::new();
+        for position in ring.as_array().into_iter().flatten() {
+            match (position[0].as_f64(), position[1].as_f64()) {
+                (Some(x), Some(y)) => coords.push(coord! { x: x, y: y }),
+                _ => return Err(format!("Invalid GeoJSON position: {}", position).into()),
+            }
+        }
+        rings.push(LineString::new(coords));
+    }
+    if rings.is_empty() {
+        return Err("A GeoJSON polygon needs an exterior ring.".into());
+    }
+    let exterior = rings.remove(0);
+    Ok(Polygon::new(exterior, rings))
+}
+
+/// The ids of the entities that are converted within a clip area.
+#[derive(Debug, Default)]
+pub struct Clip {
+    nodes: AHashSet<i64>,
+    ways: AHashSet<i64>,
+    relations: AHashSet<i64>,
+}
+
+impl Clip {
+    pub fn select(area: &ClipArea, sources: &[PbfSource]) -> Result<Self, Error> {
+        let mut clip = Clip::default();
+
+        // The ways and relations are read twice, for the members of the kept relations.
+        let num_blocks: usize = sources
+            .iter()
+            .map(|s| s.nodes.len() + 2 * (s.ways.len() + s.relations.len()))
+            .sum();
+        let mut pb = ProgressBar::new(num_blocks as u64);
+        pb.message("Selecting entities within clip area...");
+
+        for source in sources {
+            parallel::parallel_process(
+                source.nodes.iter().cloned(),
+                |idx| read_block(source.data, &idx),
+                |block: Result<osmpbf::PrimitiveBlock, _>| -> Result<(), Error> {
+                    for (id, lon, lat) in node_locations(&block?) {
+                        if area.contains(lon, lat) {
+                            clip.nodes.insert(id);
+                        }
+                    }
+                    pb.inc();
+                    Ok(())
+                },
+            )?;
+        }
+
+        // Ways are kept complete, so their nodes outside of the area are added afterwards.
+        let mut way_nodes = Vec::new();
+        for source in sources {
+            parallel::parallel_process(
+                source.ways.iter().cloned(),
+                |idx| read_block(source.data, &idx),
+                |block: Result<osmpbf::PrimitiveBlock, _>| -> Result<(), Error> {
+                    for group in &block?.primitivegroup {
+                        for way in &group.ways {
+                            let refs = way_refs(way);
+                            if refs.iter().any(|id| clip.nodes.contains(id)) {
+                                clip.ways.insert(way.id);
+                                way_nodes.extend(refs);
+                            }
+                        }
+                    }
+                    pb.inc();
+                    Ok(())
+                },
+            )?;
+        }
+        clip.nodes.extend(way_nodes);
+
+        // Relations of relations are resolved once all relations are read.
+        let mut parents = Vec::new();
+        for source in sources {
+            parallel::parallel_process(
+                source.relations.iter().cloned(),
+                |idx| read_block(source.data, &idx),
+                |block: Result<osmpbf::PrimitiveBlock, _>| -> Result<(), Error> {
+                    for group in &block?.primitivegroup {
+                        for relation in &group.relations {
+                            for (member_type, memid) in members(relation) {
+                                let kept = match member_type {
+                                    Some(MemberType::Node) => clip.nodes.contains(&memid),
+                                    Some(MemberType::Way) => clip.ways.contains(&memid),
+                                    Some(MemberType::Relation) => {
+                                        parents.push((relation.id, memid));
+                                        false
+                                    }
+                                    None => false,
+                                };
+                                if kept {
+                                    clip.relations.insert(relation.id);
+                                }
+                            }
+                        }
+                    }
+                    pb.inc();
+                    Ok(())
+                },
+            )?;
+        }
+        loop {
+            let mut changed = false;
+            for (parent, child) in &parents {
+                if clip.relations.contains(child) {
+                    changed |= clip.relations.insert(*parent);
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut member_nodes = Vec::new();
+        let mut member_ways = AHashSet::new();
+        for source in sources {
+            parallel::parallel_process(
+                source.relations.iter().cloned(),
+                |idx| read_block(source.data, &idx),
+                |block: Result<osmpbf::PrimitiveBlock, _>| -> Result<(), Error> {
+                    for group in &block?.primitivegroup {
+                        for relation in &group.relations {
+                            if !clip.relations.contains(&relation.id) {
+                                continue;
+                            }
+                            for (member_type, memid) in members(relation) {
+                                match member_type {
+                                    Some(MemberType::Node) => member_nodes.push(memid),
+                                    Some(MemberType::Way) if !clip.ways.contains(&memid) => {
+                                        member_ways.insert(memid);
+                                    }
+                                    _ => (),
+                                }
+                            }
+                        }
+                    }
+                    pb.inc();
+                    Ok(())
+                },
+            )?;
+        }
+        clip.nodes.extend(member_nodes);
+
+        let mut way_nodes = Vec::new();
+        for source in sources {
+            parallel::parallel_process(
+                source.ways.iter().cloned(),
+                |idx| read_block(source.data, &idx),
+                |block: Result<osmpbf::PrimitiveBlock, _>| -> Result<(), Error> {
+                    for group in &block?.primitivegroup {
+                        for way in &group.ways {
+                            if member_ways.contains(&way.id) {
+                                clip.ways.insert(way.id);
+                                way_nodes.extend(way_refs(way));
+                            }
+                        }
+                    }
+                    pb.inc();
+                    Ok(())
+                },
+            )?;
+        }
+        clip.nodes.extend(way_nodes);
+
+        println!(
+            "Clip area keeps {} nodes, {} ways, {} relations.",
+            clip.nodes.len(),
+            clip.ways.len(),
+            clip.relations.len()
+        );
+        Ok(clip)
+    }
+
+    pub fn keeps(&self, entity_type: &EntityType, id: i64) -> bool {
+        match entity_type {
+            EntityType::Node => self.nodes.contains(&id),
+            EntityType::Way => self.ways.contains(&id),
+            EntityType::Relation => self.relations.contains(&id),
+            _ => false,
+        }
+    }
+}
+
+/// The node ids of a way, which are delta coded.
+fn way_refs(way: &osmpbf::Way) -> Vec<i64> {
+    way.refs
+        .iter()
+        .scan(0, |id, delta| {
+            *id += delta;
+            Some(*id)
+        })
+        .collect()
+}
+
+/// The types and ids of the members of a relation, whose ids are delta coded.
+fn members(relation: &osmpbf::Relation) -> impl Iterator<Item = (Option<MemberType>, i64)> + '_ {
+    relation
+        .types
+        .iter()
+        .zip(relation.memids.iter().scan(0, |id, delta| {
+            *id += delta;
+            Some(*id)
+        }))
+        .map(|(member_type, memid)| (MemberType::from_i32(*member_type), memid))
+}
+
+/// The ids and locations in degrees of the plain and dense nodes in a block.
+fn node_locations(block: &osmpbf::PrimitiveBlock) -> Vec<(i64, f64, f64)> {
+    let granularity = i64::from(block.granularity.unwrap_or(100));
+    let lat_offset = block.lat_offset.unwrap_or(0);
+    let lon_offset = block.lon_offset.unwrap_or(0);
+    let degrees = |offset: i64, value: i64| (offset + granularity * value) as f64 * 1e-9;
+
+    let mut result = Vec::new();
+    for group in &block.primitivegroup {
+        for node in &group.nodes {
+            result.push((
+                node.id,
+                degrees(lon_offset, node.lon),
+                degrees(lat_offset, node.lat),
+            ));
+        }
+        if let Some(dense) = &group.dense {
+            let (mut id, mut lat, mut lon) = (0, 0, 0);
+            for i in 0..dense.id.len() {
+                id += dense.id[i];
+                lat += dense.lat[i];
+                lon += dense.lon[i];
+                result.push((id, degrees(lon_offset, lon), degrees(lat_offset, lat)));
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_poly() {
+        let poly = "santa_cruz
+1
+   -122.1   36.9
+   -121.9   36.9
+   -121.9   37.1
+   -122.1   37.1
+   -122.1   36.9
+END
+!2
+   -122.05  36.95
+   -122.0   36.95
+   -122.0   37.0
+   -122.05  37.0
+   -122.05  36.95
+END
+END
+";
+        let area = ClipArea {
+            bbox: None,
+            polygon: Some(parse_poly(poly).unwrap()),
+        };
+        assert!(area.contains(-121.95, 37.05));
+        // In the hole
+        assert!(!area.contains(-122.02, 36.97));
+        assert!(!area.contains(-122.2, 37.05));
+        assert_eq!(area.bounds(), (-122.1, 36.9, -121.9, 37.1));
+    }
+
+    #[test]
+    fn test_parse_geojson() {
+        let geojson = r#"{
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "properties": {},
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[[-122.1, 36.9], [-121.9, 36.9], [-121.9, 37.1], [-122.1, 36.9]]]
+                }
+            }]
+        }"#;
+        let area = ClipArea {
+            bbox: Some(Rect::new(
+                coord! { x: -122.0, y: 36.0 },
+                coord! { x: -121.0, y: 38.0 },
+            )),
+            polygon: Some(parse_geojson(geojson).unwrap()),
+        };
+        assert!(area.contains(-121.95, 36.95));
+        // Inside the polygon, but outside of the bbox
+        assert!(!area.contains(-122.05, 36.92));
+        assert_eq!(area.bounds(), (-122.0, 36.9, -121.9, 37.1));
+    }
+
+    #[test]
+    fn test_select() {
+        let data = std::fs::read("tests/fixtures/nodes4.osm.pbf").unwrap();
+        let sources = vec![PbfSource::new(&data)];
+        // Around the lighthouse on West Cliff Drive
+        let area = ClipArea {
+            bbox: Some(Rect::new(
+                coord! { x: -122.03, y: 36.95 },
+                coord! { x: -122.02, y: 36.96 },
+            )),
+            polygon: None,
+        };
+        let clip = Clip::select(&area, &sources).unwrap();
+        assert!(clip.keeps(&EntityType::Node, 137750));
+        assert!(clip.keeps(&EntityType::Node, 137752));
+        assert!(!clip.keeps(&EntityType::Node, 137747));
+    }
+
+    #[test]
+    fn test_select_relation_members() {
+        let data = std::fs::read("tests/fixtures/relations.osm.pbf").unwrap();
+        let sources = vec![PbfSource::new(&data)];
+        let select = |west: f64, south: f64| {
+            let area = ClipArea {
+                bbox: Some(Rect::new(
+                    coord! { x: west, y: south },
+                    coord! { x: west + 0.002, y: south + 0.002 },
+                )),
+                polygon: None,
+            };
+            Clip::select(&area, &sources).unwrap()
+        };
+        let keeps_all = |clip: &Clip, entity_type: EntityType, ids: &[i64]| {
+            ids.iter().all(|id| clip.keeps(&entity_type, *id))
+        };
+
+        // Only a corner of the outer ring of the forest is in the area.
+        let clip = select(-122.061, 36.989);
+        assert!(keeps_all(&clip, EntityType::Relation, &[200]));
+        assert!(keeps_all(&clip, EntityType::Way, &[102, 103]));
+        assert!(keeps_all(&clip, EntityType::Node, &[30, 32, 34, 36]));
+        assert!(!clip.keeps(&EntityType::Way, 100));
+
+        // The west end of the motorway of the bus route, whose stop is far outside.
+        let clip = select(-122.301, 36.974);
+        assert!(keeps_all(&clip, EntityType::Relation, &[201]));
+        assert!(keeps_all(&clip, EntityType::Node, &[40, 41, 1]));
+
+        // The subarea keeps its boundary, which keeps its own outer way.
+        let clip = select(-121.501, 37.199);
+        assert!(keeps_all(&clip, EntityType::Relation, &[202, 203]));
+        assert!(keeps_all(&clip, EntityType::Way, &[105, 106]));
+        assert!(keeps_all(&clip, EntityType::Node, &[50, 51, 52, 53]));
+        assert!(!clip.keeps(&EntityType::Node, 1));
+    }
+}
@@ -1,47 +1,67 @@
 use ahash::AHashMap;
 use flatdata::FileResourceStorage;
-use itertools::Itertools;
 use memmap2::Mmap;
 use pbr::ProgressBar;
 use std::collections::hash_map;
 use std::fs::File;
 use std::io;
+use std::path::Path;
 use std::str;
 use std::time::Instant;
 
 use crate::location;
-use crate::manifest::Manifest;
+use crate::manifest::{DanglingWays, Manifest};
 use crate::osmflat::osmflat_generated::osm::EntityType;
 use crate::parallel;
 
+use super::clip::{Clip, ClipArea};
+use super::dangling::Dangling;
 use super::ids;
+use super::merge::{self, Duplicates, PbfSource, Selection, SourceFilter};
 use super::osmflat_generated::osm as osmflat;
 use super::osmpbf;
-use super::osmpbf::{build_block_index, read_block, BlockIndex, BlockType};
+use super::osmpbf::{read_block, BlockType};
+use super::osmxml;
 use super::stats::Stats;
 use super::strings::StringTable;
+use super::tag_filter::TagFilter;
 
 type Error = Box<dyn std::error::Error>;
 
 pub fn convert(manifest: &Manifest) -> Result<osmflat::Osm, Error> {
     let time = Instant::now();
-    println!("Converting osm.pbf to osm.flatdata...");
-
-    let input_file = match File::open(&manifest.data.source) {
-        Ok(f) => f,
-        Err(err) => {
-            eprintln!(
-                "Unable to open source file: {}",
-                manifest.data.source.display(),
-            );
-            eprintln!(
-                "Are you pointing to the right source, planet, and archive in your manifest?"
-            );
-            return Err(Box::new(err));
-        }
-    };
-
-    let input_data = unsafe { Mmap::map(&input_file)? };
+    let sources = &manifest.data.source;
+    let is_xml = sources.iter().any(|source| is_osm_xml(source));
+    if is_xml && sources.len() > 1 {
+        return Err("Only osm.pbf sources can be merged into one planet.".into());
+    }
+    let clip_area = ClipArea::from_manifest(manifest)?;
+    if is_xml && clip_area.is_some() {
+        return Err("Only osm.pbf sources can be clipped.".into());
+    }
+    if is_xml && manifest.data.dangling_ways != DanglingWays::Keep {
+        return Err("Only ways of osm.pbf sources can be truncated or dropped.".into());
+    }
+    let source_format = if is_xml { "osm" } else { "osm.pbf" };
+    println!("Converting {} to osm.flatdata...", source_format);
+
+    let mut input_data = Vec::with_capacity(sources.len());
+    for source in sources {
+        let input_file = match File::open(source) {
+            Ok(f) => f,
+            Err(err) => {
+                eprintln!("Unable to open source file: {}", source.display());
+                eprintln!(
+                    "Are you pointing to the right source, planet, and archive in your manifest?"
+                );
+                return Err(Box::new(err));
+            }
+        };
+        input_data.push(unsafe { Mmap::map(&input_file)? });
+    }
+    if input_data.is_empty() {
+        return Err("The manifest has no source.".into());
+    }
 
     let storage = FileResourceStorage::new(&manifest.data.planet);
 
@@ -57,28 +77,92 @@ pub fn convert(manifest: &Manifest) -> Result<osmflat::Osm, Error> {
         }
     };
 
-    // TODO: Would be nice not store all these strings in memory, but to flush them
-    // from time to time to disk.
-    let mut stringtable = StringTable::new();
-    let mut tags = TagSerializer::new(&builder)?;
+    let mut stringtable = StringTable::with_index_budget(
+        &manifest.data.planet,
+        manifest.data.string_index_mb * 1_000_000,
+    )?;
+    let mut tags = TagSerializer::new(&builder)?.with_filter(TagFilter::new(
+        &manifest.data.drop_tags,
+        &manifest.data.keep_tags,
+    ));
 
     println!(
         "Initialized new osmflat archive at: {}",
         &manifest.data.planet.display()
     );
 
+    let include_metadata = manifest.data.include_metadata;
+    let dangling_ways = manifest.data.dangling_ways;
+    let mut dangling = Dangling::default();
+    let stats = if is_xml {
+        osmxml::convert(
+            &input_data[0],
+            &builder,
+            &mut tags,
+            &mut stringtable,
+            include_metadata,
+            &mut dangling,
+        )?
+    } else {
+        let input_data: Vec<&[u8]> = input_data.iter().map(|data| &data[..]).collect();
+        convert_pbf(
+            &input_data,
+            &builder,
+            &mut tags,
+            &mut stringtable,
+            include_metadata,
+            clip_area.as_ref(),
+            dangling_ways,
+            &mut dangling,
+        )?
+    };
+
+    // Finalize data structures
+    tags.close(); // drop the reference to stringtable
+
+    println!("Writing stringtable to disk...");
+    builder.set_stringtable(&stringtable.into_mmap()?)?;
+
+    std::mem::drop(builder);
+    let flatdata = osmflat::Osm::open(storage)?;
+
+    println!(
+        "Conversion from {} to osm.flatdata is complete. {}",
+        source_format,
+        humantime::format_duration(time.elapsed())
+    );
+    println!("{}", stats);
+    dangling.write(&manifest.data.planet)?;
+
+    Ok(flatdata)
+}
+
+/// OSM XML sources are selected by the .osm extension. Everything else is read as osm.pbf.
+fn is_osm_xml(source: &Path) -> bool {
+    source.extension().is_some_and(|ext| ext == "osm")
+}
+
+#[allow(clippy::too_many_arguments)]
+fn convert_pbf(
+    input_data: &[&[u8]],
+    builder: &osmflat::OsmBuilder,
+    tags: &mut TagSerializer,
+    stringtable: &mut StringTable,
+    include_metadata: bool,
+    clip_area: Option<&ClipArea>,
+    dangling_ways: DanglingWays,
+    dangling: &mut Dangling,
+) -> Result<Stats, Error> {
     println!("Building index of PBF blocks...");
-    let block_index = build_block_index(&input_data);
+    let sources: Vec<PbfSource> = input_data.iter().map(|data| PbfSource::new(data)).collect();
 
     // NHTODO Remove this granularity stuff. It's always DM7.
     let mut greatest_common_granularity = 1_000_000_000;
-    for block in &block_index {
-        if block.block_type == BlockType::DenseNodes {
-            // only DenseNodes have coordinate we need to scale
-            if let Some(block_granularity) = block.granularity {
-                greatest_common_granularity =
-                    gcd(greatest_common_granularity, block_granularity as i32);
-            }
+    for block in sources.iter().flat_map(|s| &s.nodes) {
+        // only Nodes and DenseNodes have coordinates we need to scale
+        if let Some(block_granularity) = block.granularity {
+            greatest_common_granularity =
+                gcd(greatest_common_granularity, block_granularity as i32);
         }
     }
     let coord_scale = 1_000_000_000 / greatest_common_granularity;
@@ -86,92 +170,85 @@ pub fn convert(manifest: &Manifest) -> Result<osmflat::Osm, Error> {
         "Greatest common granularity: {}, Coordinate scaling factor: {}",
         greatest_common_granularity, coord_scale
     );
-
-    // TODO: move out into a function
-    let groups = block_index.into_iter().group_by(|b| b.block_type);
-    let mut pbf_header = Vec::new();
-    let mut pbf_dense_nodes = Vec::new();
-    let mut pbf_ways = Vec::new();
-    let mut pbf_relations = Vec::new();
-    for (block_type, blocks) in &groups {
-        match block_type {
-            BlockType::Header => pbf_header = blocks.collect(),
-            BlockType::Nodes => panic!("Found nodes block, only dense nodes are supported now"),
-            BlockType::DenseNodes => pbf_dense_nodes = blocks.collect(),
-            BlockType::Ways => pbf_ways = blocks.collect(),
-            BlockType::Relations => pbf_relations = blocks.collect(),
-        }
-    }
     println!("PBF block index built.");
 
     // Serialize header
-    if pbf_header.len() != 1 {
-        return Err(format!(
-            "Require exactly one header block, but found {}",
-            pbf_header.len()
-        )
-        .into());
+    let mut headers = Vec::with_capacity(sources.len());
+    for source in &sources {
+        if source.header.len() != 1 {
+            return Err(format!(
+                "Require exactly one header block, but found {}",
+                source.header.len()
+            )
+            .into());
+        }
+        let header: osmpbf::HeaderBlock = read_block(source.data, &source.header[0])?;
+        headers.push(header);
     }
-    let idx = &pbf_header[0];
-    let pbf_header: osmpbf::HeaderBlock = read_block(&input_data, idx)?;
-    serialize_header(&pbf_header, coord_scale, &builder, &mut stringtable)?;
+    let mut pbf_header = merge::merge_headers(headers);
+    if let Some(area) = clip_area {
+        pbf_header.bbox = Some(area.header_bbox());
+    }
+    serialize_header(&pbf_header, coord_scale, builder, stringtable)?;
     println!("Header written.");
 
+    let selection = Selection {
+        duplicates: if sources.len() > 1 {
+            Duplicates::find(&sources)?
+        } else {
+            Duplicates::default()
+        },
+        clip: clip_area
+            .map(|area| Clip::select(area, &sources))
+            .transpose()?,
+    };
+
     let mut stats = Stats::default();
 
     let hilbert_node_pairs = builder.start_hilbert_node_pairs()?;
 
-    let nodes_id_to_idx = serialize_dense_node_blocks(
-        &builder,
+    let nodes_id_to_idx = serialize_node_blocks(
+        builder,
         greatest_common_granularity,
         hilbert_node_pairs,
-        pbf_dense_nodes,
-        &input_data,
-        &mut tags,
-        &mut stringtable,
+        &sources,
+        &selection,
+        tags,
+        stringtable,
+        include_metadata,
         &mut stats,
     )?;
 
     let ways_id_to_idx = serialize_way_blocks(
-        &builder,
-        pbf_ways,
-        &input_data,
+        builder,
+        &sources,
+        &selection,
         &nodes_id_to_idx,
-        &mut tags,
-        &mut stringtable,
+        tags,
+        stringtable,
+        include_metadata,
+        dangling_ways,
+        dangling,
         &mut stats,
     )?;
 
     serialize_relation_blocks(
-        &builder,
-        pbf_relations,
-        &input_data,
+        builder,
+        &sources,
+        &selection,
         &nodes_id_to_idx,
         &ways_id_to_idx,
-        &mut tags,
-        &mut stringtable,
+        tags,
+        stringtable,
+        include_metadata,
+        dangling,
         &mut stats,
     )?;
 
-    // Finalize data structures
-    tags.close(); // drop the reference to stringtable
-
-    println!("Writing stringtable to disk...");
-    builder.set_stringtable(&stringtable.into_bytes())?;
-
-    std::mem::drop(builder);
-    let flatdata = osmflat::Osm::open(storage)?;
-
-    println!(
-        "Conversion from osm.pbf to osm.flatdata is complete. {}",
-        humantime::format_duration(time.elapsed())
-    );
-    println!("{}", stats);
-
-    Ok(flatdata)
+    Ok(stats)
 }
 
-fn serialize_header(
+pub fn serialize_header(
     header_block: &osmpbf::HeaderBlock,
     coord_scale: i32,
     builder: &osmflat::OsmBuilder,
@@ -188,10 +265,10 @@ fn serialize_header(
         header.set_bbox_bottom((bbox.bottom / (1000000000 / coord_scale) as i64) as i32);
     };
 
-    header.set_writingprogram_idx(stringtable.insert("osmflatc"));
+    header.set_writingprogram_idx(stringtable.insert("osmflatc")?);
 
     if let Some(ref source) = header_block.source {
-        header.set_source_idx(stringtable.insert(source));
+        header.set_source_idx(stringtable.insert(source)?);
     }
 
     if let Some(timestamp) = header_block.osmosis_replication_timestamp {
@@ -203,7 +280,7 @@ fn serialize_header(
     }
 
     if let Some(ref url) = header_block.osmosis_replication_base_url {
-        header.set_replication_base_url_idx(stringtable.insert(url));
+        header.set_replication_base_url_idx(stringtable.insert(url)?);
     }
 
     builder.set_header(&header)?;
@@ -245,22 +322,38 @@ impl std::hash::Hash for I40 {
 }
 
 /// Holds tags external vector and deduplicates tags.
-struct TagSerializer<'a> {
+pub struct TagSerializer<'a> {
     tags: flatdata::ExternalVector<'a, osmflat::Tag>,
     tags_index: flatdata::ExternalVector<'a, osmflat::TagIndex>,
     dedup: AHashMap<(I40, I40), I40>, // deduplication table: (key_idx, val_idx) -> pos
+    filter: TagFilter,
 }
 
 impl<'a> TagSerializer<'a> {
-    fn new(builder: &'a osmflat::OsmBuilder) -> io::Result<Self> {
+    pub fn new(builder: &'a osmflat::OsmBuilder) -> io::Result<Self> {
         Ok(Self {
             tags: builder.start_tags()?,
             tags_index: builder.start_tags_index()?,
             dedup: AHashMap::new(),
+            filter: TagFilter::default(),
         })
     }
 
-    fn serialize(&mut self, key_idx: u64, val_idx: u64) -> Result<(), Error> {
+    /// Leaves out the tags the filter drops. The callers check the keys before
+    /// serializing, since they only have the string indexes here.
+    pub fn with_filter(self, filter: TagFilter) -> Self {
+        Self { filter, ..self }
+    }
+
+    pub fn keeps(&self, key: &str) -> bool {
+        self.filter.keeps(key)
+    }
+
+    pub fn dropped_keys(&self, stringtable: &osmpbf::StringTable) -> Vec<bool> {
+        self.filter.dropped_keys(stringtable)
+    }
+
+    pub fn serialize(&mut self, key_idx: u64, val_idx: u64) -> Result<(), Error> {
         let idx = match self
             .dedup
             .entry((I40::from_u64(key_idx), I40::from_u64(val_idx)))
@@ -282,11 +375,11 @@ impl<'a> TagSerializer<'a> {
         Ok(())
     }
 
-    fn next_index(&self) -> u64 {
+    pub fn next_index(&self) -> u64 {
         self.tags_index.len() as u64
     }
 
-    fn close(self) {
+    pub fn close(self) {
         if let Err(e) = self.tags.close() {
             panic!("failed to close tags: {}", e);
         }
@@ -296,20 +389,98 @@ impl<'a> TagSerializer<'a> {
     }
 }
 
-/// adds all strings in a table to the lookup and returns a vectors of
-/// references to be used instead
-fn add_string_table(
-    pbf_stringtable: &osmpbf::StringTable,
-    stringtable: &mut StringTable,
-) -> Result<Vec<u64>, Error> {
-    let mut result = Vec::with_capacity(pbf_stringtable.s.len());
-    for x in &pbf_stringtable.s {
-        let string = str::from_utf8(x)?;
-        result.push(stringtable.insert(string));
+/// Holds one of the optional metadata external vectors, which runs parallel to the
+/// nodes, ways or relations. Without `include_metadata` in the manifest, nothing is written.
+pub struct MetadataSerializer<'a> {
+    metadata: Option<flatdata::ExternalVector<'a, osmflat::Metadata>>,
+}
+
+impl<'a> MetadataSerializer<'a> {
+    pub fn new(metadata: Option<flatdata::ExternalVector<'a, osmflat::Metadata>>) -> Self {
+        Self { metadata }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.metadata.is_some()
+    }
+
+    pub fn serialize(
+        &mut self,
+        version: i32,
+        timestamp: i64,
+        changeset: i64,
+        user_idx: u64,
+    ) -> io::Result<()> {
+        if let Some(metadata) = self.metadata.as_mut() {
+            let m = metadata.grow()?;
+            m.set_version(version);
+            m.set_timestamp(timestamp);
+            m.set_changeset(changeset);
+            m.set_user_idx(user_idx);
+        }
+        Ok(())
+    }
+
+    /// Entities without metadata still get an entry, so that the vector stays parallel.
+    pub fn serialize_pbf_info(
+        &mut self,
+        info: Option<&osmpbf::Info>,
+        date_granularity: i64,
+        string_refs: &mut StringRefs,
+    ) -> Result<(), Error> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        match info {
+            Some(info) => self.serialize(
+                info.version.unwrap_or(0),
+                info.timestamp.unwrap_or(0) * date_granularity / 1000,
+                info.changeset.unwrap_or(0),
+                string_refs.get(info.user_sid.unwrap_or(0) as usize)?,
+            )?,
+            None => self.serialize(0, 0, 0, string_refs.get(0)?)?,
+        }
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<Option<&'a [osmflat::Metadata]>, Error> {
+        match self.metadata {
+            Some(metadata) => Ok(Some(metadata.close()?)),
+            None => Ok(None),
+        }
     }
-    Ok(result)
 }
 
+/// References into the stringtable for the strings of a block. A string is only added
+/// to the stringtable when it is first used, so that the strings of dropped tags and of
+/// entities that are not kept never make it into the planet.
+pub struct StringRefs<'a> {
+    pbf_stringtable: &'a osmpbf::StringTable,
+    stringtable: &'a mut StringTable,
+    refs: Vec<Option<u64>>,
+}
+
+impl<'a> StringRefs<'a> {
+    fn new(pbf_stringtable: &'a osmpbf::StringTable, stringtable: &'a mut StringTable) -> Self {
+        Self {
+            pbf_stringtable,
+            stringtable,
+            refs: vec![None; pbf_stringtable.s.len()],
+        }
+    }
+
+    fn get(&mut self, sid: usize) -> Result<u64, Error> {
+        if let Some(idx) = self.refs[sid] {
+            return Ok(idx);
+        }
+        let string = str::from_utf8(&self.pbf_stringtable.s[sid])?;
+        let idx = self.stringtable.insert(string)?;
+        self.refs[sid] = Some(idx);
+        Ok(idx)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn serialize_dense_nodes(
     block: &osmpbf::PrimitiveBlock,
     granularity: i32,
@@ -318,9 +489,12 @@ fn serialize_dense_nodes(
     hilbert_node_pairs: &mut flatdata::ExternalVector<osmflat::HilbertNodePair>,
     stringtable: &mut StringTable,
     tags: &mut TagSerializer,
+    metadata: &mut MetadataSerializer,
+    filter: SourceFilter,
 ) -> Result<Stats, Error> {
     let mut stats = Stats::default();
-    let string_refs = add_string_table(&block.stringtable, stringtable)?;
+    let mut string_refs = StringRefs::new(&block.stringtable, stringtable);
+    let dropped_keys = tags.dropped_keys(&block.stringtable);
     for group in block.primitivegroup.iter() {
         let dense_nodes = group.dense.as_ref().unwrap();
 
@@ -332,33 +506,65 @@ fn serialize_dense_nodes(
 
         let mut tags_offset = 0;
 
+        // Everything but the version is delta coded.
+        let date_granularity = i64::from(block.date_granularity.unwrap_or(1000));
+        let dense_info = dense_nodes.denseinfo.as_ref();
+        let mut timestamp = 0;
+        let mut changeset = 0;
+        let mut user_sid = 0;
+
         let mut id = 0;
         for i in 0..dense_nodes.id.len() {
+            // Skipped nodes still have to be decoded, since the following ones are delta coded.
             id += dense_nodes.id[i];
-
-            let index = nodes_id_to_idx.insert(id as u64);
-            assert_eq!(index as usize, nodes.len());
-
-            let node = nodes.grow()?;
-            node.set_osm_id(id);
-
             lat += dense_nodes.lat[i];
             lon += dense_nodes.lon[i];
-            let lat_dm7 =
-                ((lat_offset + (i64::from(pbf_granularity) * lat)) / granularity as i64) as i32;
-            let lon_dm7 =
-                ((lon_offset + (i64::from(pbf_granularity) * lon)) / granularity as i64) as i32;
-            node.set_lat(lat_dm7);
-            node.set_lon(lon_dm7);
-
-            let h = location::lonlat_to_h((lon_dm7, lat_dm7));
+            let info = dense_info.filter(|info| i < info.version.len());
+            if let Some(info) = info {
+                timestamp += info.timestamp[i];
+                changeset += info.changeset[i];
+                user_sid += info.user_sid[i];
+            }
+            let keep = filter.keeps(EntityType::Node, id);
+
+            if keep {
+                let index = nodes_id_to_idx.insert(id as u64);
+                assert_eq!(index as usize, nodes.len());
+
+                let node = nodes.grow()?;
+                node.set_osm_id(id);
+
+                let lat_dm7 =
+                    ((lat_offset + (i64::from(pbf_granularity) * lat)) / granularity as i64) as i32;
+                let lon_dm7 =
+                    ((lon_offset + (i64::from(pbf_granularity) * lon)) / granularity as i64) as i32;
+                node.set_lat(lat_dm7);
+                node.set_lon(lon_dm7);
+
+                let h = location::lonlat_to_h((lon_dm7, lat_dm7));
+
+                let pair = hilbert_node_pairs.grow()?;
+                pair.set_i(index);
+                pair.set_h(h);
+
+                match info.filter(|_| metadata.is_enabled()) {
+                    Some(info) => metadata.serialize(
+                        info.version[i],
+                        timestamp * date_granularity / 1000,
+                        changeset,
+                        string_refs.get(user_sid as usize)?,
+                    )?,
+                    None => {
+                        metadata.serialize_pbf_info(None, date_granularity, &mut string_refs)?
+                    }
+                }
 
-            let pair = hilbert_node_pairs.grow()?;
-            pair.set_i(index);
-            pair.set_h(h);
+                // Set even without tags, so that the tag range of a preceding plain node is closed.
+                node.set_tag_first_idx(tags.next_index());
+                stats.num_nodes += 1;
+            }
 
             if tags_offset < dense_nodes.keys_vals.len() {
-                node.set_tag_first_idx(tags.next_index());
                 loop {
                     let k = dense_nodes.keys_vals[tags_offset];
                     tags_offset += 1;
@@ -370,12 +576,92 @@ fn serialize_dense_nodes(
                     let v = dense_nodes.keys_vals[tags_offset];
                     tags_offset += 1;
 
-                    tags.serialize(string_refs[k as usize], string_refs[v as usize])?;
+                    if !keep {
+                        continue;
+                    }
+                    if dropped_keys[k as usize] {
+                        stats.num_dropped_tags += 1;
+                        continue;
+                    }
+                    tags.serialize(string_refs.get(k as usize)?, string_refs.get(v as usize)?)?;
                 }
             }
         }
         assert_eq!(tags_offset, dense_nodes.keys_vals.len());
-        stats.num_nodes += dense_nodes.id.len();
+    }
+    Ok(stats)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn serialize_nodes(
+    block: &osmpbf::PrimitiveBlock,
+    granularity: i32,
+    nodes: &mut flatdata::ExternalVector<osmflat::Node>,
+    nodes_id_to_idx: &mut ids::IdTableBuilder,
+    hilbert_node_pairs: &mut flatdata::ExternalVector<osmflat::HilbertNodePair>,
+    stringtable: &mut StringTable,
+    tags: &mut TagSerializer,
+    metadata: &mut MetadataSerializer,
+    filter: SourceFilter,
+) -> Result<Stats, Error> {
+    let mut stats = Stats::default();
+    let mut string_refs = StringRefs::new(&block.stringtable, stringtable);
+    let dropped_keys = tags.dropped_keys(&block.stringtable);
+    let date_granularity = i64::from(block.date_granularity.unwrap_or(1000));
+
+    let pbf_granularity = block.granularity.unwrap_or(100);
+    let lat_offset = block.lat_offset.unwrap_or(0);
+    let lon_offset = block.lon_offset.unwrap_or(0);
+
+    for group in &block.primitivegroup {
+        for pbf_node in &group.nodes {
+            if !filter.keeps(EntityType::Node, pbf_node.id) {
+                continue;
+            }
+            let index = nodes_id_to_idx.insert(pbf_node.id as u64);
+            assert_eq!(index as usize, nodes.len());
+
+            let node = nodes.grow()?;
+            node.set_osm_id(pbf_node.id);
+
+            // Unlike dense nodes, the coordinates of plain nodes are not delta coded.
+            let lat_dm7 = ((lat_offset + (i64::from(pbf_granularity) * pbf_node.lat))
+                / granularity as i64) as i32;
+            let lon_dm7 = ((lon_offset + (i64::from(pbf_granularity) * pbf_node.lon))
+                / granularity as i64) as i32;
+            node.set_lat(lat_dm7);
+            node.set_lon(lon_dm7);
+
+            let h = location::lonlat_to_h((lon_dm7, lat_dm7));
+
+            let pair = hilbert_node_pairs.grow()?;
+            pair.set_i(index);
+            pair.set_h(h);
+
+            metadata.serialize_pbf_info(
+                pbf_node.info.as_ref(),
+                date_granularity,
+                &mut string_refs,
+            )?;
+
+            debug_assert_eq!(
+                pbf_node.keys.len(),
+                pbf_node.vals.len(),
+                "invalid input data"
+            );
+            node.set_tag_first_idx(tags.next_index());
+            for i in 0..pbf_node.keys.len() {
+                if dropped_keys[pbf_node.keys[i] as usize] {
+                    stats.num_dropped_tags += 1;
+                    continue;
+                }
+                tags.serialize(
+                    string_refs.get(pbf_node.keys[i] as usize)?,
+                    string_refs.get(pbf_node.vals[i] as usize)?,
+                )?;
+            }
+            stats.num_nodes += 1;
+        }
     }
     Ok(stats)
 }
@@ -383,22 +669,30 @@ fn serialize_dense_nodes(
 fn resolve_ways(
     block: &osmpbf::PrimitiveBlock,
     nodes_id_to_idx: &ids::IdTable,
-) -> (Vec<Option<u64>>, Stats) {
+    filter: SourceFilter,
+) -> (Vec<Option<u64>>, Stats, Dangling) {
     let mut result = Vec::new();
     let mut stats = Stats::default();
+    let mut dangling = Dangling::default();
     for group in &block.primitivegroup {
         for pbf_way in &group.ways {
+            if !filter.keeps(EntityType::Way, pbf_way.id) {
+                continue;
+            }
             let mut node_ref = 0;
             for delta in &pbf_way.refs {
                 node_ref += delta;
                 let idx = nodes_id_to_idx.get(node_ref as u64);
-                stats.num_unresolved_node_ids += idx.is_none() as usize;
+                if idx.is_none() {
+                    stats.num_unresolved_node_ids += 1;
+                    dangling.way_ref(pbf_way.id, node_ref);
+                }
 
                 result.push(idx);
             }
         }
     }
-    (result, stats)
+    (result, stats, dangling)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -410,59 +704,108 @@ fn serialize_ways(
     stringtable: &mut StringTable,
     tags: &mut TagSerializer,
     nodes_index: &mut flatdata::ExternalVector<osmflat::NodeIndex>,
+    metadata: &mut MetadataSerializer,
+    filter: SourceFilter,
+    dangling_ways: DanglingWays,
+    dangling: &mut Dangling,
 ) -> Result<Stats, Error> {
     let mut stats = Stats::default();
-    let string_refs = add_string_table(&block.stringtable, stringtable)?;
+    let mut string_refs = StringRefs::new(&block.stringtable, stringtable);
+    let dropped_keys = tags.dropped_keys(&block.stringtable);
+    let date_granularity = i64::from(block.date_granularity.unwrap_or(1000));
     let mut nodes_idx = nodes_id_to_idx.iter().cloned();
     for group in &block.primitivegroup {
         for pbf_way in &group.ways {
+            if !filter.keeps(EntityType::Way, pbf_way.id) {
+                continue;
+            }
+            let mut refs: Vec<Option<u64>> = nodes_idx.by_ref().take(pbf_way.refs.len()).collect();
+            let num_missing = refs.iter().filter(|idx| idx.is_none()).count();
+            if num_missing > 0 {
+                let truncate = match dangling_ways {
+                    DanglingWays::Keep => false,
+                    // A way needs at least two nodes for a geometry.
+                    DanglingWays::Truncate => refs.len() - num_missing >= 2,
+                    DanglingWays::Drop => {
+                        stats.num_dropped_ways += 1;
+                        dangling.dropped_ways.push(pbf_way.id);
+                        continue;
+                    }
+                };
+                if truncate {
+                    refs.retain(|idx| idx.is_some());
+                    stats.num_truncated_ways += 1;
+                    dangling.truncated_ways.push(pbf_way.id);
+                } else if dangling_ways == DanglingWays::Truncate {
+                    stats.num_dropped_ways += 1;
+                    dangling.dropped_ways.push(pbf_way.id);
+                    continue;
+                }
+            }
+
             let index = ways_id_to_idx.insert(pbf_way.id as u64);
             assert_eq!(index as usize, ways.len());
 
             let way = ways.grow()?;
 
             way.set_osm_id(pbf_way.id);
+            metadata.serialize_pbf_info(
+                pbf_way.info.as_ref(),
+                date_granularity,
+                &mut string_refs,
+            )?;
 
             debug_assert_eq!(pbf_way.keys.len(), pbf_way.vals.len(), "invalid input data");
             way.set_tag_first_idx(tags.next_index());
 
             for i in 0..pbf_way.keys.len() {
+                if dropped_keys[pbf_way.keys[i] as usize] {
+                    stats.num_dropped_tags += 1;
+                    continue;
+                }
                 tags.serialize(
-                    string_refs[pbf_way.keys[i] as usize],
-                    string_refs[pbf_way.vals[i] as usize],
+                    string_refs.get(pbf_way.keys[i] as usize)?,
+                    string_refs.get(pbf_way.vals[i] as usize)?,
                 )?;
             }
 
             way.set_ref_first_idx(nodes_index.len() as u64);
-            for _ in &pbf_way.refs {
-                nodes_index.grow()?.set_value(nodes_idx.next().unwrap());
+            for idx in refs {
+                nodes_index.grow()?.set_value(idx);
             }
+            stats.num_ways += 1;
         }
-        stats.num_ways += group.ways.len();
     }
     Ok(stats)
 }
 
-fn build_relations_index<I>(data: &[u8], block_index: I) -> Result<ids::IdTable, Error>
-where
-    I: ExactSizeIterator<Item = BlockIndex> + Send + 'static,
-{
+fn build_relations_index(
+    sources: &[PbfSource],
+    selection: &Selection,
+) -> Result<ids::IdTable, Error> {
     let mut result = ids::IdTableBuilder::new();
-    let mut pb = ProgressBar::new(block_index.len() as u64);
+    let num_blocks: usize = sources.iter().map(|s| s.relations.len()).sum();
+    let mut pb = ProgressBar::new(num_blocks as u64);
     pb.message("Building relations index...");
-    parallel::parallel_process(
-        block_index,
-        |idx| read_block(data, &idx),
-        |block: Result<osmpbf::PrimitiveBlock, _>| -> Result<(), Error> {
-            for group in &block?.primitivegroup {
-                for relation in &group.relations {
-                    result.insert(relation.id as u64);
+    for (i, source) in sources.iter().enumerate() {
+        let filter = selection.filter(i);
+        result.next_segment();
+        parallel::parallel_process(
+            source.relations.iter().cloned(),
+            |idx| read_block(source.data, &idx),
+            |block: Result<osmpbf::PrimitiveBlock, _>| -> Result<(), Error> {
+                for group in &block?.primitivegroup {
+                    for relation in &group.relations {
+                        if filter.keeps(EntityType::Relation, relation.id) {
+                            result.insert(relation.id as u64);
+                        }
+                    }
                 }
-            }
-            pb.inc();
-            Ok(())
-        },
-    )?;
+                pb.inc();
+                Ok(())
+            },
+        )?;
+    }
 
     Ok(result.build())
 }
@@ -477,13 +820,26 @@ fn serialize_relations(
     relations: &mut flatdata::ExternalVector<osmflat::Relation>,
     members: &mut flatdata::ExternalVector<osmflat::Member>,
     tags: &mut TagSerializer,
+    metadata: &mut MetadataSerializer,
+    filter: SourceFilter,
+    dangling: &mut Dangling,
 ) -> Result<Stats, Error> {
     let mut stats = Stats::default();
-    let string_refs = add_string_table(&block.stringtable, stringtable)?;
+    let mut string_refs = StringRefs::new(&block.stringtable, stringtable);
+    let dropped_keys = tags.dropped_keys(&block.stringtable);
+    let date_granularity = i64::from(block.date_granularity.unwrap_or(1000));
     for group in &block.primitivegroup {
         for pbf_relation in &group.relations {
+            if !filter.keeps(EntityType::Relation, pbf_relation.id) {
+                continue;
+            }
             let relation = relations.grow()?;
             relation.set_osm_id(pbf_relation.id);
+            metadata.serialize_pbf_info(
+                pbf_relation.info.as_ref(),
+                date_granularity,
+                &mut string_refs,
+            )?;
 
             debug_assert_eq!(
                 pbf_relation.keys.len(),
@@ -492,9 +848,13 @@ fn serialize_relations(
             );
             relation.set_tag_first_idx(tags.next_index());
             for i in 0..pbf_relation.keys.len() {
+                if dropped_keys[pbf_relation.keys[i] as usize] {
+                    stats.num_dropped_tags += 1;
+                    continue;
+                }
                 tags.serialize(
-                    string_refs[pbf_relation.keys[i] as usize],
-                    string_refs[pbf_relation.vals[i] as usize],
+                    string_refs.get(pbf_relation.keys[i] as usize)?,
+                    string_refs.get(pbf_relation.vals[i] as usize)?,
                 )?;
             }
 
@@ -517,27 +877,36 @@ fn serialize_relations(
                 match member_type.unwrap() {
                     osmpbf::relation::MemberType::Node => {
                         let idx = nodes_id_to_idx.get(memid as u64);
-                        stats.num_unresolved_node_ids = idx.is_none() as usize;
+                        if idx.is_none() {
+                            stats.num_unresolved_node_ids += 1;
+                            dangling.member(pbf_relation.id, &EntityType::Node, memid);
+                        }
 
                         member.set_entity_type(EntityType::Node);
                         member.set_idx(idx);
-                        member.set_role_idx(string_refs[pbf_relation.roles_sid[i] as usize]);
+                        member.set_role_idx(string_refs.get(pbf_relation.roles_sid[i] as usize)?);
                     }
                     osmpbf::relation::MemberType::Way => {
                         let idx = ways_id_to_idx.get(memid as u64);
-                        stats.num_unresolved_way_ids = idx.is_none() as usize;
+                        if idx.is_none() {
+                            stats.num_unresolved_way_ids += 1;
+                            dangling.member(pbf_relation.id, &EntityType::Way, memid);
+                        }
 
                         member.set_entity_type(EntityType::Way);
                         member.set_idx(idx);
-                        member.set_role_idx(string_refs[pbf_relation.roles_sid[i] as usize]);
+                        member.set_role_idx(string_refs.get(pbf_relation.roles_sid[i] as usize)?);
                     }
                     osmpbf::relation::MemberType::Relation => {
                         let idx = relations_id_to_idx.get(memid as u64);
-                        stats.num_unresolved_rel_ids = idx.is_none() as usize;
+                        if idx.is_none() {
+                            stats.num_unresolved_rel_ids += 1;
+                            dangling.member(pbf_relation.id, &EntityType::Relation, memid);
+                        }
 
                         member.set_entity_type(EntityType::Relation);
                         member.set_idx(idx);
-                        member.set_role_idx(string_refs[pbf_relation.roles_sid[i] as usize]);
+                        member.set_role_idx(string_refs.get(pbf_relation.roles_sid[i] as usize)?);
                     }
                 }
             }
@@ -548,97 +917,133 @@ fn serialize_relations(
 }
 
 #[allow(clippy::too_many_arguments)]
-fn serialize_dense_node_blocks(
+fn serialize_node_blocks(
     builder: &osmflat::OsmBuilder,
     granularity: i32,
     mut hilbert_node_pairs: flatdata::ExternalVector<osmflat::HilbertNodePair>,
-    blocks: Vec<BlockIndex>,
-    data: &[u8],
+    sources: &[PbfSource],
+    selection: &Selection,
     tags: &mut TagSerializer,
     stringtable: &mut StringTable,
+    include_metadata: bool,
     stats: &mut Stats,
 ) -> Result<ids::IdTable, Error> {
     let mut nodes_id_to_idx = ids::IdTableBuilder::new();
     let mut nodes = builder.start_nodes()?;
-    let mut pb = ProgressBar::new(blocks.len() as u64);
-    pb.message("Converting dense nodes...");
+    let mut metadata = MetadataSerializer::new(
+        include_metadata
+            .then(|| builder.start_node_metadata())
+            .transpose()?,
+    );
+    let num_blocks: usize = sources.iter().map(|s| s.nodes.len()).sum();
+    let mut pb = ProgressBar::new(num_blocks as u64);
+    pb.message("Converting nodes...");
     let t = Instant::now();
 
-    parallel::parallel_process(
-        blocks.into_iter(),
-        |idx| read_block(data, &idx),
-        |block| -> Result<osmpbf::PrimitiveBlock, Error> {
-            let block = block?;
-            *stats += serialize_dense_nodes(
-                &block,
-                granularity,
-                &mut nodes,
-                &mut nodes_id_to_idx,
-                &mut hilbert_node_pairs,
-                stringtable,
-                tags,
-            )?;
+    for (i, source) in sources.iter().enumerate() {
+        let filter = selection.filter(i);
+        nodes_id_to_idx.next_segment();
+        parallel::parallel_process(
+            source.nodes.iter().cloned(),
+            |idx| (idx.block_type, read_block(source.data, &idx)),
+            |(block_type, block)| -> Result<osmpbf::PrimitiveBlock, Error> {
+                let block = block?;
+                let serialize = match block_type {
+                    BlockType::Nodes => serialize_nodes,
+                    _ => serialize_dense_nodes,
+                };
+                *stats += serialize(
+                    &block,
+                    granularity,
+                    &mut nodes,
+                    &mut nodes_id_to_idx,
+                    &mut hilbert_node_pairs,
+                    stringtable,
+                    tags,
+                    &mut metadata,
+                    filter,
+                )?;
 
-            pb.inc();
-            Ok(block)
-        },
-    )?;
+                pb.inc();
+                Ok(block)
+            },
+        )?;
+    }
 
     // fill tag_first_idx of the sentry, since it contains the end of the tag range
     // of the last node
     nodes.grow()?.set_tag_first_idx(tags.next_index());
     nodes.close()?;
     hilbert_node_pairs.close()?;
+    metadata.close()?;
 
-    println!("Dense nodes converted in {} secs.", t.elapsed().as_secs());
-    println!("Building dense nodes index...");
+    println!("Nodes converted in {} secs.", t.elapsed().as_secs());
+    println!("Building nodes index...");
     let nodes_id_to_idx = nodes_id_to_idx.build();
-    println!("Dense nodes index built.");
+    println!("Nodes index built.");
     Ok(nodes_id_to_idx)
 }
 
-type PrimitiveBlockWithIds = (osmpbf::PrimitiveBlock, (Vec<Option<u64>>, Stats));
+type PrimitiveBlockWithIds = (osmpbf::PrimitiveBlock, (Vec<Option<u64>>, Stats, Dangling));
 
 #[allow(clippy::too_many_arguments)]
 fn serialize_way_blocks(
     builder: &osmflat::OsmBuilder,
-    blocks: Vec<BlockIndex>,
-    data: &[u8],
+    sources: &[PbfSource],
+    selection: &Selection,
     nodes_id_to_idx: &ids::IdTable,
     tags: &mut TagSerializer,
     stringtable: &mut StringTable,
+    include_metadata: bool,
+    dangling_ways: DanglingWays,
+    dangling: &mut Dangling,
     stats: &mut Stats,
 ) -> Result<ids::IdTable, Error> {
     let mut ways_id_to_idx = ids::IdTableBuilder::new();
     let mut ways = builder.start_ways()?;
-    let mut pb = ProgressBar::new(blocks.len() as u64);
+    let mut metadata = MetadataSerializer::new(
+        include_metadata
+            .then(|| builder.start_way_metadata())
+            .transpose()?,
+    );
+    let num_blocks: usize = sources.iter().map(|s| s.ways.len()).sum();
+    let mut pb = ProgressBar::new(num_blocks as u64);
     let mut nodes_index = builder.start_nodes_index()?;
     pb.message("Converting ways...");
     let t = Instant::now();
-    parallel::parallel_process(
-        blocks.into_iter(),
-        |idx| {
-            let block: osmpbf::PrimitiveBlock = read_block(data, &idx)?;
-            let ids = resolve_ways(&block, nodes_id_to_idx);
-            Ok((block, ids))
-        },
-        |block: io::Result<PrimitiveBlockWithIds>| -> Result<osmpbf::PrimitiveBlock, Error> {
-            let (block, (ids, stats_resolve)) = block?;
-            *stats += stats_resolve;
-            *stats += serialize_ways(
-                &block,
-                &ids,
-                &mut ways,
-                &mut ways_id_to_idx,
-                stringtable,
-                tags,
-                &mut nodes_index,
-            )?;
-            pb.inc();
+    for (i, source) in sources.iter().enumerate() {
+        let filter = selection.filter(i);
+        ways_id_to_idx.next_segment();
+        parallel::parallel_process(
+            source.ways.iter().cloned(),
+            |idx| {
+                let block: osmpbf::PrimitiveBlock = read_block(source.data, &idx)?;
+                let ids = resolve_ways(&block, nodes_id_to_idx, filter);
+                Ok((block, ids))
+            },
+            |block: io::Result<PrimitiveBlockWithIds>| -> Result<osmpbf::PrimitiveBlock, Error> {
+                let (block, (ids, stats_resolve, dangling_resolve)) = block?;
+                *stats += stats_resolve;
+                dangling.append(dangling_resolve);
+                *stats += serialize_ways(
+                    &block,
+                    &ids,
+                    &mut ways,
+                    &mut ways_id_to_idx,
+                    stringtable,
+                    tags,
+                    &mut nodes_index,
+                    &mut metadata,
+                    filter,
+                    dangling_ways,
+                    dangling,
+                )?;
+                pb.inc();
 
-            Ok(block)
-        },
-    )?;
+                Ok(block)
+            },
+        )?;
+    }
 
     {
         let sentinel = ways.grow()?;
@@ -647,6 +1052,7 @@ fn serialize_way_blocks(
     }
     ways.close()?;
     nodes_index.close()?;
+    metadata.close()?;
 
     println!("Ways converted in {} secs", t.elapsed().as_secs());
     println!("Building ways index...");
@@ -658,44 +1064,58 @@ fn serialize_way_blocks(
 #[allow(clippy::too_many_arguments)]
 fn serialize_relation_blocks(
     builder: &osmflat::OsmBuilder,
-    blocks: Vec<BlockIndex>,
-    data: &[u8],
+    sources: &[PbfSource],
+    selection: &Selection,
     nodes_id_to_idx: &ids::IdTable,
     ways_id_to_idx: &ids::IdTable,
     tags: &mut TagSerializer,
     stringtable: &mut StringTable,
+    include_metadata: bool,
+    dangling: &mut Dangling,
     stats: &mut Stats,
 ) -> Result<(), Error> {
     // We need to build the index of relation ids first, since relations can refer
     // again to relations.
-    let relations_id_to_idx = build_relations_index(data, blocks.clone().into_iter())?;
+    let relations_id_to_idx = build_relations_index(sources, selection)?;
 
     let mut relations = builder.start_relations()?;
     let mut members = builder.start_members()?;
+    let mut metadata = MetadataSerializer::new(
+        include_metadata
+            .then(|| builder.start_relation_metadata())
+            .transpose()?,
+    );
 
-    let mut pb = ProgressBar::new(blocks.len() as u64);
+    let num_blocks: usize = sources.iter().map(|s| s.relations.len()).sum();
+    let mut pb = ProgressBar::new(num_blocks as u64);
     pb.message("Converting relations...");
     let t = Instant::now();
 
-    parallel::parallel_process(
-        blocks.into_iter(),
-        |idx| read_block(data, &idx),
-        |block| -> Result<osmpbf::PrimitiveBlock, Error> {
-            let block = block?;
-            *stats += serialize_relations(
-                &block,
-                nodes_id_to_idx,
-                ways_id_to_idx,
-                &relations_id_to_idx,
-                stringtable,
-                &mut relations,
-                &mut members,
-                tags,
-            )?;
-            pb.inc();
-            Ok(block)
-        },
-    )?;
+    for (i, source) in sources.iter().enumerate() {
+        let filter = selection.filter(i);
+        parallel::parallel_process(
+            source.relations.iter().cloned(),
+            |idx| read_block(source.data, &idx),
+            |block| -> Result<osmpbf::PrimitiveBlock, Error> {
+                let block = block?;
+                *stats += serialize_relations(
+                    &block,
+                    nodes_id_to_idx,
+                    ways_id_to_idx,
+                    &relations_id_to_idx,
+                    stringtable,
+                    &mut relations,
+                    &mut members,
+                    tags,
+                    &mut metadata,
+                    filter,
+                    dangling,
+                )?;
+                pb.inc();
+                Ok(block)
+            },
+        )?;
+    }
 
     {
         let sentinel = relations.grow()?;
@@ -705,6 +1125,7 @@ fn serialize_relation_blocks(
 
     relations.close()?;
     members.close()?;
+    metadata.close()?;
 
     println!("Relations converted in {} secs.", t.elapsed().as_secs());
 
@@ -719,3 +1140,372 @@ fn gcd(a: i32, b: i32) -> i32 {
     }
     y
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest;
+    use flatdata::MemoryResourceStorage;
+    use std::path::PathBuf;
+
+    fn convert_fixture(dir: &Path, source: &str, include_metadata: bool) -> osmflat::Osm {
+        let mut manifest = manifest::parse("tests/fixtures/nodes4_convert.yaml").unwrap();
+        manifest.data.source = vec![PathBuf::from(source)];
+        manifest.data.planet = dir.to_path_buf();
+        manifest.data.include_metadata = include_metadata;
+        convert(&manifest).unwrap()
+    }
+
+    fn string_table(strings: &[&str]) -> osmpbf::StringTable {
+        osmpbf::StringTable {
+            s: strings.iter().map(|s| s.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_mixed_node_blocks() {
+        let builder = osmflat::OsmBuilder::new(MemoryResourceStorage::new("/osm")).unwrap();
+        let mut tags = TagSerializer::new(&builder).unwrap();
+        let mut stringtable =
+            StringTable::with_index_budget(&std::env::temp_dir(), 1_000_000).unwrap();
+        let mut nodes = builder.start_nodes().unwrap();
+        let mut hilbert_node_pairs = builder.start_hilbert_node_pairs().unwrap();
+        let mut nodes_id_to_idx = ids::IdTableBuilder::new();
+        let mut metadata = MetadataSerializer::new(Some(builder.start_node_metadata().unwrap()));
+
+        let plain = osmpbf::PrimitiveBlock {
+            stringtable: string_table(&["", "amenity", "cafe", "alice"]),
+            primitivegroup: vec![osmpbf::PrimitiveGroup {
+                nodes: vec![
+                    osmpbf::Node {
+                        id: 1,
+                        keys: vec![1],
+                        vals: vec![2],
+                        info: Some(osmpbf::Info {
+                            version: Some(3),
+                            timestamp: Some(1_667_383_200),
+                            changeset: Some(128_000_000),
+                            user_sid: Some(3),
+                            ..Default::default()
+                        }),
+                        lat: 369_741_710,
+                        lon: -1_220_307_680,
+                    },
+                    osmpbf::Node {
+                        id: 2,
+                        keys: vec![],
+                        vals: vec![],
+                        info: None,
+                        lat: 369_741_720,
+                        lon: -1_220_307_690,
+                    },
+                ],
+                ..Default::default()
+            }],
+            granularity: Some(100),
+            ..Default::default()
+        };
+
+        let dense = osmpbf::PrimitiveBlock {
+            stringtable: string_table(&["", "shop", "bakery", "bob", "carol"]),
+            primitivegroup: vec![osmpbf::PrimitiveGroup {
+                dense: Some(osmpbf::DenseNodes {
+                    id: vec![3, 1],
+                    lat: vec![369_741_730, 10],
+                    lon: vec![-1_220_307_700, -10],
+                    keys_vals: vec![1, 2, 0, 0],
+                    denseinfo: Some(osmpbf::DenseInfo {
+                        version: vec![1, 7],
+                        timestamp: vec![1_667_383_200, 60],
+                        changeset: vec![128_000_001, 1],
+                        user_sid: vec![3, 1],
+                        ..Default::default()
+                    }),
+                }),
+                ..Default::default()
+            }],
+            granularity: Some(100),
+            ..Default::default()
+        };
+
+        let mut stats = Stats::default();
+        stats += serialize_nodes(
+            &plain,
+            100,
+            &mut nodes,
+            &mut nodes_id_to_idx,
+            &mut hilbert_node_pairs,
+            &mut stringtable,
+            &mut tags,
+            &mut metadata,
+            Selection::default().filter(0),
+        )
+        .unwrap();
+        stats += serialize_dense_nodes(
+            &dense,
+            100,
+            &mut nodes,
+            &mut nodes_id_to_idx,
+            &mut hilbert_node_pairs,
+            &mut stringtable,
+            &mut tags,
+            &mut metadata,
+            Selection::default().filter(0),
+        )
+        .unwrap();
+        assert_eq!(stats.num_nodes, 4);
+
+        nodes.grow().unwrap().set_tag_first_idx(tags.next_index());
+        let nodes = nodes.close().unwrap();
+        let pairs = hilbert_node_pairs.close().unwrap();
+        let metadata = metadata.close().unwrap().unwrap();
+        let tags_len = tags.next_index();
+        tags.close();
+
+        // The sentinel is not part of the closed vector.
+        assert_eq!(nodes.len(), 4);
+        assert_eq!(pairs.len(), 4);
+        assert_eq!(tags_len, 2);
+
+        let ids: Vec<i64> = nodes.iter().map(|n| n.osm_id()).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+
+        assert_eq!(nodes[0].lat(), 369_741_710);
+        assert_eq!(nodes[0].lon(), -1_220_307_680);
+        assert_eq!(nodes[3].lat(), 369_741_740);
+        assert_eq!(nodes[3].lon(), -1_220_307_710);
+
+        assert_eq!(nodes[0].tags(), 0..1);
+        assert_eq!(nodes[1].tags(), 1..1);
+        assert_eq!(nodes[2].tags(), 1..2);
+        assert_eq!(nodes[3].tags(), 2..2);
+
+        for (i, pair) in pairs.iter().enumerate() {
+            assert_eq!(pair.i(), i as u64);
+            let node = &nodes[i];
+            assert_eq!(pair.h(), location::lonlat_to_h((node.lon(), node.lat())));
+        }
+
+        let lookup = nodes_id_to_idx.build();
+        assert_eq!(lookup.get(2), Some(1));
+        assert_eq!(lookup.get(4), Some(3));
+
+        let strings = stringtable.into_mmap().unwrap();
+        let user = |m: &osmflat::Metadata| {
+            flatdata::RawData::new(&strings)
+                .substring(m.user_idx() as usize)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(metadata.len(), 4);
+        assert_eq!(metadata[0].version(), 3);
+        assert_eq!(metadata[0].timestamp(), 1_667_383_200);
+        assert_eq!(metadata[0].changeset(), 128_000_000);
+        assert_eq!(user(&metadata[0]), "alice");
+        // Without info, the entry only keeps the vector parallel to the nodes.
+        assert_eq!(metadata[1].version(), 0);
+        assert_eq!(metadata[3].version(), 7);
+        assert_eq!(metadata[3].timestamp(), 1_667_383_260);
+        assert_eq!(metadata[3].changeset(), 128_000_002);
+        assert_eq!(user(&metadata[3]), "carol");
+    }
+
+    #[test]
+    fn test_convert_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let osm = convert_fixture(
+            &dir.path().join("pbf"),
+            "tests/fixtures/nodes4.osm.pbf",
+            true,
+        );
+        let metadata = osm.node_metadata().unwrap();
+        assert_eq!(metadata.len(), osm.nodes().len());
+        let versions: Vec<i32> = metadata.iter().map(|m| m.version()).collect();
+        assert_eq!(versions, vec![1, 2, 3, 4]);
+        let m = &metadata[3];
+        assert_eq!(m.timestamp(), 1496111636);
+        assert_eq!(m.changeset(), 31575771);
+        let user = osm.stringtable().substring(m.user_idx() as usize).unwrap();
+        assert_eq!(user, "TheOutpost");
+        assert!(osm.way_metadata().unwrap().is_empty());
+
+        let osm = convert_fixture(
+            &dir.path().join("xml"),
+            "tests/fixtures/relations.osm",
+            true,
+        );
+        let strings = osm.stringtable();
+        let user = |m: &osmflat::Metadata| strings.substring(m.user_idx() as usize).unwrap();
+        let metadata = osm.node_metadata().unwrap();
+        assert_eq!(metadata.len(), osm.nodes().len());
+        assert_eq!(user(&metadata[0]), "alice");
+        let metadata = osm.way_metadata().unwrap();
+        assert_eq!(metadata.len(), osm.ways().len());
+        assert_eq!(osm.ways()[0].osm_id(), 100);
+        assert_eq!(metadata[0].version(), 3);
+        assert_eq!(metadata[0].timestamp(), 1667300400);
+        let metadata = osm.relation_metadata().unwrap();
+        assert_eq!(metadata.len(), osm.relations().len());
+        assert_eq!(metadata[3].changeset(), 128000004);
+        assert_eq!(user(&metadata[3]), "erin");
+    }
+
+    #[test]
+    fn test_convert_without_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, source) in [
+            ("pbf", "tests/fixtures/nodes4.osm.pbf"),
+            ("xml", "tests/fixtures/relations.osm"),
+        ] {
+            let osm = convert_fixture(&dir.path().join(name), source, false);
+            assert!(osm.node_metadata().is_none());
+            assert!(osm.way_metadata().is_none());
+            assert!(osm.relation_metadata().is_none());
+        }
+    }
+
+    #[test]
+    fn test_dropped_tags_strings() {
+        let builder = osmflat::OsmBuilder::new(MemoryResourceStorage::new("/osm")).unwrap();
+        let filter = TagFilter::new(&["tiger:*".to_string()], &[]);
+        let mut tags = TagSerializer::new(&builder).unwrap().with_filter(filter);
+        let mut stringtable =
+            StringTable::with_index_budget(&std::env::temp_dir(), 1_000_000).unwrap();
+        let mut nodes = builder.start_nodes().unwrap();
+        let mut hilbert_node_pairs = builder.start_hilbert_node_pairs().unwrap();
+        let mut nodes_id_to_idx = ids::IdTableBuilder::new();
+        let mut metadata = MetadataSerializer::new(None);
+
+        let block = osmpbf::PrimitiveBlock {
+            stringtable: string_table(&[
+                "",
+                "amenity",
+                "cafe",
+                "tiger:county",
+                "Santa Cruz, CA",
+                "alice",
+            ]),
+            primitivegroup: vec![osmpbf::PrimitiveGroup {
+                nodes: vec![osmpbf::Node {
+                    id: 1,
+                    keys: vec![1, 3],
+                    vals: vec![2, 4],
+                    info: Some(osmpbf::Info {
+                        user_sid: Some(5),
+                        ..Default::default()
+                    }),
+                    lat: 369_741_710,
+                    lon: -1_220_307_680,
+                }],
+                ..Default::default()
+            }],
+            granularity: Some(100),
+            ..Default::default()
+        };
+
+        let stats = serialize_nodes(
+            &block,
+            100,
+            &mut nodes,
+            &mut nodes_id_to_idx,
+            &mut hilbert_node_pairs,
+            &mut stringtable,
+            &mut tags,
+            &mut metadata,
+            Selection::default().filter(0),
+        )
+        .unwrap();
+        assert_eq!(stats.num_dropped_tags, 1);
+        nodes.grow().unwrap().set_tag_first_idx(tags.next_index());
+        nodes.close().unwrap();
+        hilbert_node_pairs.close().unwrap();
+        tags.close();
+
+        // Neither the dropped tag nor the user of the left out metadata is in the stringtable.
+        let strings = stringtable.into_mmap().unwrap();
+        let strings: Vec<&[u8]> = strings.split(|b| *b == 0).collect();
+        assert!(strings.contains(&&b"amenity"[..]));
+        assert!(strings.contains(&&b"cafe"[..]));
+        assert!(!strings.contains(&&b"tiger:county"[..]));
+        assert!(!strings.contains(&&b"Santa Cruz, CA"[..]));
+        assert!(!strings.contains(&&b"alice"[..]));
+    }
+
+    #[test]
+    fn test_dangling_ways() {
+        let builder = osmflat::OsmBuilder::new(MemoryResourceStorage::new("/osm")).unwrap();
+        let mut tags = TagSerializer::new(&builder).unwrap();
+        let mut stringtable =
+            StringTable::with_index_budget(&std::env::temp_dir(), 1_000_000).unwrap();
+        let mut ways = builder.start_ways().unwrap();
+        let mut nodes_index = builder.start_nodes_index().unwrap();
+        let mut ways_id_to_idx = ids::IdTableBuilder::new();
+        let mut metadata = MetadataSerializer::new(None);
+
+        // Only nodes 1 and 2 are in the source.
+        let mut nodes_id_to_idx = ids::IdTableBuilder::new();
+        nodes_id_to_idx.insert(1);
+        nodes_id_to_idx.insert(2);
+        let nodes_id_to_idx = nodes_id_to_idx.build();
+
+        let way = |id, refs| osmpbf::Way {
+            id,
+            refs,
+            ..Default::default()
+        };
+        let block = osmpbf::PrimitiveBlock {
+            stringtable: string_table(&[""]),
+            primitivegroup: vec![osmpbf::PrimitiveGroup {
+                ways: vec![
+                    // Delta coded refs: 1, 2, 3
+                    way(10, vec![1, 1, 1]),
+                    // 1, 3
+                    way(11, vec![1, 2]),
+                    // 1, 2
+                    way(12, vec![1, 1]),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let selection = Selection::default();
+        let (ids, stats_resolve, mut dangling) =
+            resolve_ways(&block, &nodes_id_to_idx, selection.filter(0));
+        assert_eq!(stats_resolve.num_unresolved_node_ids, 2);
+        assert_eq!(dangling.ways[&10], vec![3]);
+        assert_eq!(dangling.ways[&11], vec![3]);
+
+        let stats = serialize_ways(
+            &block,
+            &ids,
+            &mut ways,
+            &mut ways_id_to_idx,
+            &mut stringtable,
+            &mut tags,
+            &mut nodes_index,
+            &mut metadata,
+            selection.filter(0),
+            DanglingWays::Truncate,
+            &mut dangling,
+        )
+        .unwrap();
+        assert_eq!(stats.num_ways, 2);
+        assert_eq!(stats.num_truncated_ways, 1);
+        assert_eq!(stats.num_dropped_ways, 1);
+        assert_eq!(dangling.truncated_ways, vec![10]);
+        assert_eq!(dangling.dropped_ways, vec![11]);
+
+        let ways_id_to_idx = ways_id_to_idx.build();
+        assert_eq!(ways_id_to_idx.get(10), Some(0));
+        assert_eq!(ways_id_to_idx.get(11), None);
+        assert_eq!(ways_id_to_idx.get(12), Some(1));
+
+        ways.close().unwrap();
+        let nodes_index = nodes_index.close().unwrap();
+        let refs: Vec<Option<u64>> = nodes_index.iter().map(|i| i.value()).collect();
+        assert_eq!(refs, vec![Some(0), Some(1), Some(0), Some(1)]);
+        tags.close();
+    }
+}
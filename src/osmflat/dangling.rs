@@ -0,0 +1,137 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::osmflat::osmflat_generated::osm::EntityType;
+
+// References to entities that are missing from the source, which is common for the
+// ways and relations along the border of an extract. They are written to dangling.yaml
+// in the planet directory, by the OSM id of the referencing way or relation.
+
+type Error = Box<dyn std::error::Error>;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Dangling {
+    // The missing node refs of each way.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ways: BTreeMap<i64, Vec<i64>>,
+    // The missing members of each relation.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub relations: BTreeMap<i64, DanglingMembers>,
+    // Ways that had their missing node refs removed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub truncated_ways: Vec<i64>,
+    // Ways that were left out, since they did not have a valid geometry without
+    // their missing node refs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dropped_ways: Vec<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct DanglingMembers {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ways: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub relations: Vec<i64>,
+}
+
+impl Dangling {
+    pub fn is_empty(&self) -> bool {
+        self.ways.is_empty()
+            && self.relations.is_empty()
+            && self.truncated_ways.is_empty()
+            && self.dropped_ways.is_empty()
+    }
+
+    pub fn way_ref(&mut self, way_id: i64, node_id: i64) {
+        self.ways.entry(way_id).or_default().push(node_id);
+    }
+
+    pub fn member(&mut self, relation_id: i64, entity_type: &EntityType, id: i64) {
+        let members = self.relations.entry(relation_id).or_default();
+        match entity_type {
+            EntityType::Node => members.nodes.push(id),
+            EntityType::Way => members.ways.push(id),
+            EntityType::Relation => members.relations.push(id),
+            _ => (),
+        }
+    }
+
+    /// Reads the dangling.yaml of a planet, which is empty when there is none.
+    pub fn read(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join("dangling.yaml");
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_yaml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Forgets the ways and relations that `keep_way` and `keep_relation` are false for.
+    pub fn retain(&mut self, keep_way: impl Fn(i64) -> bool, keep_relation: impl Fn(i64) -> bool) {
+        self.ways.retain(|id, _| keep_way(*id));
+        self.relations.retain(|id, _| keep_relation(*id));
+        self.truncated_ways.retain(|id| keep_way(*id));
+        self.dropped_ways.retain(|id| keep_way(*id));
+    }
+
+    /// Adds the dangling references found in another block.
+    pub fn append(&mut self, other: Dangling) {
+        for (way_id, node_ids) in other.ways {
+            self.ways.entry(way_id).or_default().extend(node_ids);
+        }
+        for (relation_id, members) in other.relations {
+            let m = self.relations.entry(relation_id).or_default();
+            m.nodes.extend(members.nodes);
+            m.ways.extend(members.ways);
+            m.relations.extend(members.relations);
+        }
+        self.truncated_ways.extend(other.truncated_ways);
+        self.dropped_ways.extend(other.dropped_ways);
+    }
+
+    /// Writes dangling.yaml into the planet directory. Without dangling references,
+    /// an existing dangling.yaml is removed, so that it never describes an older planet.
+    pub fn write(&self, dir: &Path) -> Result<(), Error> {
+        let path = dir.join("dangling.yaml");
+        if self.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+        fs::write(&path, serde_yaml::to_string(self)?)?;
+        println!("Wrote dangling references to {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_serde() {
+        let mut dangling = Dangling::default();
+        dangling.way_ref(10, 1);
+        dangling.member(20, &EntityType::Way, 10);
+
+        let mut block = Dangling::default();
+        block.way_ref(10, 2);
+        block.member(20, &EntityType::Node, 3);
+        block.dropped_ways.push(11);
+        dangling.append(block);
+
+        assert_eq!(dangling.ways[&10], vec![1, 2]);
+        assert_eq!(dangling.relations[&20].nodes, vec![3]);
+        assert_eq!(dangling.relations[&20].ways, vec![10]);
+        assert_eq!(dangling.dropped_ways, vec![11]);
+
+        let s = serde_yaml::to_string(&dangling).unwrap();
+        assert!(!s.contains("truncated_ways"));
+        let dangling2: Dangling = serde_yaml::from_str(&s).unwrap();
+        assert_eq!(dangling, dangling2);
+    }
+}
@@ -94,16 +94,20 @@ impl IdBlock {
 /// Maps u64 integers to a consecutive range of ids
 #[derive(Debug)]
 pub struct IdTable {
-    // map u64 id x to u32 by storing a sorted mapping table for each value of x / 2^24
-    data: Vec<(u64, IdBlock)>,
+    // map u64 id x to u32 by storing a sorted mapping table for each value of x / 2^24.
+    // Every segment is sorted on its own, and an id is only looked up in the first
+    // segment that contains it.
+    segments: Vec<Vec<(u64, IdBlock)>>,
 }
 
 #[derive(Debug, Default)]
 pub struct IdTableBuilder {
     // stored the same data as IdTable, but still in process of being build
+    segments: Vec<Vec<(u64, IdBlock)>>,
     data: Vec<IdBlock>,
     last_id: Option<u64>,
     next_id: u64,
+    segment_start: u64,
 }
 
 impl IdTableBuilder {
@@ -127,33 +131,49 @@ impl IdTableBuilder {
         result
     }
 
-    pub fn build(mut self) -> IdTable {
-        for ids in &mut self.data {
+    /// Starts a new segment of sorted ids, which continues the mapped indexes of the
+    /// previous one. This is used to map the ids of several sources in one table.
+    pub fn next_segment(&mut self) {
+        self.finish_segment();
+        self.last_id = None;
+    }
+
+    fn finish_segment(&mut self) {
+        if self.next_id == self.segment_start {
+            return;
+        }
+        let mut data = std::mem::take(&mut self.data);
+        for ids in &mut data {
             ids.finalize();
         }
-        let result = self
-            .data
+        let segment = data
             .into_iter()
-            .scan(0, |state, ids| {
+            .scan(self.segment_start, |state, ids| {
                 let offset = *state;
                 *state += ids.count() as u64;
                 Some((offset, ids))
             })
             .collect();
-        IdTable { data: result }
+        self.segments.push(segment);
+        self.segment_start = self.next_id;
+    }
+
+    pub fn build(mut self) -> IdTable {
+        self.finish_segment();
+        IdTable {
+            segments: self.segments,
+        }
     }
 }
 
 impl IdTable {
     pub fn get(&self, x: u64) -> Option<u64> {
         let id_set = (x >> 24) as usize;
-        if id_set > self.data.len() {
-            return None;
-        }
-        self.data[id_set]
-            .1
-            .pos((x % (1u64 << 24)) as u32)
-            .map(|pos| self.data[id_set].0 + pos as u64)
+        self.segments.iter().find_map(|data| {
+            let (offset, ids) = data.get(id_set)?;
+            ids.pos((x % (1u64 << 24)) as u32)
+                .map(|pos| offset + pos as u64)
+        })
     }
 }
 
@@ -224,6 +244,26 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_segments() {
+        let mut builder = IdTableBuilder::new();
+        for x in [3, 7, 1_u64 << 33] {
+            builder.insert(x);
+        }
+        builder.next_segment();
+        for x in [1, 5, 8] {
+            builder.insert(x);
+        }
+
+        let lookup = builder.build();
+        for (pos, x) in [3, 7, 1_u64 << 33, 1, 5, 8].iter().enumerate() {
+            assert_eq!(lookup.get(*x), Some(pos as u64));
+        }
+        for x in [0, 2, 4, 6, 9, 1_u64 << 34].iter() {
+            assert_eq!(lookup.get(*x), None);
+        }
+    }
+
     #[test]
     #[ignore]
     fn test_dense() {
@@ -0,0 +1,335 @@
+use ahash::AHashMap;
+use itertools::Itertools;
+use pbr::ProgressBar;
+use std::collections::hash_map;
+
+use crate::osmflat::osmflat_generated::osm::EntityType;
+use crate::parallel;
+
+use super::clip::Clip;
+use super::ids;
+use super::osmpbf;
+use super::osmpbf::{build_block_index, read_block, BlockIndex, BlockType};
+
+// Several osm.pbf sources, such as neighbouring regional extracts or a base extract with
+// overlays, are converted into one planet. The sources are converted one after another.
+// Each of them is sorted by id, but their id ranges overlap, so the ids of every source
+// get their own segment in the id tables.
+//
+// An entity in more than one source is only converted once. The source with the highest
+// version of it keeps it. For equal versions, the entities are the same, and the first
+// source keeps it.
+
+type Error = Box<dyn std::error::Error>;
+
+/// The blocks of one osm.pbf source, grouped by type.
+pub struct PbfSource<'a> {
+    pub data: &'a [u8],
+    pub header: Vec<BlockIndex>,
+    pub nodes: Vec<BlockIndex>,
+    pub ways: Vec<BlockIndex>,
+    pub relations: Vec<BlockIndex>,
+}
+
+impl<'a> PbfSource<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        let block_index = build_block_index(data);
+        let groups = block_index.into_iter().group_by(|b| b.block_type);
+        let mut source = Self {
+            data,
+            header: Vec::new(),
+            nodes: Vec::new(),
+            ways: Vec::new(),
+            relations: Vec::new(),
+        };
+        for (block_type, blocks) in &groups {
+            match block_type {
+                BlockType::Header => source.header.extend(blocks),
+                BlockType::Nodes | BlockType::DenseNodes => source.nodes.extend(blocks),
+                BlockType::Ways => source.ways.extend(blocks),
+                BlockType::Relations => source.relations.extend(blocks),
+            }
+        }
+        // Node ids are sorted in file order, and the id table has to be built in that order,
+        // so plain and dense node blocks are converted together in the order of the file.
+        source.nodes.sort_by_key(|b| b.blob_start);
+        source
+    }
+}
+
+/// The entities that are in more than one source, by id, with the source that keeps them.
+#[derive(Debug, Default)]
+pub struct Duplicates {
+    nodes: AHashMap<i64, usize>,
+    ways: AHashMap<i64, usize>,
+    relations: AHashMap<i64, usize>,
+}
+
+impl Duplicates {
+    pub fn find(sources: &[PbfSource]) -> Result<Self, Error> {
+        let nodes: Vec<_> = sources.iter().map(|s| (s.data, &s.nodes[..])).collect();
+        let ways: Vec<_> = sources.iter().map(|s| (s.data, &s.ways[..])).collect();
+        let relations: Vec<_> = sources.iter().map(|s| (s.data, &s.relations[..])).collect();
+        let duplicates = Self {
+            nodes: find_duplicates(&nodes, node_versions, "nodes")?,
+            ways: find_duplicates(&ways, way_versions, "ways")?,
+            relations: find_duplicates(&relations, relation_versions, "relations")?,
+        };
+        println!(
+            "Found {} nodes, {} ways, {} relations in more than one source.",
+            duplicates.nodes.len(),
+            duplicates.ways.len(),
+            duplicates.relations.len()
+        );
+        Ok(duplicates)
+    }
+
+    fn keeps(&self, entity_type: &EntityType, id: i64, source: usize) -> bool {
+        let kept_by = match entity_type {
+            EntityType::Node => &self.nodes,
+            EntityType::Way => &self.ways,
+            EntityType::Relation => &self.relations,
+            _ => return true,
+        };
+        kept_by.get(&id).is_none_or(|s| *s == source)
+    }
+}
+
+/// Selects the entities that are converted out of all sources.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub duplicates: Duplicates,
+    pub clip: Option<Clip>,
+}
+
+impl Selection {
+    pub fn filter(&self, source: usize) -> SourceFilter<'_> {
+        SourceFilter {
+            selection: self,
+            source,
+        }
+    }
+}
+
+/// Selects the entities of one source that are converted.
+#[derive(Debug, Clone, Copy)]
+pub struct SourceFilter<'a> {
+    selection: &'a Selection,
+    source: usize,
+}
+
+impl<'a> SourceFilter<'a> {
+    pub fn keeps(&self, entity_type: EntityType, id: i64) -> bool {
+        let clip = &self.selection.clip;
+        clip.as_ref()
+            .is_none_or(|clip| clip.keeps(&entity_type, id))
+            && self
+                .selection
+                .duplicates
+                .keeps(&entity_type, id, self.source)
+    }
+}
+
+// Finds the duplicates of one entity type, given the data and blocks of every source.
+fn find_duplicates(
+    sources: &[(&[u8], &[BlockIndex])],
+    versions: fn(&osmpbf::PrimitiveBlock) -> Vec<(i64, i32)>,
+    name: &str,
+) -> Result<AHashMap<i64, usize>, Error> {
+    let num_blocks: usize = sources.iter().map(|(_, blocks)| blocks.len()).sum();
+    let mut pb = ProgressBar::new(2 * num_blocks as u64);
+    pb.message(&format!("Finding duplicate {}...", name));
+
+    // Index the ids of every source, so that we only keep track of the versions
+    // of the entities that are in another source as well.
+    let mut id_tables = Vec::with_capacity(sources.len());
+    for (data, blocks) in sources {
+        let mut id_table = ids::IdTableBuilder::new();
+        parallel::parallel_process(
+            blocks.iter().cloned(),
+            |idx| read_block(data, &idx),
+            |block: Result<osmpbf::PrimitiveBlock, _>| -> Result<(), Error> {
+                for (id, _) in versions(&block?) {
+                    id_table.insert(id as u64);
+                }
+                pb.inc();
+                Ok(())
+            },
+        )?;
+        id_tables.push(id_table.build());
+    }
+
+    let mut kept: AHashMap<i64, (i32, usize)> = AHashMap::new();
+    for (i, (data, blocks)) in sources.iter().enumerate() {
+        parallel::parallel_process(
+            blocks.iter().cloned(),
+            |idx| read_block(data, &idx),
+            |block: Result<osmpbf::PrimitiveBlock, _>| -> Result<(), Error> {
+                for (id, version) in versions(&block?) {
+                    let elsewhere = id_tables
+                        .iter()
+                        .enumerate()
+                        .any(|(j, table)| j != i && table.get(id as u64).is_some());
+                    if !elsewhere {
+                        continue;
+                    }
+                    match kept.entry(id) {
+                        hash_map::Entry::Occupied(mut entry) => {
+                            if version > entry.get().0 {
+                                entry.insert((version, i));
+                            }
+                        }
+                        hash_map::Entry::Vacant(entry) => {
+                            entry.insert((version, i));
+                        }
+                    }
+                }
+                pb.inc();
+                Ok(())
+            },
+        )?;
+    }
+
+    Ok(kept
+        .into_iter()
+        .map(|(id, (_, source))| (id, source))
+        .collect())
+}
+
+/// The ids and versions of the plain and dense nodes in a block.
+/// Entities without info have version 0.
+fn node_versions(block: &osmpbf::PrimitiveBlock) -> Vec<(i64, i32)> {
+    let mut result = Vec::new();
+    for group in &block.primitivegroup {
+        for node in &group.nodes {
+            result.push((node.id, info_version(node.info.as_ref())));
+        }
+        if let Some(dense) = &group.dense {
+            let versions = dense.denseinfo.as_ref().map(|info| &info.version);
+            let mut id = 0;
+            for (i, delta) in dense.id.iter().enumerate() {
+                id += delta;
+                let version = versions.and_then(|v| v.get(i)).copied().unwrap_or(0);
+                result.push((id, version));
+            }
+        }
+    }
+    result
+}
+
+fn way_versions(block: &osmpbf::PrimitiveBlock) -> Vec<(i64, i32)> {
+    block
+        .primitivegroup
+        .iter()
+        .flat_map(|group| &group.ways)
+        .map(|way| (way.id, info_version(way.info.as_ref())))
+        .collect()
+}
+
+fn relation_versions(block: &osmpbf::PrimitiveBlock) -> Vec<(i64, i32)> {
+    block
+        .primitivegroup
+        .iter()
+        .flat_map(|group| &group.relations)
+        .map(|relation| (relation.id, info_version(relation.info.as_ref())))
+        .collect()
+}
+
+fn info_version(info: Option<&osmpbf::Info>) -> i32 {
+    info.and_then(|info| info.version).unwrap_or(0)
+}
+
+/// Merges the headers of all sources into the header of the planet.
+/// The bounding box covers all sources, and the replication state is that of the oldest
+/// source, since the planet is only complete up to there.
+pub fn merge_headers(headers: Vec<osmpbf::HeaderBlock>) -> osmpbf::HeaderBlock {
+    let mut headers = headers.into_iter();
+    let mut merged = headers.next().unwrap_or_default();
+    for header in headers {
+        merged.bbox = match (merged.bbox, header.bbox) {
+            (Some(a), Some(b)) => Some(osmpbf::HeaderBBox {
+                left: a.left.min(b.left),
+                right: a.right.max(b.right),
+                top: a.top.max(b.top),
+                bottom: a.bottom.min(b.bottom),
+            }),
+            _ => None,
+        };
+        if let Some(timestamp) = header.osmosis_replication_timestamp {
+            if merged
+                .osmosis_replication_timestamp
+                .is_none_or(|t| timestamp < t)
+            {
+                merged.osmosis_replication_timestamp = Some(timestamp);
+                merged.osmosis_replication_sequence_number =
+                    header.osmosis_replication_sequence_number;
+                merged.osmosis_replication_base_url = header.osmosis_replication_base_url;
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(bbox: (i64, i64, i64, i64), timestamp: i64) -> osmpbf::HeaderBlock {
+        osmpbf::HeaderBlock {
+            bbox: Some(osmpbf::HeaderBBox {
+                left: bbox.0,
+                right: bbox.1,
+                top: bbox.2,
+                bottom: bbox.3,
+            }),
+            osmosis_replication_timestamp: Some(timestamp),
+            osmosis_replication_sequence_number: Some(timestamp / 60),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_headers() {
+        let merged = merge_headers(vec![
+            header((-10, 0, 10, 0), 1_667_383_260),
+            header((0, 10, 20, -5), 1_667_383_200),
+        ]);
+        let bbox = merged.bbox.unwrap();
+        assert_eq!(
+            (bbox.left, bbox.right, bbox.top, bbox.bottom),
+            (-10, 10, 20, -5)
+        );
+        assert_eq!(merged.osmosis_replication_timestamp, Some(1_667_383_200));
+        assert_eq!(
+            merged.osmosis_replication_sequence_number,
+            Some(1_667_383_200 / 60)
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let data = std::fs::read("tests/fixtures/nodes4.osm.pbf").unwrap();
+        let sources = vec![PbfSource::new(&data), PbfSource::new(&data)];
+        let selection = Selection {
+            duplicates: Duplicates::find(&sources).unwrap(),
+            clip: None,
+        };
+        let duplicates = &selection.duplicates;
+
+        let num_nodes: usize = sources[0]
+            .nodes
+            .iter()
+            .map(|idx| node_versions(&read_block(&data, idx).unwrap()).len())
+            .sum();
+        assert!(num_nodes > 0);
+        assert_eq!(duplicates.nodes.len(), num_nodes);
+
+        // With equal versions, the first source keeps the entity.
+        let (id, _) = duplicates.nodes.iter().next().unwrap();
+        assert!(selection.filter(0).keeps(EntityType::Node, *id));
+        assert!(!selection.filter(1).keeps(EntityType::Node, *id));
+
+        // Entities in a single source are always kept.
+        assert!(selection.filter(1).keeps(EntityType::Node, -1));
+    }
+}
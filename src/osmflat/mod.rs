@@ -4,6 +4,12 @@
 mod convert;
 pub use convert::convert;
 
+// Only the pvt binary updates planets, the fixtures binary does not.
+#[allow(dead_code)]
+mod update;
+#[allow(unused_imports)]
+pub use update::{update, Diff};
+
 #[allow(dead_code)]
 #[path = "../generated/osmflat_generated.rs"]
 pub mod osmflat_generated;
@@ -11,8 +17,13 @@ pub mod osmflat_generated;
 #[path = "../generated/osmpbf.rs"]
 mod osmpbf_generated;
 
+mod clip;
+mod dangling;
 mod ids;
-mod osmpbf;
+mod merge;
+pub mod osmpbf;
+mod osmxml;
 mod stats;
 mod strings;
-mod tags;
+mod tag_filter;
+pub mod tags;
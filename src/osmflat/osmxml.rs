@@ -0,0 +1,610 @@
+use ahash::AHashMap;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::location;
+use crate::osmflat::osmflat_generated::osm::EntityType;
+
+use super::convert::{serialize_header, MetadataSerializer, TagSerializer};
+use super::dangling::Dangling;
+use super::osmflat_generated::osm as osmflat;
+use super::osmpbf;
+use super::stats::Stats;
+use super::strings::StringTable;
+
+// Streaming reader for OSM XML, as written by JOSM, Osmosis and the OSM API.
+//
+// The XML is read twice. The first pass numbers the nodes, ways and relations, since
+// relation members may refer to relations further down in the file. The second pass
+// writes the same flatdata vectors as the osm.pbf conversion.
+//
+// Ids are mapped with hash maps rather than the sorted IdTable, because files edited
+// in JOSM contain new objects with negative ids, in no particular order. The elements
+// still have to be grouped as nodes, then ways, then relations, since the tag, ref and
+// member ranges of the flatdata archive are contiguous.
+
+type Error = Box<dyn std::error::Error>;
+
+// OSM XML coordinates have 7 decimal places.
+const COORD_SCALE: i32 = 10_000_000;
+
+#[derive(Debug, Default)]
+struct IdMaps {
+    nodes: AHashMap<i64, u64>,
+    ways: AHashMap<i64, u64>,
+    relations: AHashMap<i64, u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Section {
+    Nodes,
+    Ways,
+    Relations,
+    End,
+}
+
+pub fn convert<'a>(
+    data: &[u8],
+    builder: &'a osmflat::OsmBuilder,
+    tags: &mut TagSerializer<'a>,
+    stringtable: &mut StringTable,
+    include_metadata: bool,
+    dangling: &mut Dangling,
+) -> Result<Stats, Error> {
+    let t = Instant::now();
+    println!("Building index of OSM XML ids...");
+    let ids = index_ids(data)?;
+    println!(
+        "Found {} nodes, {} ways, {} relations.",
+        ids.nodes.len(),
+        ids.ways.len(),
+        ids.relations.len()
+    );
+
+    let mut serializer =
+        XmlSerializer::new(builder, &ids, tags, stringtable, include_metadata, dangling)?;
+    let mut reader = Reader::from_reader(data);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => serializer.start(&e, false)?,
+            Event::Empty(e) => serializer.start(&e, true)?,
+            Event::End(e) => serializer.end(e.name().as_ref()),
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    let stats = serializer.finish()?;
+
+    println!("OSM XML converted in {} secs.", t.elapsed().as_secs());
+    Ok(stats)
+}
+
+fn index_ids(data: &[u8]) -> Result<IdMaps, Error> {
+    let mut ids = IdMaps::default();
+    let mut reader = Reader::from_reader(data);
+    loop {
+        let e = match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => e,
+            Event::Eof => break,
+            _ => continue,
+        };
+        let map = match e.name().as_ref() {
+            b"node" => &mut ids.nodes,
+            b"way" => &mut ids.ways,
+            b"relation" => &mut ids.relations,
+            _ => continue,
+        };
+        if is_deleted(&e)? {
+            continue;
+        }
+        let id: i64 = attribute(&e, "id")?;
+        let idx = map.len() as u64;
+        if map.insert(id, idx).is_some() {
+            let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+            return Err(format!("Duplicate {} id {} in OSM XML.", name, id).into());
+        }
+    }
+    Ok(ids)
+}
+
+struct XmlSerializer<'a, 'b> {
+    builder: &'a osmflat::OsmBuilder,
+    ids: &'b IdMaps,
+    tags: &'b mut TagSerializer<'a>,
+    stringtable: &'b mut StringTable,
+    dangling: &'b mut Dangling,
+    header: osmpbf::HeaderBlock,
+    nodes: flatdata::ExternalVector<'a, osmflat::Node>,
+    hilbert_node_pairs: flatdata::ExternalVector<'a, osmflat::HilbertNodePair>,
+    ways: flatdata::ExternalVector<'a, osmflat::Way>,
+    nodes_index: flatdata::ExternalVector<'a, osmflat::NodeIndex>,
+    relations: flatdata::ExternalVector<'a, osmflat::Relation>,
+    members: flatdata::ExternalVector<'a, osmflat::Member>,
+    node_metadata: MetadataSerializer<'a>,
+    way_metadata: MetadataSerializer<'a>,
+    relation_metadata: MetadataSerializer<'a>,
+    section: Section,
+    // Inside of an element that was deleted in JOSM, so its children are dropped too.
+    skip: bool,
+    // The id of the current way or relation, for the dangling references.
+    id: i64,
+    stats: Stats,
+}
+
+impl<'a, 'b> XmlSerializer<'a, 'b> {
+    fn new(
+        builder: &'a osmflat::OsmBuilder,
+        ids: &'b IdMaps,
+        tags: &'b mut TagSerializer<'a>,
+        stringtable: &'b mut StringTable,
+        include_metadata: bool,
+        dangling: &'b mut Dangling,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            builder,
+            ids,
+            tags,
+            stringtable,
+            dangling,
+            header: osmpbf::HeaderBlock::default(),
+            nodes: builder.start_nodes()?,
+            hilbert_node_pairs: builder.start_hilbert_node_pairs()?,
+            ways: builder.start_ways()?,
+            nodes_index: builder.start_nodes_index()?,
+            relations: builder.start_relations()?,
+            members: builder.start_members()?,
+            node_metadata: MetadataSerializer::new(
+                include_metadata
+                    .then(|| builder.start_node_metadata())
+                    .transpose()?,
+            ),
+            way_metadata: MetadataSerializer::new(
+                include_metadata
+                    .then(|| builder.start_way_metadata())
+                    .transpose()?,
+            ),
+            relation_metadata: MetadataSerializer::new(
+                include_metadata
+                    .then(|| builder.start_relation_metadata())
+                    .transpose()?,
+            ),
+            section: Section::Nodes,
+            skip: false,
+            id: 0,
+            stats: Stats::default(),
+        })
+    }
+
+    fn start(&mut self, e: &BytesStart, empty: bool) -> Result<(), Error> {
+        match e.name().as_ref() {
+            b"bounds" => self.bounds(e),
+            b"node" | b"way" | b"relation" if is_deleted(e)? => {
+                self.skip = !empty;
+                Ok(())
+            }
+            b"node" => self.node(e),
+            b"way" => self.way(e),
+            b"relation" => self.relation(e),
+            _ if self.skip => Ok(()),
+            b"tag" => self.tag(e),
+            b"nd" => self.nd(e),
+            b"member" => self.member(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn end(&mut self, name: &[u8]) {
+        if matches!(name, b"node" | b"way" | b"relation") {
+            self.skip = false;
+        }
+    }
+
+    fn bounds(&mut self, e: &BytesStart) -> Result<(), Error> {
+        let nano = |degrees: f64| (degrees * 1_000_000_000_f64).round() as i64;
+        self.header.bbox = Some(osmpbf::HeaderBBox {
+            left: nano(attribute(e, "minlon")?),
+            right: nano(attribute(e, "maxlon")?),
+            top: nano(attribute(e, "maxlat")?),
+            bottom: nano(attribute(e, "minlat")?),
+        });
+        Ok(())
+    }
+
+    fn node(&mut self, e: &BytesStart) -> Result<(), Error> {
+        self.enter(Section::Nodes)?;
+        let id: i64 = attribute(e, "id")?;
+        let index = self.ids.nodes[&id];
+        assert_eq!(index as usize, self.nodes.len());
+
+        let lat_dm7 = to_dm7(attribute(e, "lat")?);
+        let lon_dm7 = to_dm7(attribute(e, "lon")?);
+
+        let node = self.nodes.grow()?;
+        node.set_osm_id(id);
+        node.set_lat(lat_dm7);
+        node.set_lon(lon_dm7);
+        node.set_tag_first_idx(self.tags.next_index());
+
+        let pair = self.hilbert_node_pairs.grow()?;
+        pair.set_i(index);
+        pair.set_h(location::lonlat_to_h((lon_dm7, lat_dm7)));
+
+        XmlMetadata::parse(e)?.serialize(&mut self.node_metadata, self.stringtable)?;
+
+        self.stats.num_nodes += 1;
+        Ok(())
+    }
+
+    fn way(&mut self, e: &BytesStart) -> Result<(), Error> {
+        self.enter(Section::Ways)?;
+        let id: i64 = attribute(e, "id")?;
+        assert_eq!(self.ids.ways[&id] as usize, self.ways.len());
+        self.id = id;
+
+        let way = self.ways.grow()?;
+        way.set_osm_id(id);
+        way.set_tag_first_idx(self.tags.next_index());
+        way.set_ref_first_idx(self.nodes_index.len() as u64);
+
+        XmlMetadata::parse(e)?.serialize(&mut self.way_metadata, self.stringtable)?;
+
+        self.stats.num_ways += 1;
+        Ok(())
+    }
+
+    fn relation(&mut self, e: &BytesStart) -> Result<(), Error> {
+        self.enter(Section::Relations)?;
+        let id: i64 = attribute(e, "id")?;
+        assert_eq!(self.ids.relations[&id] as usize, self.relations.len());
+        self.id = id;
+
+        let relation = self.relations.grow()?;
+        relation.set_osm_id(id);
+        relation.set_tag_first_idx(self.tags.next_index());
+        relation.set_member_first_idx(self.members.len() as u32);
+
+        XmlMetadata::parse(e)?.serialize(&mut self.relation_metadata, self.stringtable)?;
+
+        self.stats.num_relations += 1;
+        Ok(())
+    }
+
+    fn tag(&mut self, e: &BytesStart) -> Result<(), Error> {
+        let k: String = attribute(e, "k")?;
+        let v: String = attribute(e, "v")?;
+        if !self.tags.keeps(&k) {
+            self.stats.num_dropped_tags += 1;
+            return Ok(());
+        }
+        let key_idx = self.stringtable.insert(&k)?;
+        let val_idx = self.stringtable.insert(&v)?;
+        self.tags.serialize(key_idx, val_idx)
+    }
+
+    fn nd(&mut self, e: &BytesStart) -> Result<(), Error> {
+        let node_ref: i64 = attribute(e, "ref")?;
+        let idx = self.ids.nodes.get(&node_ref).copied();
+        if idx.is_none() {
+            self.stats.num_unresolved_node_ids += 1;
+            self.dangling.way_ref(self.id, node_ref);
+        }
+        self.nodes_index.grow()?.set_value(idx);
+        Ok(())
+    }
+
+    fn member(&mut self, e: &BytesStart) -> Result<(), Error> {
+        let member_type: String = attribute(e, "type")?;
+        let member_ref: i64 = attribute(e, "ref")?;
+        let role: String = attribute(e, "role")?;
+
+        let (entity_type, idx) = match member_type.as_str() {
+            "node" => {
+                let idx = self.ids.nodes.get(&member_ref).copied();
+                self.stats.num_unresolved_node_ids += idx.is_none() as usize;
+                (EntityType::Node, idx)
+            }
+            "way" => {
+                let idx = self.ids.ways.get(&member_ref).copied();
+                self.stats.num_unresolved_way_ids += idx.is_none() as usize;
+                (EntityType::Way, idx)
+            }
+            "relation" => {
+                let idx = self.ids.relations.get(&member_ref).copied();
+                self.stats.num_unresolved_rel_ids += idx.is_none() as usize;
+                (EntityType::Relation, idx)
+            }
+            _ => return Err(format!("Invalid member type in OSM XML: {}", member_type).into()),
+        };
+        if idx.is_none() {
+            self.dangling.member(self.id, &entity_type, member_ref);
+        }
+
+        let role_idx = self.stringtable.insert(&role)?;
+        let member = self.members.grow()?;
+        member.set_entity_type(entity_type);
+        member.set_idx(idx);
+        member.set_role_idx(role_idx);
+        Ok(())
+    }
+
+    /// Moves on to the given section, writing the sentinels of the sections before it.
+    fn enter(&mut self, section: Section) -> Result<(), Error> {
+        if section < self.section {
+            return Err(
+                "OSM XML has to list nodes first, then ways, then relations. Try `osmium sort`."
+                    .into(),
+            );
+        }
+        while self.section < section {
+            match self.section {
+                Section::Nodes => {
+                    // fill tag_first_idx of the sentry, since it contains the end of the tag range
+                    // of the last node
+                    self.nodes.grow()?.set_tag_first_idx(self.tags.next_index());
+                    self.section = Section::Ways;
+                }
+                Section::Ways => {
+                    let sentinel = self.ways.grow()?;
+                    sentinel.set_tag_first_idx(self.tags.next_index());
+                    sentinel.set_ref_first_idx(self.nodes_index.len() as u64);
+                    self.section = Section::Relations;
+                }
+                Section::Relations => {
+                    let sentinel = self.relations.grow()?;
+                    sentinel.set_tag_first_idx(self.tags.next_index());
+                    sentinel.set_member_first_idx(self.members.len() as u32);
+                    self.section = Section::End;
+                }
+                Section::End => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Stats, Error> {
+        self.enter(Section::End)?;
+        self.nodes.close()?;
+        self.hilbert_node_pairs.close()?;
+        self.ways.close()?;
+        self.nodes_index.close()?;
+        self.relations.close()?;
+        self.members.close()?;
+        self.node_metadata.close()?;
+        self.way_metadata.close()?;
+        self.relation_metadata.close()?;
+        serialize_header(&self.header, COORD_SCALE, self.builder, self.stringtable)?;
+        Ok(self.stats)
+    }
+}
+
+/// The version, timestamp, changeset and user of an element. JOSM leaves them out for
+/// new elements, so missing attributes are zero or empty.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct XmlMetadata {
+    pub version: i32,
+    // Seconds since the epoch.
+    pub timestamp: i64,
+    pub changeset: i64,
+    pub user: String,
+}
+
+impl XmlMetadata {
+    pub fn parse(e: &BytesStart) -> Result<Self, Error> {
+        let timestamp = match optional_attribute::<String>(e, "timestamp")? {
+            Some(timestamp) => chrono::DateTime::parse_from_rfc3339(&timestamp)?.timestamp(),
+            None => 0,
+        };
+        Ok(Self {
+            version: optional_attribute(e, "version")?.unwrap_or_default(),
+            timestamp,
+            changeset: optional_attribute(e, "changeset")?.unwrap_or_default(),
+            user: optional_attribute(e, "user")?.unwrap_or_default(),
+        })
+    }
+
+    pub fn serialize(
+        &self,
+        metadata: &mut MetadataSerializer,
+        stringtable: &mut StringTable,
+    ) -> Result<(), Error> {
+        // Users are only added to the stringtable when they are stored.
+        if metadata.is_enabled() {
+            let user_idx = stringtable.insert(&self.user)?;
+            metadata.serialize(self.version, self.timestamp, self.changeset, user_idx)?;
+        }
+        Ok(())
+    }
+}
+
+/// JOSM keeps deleted objects in the file until they are uploaded.
+fn is_deleted(e: &BytesStart) -> Result<bool, Error> {
+    let action: Option<String> = optional_attribute(e, "action")?;
+    let visible: Option<String> = optional_attribute(e, "visible")?;
+    Ok(action.as_deref() == Some("delete") || visible.as_deref() == Some("false"))
+}
+
+pub fn attribute<T>(e: &BytesStart, name: &str) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + 'static,
+{
+    match optional_attribute(e, name)? {
+        Some(value) => Ok(value),
+        None => {
+            let element = String::from_utf8_lossy(e.name().as_ref()).to_string();
+            Err(format!("Missing attribute {} of <{}> in OSM XML.", name, element).into())
+        }
+    }
+}
+
+pub fn optional_attribute<T>(e: &BytesStart, name: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + 'static,
+{
+    match e.try_get_attribute(name)? {
+        Some(attr) => Ok(Some(attr.unescape_value()?.parse::<T>()?)),
+        None => Ok(None),
+    }
+}
+
+fn to_dm7(degrees: f64) -> i32 {
+    (degrees * COORD_SCALE as f64).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flatdata::MemoryResourceStorage;
+
+    const XML: &str = r#"<?xml version='1.0' encoding='UTF-8'?>
+<osm version='0.6' generator='JOSM'>
+  <bounds minlat='36.9' minlon='-122.1' maxlat='37.1' maxlon='-121.9' />
+  <node id='-3' action='modify' visible='true' lat='36.9514859' lon='-122.026736'>
+    <tag k='amenity' v='cafe' />
+    <tag k='name' v='Beach &amp; Bean' />
+  </node>
+  <node id='1' action='delete' visible='true' lat='37.0' lon='-122.0'>
+    <tag k='amenity' v='bench' />
+  </node>
+  <node id='2' version='4' timestamp='2022-11-01T10:00:00Z' changeset='128000000' user='alice' visible='true' lat='37.0491457' lon='-122.0279745' />
+  <way id='-1' visible='true'>
+    <nd ref='-3' />
+    <nd ref='2' />
+    <nd ref='1' />
+    <tag k='highway' v='footway' />
+  </way>
+  <relation id='10' visible='true'>
+    <member type='way' ref='-1' role='outer' />
+    <member type='relation' ref='11' role='' />
+    <tag k='type' v='multipolygon' />
+  </relation>
+  <relation id='11' visible='true'>
+    <member type='node' ref='2' role='label' />
+  </relation>
+</osm>
+"#;
+
+    fn convert_xml(xml: &str) -> Result<(osmflat::Osm, Stats, Dangling), Error> {
+        let storage = MemoryResourceStorage::new("/osm");
+        let builder = osmflat::OsmBuilder::new(storage.clone())?;
+        let mut tags = TagSerializer::new(&builder)?;
+        let mut stringtable = StringTable::with_index_budget(&std::env::temp_dir(), 1_000_000)?;
+        let mut dangling = Dangling::default();
+        let stats = convert(
+            xml.as_bytes(),
+            &builder,
+            &mut tags,
+            &mut stringtable,
+            true,
+            &mut dangling,
+        )?;
+        tags.close();
+        builder.set_stringtable(&stringtable.into_mmap()?)?;
+        std::mem::drop(builder);
+        Ok((osmflat::Osm::open(storage)?, stats, dangling))
+    }
+
+    #[test]
+    fn test_convert_xml() {
+        let (osm, stats, dangling) = convert_xml(XML).unwrap();
+        assert_eq!(stats.num_nodes, 2);
+        assert_eq!(stats.num_ways, 1);
+        assert_eq!(stats.num_relations, 2);
+        assert_eq!(stats.num_unresolved_node_ids, 1);
+        // The deleted node is missing from the way.
+        assert_eq!(dangling.ways[&-1], vec![1]);
+        assert!(dangling.relations.is_empty());
+
+        let strings = osm.stringtable();
+        let tag = |i: u64| {
+            let tag = &osm.tags()[osm.tags_index()[i as usize].value() as usize];
+            (
+                strings
+                    .substring(tag.key_idx() as usize)
+                    .unwrap()
+                    .to_string(),
+                strings
+                    .substring(tag.value_idx() as usize)
+                    .unwrap()
+                    .to_string(),
+            )
+        };
+
+        let header = osm.header();
+        assert_eq!(header.coord_scale(), COORD_SCALE);
+        assert_eq!(header.bbox_left(), -1_221_000_000);
+        assert_eq!(header.bbox_top(), 371_000_000);
+
+        let nodes = osm.nodes();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].osm_id(), -3);
+        assert_eq!(nodes[0].lat(), 369_514_859);
+        assert_eq!(nodes[0].lon(), -1_220_267_360);
+        assert_eq!(nodes[0].tags(), 0..2);
+        assert_eq!(tag(1), ("name".to_string(), "Beach & Bean".to_string()));
+        assert_eq!(nodes[1].osm_id(), 2);
+        assert_eq!(nodes[1].tags(), 2..2);
+
+        // New JOSM elements have no metadata yet.
+        let node_metadata = osm.node_metadata().unwrap();
+        assert_eq!(node_metadata.len(), 2);
+        assert_eq!(node_metadata[0].version(), 0);
+        assert_eq!(node_metadata[1].version(), 4);
+        assert_eq!(node_metadata[1].timestamp(), 1667296800);
+        assert_eq!(node_metadata[1].changeset(), 128000000);
+        assert_eq!(
+            strings
+                .substring(node_metadata[1].user_idx() as usize)
+                .unwrap(),
+            "alice"
+        );
+        assert_eq!(osm.way_metadata().unwrap().len(), 1);
+        assert_eq!(osm.relation_metadata().unwrap().len(), 2);
+
+        let pairs = osm.hilbert_node_pairs().unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(
+            pairs[1].h(),
+            location::lonlat_to_h((nodes[1].lon(), nodes[1].lat()))
+        );
+
+        let way = &osm.ways()[0];
+        assert_eq!(way.osm_id(), -1);
+        assert_eq!(way.tags(), 2..3);
+        assert_eq!(tag(2), ("highway".to_string(), "footway".to_string()));
+        let refs: Vec<Option<u64>> = way
+            .refs()
+            .map(|i| osm.nodes_index()[i as usize].value())
+            .collect();
+        assert_eq!(refs, vec![Some(0), Some(1), None]);
+
+        let relations = osm.relations();
+        assert_eq!(relations.len(), 2);
+        assert_eq!(relations[0].tags(), 3..4);
+        assert_eq!(relations[0].members(), 0..2);
+        let members = osm.members();
+        assert_eq!(members[0].entity_type(), EntityType::Way);
+        assert_eq!(members[0].idx(), Some(0));
+        assert_eq!(members[1].entity_type(), EntityType::Relation);
+        assert_eq!(members[1].idx(), Some(1));
+        assert_eq!(relations[1].members(), 2..3);
+        assert_eq!(members[2].entity_type(), EntityType::Node);
+        assert_eq!(members[2].idx(), Some(1));
+        assert_eq!(
+            strings.substring(members[2].role_idx() as usize).unwrap(),
+            "label"
+        );
+    }
+
+    #[test]
+    fn test_xml_section_order() {
+        let xml = r#"<osm version='0.6'>
+  <way id='1'><nd ref='1' /></way>
+  <node id='1' lat='0' lon='0' />
+</osm>"#;
+        assert!(convert_xml(xml).is_err());
+    }
+}
@@ -9,6 +9,9 @@ pub struct Stats {
     pub num_unresolved_node_ids: usize,
     pub num_unresolved_way_ids: usize,
     pub num_unresolved_rel_ids: usize,
+    pub num_dropped_tags: usize,
+    pub num_truncated_ways: usize,
+    pub num_dropped_ways: usize,
 }
 
 impl AddAssign for Stats {
@@ -20,6 +23,9 @@ impl AddAssign for Stats {
         self.num_unresolved_node_ids += other.num_unresolved_node_ids;
         self.num_unresolved_way_ids += other.num_unresolved_way_ids;
         self.num_unresolved_rel_ids += other.num_unresolved_rel_ids;
+        self.num_dropped_tags += other.num_dropped_tags;
+        self.num_truncated_ways += other.num_truncated_ways;
+        self.num_dropped_ways += other.num_dropped_ways;
     }
 }
 
@@ -34,13 +40,20 @@ impl fmt::Display for Stats {
 Unresolved ids:
   nodes:        {}
   ways:         {}
-  relations:    {}"#,
+  relations:    {}
+Dangling ways:
+  truncated:    {}
+  dropped:      {}
+Dropped tags:   {}"#,
             self.num_nodes,
             self.num_ways,
             self.num_relations,
             self.num_unresolved_node_ids,
             self.num_unresolved_way_ids,
-            self.num_unresolved_rel_ids
+            self.num_unresolved_rel_ids,
+            self.num_truncated_ways,
+            self.num_dropped_ways,
+            self.num_dropped_tags
         )
     }
 }
@@ -1,4 +1,8 @@
 use ahash::AHashMap;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 
 #[derive(Debug, Clone, Copy)]
 struct TerminatedStringPtr {
@@ -48,8 +52,22 @@ impl std::borrow::Borrow<[u8]> for TerminatedStringPtr {
     }
 }
 
+// The strings themselves are written to a temporary file as they are inserted, so that
+// only the deduplication index is held in memory. The index is bounded by a budget in
+// bytes and split into two generations. When the current generation fills up half of
+// the budget, it replaces the previous one, and strings that are found in the previous
+// generation are promoted again. Frequent strings such as tag keys and common values
+// therefore stay indexed, while rare strings fall out of the index.
+//
+// A string that fell out of the index is written again when it comes back, and the
+// new index points to the new copy. Both copies are valid, so the table stays correct,
+// only slightly larger than a fully deduplicated one.
+
+// Approximate memory used by an index entry besides the string: hash map slot and \0.
+const ENTRY_OVERHEAD: usize = 32;
+
 #[derive(Debug, Default)]
-pub struct StringTable {
+struct Generation {
     // Append only, we will never reallocate any data inside
     data: Vec<Vec<u8>>,
 
@@ -58,26 +76,15 @@ pub struct StringTable {
     // will be "alive" long enough we have to manage lifetime ourselves
     indexed_data: AHashMap<TerminatedStringPtr, u64>,
 
-    size_in_bytes: u64,
+    size_in_bytes: usize,
 }
 
-impl StringTable {
-    pub fn new() -> Self {
-        Default::default()
+impl Generation {
+    fn get(&self, s: &str) -> Option<u64> {
+        self.indexed_data.get(s.as_bytes()).copied()
     }
 
-    /// Inserts a string into string table and returns its index.
-    ///
-    /// If the string was already inserted before, the string is deduplicated
-    /// and the index to the previous string is returned.
-    pub fn insert(&mut self, s: &str) -> u64 {
-        // Horrible news, we cannot use entry API since it does not support Borrow
-        // See: https://github.com/rust-lang/rust/issues/56167
-        if let Some(&idx) = self.indexed_data.get(s.as_bytes()) {
-            return idx;
-        }
-
-        let idx = self.size_in_bytes;
+    fn insert(&mut self, s: &str, idx: u64) {
         if self
             .data
             .last()
@@ -104,24 +111,65 @@ impl StringTable {
         };
         self.indexed_data.insert(key, idx);
 
-        self.size_in_bytes += s.len() as u64 + 1;
-        idx
+        self.size_in_bytes += s.len() + ENTRY_OVERHEAD;
     }
+}
 
-    pub fn into_bytes(self) -> Vec<u8> {
-        let Self {
-            data,
-            indexed_data,
-            size_in_bytes,
-        } = self;
-        std::mem::drop(indexed_data);
+#[derive(Debug)]
+pub struct StringTable {
+    file: BufWriter<File>,
+    size_in_bytes: u64,
+    current: Generation,
+    previous: Generation,
+    index_budget: usize,
+}
 
-        let mut result = Vec::new();
-        result.reserve(size_in_bytes as usize);
-        for buffer in data {
-            result.extend(buffer); // also drops buffer
+impl StringTable {
+    /// Creates a string table that keeps its strings in a temporary file in `dir`,
+    /// and whose deduplication index uses at most about `index_budget` bytes of memory.
+    pub fn with_index_budget(dir: &Path, index_budget: usize) -> io::Result<Self> {
+        Ok(Self {
+            file: BufWriter::with_capacity(1024 * 1024 * 4, tempfile::tempfile_in(dir)?),
+            size_in_bytes: 0,
+            current: Generation::default(),
+            previous: Generation::default(),
+            index_budget,
+        })
+    }
+
+    /// Inserts a string into string table and returns its index.
+    ///
+    /// If the string is still in the index, the string is deduplicated
+    /// and the index to the previous string is returned.
+    pub fn insert(&mut self, s: &str) -> io::Result<u64> {
+        if let Some(idx) = self.current.get(s) {
+            return Ok(idx);
         }
-        result
+        if let Some(idx) = self.previous.get(s) {
+            self.index(s, idx);
+            return Ok(idx);
+        }
+
+        let idx = self.size_in_bytes;
+        self.file.write_all(s.as_bytes())?;
+        self.file.write_all(&[0])?;
+        self.size_in_bytes += s.len() as u64 + 1;
+        self.index(s, idx);
+        Ok(idx)
+    }
+
+    fn index(&mut self, s: &str, idx: u64) {
+        self.current.insert(s, idx);
+        if self.current.size_in_bytes > self.index_budget / 2 {
+            self.previous = std::mem::take(&mut self.current);
+        }
+    }
+
+    /// Drops the index and maps the strings, separated by \0, from the temporary file.
+    pub fn into_mmap(self) -> io::Result<Mmap> {
+        let Self { file, .. } = self;
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        unsafe { Mmap::map(&file) }
     }
 }
 
@@ -131,38 +179,71 @@ mod test {
     use proptest::prelude::*;
     use std::collections::HashSet;
 
+    fn string_table() -> StringTable {
+        StringTable::with_index_budget(&std::env::temp_dir(), 64 * 1024 * 1024).unwrap()
+    }
+
     #[test]
     fn test_simple_insert() {
-        let mut st = StringTable::new();
-        assert_eq!(st.insert("hello"), 0);
-        assert_eq!(st.insert("world"), 6);
-        assert_eq!(st.insert("world"), 6);
-        assert_eq!(st.insert("!"), 6 + 6);
-        assert_eq!(st.insert("!"), 6 + 6);
-        assert_eq!(st.insert("!"), 6 + 6);
-
-        let bytes = st.into_bytes();
+        let mut st = string_table();
+        assert_eq!(st.insert("hello").unwrap(), 0);
+        assert_eq!(st.insert("world").unwrap(), 6);
+        assert_eq!(st.insert("world").unwrap(), 6);
+        assert_eq!(st.insert("!").unwrap(), 6 + 6);
+        assert_eq!(st.insert("!").unwrap(), 6 + 6);
+        assert_eq!(st.insert("!").unwrap(), 6 + 6);
+
+        let bytes = st.into_mmap().unwrap();
         println!("{}", ::std::str::from_utf8(&bytes).unwrap());
-        assert_eq!(bytes, b"hello\0world\0!\0");
+        assert_eq!(&bytes[..], b"hello\0world\0!\0");
     }
 
     #[test]
     #[ignore]
     fn test_large_insert() {
-        let mut st = StringTable::new();
-        assert_eq!(st.insert("hello"), 0);
-        assert_eq!(st.insert(&str::repeat("x", 1024 * 1024 * 5)), 6);
-        assert_eq!(st.insert("huh"), 1024 * 1024 * 5 + 1 + 6);
-        assert_eq!(st.insert(&str::repeat("x", 1024 * 1024 * 5)), 6);
-        assert_eq!(st.insert("hello"), 0);
-
-        let bytes = st.into_bytes();
+        let mut st = string_table();
+        assert_eq!(st.insert("hello").unwrap(), 0);
+        assert_eq!(st.insert(&str::repeat("x", 1024 * 1024 * 5)).unwrap(), 6);
+        assert_eq!(st.insert("huh").unwrap(), 1024 * 1024 * 5 + 1 + 6);
+        assert_eq!(st.insert(&str::repeat("x", 1024 * 1024 * 5)).unwrap(), 6);
+        assert_eq!(st.insert("hello").unwrap(), 0);
+
+        let bytes = st.into_mmap().unwrap();
         assert_eq!(
-            bytes,
+            &bytes[..],
             ("hello\0".to_string() + &str::repeat("x", 1024 * 1024 * 5) + "\0huh\0").as_bytes()
         );
     }
 
+    #[test]
+    fn test_bounded_index() {
+        // Room for about two entries per generation.
+        let mut st = StringTable::with_index_budget(&std::env::temp_dir(), 160).unwrap();
+        let hello = st.insert("hello").unwrap();
+        for _ in 0..10 {
+            // Frequent strings stay in the index.
+            assert_eq!(st.insert("hello").unwrap(), hello);
+        }
+        let words: Vec<String> = (0..10).map(|i| format!("word{}", i)).collect();
+        let idxs: Vec<u64> = words.iter().map(|w| st.insert(w).unwrap()).collect();
+
+        // hello fell out of the index and is written again.
+        let hello_again = st.insert("hello").unwrap();
+        assert_ne!(hello_again, hello);
+
+        let bytes = st.into_mmap().unwrap();
+        let string_at = |idx: u64| {
+            let rest = &bytes[idx as usize..];
+            let end = rest.iter().position(|b| *b == 0).unwrap();
+            std::str::from_utf8(&rest[..end]).unwrap().to_string()
+        };
+        assert_eq!(string_at(hello), "hello");
+        assert_eq!(string_at(hello_again), "hello");
+        for (word, idx) in words.iter().zip(idxs) {
+            assert_eq!(&string_at(idx), word);
+        }
+    }
+
     #[derive(Debug, Default)]
     struct ReferenceStringTable {
         words: HashSet<String>,
@@ -183,13 +264,13 @@ mod test {
         #[test]
         fn sequence_of_insert(ref seq in prop::collection::vec("[^\x00]*", 1..100))
         {
-            let mut st = StringTable::new();
+            let mut st = string_table();
             let mut reference_st = ReferenceStringTable::default();
             for input in seq {
-                st.insert(input);
+                st.insert(input).unwrap();
                 reference_st.insert(input.into());
             }
-            assert_eq!(st.into_bytes(), reference_st.data);
+            assert_eq!(&st.into_mmap().unwrap()[..], &reference_st.data[..]);
         }
     }
 }
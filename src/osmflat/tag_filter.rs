@@ -0,0 +1,111 @@
+use super::osmpbf;
+
+/// Selects the tags that are converted by their key, with the drop_tags and keep_tags
+/// of the manifest. A pattern is either an exact key, or a prefix followed by *, such as
+/// `tiger:*`. A tag is dropped when its key matches drop_tags, or when keep_tags is
+/// given and its key does not match it.
+#[derive(Debug, Default, Clone)]
+pub struct TagFilter {
+    drop: Vec<KeyPattern>,
+    keep: Vec<KeyPattern>,
+}
+
+#[derive(Debug, Clone)]
+enum KeyPattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl KeyPattern {
+    fn parse(s: &str) -> Self {
+        match s.strip_suffix('*') {
+            Some(prefix) => KeyPattern::Prefix(prefix.to_string()),
+            None => KeyPattern::Exact(s.to_string()),
+        }
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyPattern::Exact(k) => key == k,
+            KeyPattern::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl TagFilter {
+    pub fn new(drop_tags: &[String], keep_tags: &[String]) -> Self {
+        Self {
+            drop: drop_tags.iter().map(|s| KeyPattern::parse(s)).collect(),
+            keep: keep_tags.iter().map(|s| KeyPattern::parse(s)).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.drop.is_empty() && self.keep.is_empty()
+    }
+
+    pub fn keeps(&self, key: &str) -> bool {
+        if self.drop.iter().any(|p| p.matches(key)) {
+            return false;
+        }
+        self.keep.is_empty() || self.keep.iter().any(|p| p.matches(key))
+    }
+
+    /// For every string in the stringtable of a PBF block, whether tags with it as
+    /// their key are dropped.
+    pub fn dropped_keys(&self, stringtable: &osmpbf::StringTable) -> Vec<bool> {
+        if self.is_empty() {
+            return vec![false; stringtable.s.len()];
+        }
+        stringtable
+            .s
+            .iter()
+            .map(|s| match std::str::from_utf8(s) {
+                Ok(key) => !self.keeps(key),
+                Err(_) => false,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_drop_tags() {
+        let filter = TagFilter::new(&strings(&["created_by", "tiger:*", "note"]), &[]);
+        assert!(!filter.keeps("created_by"));
+        assert!(!filter.keeps("tiger:county"));
+        assert!(!filter.keeps("note"));
+        assert!(filter.keeps("note:en"));
+        assert!(filter.keeps("highway"));
+        assert!(filter.keeps("tiger"));
+    }
+
+    #[test]
+    fn test_keep_tags() {
+        let filter = TagFilter::new(&strings(&["name:*"]), &strings(&["highway", "name*"]));
+        assert!(filter.keeps("highway"));
+        assert!(filter.keeps("name"));
+        assert!(!filter.keeps("name:en"));
+        assert!(!filter.keeps("building"));
+    }
+
+    #[test]
+    fn test_dropped_keys() {
+        let filter = TagFilter::new(&strings(&["source:*"]), &[]);
+        let stringtable = osmpbf::StringTable {
+            s: vec![b"".to_vec(), b"source:date".to_vec(), b"source".to_vec()],
+        };
+        assert_eq!(filter.dropped_keys(&stringtable), vec![false, true, false]);
+        assert_eq!(
+            TagFilter::default().dropped_keys(&stringtable),
+            vec![false; 3]
+        );
+    }
+}
@@ -85,3 +85,33 @@ pub fn has_tag(archive: &Osm, range: Range<u64>, key: &[u8], value: &[u8]) -> bo
     }
     false
 }
+
+// Closed ways with these keys are lines, such as roundabouts and fences, unless they
+// are tagged area=yes.
+const LINEAR_KEYS: [&[u8]; 5] = [
+    b"highway",
+    b"barrier",
+    b"railway",
+    b"waterway",
+    b"aerialway",
+];
+const LINEAR_TAGS: [(&[u8], &[u8]); 5] = [
+    (b"natural", b"coastline"),
+    (b"natural", b"cliff"),
+    (b"natural", b"tree_row"),
+    (b"power", b"line"),
+    (b"man_made", b"embankment"),
+];
+
+/// Checks if the tags of a closed way in `range` make it an area. It is an area unless
+/// it has a linear tag, such as a closed highway, and area=yes or area=no always decide.
+pub fn is_area(archive: &Osm, range: Range<u64>) -> bool {
+    let mut linear = false;
+    for (key, value) in iter_tags(archive, range) {
+        if key == b"area" {
+            return value != b"no";
+        }
+        linear |= LINEAR_KEYS.contains(&key) || LINEAR_TAGS.contains(&(key, value));
+    }
+    !linear
+}
@@ -0,0 +1,1208 @@
+use ahash::{AHashMap, AHashSet};
+use flatdata::FileResourceStorage;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::location;
+use crate::manifest::{DanglingWays, Manifest};
+use crate::osmflat::osmflat_generated::osm::EntityType;
+
+use super::clip::ClipArea;
+use super::convert::{MetadataSerializer, TagSerializer};
+use super::dangling::Dangling;
+use super::osmflat_generated::osm as osmflat;
+use super::osmpbf::relation::MemberType;
+use super::osmxml::{attribute, optional_attribute, XmlMetadata};
+use super::stats::Stats;
+use super::strings::StringTable;
+use super::tag_filter::TagFilter;
+
+// Applies an OsmChange (.osc) diff to the flatdata of a planet.
+//
+// The flatdata vectors are contiguous and ordered along the Hilbert curve, so entities
+// can not be inserted or removed in place. Instead, the existing planet is streamed into
+// a new, unsorted flatdata archive, leaving out the entities that the diff modifies or
+// deletes and appending the created and modified ones after them. The result is sorted
+// just like the output of `convert`, without going back to the source osm.pbf. Along
+// with it comes the Diff, which tells where the entities of the old planet went, so
+// that the Hilbert tree only has to be rebuilt and rendered where the diff touches it.
+
+type Error = Box<dyn std::error::Error>;
+
+type Tags = Vec<(String, String)>;
+
+#[derive(Debug, PartialEq)]
+pub struct ChangedNode {
+    pub lat: f64,
+    pub lon: f64,
+    pub tags: Tags,
+    pub metadata: XmlMetadata,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ChangedWay {
+    pub refs: Vec<i64>,
+    pub tags: Tags,
+    pub metadata: XmlMetadata,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ChangedRelation {
+    pub members: Vec<(MemberType, i64, String)>,
+    pub tags: Tags,
+    pub metadata: XmlMetadata,
+}
+
+/// The entities of an OsmChange by id. None marks a deleted entity.
+/// Creates and modifies are the same to us, since both replace the whole entity.
+#[derive(Debug, Default)]
+pub struct OsmChange {
+    pub nodes: BTreeMap<i64, Option<ChangedNode>>,
+    pub ways: BTreeMap<i64, Option<ChangedWay>>,
+    pub relations: BTreeMap<i64, Option<ChangedRelation>>,
+    // The latest timestamp of a changed entity, in seconds since the epoch.
+    pub timestamp: Option<i64>,
+}
+
+enum Changed {
+    Node(i64, ChangedNode),
+    Way(i64, ChangedWay),
+    Relation(i64, ChangedRelation),
+}
+
+impl OsmChange {
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut change = OsmChange::default();
+        let mut reader = Reader::from_reader(data);
+        let mut delete = false;
+        let mut current: Option<Changed> = None;
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) => change.start(&e, &mut delete, &mut current)?,
+                Event::Empty(e) => {
+                    change.start(&e, &mut delete, &mut current)?;
+                    change.end(e.name().as_ref(), delete, &mut current);
+                }
+                Event::End(e) => change.end(e.name().as_ref(), delete, &mut current),
+                Event::Eof => break,
+                _ => (),
+            }
+        }
+        Ok(change)
+    }
+
+    fn start(
+        &mut self,
+        e: &BytesStart,
+        delete: &mut bool,
+        current: &mut Option<Changed>,
+    ) -> Result<(), Error> {
+        match e.name().as_ref() {
+            b"create" | b"modify" => *delete = false,
+            b"delete" => *delete = true,
+            b"node" | b"way" | b"relation" => {
+                let metadata = XmlMetadata::parse(e)?;
+                // A missing timestamp is parsed as 0.
+                if metadata.timestamp > 0 {
+                    let seconds = metadata.timestamp;
+                    self.timestamp = Some(self.timestamp.map_or(seconds, |t| t.max(seconds)));
+                }
+                let id: i64 = attribute(e, "id")?;
+                *current = Some(match e.name().as_ref() {
+                    b"node" => Changed::Node(
+                        id,
+                        ChangedNode {
+                            // Deletes do not need to carry coordinates.
+                            lat: optional_attribute(e, "lat")?.unwrap_or_default(),
+                            lon: optional_attribute(e, "lon")?.unwrap_or_default(),
+                            tags: Vec::new(),
+                            metadata,
+                        },
+                    ),
+                    b"way" => Changed::Way(
+                        id,
+                        ChangedWay {
+                            refs: Vec::new(),
+                            tags: Vec::new(),
+                            metadata,
+                        },
+                    ),
+                    _ => Changed::Relation(
+                        id,
+                        ChangedRelation {
+                            members: Vec::new(),
+                            tags: Vec::new(),
+                            metadata,
+                        },
+                    ),
+                });
+            }
+            b"tag" => {
+                let tag = (attribute(e, "k")?, attribute(e, "v")?);
+                match current {
+                    Some(Changed::Node(_, node)) => node.tags.push(tag),
+                    Some(Changed::Way(_, way)) => way.tags.push(tag),
+                    Some(Changed::Relation(_, relation)) => relation.tags.push(tag),
+                    None => (),
+                }
+            }
+            b"nd" => {
+                if let Some(Changed::Way(_, way)) = current {
+                    way.refs.push(attribute(e, "ref")?);
+                }
+            }
+            b"member" => {
+                if let Some(Changed::Relation(_, relation)) = current {
+                    let member_type: String = attribute(e, "type")?;
+                    let member_type = match member_type.as_str() {
+                        "node" => MemberType::Node,
+                        "way" => MemberType::Way,
+                        "relation" => MemberType::Relation,
+                        _ => {
+                            return Err(format!(
+                                "Invalid member type in OsmChange: {}",
+                                member_type
+                            )
+                            .into())
+                        }
+                    };
+                    relation.members.push((
+                        member_type,
+                        attribute(e, "ref")?,
+                        attribute(e, "role")?,
+                    ));
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Later changes of the same entity replace earlier ones.
+    fn end(&mut self, name: &[u8], delete: bool, current: &mut Option<Changed>) {
+        if !matches!(name, b"node" | b"way" | b"relation") {
+            return;
+        }
+        match current.take() {
+            Some(Changed::Node(id, node)) => {
+                self.nodes.insert(id, (!delete).then_some(node));
+            }
+            Some(Changed::Way(id, way)) => {
+                self.ways.insert(id, (!delete).then_some(way));
+            }
+            Some(Changed::Relation(id, relation)) => {
+                self.relations.insert(id, (!delete).then_some(relation));
+            }
+            None => (),
+        }
+    }
+
+    /// Deletes the created and modified entities that the clip area does not keep, so that
+    /// the changes are clipped like `convert` clips the sources. The entities of the old
+    /// planet that the diff does not change are within the clip already.
+    fn clip(&mut self, area: &ClipArea, old: &osmflat::Osm) {
+        let (node_refs, way_refs, relation_refs) = self.referenced_ids();
+        let coord_scale = old.header().coord_scale() as f64;
+        let all_nodes = old.nodes();
+        let all_ways = old.ways();
+        let all_relations = old.relations();
+
+        // The unchanged entities of the old planet that the changes refer to.
+        let mut old_nodes = AHashSet::new();
+        let mut old_inside = AHashSet::new();
+        for node in all_nodes {
+            let id = node.osm_id();
+            if node_refs.contains(&id) && !self.nodes.contains_key(&id) {
+                old_nodes.insert(id);
+                let (lon, lat) = (node.lon() as f64, node.lat() as f64);
+                if area.contains(lon / coord_scale, lat / coord_scale) {
+                    old_inside.insert(id);
+                }
+            }
+        }
+        let old_ways: AHashSet<i64> = all_ways
+            .iter()
+            .map(|w| w.osm_id())
+            .filter(|id| way_refs.contains(id) && !self.ways.contains_key(id))
+            .collect();
+        let old_relations: AHashSet<i64> = all_relations
+            .iter()
+            .map(|r| r.osm_id())
+            .filter(|id| relation_refs.contains(id) && !self.relations.contains_key(id))
+            .collect();
+
+        // Nodes within the area, and the ways with a node within it, which are kept complete.
+        let mut nodes: AHashSet<i64> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.as_ref().is_some_and(|n| area.contains(n.lon, n.lat)))
+            .map(|(id, _)| *id)
+            .collect();
+        let inside = |id: &i64| nodes.contains(id) || old_inside.contains(id);
+        let mut ways = AHashSet::new();
+        for (id, way) in &self.ways {
+            let Some(way) = way else { continue };
+            if way.refs.iter().any(inside) {
+                ways.insert(*id);
+            }
+        }
+        for way in ways.iter().flat_map(|id| &self.ways[id]) {
+            nodes.extend(way.refs.iter().copied());
+        }
+        // The unchanged ways keep their nodes as well.
+        let old_nodes_index = old.nodes_index();
+        for way in all_ways {
+            if self.ways.contains_key(&way.osm_id()) {
+                continue;
+            }
+            let refs = way.refs();
+            let end = if refs.end == 0 {
+                old_nodes_index.len() as u64
+            } else {
+                refs.end
+            };
+            for r in refs.start..end {
+                let Some(idx) = old_nodes_index[r as usize].value() else {
+                    continue;
+                };
+                let id = all_nodes[idx as usize].osm_id();
+                if self.nodes.contains_key(&id) {
+                    nodes.insert(id);
+                }
+            }
+        }
+
+        // Relations with a kept member, or a kept member relation.
+        let kept = |t: &MemberType, id: &i64, relations: &AHashSet<i64>| match t {
+            MemberType::Node => nodes.contains(id) || old_nodes.contains(id),
+            MemberType::Way => ways.contains(id) || old_ways.contains(id),
+            MemberType::Relation => relations.contains(id) || old_relations.contains(id),
+        };
+        let mut relations = AHashSet::new();
+        loop {
+            let mut changed = false;
+            for (id, relation) in &self.relations {
+                let Some(relation) = relation else { continue };
+                if relations.contains(id) {
+                    continue;
+                }
+                let members = &relation.members;
+                if members.iter().any(|(t, m, _)| kept(t, m, &relations)) {
+                    relations.insert(*id);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // Kept relations are complete with their member nodes and ways, and the nodes of those.
+        let mut member_ways = Vec::new();
+        for id in &relations {
+            for (member_type, m, _) in self.relations[id].iter().flat_map(|r| &r.members) {
+                match member_type {
+                    MemberType::Node => {
+                        nodes.insert(*m);
+                    }
+                    MemberType::Way => member_ways.push(*m),
+                    MemberType::Relation => (),
+                }
+            }
+        }
+        let old_members = old.members();
+        for (i, relation) in all_relations.iter().enumerate() {
+            if self.relations.contains_key(&relation.osm_id()) {
+                continue;
+            }
+            let start = relation.member_first_idx() as usize;
+            let end = match all_relations.get(i + 1) {
+                Some(next) => next.member_first_idx() as usize,
+                None => old_members.len(),
+            };
+            for member in &old_members[start..end] {
+                let Some(idx) = member.idx() else { continue };
+                match member.entity_type() {
+                    EntityType::Node => {
+                        let id = all_nodes[idx as usize].osm_id();
+                        if self.nodes.contains_key(&id) {
+                            nodes.insert(id);
+                        }
+                    }
+                    EntityType::Way => {
+                        let id = all_ways[idx as usize].osm_id();
+                        if self.ways.contains_key(&id) {
+                            member_ways.push(id);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        for id in member_ways {
+            if let Some(Some(way)) = self.ways.get(&id) {
+                ways.insert(id);
+                nodes.extend(way.refs.iter().copied());
+            }
+        }
+
+        println!(
+            "Clip area keeps {} changed nodes, {} ways, {} relations.",
+            delete_unkept(&mut self.nodes, &nodes),
+            delete_unkept(&mut self.ways, &ways),
+            delete_unkept(&mut self.relations, &relations)
+        );
+    }
+
+    /// The ids of every entity the changed ways and relations refer to.
+    fn referenced_ids(&self) -> (AHashSet<i64>, AHashSet<i64>, AHashSet<i64>) {
+        let mut nodes = AHashSet::new();
+        let mut ways = AHashSet::new();
+        let mut relations = AHashSet::new();
+        for way in self.ways.values().flatten() {
+            nodes.extend(way.refs.iter().copied());
+        }
+        for relation in self.relations.values().flatten() {
+            for (member_type, id, _) in &relation.members {
+                match member_type {
+                    MemberType::Node => nodes.insert(*id),
+                    MemberType::Way => ways.insert(*id),
+                    MemberType::Relation => relations.insert(*id),
+                };
+            }
+        }
+        (nodes, ways, relations)
+    }
+}
+
+// Turns the changes of the entities that are not kept into deletes. Returns how many
+// entities are still created or modified.
+fn delete_unkept<T>(changes: &mut BTreeMap<i64, Option<T>>, kept: &AHashSet<i64>) -> usize {
+    for (id, change) in changes.iter_mut() {
+        if !kept.contains(id) {
+            *change = None;
+        }
+    }
+    changes.values().flatten().count()
+}
+
+/// Where the entities of the old planet are in the updated planet, before it is sorted.
+#[derive(Debug)]
+pub struct Diff {
+    pub nodes: IndexMap,
+    pub ways: IndexMap,
+    pub relations: IndexMap,
+}
+
+/// Maps the entities of one type from their index in the old planet, or their id, to
+/// their index in the updated planet. The kept entities keep their relative order and
+/// the changed entities are appended after them in id order. Only the ids the diff
+/// refers to are looked up, so this stays as small as the diff.
+#[derive(Debug)]
+pub struct IndexMap {
+    // Sorted old indexes of the modified and deleted entities, with their new index.
+    removed: Vec<(u64, Option<u64>)>,
+    changed: AHashMap<i64, Option<u64>>,
+    referenced: AHashMap<i64, u64>,
+}
+
+impl IndexMap {
+    fn new<T>(
+        old_ids: impl Iterator<Item = i64>,
+        changes: &BTreeMap<i64, Option<T>>,
+        referenced_ids: &AHashSet<i64>,
+    ) -> Self {
+        let mut removed_ids = Vec::new();
+        let mut referenced = AHashMap::new();
+        let mut kept = 0;
+        for (i, id) in old_ids.enumerate() {
+            if changes.contains_key(&id) {
+                removed_ids.push((i as u64, id));
+            } else {
+                kept += 1;
+            }
+            if referenced_ids.contains(&id) {
+                referenced.insert(id, i as u64);
+            }
+        }
+
+        let mut next = kept;
+        let mut changed = AHashMap::with_capacity(changes.len());
+        for (id, change) in changes {
+            let idx = change.as_ref().map(|_| {
+                next += 1;
+                next - 1
+            });
+            changed.insert(*id, idx);
+        }
+
+        let removed = removed_ids
+            .into_iter()
+            .map(|(i, id)| (i, changed[&id]))
+            .collect();
+
+        Self {
+            removed,
+            changed,
+            referenced,
+        }
+    }
+
+    /// The old indexes of the modified and deleted entities.
+    pub fn removed(&self) -> impl Iterator<Item = u64> + '_ {
+        self.removed.iter().map(|(i, _)| *i)
+    }
+
+    /// The new indexes of the created and modified entities.
+    pub fn changed(&self) -> impl Iterator<Item = u64> + '_ {
+        self.changed.values().flatten().copied()
+    }
+
+    pub fn by_old_idx(&self, old_idx: u64) -> Option<u64> {
+        match self.removed.binary_search_by_key(&old_idx, |(i, _)| *i) {
+            Ok(pos) => self.removed[pos].1,
+            Err(pos) => Some(old_idx - pos as u64),
+        }
+    }
+
+    fn by_id(&self, id: i64) -> Option<u64> {
+        match self.changed.get(&id) {
+            Some(idx) => *idx,
+            None => self
+                .referenced
+                .get(&id)
+                .and_then(|old_idx| self.by_old_idx(*old_idx)),
+        }
+    }
+}
+
+/// Writes the planet of the manifest with the OsmChange at `osc` applied into `dir`
+/// as a new, unsorted flatdata archive.
+pub fn update(
+    manifest: &Manifest,
+    osc: &Path,
+    dir: &Path,
+    sequence_number: Option<i64>,
+) -> Result<(osmflat::Osm, Diff), Error> {
+    let time = Instant::now();
+    println!("Applying {} to planet...", osc.display());
+
+    let mut change = OsmChange::parse(&fs::read(osc)?)?;
+    println!(
+        "Changed {} nodes, {} ways, {} relations.",
+        change.nodes.len(),
+        change.ways.len(),
+        change.relations.len()
+    );
+
+    let old = osmflat::Osm::open(FileResourceStorage::new(&manifest.data.planet))?;
+    let clip_area = ClipArea::from_manifest(manifest)?;
+    if let Some(area) = &clip_area {
+        change.clip(area, &old);
+    }
+
+    let storage = FileResourceStorage::new(dir);
+    let builder = osmflat::OsmBuilder::new(storage.clone())?;
+    let mut stringtable =
+        StringTable::with_index_budget(dir, manifest.data.string_index_mb * 1_000_000)?;
+    let mut tags = TagSerializer::new(&builder)?.with_filter(TagFilter::new(
+        &manifest.data.drop_tags,
+        &manifest.data.keep_tags,
+    ));
+
+    // The dangling references of the entities that the diff changes are found again.
+    let mut dangling = Dangling::read(&manifest.data.planet)?;
+    dangling.retain(
+        |id| !change.ways.contains_key(&id),
+        |id| !change.relations.contains_key(&id),
+    );
+
+    let (stats, diff) = serialize_update(
+        &old,
+        &mut change,
+        sequence_number,
+        clip_area.as_ref(),
+        manifest.data.dangling_ways,
+        &mut dangling,
+        &builder,
+        &mut tags,
+        &mut stringtable,
+    )?;
+
+    tags.close();
+    println!("Writing stringtable to disk...");
+    builder.set_stringtable(&stringtable.into_mmap()?)?;
+
+    std::mem::drop(builder);
+    let flatdata = osmflat::Osm::open(storage)?;
+
+    println!(
+        "Update of osm.flatdata is complete. {}",
+        humantime::format_duration(time.elapsed())
+    );
+    println!("{}", stats);
+    dangling.write(dir)?;
+
+    Ok((flatdata, diff))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn serialize_update(
+    old: &osmflat::Osm,
+    change: &mut OsmChange,
+    sequence_number: Option<i64>,
+    clip_area: Option<&ClipArea>,
+    dangling_ways: DanglingWays,
+    dangling: &mut Dangling,
+    builder: &osmflat::OsmBuilder,
+    tags: &mut TagSerializer,
+    stringtable: &mut StringTable,
+) -> Result<(Stats, Diff), Error> {
+    let mut stats = Stats::default();
+    let old_strings = old.stringtable();
+    let old_nodes = old.nodes();
+    let old_ways = old.ways();
+    let old_relations = old.relations();
+
+    // A sorted planet does not keep the sentinels of its vectors, so the ranges of its last
+    // node, way and relation end at 0 rather than where the next vector starts.
+    let tags_len = old.tags_index().len() as u64;
+    let relation_tags_start = old_relations
+        .first()
+        .map_or(tags_len, |r| r.tag_first_idx());
+    let way_tags_start = old_ways
+        .first()
+        .map_or(relation_tags_start, |w| w.tag_first_idx());
+    let last = |range: Range<u64>, i: usize, len: usize, end: u64| {
+        if i + 1 == len && range.end == 0 {
+            range.start..end
+        } else {
+            range
+        }
+    };
+
+    let (node_refs, way_refs, relation_refs) = change.referenced_ids();
+    let node_map = IndexMap::new(
+        old_nodes.iter().map(|n| n.osm_id()),
+        &change.nodes,
+        &node_refs,
+    );
+    resolve_ways(
+        &mut change.ways,
+        &node_map,
+        dangling_ways,
+        dangling,
+        &mut stats,
+    );
+    let way_map = IndexMap::new(old_ways.iter().map(|w| w.osm_id()), &change.ways, &way_refs);
+    let relation_map = IndexMap::new(
+        old_relations.iter().map(|r| r.osm_id()),
+        &change.relations,
+        &relation_refs,
+    );
+
+    // Nodes
+    let t = Instant::now();
+    let coord_scale = old.header().coord_scale() as f64;
+    let mut nodes = builder.start_nodes()?;
+    let mut hilbert_node_pairs = builder.start_hilbert_node_pairs()?;
+    // Metadata is kept when the planet was converted with it.
+    let mut node_metadata = MetadataSerializer::new(
+        old.node_metadata()
+            .map(|_| builder.start_node_metadata())
+            .transpose()?,
+    );
+    let mut push_node = |id: i64, lat: i32, lon: i32, tag_first_idx: u64| -> Result<(), Error> {
+        let index = nodes.len() as u64;
+        let node = nodes.grow()?;
+        node.set_osm_id(id);
+        node.set_lat(lat);
+        node.set_lon(lon);
+        node.set_tag_first_idx(tag_first_idx);
+
+        let pair = hilbert_node_pairs.grow()?;
+        pair.set_i(index);
+        pair.set_h(location::lonlat_to_h((lon, lat)));
+        Ok(())
+    };
+    for (i, node) in old_nodes.iter().enumerate() {
+        if change.nodes.contains_key(&node.osm_id()) {
+            continue;
+        }
+        push_node(node.osm_id(), node.lat(), node.lon(), tags.next_index())?;
+        let node_tags = last(node.tags(), i, old_nodes.len(), way_tags_start);
+        copy_tags(old, node_tags, tags, stringtable)?;
+        copy_metadata(old, old.node_metadata(), i, &mut node_metadata, stringtable)?;
+        stats.num_nodes += 1;
+    }
+    for (id, node) in &change.nodes {
+        let Some(node) = node else { continue };
+        let lat = (node.lat * coord_scale).round() as i32;
+        let lon = (node.lon * coord_scale).round() as i32;
+        push_node(*id, lat, lon, tags.next_index())?;
+        stats.num_dropped_tags += serialize_tags(&node.tags, tags, stringtable)?;
+        node.metadata.serialize(&mut node_metadata, stringtable)?;
+        stats.num_nodes += 1;
+    }
+    nodes.grow()?.set_tag_first_idx(tags.next_index());
+    nodes.close()?;
+    hilbert_node_pairs.close()?;
+    node_metadata.close()?;
+    println!("Nodes updated in {} secs.", t.elapsed().as_secs());
+
+    // Ways
+    let t = Instant::now();
+    let old_nodes_index = old.nodes_index();
+    let mut ways = builder.start_ways()?;
+    let mut nodes_index = builder.start_nodes_index()?;
+    let mut way_metadata = MetadataSerializer::new(
+        old.way_metadata()
+            .map(|_| builder.start_way_metadata())
+            .transpose()?,
+    );
+    for (i, way) in old_ways.iter().enumerate() {
+        if change.ways.contains_key(&way.osm_id()) {
+            continue;
+        }
+        let new_way = ways.grow()?;
+        new_way.set_osm_id(way.osm_id());
+        new_way.set_tag_first_idx(tags.next_index());
+        new_way.set_ref_first_idx(nodes_index.len() as u64);
+        let way_tags = last(way.tags(), i, old_ways.len(), relation_tags_start);
+        copy_tags(old, way_tags, tags, stringtable)?;
+        copy_metadata(old, old.way_metadata(), i, &mut way_metadata, stringtable)?;
+        for r in last(way.refs(), i, old_ways.len(), old_nodes_index.len() as u64) {
+            let old_idx = old_nodes_index[r as usize].value();
+            let idx = old_idx.and_then(|old_idx| node_map.by_old_idx(old_idx));
+            stats.num_unresolved_node_ids += idx.is_none() as usize;
+            // The refs that were missing before are in the dangling.yaml of the old planet.
+            if let (Some(old_idx), None) = (old_idx, idx) {
+                dangling.way_ref(way.osm_id(), old_nodes[old_idx as usize].osm_id());
+            }
+            nodes_index.grow()?.set_value(idx);
+        }
+        stats.num_ways += 1;
+    }
+    for (id, way) in &change.ways {
+        let Some(way) = way else { continue };
+        let new_way = ways.grow()?;
+        new_way.set_osm_id(*id);
+        new_way.set_tag_first_idx(tags.next_index());
+        new_way.set_ref_first_idx(nodes_index.len() as u64);
+        stats.num_dropped_tags += serialize_tags(&way.tags, tags, stringtable)?;
+        way.metadata.serialize(&mut way_metadata, stringtable)?;
+        for node_id in &way.refs {
+            nodes_index.grow()?.set_value(node_map.by_id(*node_id));
+        }
+        stats.num_ways += 1;
+    }
+    {
+        let sentinel = ways.grow()?;
+        sentinel.set_tag_first_idx(tags.next_index());
+        sentinel.set_ref_first_idx(nodes_index.len() as u64);
+    }
+    ways.close()?;
+    nodes_index.close()?;
+    way_metadata.close()?;
+    println!("Ways updated in {} secs.", t.elapsed().as_secs());
+
+    // Relations
+    let t = Instant::now();
+    let old_members = old.members();
+    let mut relations = builder.start_relations()?;
+    let mut members = builder.start_members()?;
+    let mut relation_metadata = MetadataSerializer::new(
+        old.relation_metadata()
+            .map(|_| builder.start_relation_metadata())
+            .transpose()?,
+    );
+    for (i, relation) in old_relations.iter().enumerate() {
+        if change.relations.contains_key(&relation.osm_id()) {
+            continue;
+        }
+        let new_relation = relations.grow()?;
+        new_relation.set_osm_id(relation.osm_id());
+        new_relation.set_tag_first_idx(tags.next_index());
+        new_relation.set_member_first_idx(members.len() as u32);
+        let relation_tags = last(relation.tags(), i, old_relations.len(), tags_len);
+        copy_tags(old, relation_tags, tags, stringtable)?;
+        copy_metadata(
+            old,
+            old.relation_metadata(),
+            i,
+            &mut relation_metadata,
+            stringtable,
+        )?;
+        let members_range = relation.members();
+        let members_range = members_range.start as u64..members_range.end as u64;
+        for m in last(
+            members_range,
+            i,
+            old_relations.len(),
+            old_members.len() as u64,
+        ) {
+            let old_member = &old_members[m as usize];
+            let entity_type = old_member.entity_type();
+            let old_idx = old_member.idx();
+            let idx = old_idx.and_then(|old_idx| match entity_type {
+                EntityType::Node => node_map.by_old_idx(old_idx),
+                EntityType::Way => way_map.by_old_idx(old_idx),
+                _ => relation_map.by_old_idx(old_idx),
+            });
+            if let (Some(old_idx), None) = (old_idx, idx) {
+                let old_idx = old_idx as usize;
+                let id = match entity_type {
+                    EntityType::Node => old_nodes[old_idx].osm_id(),
+                    EntityType::Way => old_ways[old_idx].osm_id(),
+                    _ => old_relations[old_idx].osm_id(),
+                };
+                dangling.member(relation.osm_id(), &entity_type, id);
+            }
+            let role = old_strings.substring(old_member.role_idx() as usize)?;
+            let member = members.grow()?;
+            member.set_entity_type(entity_type);
+            member.set_idx(idx);
+            member.set_role_idx(stringtable.insert(role)?);
+        }
+        stats.num_relations += 1;
+    }
+    for (id, relation) in &change.relations {
+        let Some(relation) = relation else { continue };
+        let new_relation = relations.grow()?;
+        new_relation.set_osm_id(*id);
+        new_relation.set_tag_first_idx(tags.next_index());
+        new_relation.set_member_first_idx(members.len() as u32);
+        stats.num_dropped_tags += serialize_tags(&relation.tags, tags, stringtable)?;
+        relation
+            .metadata
+            .serialize(&mut relation_metadata, stringtable)?;
+        for (member_type, member_id, role) in &relation.members {
+            let (entity_type, idx) = match member_type {
+                MemberType::Node => {
+                    let idx = node_map.by_id(*member_id);
+                    stats.num_unresolved_node_ids += idx.is_none() as usize;
+                    (EntityType::Node, idx)
+                }
+                MemberType::Way => {
+                    let idx = way_map.by_id(*member_id);
+                    stats.num_unresolved_way_ids += idx.is_none() as usize;
+                    (EntityType::Way, idx)
+                }
+                MemberType::Relation => {
+                    let idx = relation_map.by_id(*member_id);
+                    stats.num_unresolved_rel_ids += idx.is_none() as usize;
+                    (EntityType::Relation, idx)
+                }
+            };
+            if idx.is_none() {
+                dangling.member(*id, &entity_type, *member_id);
+            }
+            let member = members.grow()?;
+            member.set_entity_type(entity_type);
+            member.set_idx(idx);
+            member.set_role_idx(stringtable.insert(role)?);
+        }
+        stats.num_relations += 1;
+    }
+    {
+        let sentinel = relations.grow()?;
+        sentinel.set_tag_first_idx(tags.next_index());
+        sentinel.set_member_first_idx(members.len() as u32);
+    }
+    relations.close()?;
+    members.close()?;
+    relation_metadata.close()?;
+    println!("Relations updated in {} secs.", t.elapsed().as_secs());
+
+    serialize_header(
+        old,
+        change,
+        sequence_number,
+        clip_area,
+        builder,
+        stringtable,
+    )?;
+    let diff = Diff {
+        nodes: node_map,
+        ways: way_map,
+        relations: relation_map,
+    };
+    Ok((stats, diff))
+}
+
+// Finds the missing node refs of the changed ways, and applies the dangling ways policy of
+// the manifest to them like convert does. Truncated ways lose their missing refs, and
+// dropped ways become deletes.
+fn resolve_ways(
+    ways: &mut BTreeMap<i64, Option<ChangedWay>>,
+    node_map: &IndexMap,
+    dangling_ways: DanglingWays,
+    dangling: &mut Dangling,
+    stats: &mut Stats,
+) {
+    for (id, change) in ways.iter_mut() {
+        let Some(way) = change else { continue };
+        let missing: Vec<i64> = way
+            .refs
+            .iter()
+            .copied()
+            .filter(|node_id| node_map.by_id(*node_id).is_none())
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        stats.num_unresolved_node_ids += missing.len();
+        for node_id in &missing {
+            dangling.way_ref(*id, *node_id);
+        }
+        match dangling_ways {
+            DanglingWays::Keep => (),
+            // A way needs at least two nodes for a geometry.
+            DanglingWays::Truncate if way.refs.len() - missing.len() >= 2 => {
+                way.refs.retain(|node_id| !missing.contains(node_id));
+                stats.num_truncated_ways += 1;
+                dangling.truncated_ways.push(*id);
+            }
+            DanglingWays::Truncate | DanglingWays::Drop => {
+                *change = None;
+                stats.num_dropped_ways += 1;
+                dangling.dropped_ways.push(*id);
+            }
+        }
+    }
+}
+
+fn copy_tags(
+    old: &osmflat::Osm,
+    range: Range<u64>,
+    tags: &mut TagSerializer,
+    stringtable: &mut StringTable,
+) -> Result<(), Error> {
+    let old_strings = old.stringtable();
+    let old_tags = old.tags();
+    let old_tags_index = old.tags_index();
+    for i in range {
+        let tag = &old_tags[old_tags_index[i as usize].value() as usize];
+        let key_idx = stringtable.insert(old_strings.substring(tag.key_idx() as usize)?)?;
+        let val_idx = stringtable.insert(old_strings.substring(tag.value_idx() as usize)?)?;
+        tags.serialize(key_idx, val_idx)?;
+    }
+    Ok(())
+}
+
+fn copy_metadata(
+    old: &osmflat::Osm,
+    old_metadata: Option<&[osmflat::Metadata]>,
+    i: usize,
+    metadata: &mut MetadataSerializer,
+    stringtable: &mut StringTable,
+) -> Result<(), Error> {
+    if let Some(old_metadata) = old_metadata {
+        let m = &old_metadata[i];
+        let user = old.stringtable().substring(m.user_idx() as usize)?;
+        metadata.serialize(
+            m.version(),
+            m.timestamp(),
+            m.changeset(),
+            stringtable.insert(user)?,
+        )?;
+    }
+    Ok(())
+}
+
+// Returns the number of tags the tag filter of the manifest dropped.
+fn serialize_tags(
+    changed_tags: &Tags,
+    tags: &mut TagSerializer,
+    stringtable: &mut StringTable,
+) -> Result<usize, Error> {
+    let mut dropped = 0;
+    for (key, value) in changed_tags {
+        if !tags.keeps(key) {
+            dropped += 1;
+            continue;
+        }
+        let key_idx = stringtable.insert(key)?;
+        let val_idx = stringtable.insert(value)?;
+        tags.serialize(key_idx, val_idx)?;
+    }
+    Ok(dropped)
+}
+
+// Carries the header of the old planet over, with the replication state of the diff and
+// the bbox of the clip area.
+fn serialize_header(
+    old: &osmflat::Osm,
+    change: &OsmChange,
+    sequence_number: Option<i64>,
+    clip_area: Option<&ClipArea>,
+    builder: &osmflat::OsmBuilder,
+    stringtable: &mut StringTable,
+) -> Result<(), Error> {
+    let old_header = old.header();
+    let old_strings = old.stringtable();
+    let mut header = osmflat::Header::new();
+    header.fill_from(old_header);
+
+    let string = |idx: u64| old_strings.substring(idx as usize);
+    header.set_writingprogram_idx(stringtable.insert(string(old_header.writingprogram_idx())?)?);
+    header.set_source_idx(stringtable.insert(string(old_header.source_idx())?)?);
+    header.set_replication_base_url_idx(
+        stringtable.insert(string(old_header.replication_base_url_idx())?)?,
+    );
+
+    header.set_replication_sequence_number(
+        sequence_number.unwrap_or(old_header.replication_sequence_number() + 1),
+    );
+    if let Some(timestamp) = change.timestamp {
+        header.set_replication_timestamp(timestamp.max(old_header.replication_timestamp()));
+    }
+    if let Some(area) = clip_area {
+        let (west, south, east, north) = area.bounds();
+        let coord_scale = old_header.coord_scale() as f64;
+        let scaled = |degrees: f64| (degrees * coord_scale).round() as i32;
+        header.set_bbox_left(scaled(west));
+        header.set_bbox_right(scaled(east));
+        header.set_bbox_top(scaled(north));
+        header.set_bbox_bottom(scaled(south));
+    }
+
+    builder.set_header(&header)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest;
+
+    const OSC: &str = r#"<?xml version='1.0' encoding='UTF-8'?>
+<osmChange version="0.6" generator="osmosis">
+  <modify>
+    <node id="137747" version="2" timestamp="2022-11-01T10:00:00Z" lat="37.05" lon="-122.03">
+      <tag k="amenity" v="bus_station"/>
+    </node>
+  </modify>
+  <delete>
+    <node id="137750" version="3" timestamp="2022-11-02T10:00:00Z"/>
+  </delete>
+  <create>
+    <node id="1" version="1" timestamp="2022-11-01T12:00:00Z" lat="36.95" lon="-122.02"/>
+    <way id="2" version="1" timestamp="2022-11-01T12:00:00Z">
+      <nd ref="137752"/>
+      <nd ref="1"/>
+      <nd ref="137750"/>
+      <tag k="highway" v="footway"/>
+    </way>
+    <relation id="3" version="1" timestamp="2022-11-01T12:00:00Z">
+      <member type="way" ref="2" role="outer"/>
+      <member type="node" ref="137747" role="label"/>
+    </relation>
+  </create>
+</osmChange>
+"#;
+
+    #[test]
+    fn test_parse_osc() {
+        let change = OsmChange::parse(OSC.as_bytes()).unwrap();
+        assert_eq!(change.nodes.len(), 3);
+        assert_eq!(change.nodes[&137750], None);
+        let node = change.nodes[&137747].as_ref().unwrap();
+        assert_eq!(node.lat, 37.05);
+        assert_eq!(
+            node.tags,
+            vec![("amenity".to_string(), "bus_station".to_string())]
+        );
+        let way = change.ways[&2].as_ref().unwrap();
+        assert_eq!(way.refs, vec![137752, 1, 137750]);
+        let relation = change.relations[&3].as_ref().unwrap();
+        assert_eq!(
+            relation.members[0],
+            (MemberType::Way, 2, "outer".to_string())
+        );
+        assert_eq!(change.timestamp, Some(1667383200));
+    }
+
+    #[test]
+    fn test_update_nodes4() {
+        let manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("sort.update");
+        fs::create_dir_all(&dir).unwrap();
+        let osc = tmp.path().join("test.osc");
+        fs::write(&osc, OSC).unwrap();
+        let old = osmflat::Osm::open(FileResourceStorage::new(&manifest.data.planet)).unwrap();
+
+        let (osm, diff) = update(&manifest, &osc, &dir, Some(42)).unwrap();
+
+        // 137754 and 137752 are kept, 137747 is modified and 1 is created.
+        let nodes = osm.nodes();
+        assert_eq!(nodes.len(), 4);
+        let ids: Vec<i64> = nodes.iter().map(|n| n.osm_id()).collect();
+        assert_eq!(&ids[2..], &[1, 137747]);
+        assert!(ids[..2].contains(&137752) && ids[..2].contains(&137754));
+        assert_eq!(nodes[3].lat(), 370_500_000);
+        assert_eq!(nodes[3].tags().end - nodes[3].tags().start, 1);
+        assert_eq!(osm.hilbert_node_pairs().unwrap().len(), 4);
+
+        let kept = nodes.iter().find(|n| n.osm_id() == 137754).unwrap();
+        let original = old.nodes().iter().find(|n| n.osm_id() == 137754).unwrap();
+        assert_eq!(kept.lat(), original.lat());
+        assert_eq!(
+            kept.tags().end - kept.tags().start,
+            original.tags().end - original.tags().start
+        );
+
+        let way = &osm.ways()[0];
+        let refs: Vec<Option<u64>> = way
+            .refs()
+            .map(|i| osm.nodes_index()[i as usize].value())
+            .collect();
+        let idx_137752 = ids.iter().position(|id| *id == 137752).unwrap() as u64;
+        assert_eq!(refs, vec![Some(idx_137752), Some(2), None]);
+
+        let relation = &osm.relations()[0];
+        let members = &osm.members()[relation.members().start as usize..];
+        assert_eq!(members[0].idx(), Some(0));
+        assert_eq!(members[1].idx(), Some(3));
+
+        let header = osm.header();
+        assert_eq!(header.replication_sequence_number(), 42);
+        assert_eq!(header.replication_timestamp(), 1667383200);
+        assert_eq!(header.coord_scale(), old.header().coord_scale());
+
+        let mut removed: Vec<i64> = diff
+            .nodes
+            .removed()
+            .map(|i| old.nodes()[i as usize].osm_id())
+            .collect();
+        removed.sort();
+        assert_eq!(removed, vec![137747, 137750]);
+        let mut changed: Vec<u64> = diff.nodes.changed().collect();
+        changed.sort();
+        assert_eq!(changed, vec![2, 3]);
+        assert_eq!(diff.ways.changed().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn test_update_clip() {
+        let mut manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        manifest.data.clip_bbox = Some([-122.1, 36.9, -122.0, 37.0]);
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("sort.update");
+        fs::create_dir_all(&dir).unwrap();
+        let osc = tmp.path().join("test.osc");
+        // Way 9 has a node inside of the area, so it is kept along with node 8, and
+        // relation 11 is kept with it, along with its member node 12. Way 7 and its
+        // nodes, node 4, relation 10 and the moved bus station are outside of it.
+        fs::write(
+            &osc,
+            r#"<?xml version='1.0' encoding='UTF-8'?>
+<osmChange version="0.6" generator="osmosis">
+  <modify>
+    <node id="137747" version="2" timestamp="2022-11-01T10:00:00Z" lat="37.2" lon="-122.03"/>
+  </modify>
+  <create>
+    <node id="4" version="1" timestamp="2022-11-01T12:00:00Z" lat="37.5" lon="-122.05"/>
+    <node id="5" version="1" timestamp="2022-11-01T12:00:00Z" lat="37.6" lon="-122.05"/>
+    <node id="6" version="1" timestamp="2022-11-01T12:00:00Z" lat="37.7" lon="-122.05"/>
+    <node id="8" version="1" timestamp="2022-11-01T12:00:00Z" lat="37.6" lon="-122.05"/>
+    <node id="12" version="1" timestamp="2022-11-01T12:00:00Z" lat="37.8" lon="-122.05"/>
+    <way id="7" version="1" timestamp="2022-11-01T12:00:00Z">
+      <nd ref="5"/>
+      <nd ref="6"/>
+    </way>
+    <way id="9" version="1" timestamp="2022-11-01T12:00:00Z">
+      <nd ref="137752"/>
+      <nd ref="8"/>
+    </way>
+    <relation id="10" version="1" timestamp="2022-11-01T12:00:00Z">
+      <member type="node" ref="4" role=""/>
+      <member type="way" ref="7" role=""/>
+    </relation>
+    <relation id="11" version="1" timestamp="2022-11-01T12:00:00Z">
+      <member type="way" ref="9" role=""/>
+      <member type="node" ref="12" role=""/>
+    </relation>
+  </create>
+</osmChange>
+"#,
+        )
+        .unwrap();
+
+        let (osm, _) = update(&manifest, &osc, &dir, None).unwrap();
+
+        let mut node_ids: Vec<i64> = osm.nodes().iter().map(|n| n.osm_id()).collect();
+        node_ids.sort();
+        assert_eq!(node_ids, vec![8, 12, 137750, 137752, 137754]);
+        let way_ids: Vec<i64> = osm.ways().iter().map(|w| w.osm_id()).collect();
+        assert_eq!(way_ids, vec![9]);
+        let relation_ids: Vec<i64> = osm.relations().iter().map(|r| r.osm_id()).collect();
+        assert_eq!(relation_ids, vec![11]);
+
+        let header = osm.header();
+        let coord_scale = header.coord_scale() as f64;
+        assert_eq!(header.bbox_left(), (-122.1 * coord_scale).round() as i32);
+        assert_eq!(header.bbox_top(), (37.0 * coord_scale).round() as i32);
+    }
+
+    #[test]
+    fn test_update_dangling() {
+        let mut manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        manifest.data.dangling_ways = DanglingWays::Truncate;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("sort.update");
+        fs::create_dir_all(&dir).unwrap();
+        let osc = tmp.path().join("test.osc");
+        // Way 2 keeps two nodes without the deleted lighthouse, so it is truncated. Way 5
+        // only keeps one, so it is dropped, and is missing from relation 3.
+        fs::write(
+            &osc,
+            r#"<?xml version='1.0' encoding='UTF-8'?>
+<osmChange version="0.6" generator="osmosis">
+  <delete>
+    <node id="137750" version="3" timestamp="2022-11-02T10:00:00Z"/>
+  </delete>
+  <create>
+    <node id="1" version="1" timestamp="2022-11-01T12:00:00Z" lat="36.95" lon="-122.02"/>
+    <way id="2" version="1" timestamp="2022-11-01T12:00:00Z">
+      <nd ref="137752"/>
+      <nd ref="1"/>
+      <nd ref="137750"/>
+    </way>
+    <way id="5" version="1" timestamp="2022-11-01T12:00:00Z">
+      <nd ref="137754"/>
+      <nd ref="99"/>
+    </way>
+    <relation id="3" version="1" timestamp="2022-11-01T12:00:00Z">
+      <member type="way" ref="2" role="outer"/>
+      <member type="way" ref="5" role="outer"/>
+    </relation>
+  </create>
+</osmChange>
+"#,
+        )
+        .unwrap();
+
+        let (osm, _) = update(&manifest, &osc, &dir, None).unwrap();
+
+        let ways = osm.ways();
+        assert_eq!(ways.len(), 1);
+        assert_eq!(ways[0].osm_id(), 2);
+        assert_eq!(ways[0].refs().end - ways[0].refs().start, 2);
+        let members = &osm.members()[osm.relations()[0].members().start as usize..];
+        assert_eq!(members[1].idx(), None);
+
+        let dangling = Dangling::read(&dir).unwrap();
+        assert_eq!(dangling.ways[&2], vec![137750]);
+        assert_eq!(dangling.ways[&5], vec![99]);
+        assert_eq!(dangling.truncated_ways, vec![2]);
+        assert_eq!(dangling.dropped_ways, vec![5]);
+        assert_eq!(dangling.relations[&3].ways, vec![5]);
+    }
+}
@@ -9,6 +9,7 @@ use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::{fs, ops::Range};
 
 use crate::{
+    archive::Archive,
     manifest::{IncludeTags, Manifest},
     osmflat::osmflat_generated::osm::Osm,
     util,
@@ -61,6 +62,18 @@ impl Rules {
         rules
     }
 
+    pub fn open_archive(archive: &Archive, manifest: &Manifest) -> Self {
+        let Some(bytes) = archive.get("rules.yaml") else {
+            println!("No rules in {}. Using default.", archive.path.display());
+            return Rules::default(manifest);
+        };
+        let Ok(rules) = serde_yaml::from_slice(bytes) else {
+            println!("Unable to parse rules in {}. Using default.", archive.path.display());
+            return Rules::default(manifest);
+        };
+        rules
+    }
+
     pub fn default(manifest: &Manifest) -> Self {
         Rules {
             evals: vec![RuleEval {
@@ -1,10 +1,11 @@
 use crate::{
-    location,
+    external_sort, label, location, manifest,
     mutant::Mutant,
     osmflat::osmflat_generated::osm::{
-        EntityType, HilbertNodePair, HilbertRelationPair, HilbertWayPair, Member, Node, NodeIndex,
-        Osm, Relation, TagIndex, Way,
+        EntityType, HilbertNodePair, HilbertRelationPair, HilbertWayPair, Member, Metadata, Node,
+        NodeIndex, Osm, Relation, TagIndex, Way,
     },
+    osmflat::tags,
     util::{self, finish, timer},
 };
 use crossbeam::queue::SegQueue;
@@ -18,11 +19,16 @@ use std::{
     fs,
     io::{Error, ErrorKind, Stdout},
     panic,
-    path::PathBuf,
+    path::Path,
     time::Instant,
 };
 
-pub fn sort_flatdata(flatdata: Osm, dir: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+/// Sorts the planet along the Hilbert curve. Areas are located at their label point,
+/// which is found to within the label_precision of the manifest. Hilbert pairs that do
+/// not fit in its sort_memory_mb are sorted in runs, which are merged.
+pub fn sort_flatdata(flatdata: Osm, data: &manifest::Data) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = &data.planet;
+    let sort_memory = data.sort_memory_mb * 1_000_000;
     match flatdata.hilbert_node_pairs() {
         Some(p) => p,
         None => {
@@ -39,25 +45,26 @@ pub fn sort_flatdata(flatdata: Osm, dir: &PathBuf) -> Result<(), Box<dyn std::er
     let m_way_pairs = Mutant::<HilbertWayPair>::new(dir, "hilbert_way_pairs", ways_len)?;
     // let m_way_pairs = Mutant::<HilbertWayPair>::open(dir, "hilbert_way_pairs", true).unwrap();
     let way_pairs = m_way_pairs.mutable_slice();
-    build_hilbert_way_pairs(way_pairs, &flatdata)?;
+    let label_precision = location::meters_to_xy(data.label_precision);
+    build_hilbert_way_pairs(way_pairs, &flatdata, label_precision)?;
 
     // Build hilbert relation pairs
     let relations_len = flatdata.relations().len();
     let m_relation_pairs =
         Mutant::<HilbertRelationPair>::new(dir, "hilbert_relation_pairs", relations_len)?;
-    build_hilbert_relation_pairs(&m_way_pairs, &m_relation_pairs, &flatdata)?;
+    build_hilbert_relation_pairs(&m_way_pairs, &m_relation_pairs, &flatdata, label_precision)?;
 
     // Sort hilbert node pairs.
     let t = util::timer("Sorting hilbert node pairs.");
     let nodes_len = flatdata.nodes().len();
     let node_pairs_mut = Mutant::<HilbertNodePair>::open(dir, "hilbert_node_pairs", true)?;
     let node_pairs = node_pairs_mut.mutable_slice();
-    node_pairs.par_sort_unstable_by_key(|idx| idx.h());
+    external_sort::sort_by_key(&node_pairs_mut, sort_memory, |idx| idx.h())?;
     finish(t);
 
     // Sort hilbert way pairs.
     let t = util::timer("Sorting hilbert way pairs.");
-    way_pairs.par_sort_unstable_by_key(|idx| idx.h());
+    external_sort::sort_by_key(&m_way_pairs, sort_memory, |idx| idx.h())?;
     finish(t);
 
     // Reorder nodes to sorted hilbert node pairs.
@@ -102,8 +109,9 @@ pub fn sort_flatdata(flatdata: Osm, dir: &PathBuf) -> Result<(), Box<dyn std::er
     let nodes_index_len = nodes_index.len();
     for i in 0..nodes_index_len {
         let node_index = &mut nodes_index[i];
-        let old_i = node_index.value().unwrap() as usize;
-        let new_i = old_node_idx[old_i];
+        // Dangling refs stay invalid.
+        let Some(old_i) = node_index.value() else { continue; };
+        let new_i = old_node_idx[old_i as usize];
         node_index.set_value(Some(new_i as u64));
         pb.tick(i);
     }
@@ -224,6 +232,20 @@ pub fn sort_flatdata(flatdata: Osm, dir: &PathBuf) -> Result<(), Box<dyn std::er
         };
     });
 
+    // Reorder the optional metadata of nodes, ways and relations.
+    reorder_metadata(flatdata.node_metadata(), dir, "node_metadata", |i| {
+        node_pairs[i].i() as usize
+    })?;
+    reorder_metadata(flatdata.way_metadata(), dir, "way_metadata", |i| {
+        old_way_idx[i] as usize
+    })?;
+    reorder_metadata(
+        flatdata.relation_metadata(),
+        dir,
+        "relation_metadata",
+        |i| old_relation_idx[i] as usize,
+    )?;
+
     // Remove temporary old index arrays.
     let old_node_idx_path = m_old_node_idx.path.clone();
     let _ = fs::remove_file(old_node_idx_path);
@@ -248,9 +270,31 @@ pub fn sort_flatdata(flatdata: Osm, dir: &PathBuf) -> Result<(), Box<dyn std::er
     Ok(())
 }
 
+// Nothing else reads the metadata, so it is moved into place right away.
+fn reorder_metadata(
+    metadata: Option<&[Metadata]>,
+    dir: &Path,
+    name: &str,
+    old_idx: impl Fn(usize) -> usize + Sync,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(metadata) = metadata else { return Ok(()); };
+    let t = timer(&format!("Reordering {}.", name));
+    let mut m_sorted = Mutant::<Metadata>::new_from_flatdata(dir, &format!("sorted_{}", name), name)?;
+    m_sorted
+        .mutable_slice()
+        .par_iter_mut()
+        .take(metadata.len())
+        .enumerate()
+        .for_each(|(i, m)| m.fill_from(&metadata[old_idx(i)]));
+    m_sorted.mv(name)?;
+    finish(t);
+    Ok(())
+}
+
 fn build_hilbert_way_pairs(
     way_pairs: &mut [HilbertWayPair],
     flatdata: &Osm,
+    label_precision: f64,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let nodes = flatdata.nodes();
     let nodes_index = flatdata.nodes_index();
@@ -284,12 +328,14 @@ fn build_hilbert_way_pairs(
         }
 
         let mut coords = Vec::<Coord<f64>>::with_capacity(len as usize);
+        let mut ring = Vec::<u64>::with_capacity(len as usize);
 
         for r in refs {
             if let Some(idx) = nodes_index[r as usize].value() {
                 let node = &nodes[idx as usize];
                 // georust lib requires f64 for a coordinate.
                 coords.push(coord! { x: node.lon() as f64, y: node.lat() as f64 });
+                ring.push(idx);
             };
         }
 
@@ -309,20 +355,28 @@ fn build_hilbert_way_pairs(
             return;
         }
 
+        // Areas are located at their label point. Closed ways are areas unless their tags
+        // make them lines, such as roundabouts, which are located on the line instead.
+        let is_area = ring.first() == ring.last() && tags::is_area(flatdata, way.tags());
+        if is_area {
+            if let Some(h) = label_h(&[ring], nodes, label_precision) {
+                pair.set_i(i as u32);
+                pair.set_h(h);
+                return;
+            }
+        }
+
         // Calculate point on surface.
         // http://libgeos.org/doxygen/classgeos_1_1algorithm_1_1InteriorPointArea.html
         // https://docs.rs/geo/latest/geo/algorithm/interior_point/trait.InteriorPoint.html
         // https://github.com/georust/geo/blob/main/geo/src/algorithm/interior_point.rs
 
-        // NHTODO https://crates.io/crates/polylabel
-
         let point_on_surface_res = panic::catch_unwind(|| {
-            let point_on_surface = if coords.first() == coords.last() {
+            if is_area {
                 Polygon::new(LineString::new(coords), vec![]).interior_point()
             } else {
                 LineString::new(coords).interior_point()
-            };
-            point_on_surface
+            }
         });
 
         let point_on_surface = match point_on_surface_res {
@@ -390,6 +444,7 @@ fn build_hilbert_relation_pairs(
     m_way_pairs: &Mutant<HilbertWayPair>,
     m_relation_pairs: &Mutant<HilbertRelationPair>,
     flatdata: &Osm,
+    label_precision: f64,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let t = timer("Building hilbert relation pairs.");
 
@@ -403,6 +458,10 @@ fn build_hilbert_relation_pairs(
 
     let relations = flatdata.relations();
     let members = flatdata.members();
+    let nodes = flatdata.nodes();
+    let nodes_index = flatdata.nodes_index();
+    let ways = flatdata.ways();
+    let tags_index_len = flatdata.tags_index().len();
 
     let compute_relation_h = |relation_i: usize, relation: &Relation| {
         let relation_pair = &mut m_relation_pairs.mutable_slice()[relation_i];
@@ -419,6 +478,33 @@ fn build_hilbert_relation_pairs(
             members.len()
         };
 
+        // Multipolygons are located at their label point, when their ways make up rings.
+        let tags_end = if relation_i + 1 < relations.len() {
+            relations[relation_i + 1].tag_first_idx()
+        } else {
+            tags_index_len as u64
+        };
+        let tags_range = relation.tag_first_idx()..tags_end;
+        if tags::has_tag(flatdata, tags_range, b"type", b"multipolygon") {
+            let lines = members[members_start..members_end]
+                .iter()
+                .filter(|m| m.entity_type() == EntityType::Way)
+                .filter_map(|m| m.idx())
+                .map(|idx| {
+                    let refs = ways[idx as usize].refs();
+                    nodes_index[refs.start as usize..refs.end as usize]
+                        .iter()
+                        .filter_map(|r| r.value())
+                        .collect()
+                })
+                .collect();
+            let rings = label::assemble_rings(lines);
+            if let Some(h) = label_h(&rings, nodes, label_precision) {
+                relation_pair.set_h(h);
+                return;
+            }
+        }
+
         let mut missing_member = false;
         for member in &members[members_start..members_end] {
             let idx = member.idx();
@@ -490,6 +576,25 @@ fn build_hilbert_relation_pairs(
     Ok(())
 }
 
+// The Hilbert location of the label point of an area, given the node indexes of its rings.
+// The label point is found in Web Mercator, where the labels are shown.
+fn label_h(rings: &[Vec<u64>], nodes: &[Node], precision: f64) -> Option<u64> {
+    let rings: Vec<Vec<(f64, f64)>> = rings
+        .iter()
+        .map(|ring| {
+            ring.iter()
+                .map(|idx| {
+                    let node = &nodes[*idx as usize];
+                    let (x, y) = location::lonlat_to_xy((node.lon(), node.lat()));
+                    (x as f64, y as f64)
+                })
+                .collect()
+        })
+        .collect();
+    let (x, y) = label::polylabel(&rings, precision)?;
+    Some(location::xy_to_h((x as u32, y as u32)))
+}
+
 struct Prog {
     prog_counter: usize,
     pb: ProgressBar<Stdout>,
@@ -522,6 +627,7 @@ impl Prog {
 #[cfg(test)]
 mod tests {
     use flatdata::FileResourceStorage;
+    use std::path::PathBuf;
 
     use super::*;
 
@@ -550,6 +656,101 @@ mod tests {
         assert_eq!(w_lat, 36.9000422);
     }
 
+    #[test]
+    fn test_reorder_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest::parse("tests/fixtures/relations_convert.yaml").unwrap();
+        manifest.data.planet = dir.path().to_path_buf();
+        manifest.data.include_metadata = true;
+
+        // The metadata of every entity by its OSM id, before and after sorting.
+        let by_id = |flatdata: &Osm| {
+            let strings = flatdata.stringtable();
+            let entry = |osm_id: i64, m: &Metadata| {
+                let user = strings.substring(m.user_idx() as usize).unwrap();
+                let metadata = (m.version(), m.timestamp(), m.changeset(), user.to_string());
+                (osm_id, metadata)
+            };
+            let nodes: Vec<_> = flatdata
+                .nodes()
+                .iter()
+                .zip(flatdata.node_metadata().unwrap())
+                .map(|(n, m)| entry(n.osm_id(), m))
+                .collect();
+            let ways: Vec<_> = flatdata
+                .ways()
+                .iter()
+                .zip(flatdata.way_metadata().unwrap())
+                .map(|(w, m)| entry(w.osm_id(), m))
+                .collect();
+            let relations: Vec<_> = flatdata
+                .relations()
+                .iter()
+                .zip(flatdata.relation_metadata().unwrap())
+                .map(|(r, m)| entry(r.osm_id(), m))
+                .collect();
+            (nodes, ways, relations)
+        };
+
+        let flatdata = crate::osmflat::convert(&manifest).unwrap();
+        let (mut nodes, mut ways, mut relations) = by_id(&flatdata);
+        sort_flatdata(flatdata, &manifest.data).unwrap();
+
+        let sorted = Osm::open(FileResourceStorage::new(dir.path())).unwrap();
+        let (sorted_nodes, sorted_ways, sorted_relations) = by_id(&sorted);
+        // The planet is in a different order, but every entity kept its metadata.
+        assert_ne!(sorted_nodes, nodes);
+        for (entities, mut sorted) in [
+            (&mut nodes, sorted_nodes),
+            (&mut ways, sorted_ways),
+            (&mut relations, sorted_relations),
+        ] {
+            entities.sort();
+            sorted.sort();
+            assert_eq!(&sorted, entities);
+        }
+    }
+
+    #[test]
+    fn test_way_locations() {
+        let dir = PathBuf::from("tests/fixtures/relations/sort");
+        let flatdata = Osm::open(FileResourceStorage::new(&dir)).unwrap();
+        let m_way_pairs = Mutant::<HilbertWayPair>::open(&dir, "hilbert_way_pairs", true).unwrap();
+        let way_pairs = m_way_pairs.slice();
+        let nodes = flatdata.nodes();
+        let nodes_index = flatdata.nodes_index();
+
+        // The Hilbert location of a way, and the locations of its nodes.
+        let locations = |osm_id: i64| {
+            let i = flatdata
+                .ways()
+                .iter()
+                .position(|w| w.osm_id() == osm_id)
+                .unwrap();
+            let refs = flatdata.ways()[i].refs();
+            let node_hs: Vec<u64> = nodes_index[refs.start as usize..refs.end as usize]
+                .iter()
+                .filter_map(|r| r.value())
+                .map(|idx| {
+                    let node = &nodes[idx as usize];
+                    location::lonlat_to_h((node.lon(), node.lat()))
+                })
+                .collect();
+            (way_pairs[i].h(), node_hs)
+        };
+
+        // The museum is located at its label point, inside of it.
+        let (h, node_hs) = locations(100);
+        assert!(!node_hs.contains(&h));
+        let (lon, lat) = location::xy_to_decimal_lonlat(location::h_to_xy(h));
+        assert!((-122.03..-122.029).contains(&lon));
+        assert!((36.96..36.961).contains(&lat));
+
+        // The roundabout is a line, so it is located on the line.
+        let (h, node_hs) = locations(101);
+        assert!(node_hs.contains(&h));
+    }
+
     #[test]
     fn test_tags_index() {
         let dir = PathBuf::from("tests/fixtures/santa_cruz/sort");
@@ -587,7 +788,7 @@ mod tests {
         let flatdata = Osm::open(FileResourceStorage::new(&dir)).unwrap();
         let m_way_pairs = Mutant::<HilbertWayPair>::open(&dir, "hilbert_way_pairs", true).unwrap();
         let way_pairs = m_way_pairs.mutable_slice();
-        let _ = build_hilbert_way_pairs(way_pairs, &flatdata);
+        let _ = build_hilbert_way_pairs(way_pairs, &flatdata, location::meters_to_xy(10.0));
     }
 
     #[test]
@@ -604,7 +805,13 @@ mod tests {
         let m_relation_pairs =
             Mutant::<HilbertRelationPair>::new(&dir, "hilbert_relation_pairs", len).unwrap();
 
-        build_hilbert_relation_pairs(&m_way_pairs, &m_relation_pairs, &flatdata).unwrap();
+        build_hilbert_relation_pairs(
+            &m_way_pairs,
+            &m_relation_pairs,
+            &flatdata,
+            location::meters_to_xy(10.0),
+        )
+        .unwrap();
         println!("after");
         for p in m_relation_pairs.slice() {
             println!("h {} i {}", p.h(), p.i());
@@ -0,0 +1,256 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::manifest::Manifest;
+
+// A planet is built in stages. When a stage is done, it writes a marker into the stages
+// directory of the planet, with a hash of the inputs it was built from. The hash of a stage
+// includes the marker of the stage before it, so a stage is only done as long as every
+// stage before it is done for the same inputs as well. `pvt build --resume` skips the
+// stages that are done, and starts from the first one that is not.
+
+type Err = Box<dyn std::error::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    // Converts the sources to flatdata and sorts it. Sorting reorders the converted
+    // flatdata in place, so a sort that failed half way can only be redone from the
+    // sources, and the two make up a single stage.
+    Convert,
+    // Builds the leaves and tiles of the Hilbert tree.
+    Tree,
+    // Renders the tile content according to the rules.
+    Render,
+    // Packs the planet into its .pvt archive.
+    Archive,
+}
+
+impl Stage {
+    const ALL: [Stage; 4] = [Stage::Convert, Stage::Tree, Stage::Render, Stage::Archive];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Convert => "convert",
+            Stage::Tree => "tree",
+            Stage::Render => "render",
+            Stage::Archive => "archive",
+        }
+    }
+
+    fn previous(&self) -> Option<Stage> {
+        match self {
+            Stage::Convert => None,
+            Stage::Tree => Some(Stage::Convert),
+            Stage::Render => Some(Stage::Tree),
+            Stage::Archive => Some(Stage::Render),
+        }
+    }
+}
+
+/// The completion markers of the stages of a planet.
+pub struct Stages<'a> {
+    manifest: &'a Manifest,
+    dir: PathBuf,
+}
+
+impl<'a> Stages<'a> {
+    pub fn new(manifest: &'a Manifest) -> Self {
+        Self {
+            manifest,
+            dir: manifest.data.planet.join("stages"),
+        }
+    }
+
+    /// Runs a stage. With `resume`, a stage that is already done for the same inputs
+    /// is skipped.
+    pub fn run(
+        &self,
+        stage: Stage,
+        resume: bool,
+        f: impl FnOnce() -> Result<(), Err>,
+    ) -> Result<(), Err> {
+        if resume && self.is_done(stage) {
+            println!("Skipping the {} stage, it is already done.", stage.name());
+            return Ok(());
+        }
+        self.start(stage)?;
+        f()?;
+        self.finish(stage)
+    }
+
+    pub fn is_done(&self, stage: Stage) -> bool {
+        let Some(marker) = self.marker(stage) else {
+            return false;
+        };
+        if stage == Stage::Archive && !self.manifest.data.archive.exists() {
+            return false;
+        }
+        marker == self.input_hash(stage)
+    }
+
+    // Running a stage changes its output, so its marker and the markers of the stages
+    // after it no longer hold, even when the stage fails.
+    fn start(&self, stage: Stage) -> Result<(), Err> {
+        for later in Stage::ALL.iter().filter(|s| **s >= stage) {
+            let path = self.path(*later);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&self, stage: Stage) -> Result<(), Err> {
+        // Converting can start out by removing the planet directory, stages and all.
+        fs::create_dir_all(&self.dir)?;
+        let hash = self.input_hash(stage);
+        fs::write(self.path(stage), format!("{:016x}\n", hash))?;
+        Ok(())
+    }
+
+    fn path(&self, stage: Stage) -> PathBuf {
+        self.dir.join(stage.name())
+    }
+
+    fn marker(&self, stage: Stage) -> Option<u64> {
+        let s = fs::read_to_string(self.path(stage)).ok()?;
+        u64::from_str_radix(s.trim(), 16).ok()
+    }
+
+    fn input_hash(&self, stage: Stage) -> u64 {
+        let previous = stage.previous().and_then(|s| self.marker(s)).unwrap_or(0);
+        let inputs = match stage {
+            Stage::Convert => convert_inputs(self.manifest),
+            Stage::Tree => format!("leaf_zoom: {}", self.manifest.render.leaf_zoom),
+            Stage::Render => render_inputs(self.manifest),
+            Stage::Archive => self.manifest.data.archive.display().to_string(),
+        };
+        fnv1a(format!("{:016x}\n{}", previous, inputs).as_bytes())
+    }
+}
+
+// How far tiles are overzoomed only matters when serving them.
+fn render_inputs(manifest: &Manifest) -> String {
+    let mut render = manifest.render.clone();
+    render.max_overzoom = 0;
+    serde_yaml::to_string(&(&render, &manifest.layers, &manifest.rules)).unwrap_or_default()
+}
+
+// The data section of the manifest, without the settings that do not change the planet,
+// along with the size and modification time of the source files, rather than their
+// content, which would take as long to hash as to convert.
+fn convert_inputs(manifest: &Manifest) -> String {
+    let mut data = manifest.data.clone();
+    data.archive = PathBuf::new();
+    data.include_leaves.clear();
+    data.sort_memory_mb = 0;
+    let mut inputs = serde_yaml::to_string(&data).unwrap_or_default();
+    for path in data.source.iter().chain(data.clip_polygon.iter()) {
+        inputs.push_str(&file_stamp(path));
+    }
+    inputs
+}
+
+fn file_stamp(path: &Path) -> String {
+    let Ok(metadata) = fs::metadata(path) else {
+        return format!("{}: missing\n", path.display());
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("{}: {} {}\n", path.display(), metadata.len(), modified)
+}
+
+// FNV-1a, since the markers have to stay the same across builds of pvt,
+// which the hashers of std do not promise.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest;
+
+    fn manifest(planet: &Path) -> Manifest {
+        let mut manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        manifest.data.planet = planet.to_path_buf();
+        manifest
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest(dir.path());
+        let stages = Stages::new(&manifest);
+
+        for stage in [Stage::Convert, Stage::Tree, Stage::Render] {
+            stages.run(stage, true, || Ok(())).unwrap();
+            assert!(stages.is_done(stage));
+        }
+
+        // Done stages are skipped.
+        stages
+            .run(Stage::Tree, true, || Err("ran again".into()))
+            .unwrap();
+
+        // A failed stage is not done, and neither is any stage after it.
+        assert!(stages
+            .run(Stage::Tree, false, || Err("failed".into()))
+            .is_err());
+        assert!(stages.is_done(Stage::Convert));
+        assert!(!stages.is_done(Stage::Tree));
+        assert!(!stages.is_done(Stage::Render));
+    }
+
+    #[test]
+    fn test_rules_only_change_render() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest(dir.path());
+        {
+            let stages = Stages::new(&manifest);
+            for stage in [Stage::Convert, Stage::Tree, Stage::Render] {
+                stages.run(stage, false, || Ok(())).unwrap();
+            }
+        }
+
+        manifest.render.max_overzoom = 14;
+        let stages = Stages::new(&manifest);
+        assert!(stages.is_done(Stage::Render));
+
+        manifest.rules.remove("water");
+        let stages = Stages::new(&manifest);
+        assert!(stages.is_done(Stage::Convert));
+        assert!(stages.is_done(Stage::Tree));
+        assert!(!stages.is_done(Stage::Render));
+
+        manifest.render.leaf_zoom = 10;
+        let stages = Stages::new(&manifest);
+        assert!(stages.is_done(Stage::Convert));
+        assert!(!stages.is_done(Stage::Tree));
+    }
+
+    #[test]
+    fn test_convert_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest(dir.path());
+        let inputs = convert_inputs(&manifest);
+        assert!(inputs.contains("nodes4.osm.pbf"));
+
+        manifest.data.archive = PathBuf::from("elsewhere.pvt");
+        manifest.data.sort_memory_mb = 1;
+        assert_eq!(convert_inputs(&manifest), inputs);
+
+        manifest.data.drop_tags.push("name".to_string());
+        assert_ne!(convert_inputs(&manifest), inputs);
+    }
+}
@@ -23,12 +23,15 @@ use planet_vector_tile_generated::*;
 const U32_SIZE: f64 = u32::MAX as f64 + 1_f64;
 
 // https://github.com/maplibre/maplibre-gl-js/blob/9aabd047281ac94c246a8ebedb850ff1133a0407/src/data/extent.ts#L16
-const TILE_EXTENT: f64 = 8192_f64;
+pub const TILE_EXTENT: f64 = 8192_f64;
 
 // https://github.com/maplibre/maplibre-gl-js/blob/9aabd047281ac94c246a8ebedb850ff1133a0407/src/data/load_geometry.ts#L12-L14
 const TILE_MAX: f64 = 16383_f64;
 const TILE_MIN: f64 = -16384_f64;
 
+// A point in tile space, before it is rounded to a tile point.
+type Point = (f64, f64);
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Tile {
     pub z: u8,
@@ -296,23 +299,7 @@ impl Tile {
 
     // Projects a point from location space to tile space.
     pub fn project(&self, loc: (u32, u32)) -> PVTTilePoint {
-        // location in planet resolution
-        let loc_x = loc.0 as f64;
-        let loc_y = loc.1 as f64;
-
-        // where coord is between 0 -> 1 for planet space
-        let unit_x = loc_x / U32_SIZE;
-        let unit_y = loc_y / U32_SIZE;
-
-        let resolution = self.axis_tile_count() * TILE_EXTENT;
-        let tile_x = unit_x * resolution;
-        let tile_y = unit_y * resolution;
-
-        let origin_x = self.x as f64 * TILE_EXTENT;
-        let origin_y = self.y as f64 * TILE_EXTENT;
-
-        let mut x = tile_x - origin_x;
-        let mut y = tile_y - origin_y;
+        let (mut x, mut y) = self.project_f64(loc);
 
         // TODO: Provide offset around the tile bounds for clamping.
 
@@ -333,6 +320,51 @@ impl Tile {
         PVTTilePoint::new(x as i16, y as i16)
     }
 
+    /// Projects a path into the tile, clipped to the coordinates that a tile point can
+    /// hold. Clamping the points instead bends the parts of the path that leave the tile,
+    /// which shows once the tile is much smaller than the path, such as when overzooming.
+    /// A closed path is clipped as a ring, and an open path can be cut into several lines.
+    pub fn project_clipped(&self, locs: &[(u32, u32)]) -> Vec<Vec<PVTTilePoint>> {
+        let points: Vec<Point> = locs.iter().map(|loc| self.project_f64(*loc)).collect();
+        let parts = if points.len() >= 4 && points.first() == points.last() {
+            let ring = clip_ring(&points);
+            if ring.len() >= 4 {
+                vec![ring]
+            } else {
+                vec![]
+            }
+        } else {
+            clip_line(&points)
+        };
+        parts
+            .into_iter()
+            .map(|part| {
+                part.into_iter()
+                    .map(|(x, y)| PVTTilePoint::new(x as i16, y as i16))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn project_f64(&self, loc: (u32, u32)) -> Point {
+        // location in planet resolution
+        let loc_x = loc.0 as f64;
+        let loc_y = loc.1 as f64;
+
+        // where coord is between 0 -> 1 for planet space
+        let unit_x = loc_x / U32_SIZE;
+        let unit_y = loc_y / U32_SIZE;
+
+        let resolution = self.axis_tile_count() * TILE_EXTENT;
+        let tile_x = unit_x * resolution;
+        let tile_y = unit_y * resolution;
+
+        let origin_x = self.x as f64 * TILE_EXTENT;
+        let origin_y = self.y as f64 * TILE_EXTENT;
+
+        (tile_x - origin_x, tile_y - origin_y)
+    }
+
     pub fn hilbert_bearing(&self) -> HilbertBearing {
         let hilbert_order_max: u32 = 1_u32 << self.z;
 
@@ -430,6 +462,125 @@ pub fn axis_tile_count(z: u8) -> f64 {
     }
 }
 
+// Clips a closed ring to the tile point extent, one edge at a time (Sutherland-Hodgman).
+fn clip_ring(ring: &[Point]) -> Vec<Point> {
+    let mut clipped = ring[..ring.len() - 1].to_vec();
+    for edge in 0..4 {
+        let points = std::mem::take(&mut clipped);
+        let Some(mut prev) = points.last().copied() else {
+            break;
+        };
+        for p in points {
+            if inside_edge(edge, p) {
+                if !inside_edge(edge, prev) {
+                    clipped.push(intersect_edge(edge, prev, p));
+                }
+                clipped.push(p);
+            } else if inside_edge(edge, prev) {
+                clipped.push(intersect_edge(edge, prev, p));
+            }
+            prev = p;
+        }
+    }
+    if let Some(first) = clipped.first().copied() {
+        clipped.push(first);
+    }
+    clipped
+}
+
+fn inside_edge(edge: u8, p: Point) -> bool {
+    match edge {
+        0 => p.0 >= TILE_MIN,
+        1 => p.0 <= TILE_MAX,
+        2 => p.1 >= TILE_MIN,
+        _ => p.1 <= TILE_MAX,
+    }
+}
+
+fn intersect_edge(edge: u8, a: Point, b: Point) -> Point {
+    match edge {
+        0 | 1 => {
+            let x = if edge == 0 { TILE_MIN } else { TILE_MAX };
+            (x, a.1 + (b.1 - a.1) * (x - a.0) / (b.0 - a.0))
+        }
+        _ => {
+            let y = if edge == 2 { TILE_MIN } else { TILE_MAX };
+            (a.0 + (b.0 - a.0) * (y - a.1) / (b.1 - a.1), y)
+        }
+    }
+}
+
+// Clips a line to the tile point extent, a segment at a time (Liang-Barsky).
+// The line is cut into several wherever it leaves the extent.
+fn clip_line(points: &[Point]) -> Vec<Vec<Point>> {
+    if points.len() < 2 {
+        return match points.first() {
+            Some(p) if (0..4).all(|edge| inside_edge(edge, *p)) => vec![vec![*p]],
+            _ => vec![],
+        };
+    }
+    let mut lines = Vec::new();
+    let mut line: Vec<Point> = Vec::new();
+    for segment in points.windows(2) {
+        let Some((a, b, enters, leaves)) = clip_segment(segment[0], segment[1]) else {
+            continue;
+        };
+        if enters || line.is_empty() {
+            if line.len() >= 2 {
+                lines.push(std::mem::take(&mut line));
+            }
+            line.clear();
+            line.push(a);
+        }
+        line.push(b);
+        if leaves {
+            lines.push(std::mem::take(&mut line));
+        }
+    }
+    if line.len() >= 2 {
+        lines.push(line);
+    }
+    lines
+}
+
+// The part of a segment within the tile point extent, and whether the segment enters
+// or leaves the extent.
+fn clip_segment(a: Point, b: Point) -> Option<(Point, Point, bool, bool)> {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let (mut t0, mut t1) = (0_f64, 1_f64);
+    for (p, q) in [
+        (-dx, a.0 - TILE_MIN),
+        (dx, TILE_MAX - a.0),
+        (-dy, a.1 - TILE_MIN),
+        (dy, TILE_MAX - a.1),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+            continue;
+        }
+        let r = q / p;
+        if p < 0.0 {
+            if r > t1 {
+                return None;
+            }
+            t0 = t0.max(r);
+        } else {
+            if r < t0 {
+                return None;
+            }
+            t1 = t1.min(r);
+        }
+    }
+    Some((
+        (a.0 + t0 * dx, a.1 + t0 * dy),
+        (a.0 + t1 * dx, a.1 + t1 * dy),
+        t0 > 0.0,
+        t1 < 1.0,
+    ))
+}
+
 pub fn tile_count_for_zoom(z: u8) -> u128 {
     if z == 0 {
         1_u128
@@ -459,6 +610,22 @@ impl BBox {
     pub fn nw(&self) -> (u32, u32) {
         self.nw
     }
+    pub fn contains(&self, loc: (u32, u32)) -> bool {
+        self.nw.0 <= loc.0 && loc.0 <= self.se.0 && self.nw.1 <= loc.1 && loc.1 <= self.se.1
+    }
+    // Whether the bounding box of the given locations overlaps this one.
+    pub fn overlaps(&self, locs: &[(u32, u32)]) -> bool {
+        let (mut min, mut max) = ((u32::MAX, u32::MAX), (0, 0));
+        for loc in locs {
+            min = (min.0.min(loc.0), min.1.min(loc.1));
+            max = (max.0.max(loc.0), max.1.max(loc.1));
+        }
+        !locs.is_empty()
+            && min.0 <= self.se.0
+            && self.nw.0 <= max.0
+            && min.1 <= self.se.1
+            && self.nw.1 <= max.1
+    }
     pub fn sw(&self) -> (u32, u32) {
         (self.nw.0, self.se.1)
     }
@@ -596,6 +763,42 @@ mod tests {
         assert_eq!(b3.nw.1, 0);
         assert_eq!(b3.se.0, 4294967295);
         assert_eq!(b3.se.1, 2147483647);
+
+        assert!(b3.contains((2147483648, 0)));
+        assert!(!b3.contains((2147483647, 0)));
+        assert!(b3.overlaps(&[(0, 10), (2147483648, 10)]));
+        assert!(!b3.overlaps(&[(0, 10), (10, 2147483648)]));
+        assert!(!b3.overlaps(&[]));
+    }
+
+    #[test]
+    fn test_project_clipped() {
+        // A tile is 2^28 apart at z4, which is 32768 per tile point.
+        let t = Tile::from_zxy(4, 2, 2);
+        let (x, y) = (1 << 29, 1 << 29);
+        let far = 1 << 30;
+
+        // A line out of the tile and back in is cut in two at the edge of the extent.
+        let line = t.project_clipped(&[(x, y), (far, y), (x, y + 32768)]);
+        assert_eq!(line.len(), 2);
+        assert_eq!(line[0].len(), 2);
+        assert_eq!((line[0][1].x(), line[0][1].y()), (16383, 0));
+        assert_eq!((line[1][0].x(), line[1][0].y()), (16383, 0));
+        assert_eq!((line[1][1].x(), line[1][1].y()), (0, 1));
+
+        // A ring around the tile is clipped to the square of the extent.
+        let ring = t.project_clipped(&[(0, 0), (far, 0), (far, far), (0, far), (0, 0)]);
+        assert_eq!(ring.len(), 1);
+        assert_eq!(ring[0].len(), 5);
+        assert_eq!(ring[0].first(), ring[0].last());
+        assert!(ring[0]
+            .iter()
+            .all(|p| p.x().abs() >= 16383 && p.y().abs() >= 16383));
+
+        // Paths outside of the extent are left out.
+        assert!(t.project_clipped(&[(far, far), (far + 1, far)]).is_empty());
+        assert!(t.project_clipped(&[(far, far)]).is_empty());
+        assert_eq!(t.project_clipped(&[(x, y)]).len(), 1);
     }
 
     #[test]
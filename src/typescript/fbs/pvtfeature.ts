@@ -84,8 +84,13 @@ export class PVTFeature {
         return offset ? this.bb!.__vector_len(this.bb_pos + offset) : 0;
     }
 
+    area(): boolean {
+        const offset = this.bb!.__offset(this.bb_pos, 14);
+        return offset ? !!this.bb!.readInt8(this.bb_pos + offset) : false;
+    }
+
     static startPVTFeature(builder: flatbuffers.Builder) {
-        builder.startObject(4);
+        builder.startObject(6);
     }
 
     static addId(builder: flatbuffers.Builder, id: bigint) {
@@ -156,6 +161,10 @@ export class PVTFeature {
         builder.startVector(4, numElems, 4);
     }
 
+    static addArea(builder: flatbuffers.Builder, area: boolean) {
+        builder.addFieldInt8(5, +area, +false);
+    }
+
     static endPVTFeature(builder: flatbuffers.Builder): flatbuffers.Offset {
         const offset = builder.endObject();
         return offset;
@@ -166,13 +175,15 @@ export class PVTFeature {
         id: bigint,
         keysOffset: flatbuffers.Offset,
         valuesOffset: flatbuffers.Offset,
-        geometriesOffset: flatbuffers.Offset
+        geometriesOffset: flatbuffers.Offset,
+        area: boolean
     ): flatbuffers.Offset {
         PVTFeature.startPVTFeature(builder);
         PVTFeature.addId(builder, id);
         PVTFeature.addKeys(builder, keysOffset);
         PVTFeature.addValues(builder, valuesOffset);
         PVTFeature.addGeometries(builder, geometriesOffset);
+        PVTFeature.addArea(builder, area);
         return PVTFeature.endPVTFeature(builder);
     }
 }
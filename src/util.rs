@@ -1,5 +1,6 @@
 use chrono::Local;
-use std::time::Instant;
+use fs_extra::dir::{copy, CopyOptions};
+use std::{path::Path, time::Instant};
 
 pub fn timer(msg: &str) -> Instant {
     let time = Instant::now();
@@ -11,3 +12,15 @@ pub fn timer(msg: &str) -> Instant {
 pub fn finish(t: Instant) {
     println!("Finished in {} secs.", t.elapsed().as_secs());
 }
+
+/// Copies the contents of a planet dir into another dir, such as a sorted fixture into
+/// a tempdir for a test that writes to it.
+// Only the fixtures binary and the tests copy planets, the pvt binary does not.
+#[allow(dead_code)]
+pub fn copy_planet(from: &Path, to: &Path) -> Result<u64, fs_extra::error::Error> {
+    let opts = CopyOptions {
+        content_only: true,
+        ..Default::default()
+    };
+    copy(from, to, &opts)
+}
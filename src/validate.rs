@@ -0,0 +1,700 @@
+use rayon::prelude::*;
+
+use crate::{
+    hilbert::{compose::LABEL_LAYER, tree::HilbertTree},
+    manifest::Manifest,
+    osmflat::osmflat_generated::osm::{EntityType, Osm},
+    rules::IncludeTagIdxs,
+    tile::tile_count_for_zoom,
+    util,
+};
+
+// Checks the invariants that rendering and serving a planet rely on: that the flatdata
+// ranges are ordered, that the Hilbert pairs are sorted, that the leaves and tiles of the
+// tree agree with each other, that the tile content indexes entities that exist, and that
+// rules.yaml was built from the stringtable of the planet. `pvt validate` reports every
+// check, and fails when any of them does.
+
+type Err = Box<dyn std::error::Error>;
+
+// The failures of a check that are listed in the report. The rest are only counted.
+const EXAMPLES: usize = 10;
+
+pub struct Check {
+    pub name: String,
+    // How many items were checked.
+    pub count: usize,
+    pub failures: usize,
+    pub examples: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Validation {
+    pub checks: Vec<Check>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.checks.iter().all(|c| c.failures == 0)
+    }
+
+    pub fn print(&self) {
+        for check in &self.checks {
+            if check.failures == 0 {
+                println!("  ok      {} ({})", check.name, check.count);
+                continue;
+            }
+            println!(
+                "  FAILED  {} ({} of {})",
+                check.name, check.failures, check.count
+            );
+            for example in &check.examples {
+                println!("            {}", example);
+            }
+            if check.failures > check.examples.len() {
+                println!(
+                    "            ...and {} more",
+                    check.failures - check.examples.len()
+                );
+            }
+        }
+        let failed = self.checks.iter().filter(|c| c.failures > 0).count();
+        if failed == 0 {
+            println!("All {} checks passed.", self.checks.len());
+        } else {
+            println!("{} of {} checks FAILED.", failed, self.checks.len());
+        }
+    }
+
+    // Checks the items 0..count, which fail when f describes what is wrong with them.
+    fn check(&mut self, name: &str, count: usize, f: impl Fn(usize) -> Option<String> + Sync) {
+        let failures = (0..count)
+            .into_par_iter()
+            .filter(|i| f(*i).is_some())
+            .count();
+        let examples = if failures > 0 {
+            (0..count).filter_map(&f).take(EXAMPLES).collect()
+        } else {
+            Vec::new()
+        };
+        self.checks.push(Check {
+            name: name.to_string(),
+            count,
+            failures,
+            examples,
+        });
+    }
+
+    // Records a check that was done in one pass, along with everything it found wrong.
+    // An item can have more than one problem, so the failures are counted separately.
+    fn record(&mut self, name: &str, count: usize, failures: usize, problems: Vec<String>) {
+        self.checks.push(Check {
+            name: name.to_string(),
+            count,
+            failures,
+            examples: problems.into_iter().take(EXAMPLES).collect(),
+        });
+    }
+}
+
+/// Validates the rendered planet directory of the manifest.
+pub fn validate(manifest: &Manifest) -> Result<Validation, Err> {
+    let t = util::timer(&format!(
+        "Validating planet {}",
+        manifest.data.planet.display()
+    ));
+    let tree = HilbertTree::open(manifest)?;
+    let mut v = Validation::default();
+    check_flatdata(&mut v, &tree.flatdata);
+    check_pairs(&mut v, &tree);
+    check_leaves(&mut v, &tree);
+    check_tiles(&mut v, &tree);
+    check_content(&mut v, &tree);
+    check_rules(&mut v, &tree, manifest);
+    util::finish(t);
+    Ok(v)
+}
+
+fn check_flatdata(v: &mut Validation, flatdata: &Osm) {
+    let nodes = flatdata.nodes();
+    let ways = flatdata.ways();
+    let relations = flatdata.relations();
+    let members = flatdata.members();
+    let tags = flatdata.tags();
+    let tags_index = flatdata.tags_index();
+    let nodes_index = flatdata.nodes_index();
+    let strings = flatdata.stringtable().as_bytes();
+
+    check_ranges(
+        v,
+        "node",
+        nodes.len(),
+        "tags_index",
+        tags_index.len(),
+        |i| nodes[i].tag_first_idx(),
+    );
+    check_ranges(v, "way", ways.len(), "tags_index", tags_index.len(), |i| {
+        ways[i].tag_first_idx()
+    });
+    check_ranges(
+        v,
+        "way",
+        ways.len(),
+        "nodes_index",
+        nodes_index.len(),
+        |i| ways[i].ref_first_idx(),
+    );
+    check_ranges(
+        v,
+        "relation",
+        relations.len(),
+        "tags_index",
+        tags_index.len(),
+        |i| relations[i].tag_first_idx(),
+    );
+    check_ranges(
+        v,
+        "relation",
+        relations.len(),
+        "members",
+        members.len(),
+        |i| relations[i].member_first_idx() as u64,
+    );
+
+    v.check("nodes_index refs are nodes", nodes_index.len(), |i| {
+        let idx = nodes_index[i].value()?;
+        (idx as usize >= nodes.len())
+            .then(|| format!("nodes_index {}: node {} of {}", i, idx, nodes.len()))
+    });
+    v.check("tags_index refs are tags", tags_index.len(), |i| {
+        let idx = tags_index[i].value();
+        (idx as usize >= tags.len())
+            .then(|| format!("tags_index {}: tag {} of {}", i, idx, tags.len()))
+    });
+    v.check("tags are in the stringtable", tags.len(), |i| {
+        let (k, v) = (tags[i].key_idx(), tags[i].value_idx());
+        (!is_string(strings, k) || !is_string(strings, v)).then(|| {
+            format!(
+                "tag {}: key {} or value {} does not start a string",
+                i, k, v
+            )
+        })
+    });
+    v.check("members are entities", members.len(), |i| {
+        let member = &members[i];
+        let role = member.role_idx();
+        if !is_string(strings, role) {
+            return Some(format!(
+                "member {}: role {} does not start a string",
+                i, role
+            ));
+        }
+        // Members that are missing from the planet do not have an index.
+        let idx = member.idx()? as usize;
+        let (entity, len) = match member.entity_type() {
+            EntityType::Node => ("node", nodes.len()),
+            EntityType::Way => ("way", ways.len()),
+            EntityType::Relation => ("relation", relations.len()),
+            _ => return Some(format!("member {}: unknown entity type", i)),
+        };
+        (idx >= len).then(|| format!("member {}: {} {} of {}", i, entity, idx, len))
+    });
+}
+
+// Checks that every entity starts its range where the entity before it ends, and
+// that the ranges stay within the vector they index.
+fn check_ranges(
+    v: &mut Validation,
+    entity: &str,
+    len: usize,
+    target: &str,
+    target_len: usize,
+    first_idx: impl Fn(usize) -> u64 + Sync,
+) {
+    let name = format!("{} {} ranges are ordered", entity, target);
+    v.check(&name, len, |i| {
+        let start = first_idx(i);
+        let end = if i + 1 < len {
+            first_idx(i + 1)
+        } else {
+            target_len as u64
+        };
+        if start > end {
+            Some(format!(
+                "{} {}: {} range {}..{} is reversed",
+                entity, i, target, start, end
+            ))
+        } else if end > target_len as u64 {
+            Some(format!(
+                "{} {}: {} range {}..{} is beyond its length of {}",
+                entity, i, target, start, end, target_len
+            ))
+        } else {
+            None
+        }
+    });
+}
+
+// Strings are null terminated in the stringtable, and referenced by their first byte.
+fn is_string(strings: &[u8], idx: u64) -> bool {
+    let i = idx as usize;
+    i < strings.len() && (i == 0 || strings[i - 1] == 0)
+}
+
+fn check_pairs(v: &mut Validation, tree: &HilbertTree) {
+    let flatdata = &tree.flatdata;
+    let nodes_len = flatdata.nodes().len();
+    let ways_len = flatdata.ways().len();
+    let relations_len = flatdata.relations().len();
+
+    let node_pairs = flatdata.hilbert_node_pairs().unwrap_or(&[]);
+    v.check("hilbert node pairs are one per node", 1, |_| {
+        (node_pairs.len() != nodes_len)
+            .then(|| format!("{} pairs for {} nodes", node_pairs.len(), nodes_len))
+    });
+    v.check(
+        "hilbert node pairs are sorted",
+        node_pairs.len().saturating_sub(1),
+        |i| {
+            let (a, b) = (node_pairs[i].h(), node_pairs[i + 1].h());
+            (a > b).then(|| format!("node pair {}: h {} is after h {}", i + 1, b, a))
+        },
+    );
+    v.check("hilbert node pairs are nodes", node_pairs.len(), |i| {
+        let idx = node_pairs[i].i();
+        (idx as usize >= nodes_len)
+            .then(|| format!("node pair {}: node {} of {}", i, idx, nodes_len))
+    });
+
+    let way_pairs = tree.way_pairs.slice();
+    v.check("hilbert way pairs are one per way", 1, |_| {
+        (way_pairs.len() != ways_len)
+            .then(|| format!("{} pairs for {} ways", way_pairs.len(), ways_len))
+    });
+    v.check(
+        "hilbert way pairs are sorted",
+        way_pairs.len().saturating_sub(1),
+        |i| {
+            let (a, b) = (way_pairs[i].h(), way_pairs[i + 1].h());
+            (a > b).then(|| format!("way pair {}: h {} is after h {}", i + 1, b, a))
+        },
+    );
+    v.check("hilbert way pairs are ways", way_pairs.len(), |i| {
+        let idx = way_pairs[i].i();
+        (idx as usize >= ways_len).then(|| format!("way pair {}: way {} of {}", i, idx, ways_len))
+    });
+
+    // Relations are ordered by the relations they contain, rather than by h.
+    let relation_pairs = tree.relation_pairs.slice();
+    v.check("hilbert relation pairs are one per relation", 1, |_| {
+        (relation_pairs.len() != relations_len).then(|| {
+            format!(
+                "{} pairs for {} relations",
+                relation_pairs.len(),
+                relations_len
+            )
+        })
+    });
+    v.check(
+        "hilbert relation pairs are relations",
+        relation_pairs.len(),
+        |i| {
+            let idx = relation_pairs[i].i();
+            (idx as usize >= relations_len)
+                .then(|| format!("relation pair {}: relation {} of {}", i, idx, relations_len))
+        },
+    );
+}
+
+fn check_leaves(v: &mut Validation, tree: &HilbertTree) {
+    let leaf_zoom = tree.manifest.render.leaf_zoom;
+    let leaves = tree.leaves.slice();
+    let external_ways = tree.leaves_external_ways.slice();
+    let external_relations = tree.leaves_external_relations.slice();
+    let ways_len = tree.flatdata.ways().len();
+    let relations_len = tree.flatdata.relations().len();
+
+    v.check("leaves exist", 1, |_| {
+        leaves
+            .is_empty()
+            .then(|| "the tree has no leaves".to_string())
+    });
+
+    let tile_count = tile_count_for_zoom(leaf_zoom);
+    v.check("leaf h are increasing", leaves.len(), |i| {
+        let h = leaves[i].h;
+        if h as u128 >= tile_count {
+            return Some(format!(
+                "leaf {}: h {} is beyond the {} tiles of z{}",
+                i, h, tile_count, leaf_zoom
+            ));
+        }
+        let previous = leaves[i.checked_sub(1)?].h;
+        (h <= previous).then(|| format!("leaf {}: h {} is not after h {}", i, h, previous))
+    });
+
+    // The first entity of every kind in each leaf, and how many there are of them.
+    let lens = [
+        ("n", tree.flatdata.nodes().len()),
+        ("w", ways_len),
+        ("r", relations_len),
+        ("w_ext", external_ways.len()),
+        ("r_ext", external_relations.len()),
+    ];
+    let fields = |i: usize| {
+        let leaf = leaves[i];
+        [
+            leaf.n as usize,
+            leaf.w as usize,
+            leaf.r as usize,
+            leaf.w_ext as usize,
+            leaf.r_ext as usize,
+        ]
+    };
+    v.check("leaf entity ranges are ordered", leaves.len(), |i| {
+        let current = fields(i);
+        let previous = match i {
+            0 => [0; 5],
+            _ => fields(i - 1),
+        };
+        for (f, (name, len)) in lens.iter().enumerate() {
+            if current[f] > *len {
+                return Some(format!(
+                    "leaf {}: {} {} is beyond its length of {}",
+                    i, name, current[f], len
+                ));
+            }
+            if current[f] < previous[f] || (i == 0 && current[f] != 0) {
+                return Some(format!(
+                    "leaf {}: {} {} is before {}, where the previous leaf starts",
+                    i, name, current[f], previous[f]
+                ));
+            }
+        }
+        None
+    });
+
+    v.check("external leaf ways are ways", external_ways.len(), |i| {
+        let idx = external_ways[i];
+        (idx as usize >= ways_len)
+            .then(|| format!("external way {}: way {} of {}", i, idx, ways_len))
+    });
+    v.check(
+        "external leaf relations are relations",
+        external_relations.len(),
+        |i| {
+            let idx = external_relations[i];
+            (idx as usize >= relations_len).then(|| {
+                format!(
+                    "external relation {}: relation {} of {}",
+                    i, idx, relations_len
+                )
+            })
+        },
+    );
+}
+
+// The tiles are stored a level at a time, from the parents of the leaves up to z0.
+// The children of a tile start at its child index, and are as many as the bits set in
+// its mask, which are at the positions of the children within the tile.
+fn check_tiles(v: &mut Validation, tree: &HilbertTree) {
+    let leaf_zoom = tree.manifest.render.leaf_zoom;
+    let tiles = tree.tiles.slice();
+    let leaves = tree.leaves.slice();
+    let mut problems = Vec::new();
+    let mut failed_tiles = 0;
+
+    // The tiles or leaves of the level below, and their h at their own zoom.
+    let mut below = 0..leaves.len();
+    let mut below_hs: Vec<u64> = leaves.iter().map(|leaf| leaf.h).collect();
+    let mut i = 0;
+    let mut z = leaf_zoom;
+    while z >= 2 && !below_hs.is_empty() {
+        z -= 2;
+        let level_start = i;
+        let mut level_hs = Vec::new();
+        let mut child = below.start;
+        while child < below.end && i < tiles.len() {
+            let tile = tiles[i];
+            let (tile_child, mask) = (tile.child as usize, tile.mask);
+            let tile_problems = problems.len();
+            if tile_child != child {
+                problems.push(format!(
+                    "tile {} at z{}: first child is {}, but the tile before ends at {}",
+                    i, z, tile_child, child
+                ));
+            }
+            if mask == 0 {
+                problems.push(format!("tile {} at z{}: mask has no children", i, z));
+            }
+            let children = child..(child + mask.count_ones() as usize).min(below.end);
+            let h = below_hs[child - below.start] >> 4;
+            let mut children_mask: u16 = 0;
+            for c in children.clone() {
+                let child_h = below_hs[c - below.start];
+                if child_h >> 4 != h {
+                    problems.push(format!(
+                        "tile {} at z{}: child {} with h {} is not within h {}",
+                        i, z, c, child_h, h
+                    ));
+                }
+                children_mask |= 1 << (child_h & 0xf);
+            }
+            if children_mask != mask {
+                problems.push(format!(
+                    "tile {} at z{}: mask {:016b} does not match its children {:016b}",
+                    i, z, mask, children_mask
+                ));
+            }
+            if problems.len() > tile_problems {
+                failed_tiles += 1;
+            }
+            level_hs.push(h);
+            child = children.end.max(child + 1);
+            i += 1;
+        }
+        if child != below.end {
+            problems.push(format!(
+                "z{}: masks count {} children, but the level below has {}",
+                z,
+                child - below.start,
+                below.len()
+            ));
+        }
+        below = level_start..i;
+        below_hs = level_hs;
+    }
+    if z != 0 || below.len() != 1 {
+        problems.push(format!(
+            "z{}: the top level of the tree has {} tiles, rather than a single z0 tile",
+            z,
+            below.len()
+        ));
+    }
+    if i != tiles.len() {
+        problems.push(format!(
+            "{} tiles are left over above z0",
+            tiles.len().saturating_sub(i)
+        ));
+    }
+    // A level can be wrong as a whole, without any one of its tiles being wrong.
+    let failures = failed_tiles.max(usize::from(!problems.is_empty()));
+    v.record(
+        "tile masks match their children",
+        tiles.len(),
+        failures,
+        problems,
+    );
+}
+
+fn check_content(v: &mut Validation, tree: &HilbertTree) {
+    let tiles = tree.tiles.slice();
+    let n = tree.n.slice();
+    let w = tree.w.slice();
+    let r = tree.r.slice();
+    let nodes_len = tree.flatdata.nodes().len();
+    let ways_len = tree.flatdata.ways().len();
+    let relations_len = tree.flatdata.relations().len();
+
+    let lens = [("n", n.len()), ("w", w.len()), ("r", r.len())];
+    let fields = |i: usize| {
+        let tile = tiles[i];
+        [tile.n as usize, tile.w as usize, tile.r as usize]
+    };
+    v.check("tile content ranges are ordered", tiles.len(), |i| {
+        let current = fields(i);
+        let previous = match i {
+            0 => [0; 3],
+            _ => fields(i - 1),
+        };
+        for (f, (name, len)) in lens.iter().enumerate() {
+            if current[f] > *len {
+                return Some(format!(
+                    "tile {}: {} {} is beyond its length of {}",
+                    i, name, current[f], len
+                ));
+            }
+            if current[f] < previous[f] || (i == 0 && current[f] != 0) {
+                return Some(format!(
+                    "tile {}: {} {} is before {}, where the previous tile starts",
+                    i, name, current[f], previous[f]
+                ));
+            }
+        }
+        None
+    });
+
+    v.check("tile content n are nodes", n.len(), |i| {
+        (n[i] as usize >= nodes_len).then(|| format!("n {}: node {} of {}", i, n[i], nodes_len))
+    });
+    v.check("tile content w are ways", w.len(), |i| {
+        (w[i] as usize >= ways_len).then(|| format!("w {}: way {} of {}", i, w[i], ways_len))
+    });
+    v.check("tile content r are relations", r.len(), |i| {
+        (r[i] as usize >= relations_len)
+            .then(|| format!("r {}: relation {} of {}", i, r[i], relations_len))
+    });
+}
+
+// rules.yaml refers to the strings and tags of the planet by index, so it only holds
+// for the stringtable it was built from. The strings it points to have to be the keys,
+// values and tags of the rules of the manifest.
+fn check_rules(v: &mut Validation, tree: &HilbertTree, manifest: &Manifest) {
+    let path = manifest.data.planet.join("rules.yaml");
+    v.check("rules.yaml exists", 1, |_| {
+        (!path.exists()).then(|| format!("{} is missing", path.display()))
+    });
+
+    let rules = &tree.rules;
+    let strings = tree.flatdata.stringtable();
+    let bytes = strings.as_bytes();
+    let tags = tree.flatdata.tags();
+
+    let mut layers = vec!["no_rule".to_string()];
+    layers.extend(manifest.render.layer_order.iter().cloned());
+    v.check("rules layers match the layer order", 1, |_| {
+        (rules.layers != layers).then(|| {
+            format!(
+                "rules.yaml has layers {:?}, the manifest {:?}",
+                rules.layers, layers
+            )
+        })
+    });
+
+    v.check("the label layer is not a rules layer", 1, |_| {
+        rules.layers.iter().any(|l| l == LABEL_LAYER).then(|| {
+            format!(
+                "the layer {} would be mixed with the label points of areas",
+                LABEL_LAYER
+            )
+        })
+    });
+
+    let evals = &rules.evals;
+    v.check("rules are in the manifest", evals.len(), |i| {
+        let eval = &evals[i];
+        if eval.name != "no_rule" && !manifest.rules.contains_key(&eval.name) {
+            return Some(format!("rule {} is not in the manifest", eval.name));
+        }
+        if let Some(layer) = eval.layers.iter().find(|l| **l >= rules.layers.len()) {
+            return Some(format!(
+                "rule {}: layer {} of {}",
+                eval.name,
+                layer,
+                rules.layers.len()
+            ));
+        }
+        if let IncludeTagIdxs::Keys(keys) = &eval.include {
+            if let Some(k) = keys.iter().find(|k| !is_string(bytes, **k as u64)) {
+                return Some(format!(
+                    "rule {}: include key {} does not start a string",
+                    eval.name, k
+                ));
+            }
+        }
+        None
+    });
+
+    // The string at an index of rules.yaml, and the manifest rule it belongs to.
+    let string_rule = |kind: &str, idx: usize, eval_i: usize| {
+        if !is_string(bytes, idx as u64) {
+            return Err(format!("{} {} does not start a string", kind, idx));
+        }
+        let s = strings.substring_lossy(idx);
+        let Some(eval) = evals.get(eval_i) else {
+            return Err(format!(
+                "{} {}: rule {} of {}",
+                kind,
+                s,
+                eval_i,
+                evals.len()
+            ));
+        };
+        match manifest.rules.get(&eval.name) {
+            Some(rule) => Ok((s, rule)),
+            None => Err(format!(
+                "{} {}: rule {} is not in the manifest",
+                kind, s, eval.name
+            )),
+        }
+    };
+
+    let keys: Vec<(usize, usize)> = rules.keys.iter().map(|(k, e)| (*k, *e)).collect();
+    v.check("rules keys are in the stringtable", keys.len(), |i| {
+        let (idx, eval_i) = keys[i];
+        match string_rule("key", idx, eval_i) {
+            Ok((s, rule)) => (!rule.keys.iter().any(|k| *k == s))
+                .then(|| format!("key {} is not a key of rule {}", s, evals[eval_i].name)),
+            Err(e) => Some(e),
+        }
+    });
+
+    let values: Vec<(usize, usize)> = rules.values.iter().map(|(k, e)| (*k, *e)).collect();
+    v.check("rules values are in the stringtable", values.len(), |i| {
+        let (idx, eval_i) = values[i];
+        match string_rule("value", idx, eval_i) {
+            Ok((s, rule)) => (!rule.values.iter().any(|v| *v == s))
+                .then(|| format!("value {} is not a value of rule {}", s, evals[eval_i].name)),
+            Err(e) => Some(e),
+        }
+    });
+
+    let rule_tags: Vec<(usize, usize)> = rules.tags.iter().map(|(t, e)| (*t, *e)).collect();
+    v.check("rules tags are tags", rule_tags.len(), |i| {
+        let (idx, eval_i) = rule_tags[i];
+        let Some(tag) = tags.get(idx) else {
+            return Some(format!("tag {} of {}", idx, tags.len()));
+        };
+        match string_rule("tag key", tag.key_idx() as usize, eval_i) {
+            Ok((k, rule)) => {
+                let v = strings.substring_lossy(tag.value_idx() as usize);
+                (!rule.tags.iter().any(|(rk, rv)| *rk == k && *rv == v)).then(|| {
+                    format!(
+                        "tag {}={} is not a tag of rule {}",
+                        k, v, evals[eval_i].name
+                    )
+                })
+            }
+            Err(e) => Some(e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manifest, util::copy_planet};
+
+    #[test]
+    fn test_nodes4() {
+        let manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        let v = validate(&manifest).unwrap();
+        v.print();
+        assert!(v.is_valid());
+    }
+
+    #[test]
+    fn test_broken_leaves() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest::parse("tests/fixtures/nodes4_sort.yaml").unwrap();
+        copy_planet(&manifest.data.planet, dir.path()).unwrap();
+        manifest.data.planet = dir.path().to_path_buf();
+
+        let tree = HilbertTree::open(&manifest).unwrap();
+        let leaves = tree.leaves.mutable_slice();
+        let last = leaves.len() - 1;
+        leaves[last].h = leaves[0].h;
+        leaves[0].n = 1;
+
+        let v = validate(&manifest).unwrap();
+        assert!(!v.is_valid());
+        let failed: Vec<&str> = v
+            .checks
+            .iter()
+            .filter(|c| c.failures > 0)
+            .map(|c| c.name.as_str())
+            .collect();
+        assert!(failed.contains(&"leaf h are increasing"));
+        assert!(failed.contains(&"leaf entity ranges are ordered"));
+    }
+}